@@ -0,0 +1,409 @@
+//! Downloading blobs from Azure Blob Storage
+//!
+//! Blobs are addressed by their https url,
+//! `https://<account>.blob.core.windows.net/<container>/<blob>`, as
+//! `az://<account>.blob.core.windows.net/<container>/<blob>`, or as
+//! `az://<container>/<blob>` in the account named by `$AZURE_STORAGE_ACCOUNT`.
+//! Like any other download they are fetched in parallel segments, with each
+//! range also sent as `x-ms-range`, the header Blob Storage documents for
+//! ranged reads. Requests are authorized with the first of:
+//!
+//! 1. a SAS token already in the url's query
+//! 2. the account key of `$AZURE_STORAGE_KEY`, signing each request with
+//!    Shared Key
+//! 3. the SAS token of `$AZURE_STORAGE_SAS_TOKEN`, added to the query
+//!
+//! `$AZURE_STORAGE_CONNECTION_STRING` may give the `AccountName`,
+//! `AccountKey`, `SharedAccessSignature` and `BlobEndpoint` (e.g. of
+//! Azurite) instead. Keys and tokens only apply to their own account.
+//! Without any, requests are sent anonymously, which is enough for public
+//! containers.
+
+use chrono::{
+    DateTime,
+    Utc,
+};
+use checksum::{
+    decode_base64,
+    encode_base64,
+};
+use client::{
+    ClientConfig,
+    RequestSigner,
+};
+use cloud::{
+    env_var,
+    hmac_sha256,
+    host_header,
+    split_bucket_url,
+    uri_encode,
+};
+use hyper::Url;
+use hyper::header::Headers;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+
+
+/// Suffix of the hosts of accounts' blob endpoints
+const BLOB_HOST_SUFFIX: &'static str = ".blob.core.windows.net";
+
+/// Version of the Blob Storage REST API requested
+const API_VERSION: &'static str = "2021-08-06";
+
+/// Headers of the Shared Key string to sign, in order, before the
+/// `x-ms-*` headers
+const SIGNED_HEADERS: &'static [&'static str] = &[
+    "Content-Encoding", "Content-Language", "Content-Length", "Content-MD5", "Content-Type",
+    "Date", "If-Modified-Since", "If-Match", "If-None-Match", "If-Unmodified-Since", "Range",
+];
+
+/// Settings found in the environment, once looked for
+static SETTINGS: Mutex<Option<Settings>> = Mutex::new(None);
+
+/// The account, credentials and endpoint configured in the environment
+#[derive(Clone,Debug,Default)]
+struct Settings {
+    account: Option<String>,
+    /// The decoded account key
+    key: Option<Vec<u8>>,
+    /// A SAS token, without a leading `?`
+    sas: Option<String>,
+    /// The blob endpoint, if not the account's default
+    endpoint: Option<String>,
+}
+
+impl Settings {
+
+    fn find() -> Settings
+    {
+        let mut found = SETTINGS.lock().unwrap();
+        if found.is_none() {
+            *found = Some(Settings::lookup());
+        }
+        found.clone().unwrap()
+    }
+
+    fn lookup() -> Settings
+    {
+        let mut settings = Settings::default();
+        let mut key = None;
+        if let Some(connection) = env_var("AZURE_STORAGE_CONNECTION_STRING") {
+            for (name, value) in connection.split(';').filter_map(|part| {
+                part.find('=').map(|i| (part[..i].trim(), part[i + 1..].trim()))
+            }) {
+                match name {
+                    "AccountName" => settings.account = Some(value.to_owned()),
+                    "AccountKey" => key = Some(value.to_owned()),
+                    "SharedAccessSignature" => settings.sas = Some(value.to_owned()),
+                    "BlobEndpoint" => settings.endpoint = Some(value.to_owned()),
+                    _ => (),
+                }
+            }
+        }
+        settings.account = env_var("AZURE_STORAGE_ACCOUNT").or(settings.account);
+        settings.sas = env_var("AZURE_STORAGE_SAS_TOKEN").or(settings.sas)
+            .map(|sas| sas.trim_start_matches('?').to_owned());
+        if let Some(key) = env_var("AZURE_STORAGE_KEY").or(key) {
+            match decode_base64(key.trim()) {
+                Some(key) => settings.key = Some(key),
+                None => warn!("Ignoring the Azure storage account key, it isn't base64"),
+            }
+        }
+        if settings.key.is_some() && settings.account.is_none() {
+            warn!("Ignoring the Azure storage account key, no account is named");
+            settings.key = None;
+        }
+        if settings.key.is_none() && settings.sas.is_none() {
+            info!("No Azure storage credentials found, requesting blobs anonymously");
+        }
+        settings
+    }
+}
+
+/// The container and blob of an `az://` url
+#[derive(Clone,Debug,PartialEq)]
+pub struct AzUrl {
+    /// Host of the account's blob endpoint, if named by the url
+    pub host: Option<String>,
+    pub container: String,
+    pub blob: String,
+}
+
+impl AzUrl {
+
+    /// Parse `az://container/blob` or
+    /// `az://account.blob.core.windows.net/container/blob`. The blob is
+    /// taken as written, not decoded.
+    pub fn parse(url: &str) -> Result<AzUrl, String>
+    {
+        let (first, rest) = try!(split_bucket_url(url, "az"));
+        // Container names can't have dots, so a name with them is the host
+        let (host, container, blob) = if first.contains('.') {
+            match rest.find('/') {
+                Some(i) => (Some(first), rest[..i].to_owned(), rest[i + 1..].to_owned()),
+                None => (Some(first), rest, String::new()),
+            }
+        } else {
+            (None, first, rest)
+        };
+        if container.is_empty() {
+            return Err(format!("'{}' has no container", url))
+        }
+        Ok(AzUrl { host: host, container: container, blob: blob })
+    }
+
+    /// The https url of the blob
+    fn https_url(&self, settings: &Settings) -> Result<String, String>
+    {
+        let endpoint = match (&self.host, &settings.endpoint, &settings.account) {
+            (&Some(ref host), _, _) => format!("https://{}", host),
+            (&None, &Some(ref endpoint), _) => endpoint.trim_end_matches('/').to_owned(),
+            (&None, &None, &Some(ref account)) => format!("https://{}{}", account, BLOB_HOST_SUFFIX),
+            (&None, &None, &None) => return Err(
+                "no storage account, set $AZURE_STORAGE_ACCOUNT or use \
+                 az://<account>.blob.core.windows.net/<container>/<blob>".to_owned()),
+        };
+        Ok(format!("{}/{}/{}", endpoint, self.container, uri_encode(&*self.blob, false)))
+    }
+}
+
+/// Whether `url` is an https url of an account's blob endpoint
+pub fn is_blob_url(url: &str) -> bool
+{
+    Url::parse(url).ok().map_or(false, |url| {
+        url.scheme() == "https" && url.host_str().map_or(false, |host| {
+            host.to_lowercase().ends_with(BLOB_HOST_SUFFIX)
+        })
+    })
+}
+
+/// Adds the `x-ms-*` headers of the requests made to one account's blob
+/// endpoint, and signs them with Shared Key if its key is known
+pub struct AzureSigner {
+    /// Host (and port, if not the default) requests are signed for
+    host: String,
+    /// The account and its key
+    key: Option<(String, Vec<u8>)>,
+}
+
+impl AzureSigner {
+
+    pub fn new(url: &Url, key: Option<(String, Vec<u8>)>) -> AzureSigner
+    {
+        AzureSigner { host: host_header(url), key: key }
+    }
+}
+
+impl RequestSigner for AzureSigner {
+
+    fn signs(&self, url: &Url) -> bool
+    {
+        host_header(url) == self.host
+    }
+
+    /// Add `x-ms-version` and, for a ranged request, `x-ms-range`, then
+    /// `x-ms-date` and the Shared Key `Authorization` if there is a key,
+    /// signed now
+    fn sign(&self, method: &str, url: &Url, headers: &mut Headers)
+    {
+        self.sign_at(method, url, headers, Utc::now())
+    }
+}
+
+impl AzureSigner {
+
+    /// Sign a request as if it were made at `now`
+    fn sign_at(&self, method: &str, url: &Url, headers: &mut Headers, now: DateTime<Utc>)
+    {
+        headers.set_raw("x-ms-version", vec![API_VERSION.as_bytes().to_vec()]);
+        if let Some(range) = headers.get_raw("Range").map(|lines| lines.to_vec()) {
+            headers.set_raw("x-ms-range", range);
+        }
+        let (account, key) = match self.key {
+            Some((ref account, ref key)) => (account, key),
+            None => return,
+        };
+        let date = now.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        headers.set_raw("x-ms-date", vec![date.into_bytes()]);
+
+        let value = |name: &str| headers.get_raw(name).map(|lines| {
+            lines.iter().map(|line| String::from_utf8_lossy(line).trim().to_owned())
+                .collect::<Vec<_>>().join(",")
+        });
+        let mut string_to_sign = format!("{}\n", method);
+        for name in SIGNED_HEADERS {
+            // An empty body is signed as no Content-Length
+            let value = value(name).filter(|v| !(*name == "Content-Length" && v == "0"));
+            string_to_sign.push_str(&*format!("{}\n", value.unwrap_or_default()));
+        }
+        let mut ms_headers: Vec<(String, String)> = headers.iter()
+            .map(|header| header.name().to_lowercase())
+            .filter(|name| name.starts_with("x-ms-"))
+            .map(|name| {
+                let value = value(&*name).unwrap_or_default();
+                (name, value.split_whitespace().collect::<Vec<_>>().join(" "))
+            })
+            .collect();
+        ms_headers.sort();
+        for &(ref name, ref value) in &ms_headers {
+            string_to_sign.push_str(&*format!("{}:{}\n", name, value));
+        }
+        string_to_sign.push_str(&*canonical_resource(account, url));
+
+        let signature = encode_base64(&hmac_sha256(key, string_to_sign.as_bytes()));
+        headers.set_raw("Authorization",
+                        vec![format!("SharedKey {}:{}", account, signature).into_bytes()]);
+    }
+}
+
+/// The https url of a blob, given as an `az://` or https url, authorized
+/// with a SAS token if one applies, and `config` adding the blob headers to
+/// its requests and signing them if the account's key is known
+pub fn resolve(config: &ClientConfig, url: &str) -> Result<(Url, ClientConfig), String>
+{
+    let settings = Settings::find();
+    let https_url = if url.starts_with("az://") {
+        let az_url = try!(AzUrl::parse(url));
+        if az_url.blob.is_empty() {
+            return Err("no blob to download".to_owned())
+        }
+        try!(az_url.https_url(&settings))
+    } else {
+        url.to_owned()
+    };
+    let mut url = try!(Url::parse(&*https_url).map_err(|e| format!("invalid url {}: {}", https_url, e)));
+
+    let account = account(&url).or_else(|| settings.account.clone());
+    // Credentials of another account would be refused
+    let ours = settings.account.is_none() || account == settings.account;
+    let presigned = url.query_pairs().any(|(name, _)| name == "sig");
+    let key = match (settings.key, account) {
+        (Some(key), Some(account)) if ours && !presigned => Some((account, key)),
+        _ => None,
+    };
+    if let (Some(sas), true) = (settings.sas, ours && !presigned && key.is_none()) {
+        let query = match url.query() {
+            Some(query) if !query.is_empty() => format!("{}&{}", query, sas),
+            _ => sas,
+        };
+        url.set_query(Some(&*query));
+    }
+    let signer = AzureSigner::new(&url, key);
+    Ok((url, config.clone().request_signer(Arc::new(signer))))
+}
+
+/// The account of a url of its default blob endpoint
+fn account(url: &Url) -> Option<String>
+{
+    url.host_str().and_then(|host| {
+        let host = host.to_lowercase();
+        if host.ends_with(BLOB_HOST_SUFFIX) {
+            Some(host[..host.len() - BLOB_HOST_SUFFIX.len()].to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+/// The resource of a request to `url`, as signed by Shared Key: the
+/// account and path followed by each query parameter, sorted
+fn canonical_resource(account: &str, url: &Url) -> String
+{
+    let mut resource = format!("/{}{}", account, url.path());
+    let mut params: Vec<(String, Vec<String>)> = vec![];
+    for (name, value) in url.query_pairs() {
+        let name = name.to_lowercase();
+        match params.iter().position(|&(ref n, _)| *n == name) {
+            Some(i) => params[i].1.push(value.into_owned()),
+            None => params.push((name, vec![value.into_owned()])),
+        }
+    }
+    params.sort();
+    for (name, mut values) in params {
+        values.sort();
+        resource.push_str(&*format!("\n{}:{}", name, values.join(",")));
+    }
+    resource
+}
+
+#[cfg(test)]
+mod tests {
+    use checksum::decode_base64;
+    use chrono::{
+        DateTime,
+        Utc,
+    };
+    use hyper::Url;
+    use hyper::header::Headers;
+    use super::{
+        AzureSigner,
+        canonical_resource,
+    };
+
+    /// The well known key of Azurite's development account
+    const DEV_KEY: &'static str = "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/\
+                                   K1SZFPTOtr/KBHBeksoGMGw==";
+
+    fn signed(headers: &[(&str, &str)]) -> Headers
+    {
+        let url = Url::parse("https://devstoreaccount1.blob.core.windows.net/container/dir/blob.txt")
+            .unwrap();
+        let key = decode_base64(DEV_KEY).unwrap();
+        let signer = AzureSigner::new(&url, Some(("devstoreaccount1".to_owned(), key)));
+        let now = DateTime::parse_from_rfc3339("2024-01-02T03:04:05Z").unwrap().with_timezone(&Utc);
+        let mut request_headers = Headers::new();
+        for &(name, value) in headers {
+            request_headers.set_raw(name.to_owned(), vec![value.as_bytes().to_vec()]);
+        }
+        signer.sign_at("GET", &url, &mut request_headers, now);
+        request_headers
+    }
+
+    fn header(headers: &Headers, name: &str) -> String
+    {
+        String::from_utf8(headers.get_raw(name).unwrap()[0].clone()).unwrap()
+    }
+
+    #[test]
+    fn signs_ranged_get()
+    {
+        // Signs "GET\n" and ten empty lines (Content-Length 0 among them),
+        // then "bytes=0-1023\n", the sorted x-ms-date, x-ms-range and
+        // x-ms-version lines and "/devstoreaccount1/container/dir/blob.txt"
+        let authorization = "SharedKey devstoreaccount1:Gj5G7WUed3aydElTUmm6pdGUnsem0ky+T0PvBJ98EyQ=";
+        let headers = signed(&[("Range", "bytes=0-1023"), ("Content-Length", "0")]);
+        assert_eq!(header(&headers, "x-ms-date"), "Tue, 02 Jan 2024 03:04:05 GMT");
+        assert_eq!(header(&headers, "x-ms-range"), "bytes=0-1023");
+        assert_eq!(header(&headers, "x-ms-version"), "2021-08-06");
+        assert_eq!(header(&headers, "Authorization"), authorization);
+        // An empty body is signed as no Content-Length
+        let headers = signed(&[("Range", "bytes=0-1023")]);
+        assert_eq!(header(&headers, "Authorization"), authorization);
+        let headers = signed(&[("Range", "bytes=0-1023"), ("Content-Length", "1")]);
+        assert!(header(&headers, "Authorization") != authorization);
+    }
+
+    #[test]
+    fn anonymous_requests_are_not_signed()
+    {
+        let url = Url::parse("https://account.blob.core.windows.net/container/blob").unwrap();
+        let mut headers = Headers::new();
+        headers.set_raw("Range", vec![b"bytes=5-".to_vec()]);
+        AzureSigner::new(&url, None).sign_at("GET", &url, &mut headers, Utc::now());
+        assert_eq!(header(&headers, "x-ms-range"), "bytes=5-");
+        assert!(headers.get_raw("x-ms-date").is_none());
+        assert!(headers.get_raw("Authorization").is_none());
+    }
+
+    #[test]
+    fn canonicalizes_resource()
+    {
+        let url = Url::parse("https://account.blob.core.windows.net/container\
+                              ?restype=container&comp=list&Include=metadata&include=snapshots")
+            .unwrap();
+        assert_eq!(canonical_resource("account", &url),
+                   "/account/container\ncomp:list\ninclude:metadata,snapshots\nrestype:container");
+    }
+}
@@ -0,0 +1,283 @@
+//! Per-block hash manifests
+//!
+//! A manifest lists the SHA-256 of every fixed size block of a file along
+//! with the root of a binary hash tree over them, giving later repair runs
+//! and downstream verifiers block-level ground truth. It is written next to
+//! the file as `<file>.blocks`:
+//!
+//! ```text
+//! sledge-blocks 1
+//! length 10485760
+//! block-size 1048576
+//! root <hex>
+//! <hex of block 0>
+//! <hex of block 1>
+//! ...
+//! ```
+
+use cloud::hex;
+use sha2::{
+    Digest,
+    Sha256,
+};
+use paths::create_staging;
+use std::fs::{
+    self,
+    File,
+    Permissions,
+};
+use std::io::{
+    self,
+    BufRead,
+    BufReader,
+    BufWriter,
+    Read,
+    Write,
+};
+use std::path::Path;
+
+
+/// Default size of a hashed block
+pub const DEFAULT_BLOCK_SIZE: u64 = 1024 * 1024;  // 1 MB
+
+const HEADER: &'static str = "sledge-blocks 1";
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct BlockHashes {
+    /// Length of the file
+    pub length: u64,
+    /// Size of every block but the last
+    pub block_size: u64,
+    /// Hex SHA-256 of each block, in order
+    pub blocks: Vec<String>,
+}
+
+impl BlockHashes {
+
+    /// The manifest path for a target path
+    pub fn path_for(target: &str) -> String
+    {
+        format!("{}.blocks", target)
+    }
+
+    /// Hash the file at `path` in blocks of `block_size` bytes
+    pub fn compute<P: AsRef<Path>>(path: P, block_size: u64) -> io::Result<BlockHashes>
+    {
+        let mut file = try!(File::open(path));
+        let mut buf = vec![0; block_size as usize];
+        let mut blocks = vec![];
+        let mut length = 0;
+        loop {
+            let len = try!(read_block(&mut file, &mut buf));
+            if len == 0 {
+                break
+            }
+            blocks.push(hex(&Sha256::digest(&buf[..len])));
+            length += len as u64;
+        }
+        Ok(BlockHashes { length: length, block_size: block_size, blocks: blocks })
+    }
+
+    /// The root of the hash tree whose leaves are the block hashes: each
+    /// node is the SHA-256 of its children's digests, and an unpaired node
+    /// moves up a level unchanged
+    pub fn root(&self) -> String
+    {
+        let mut level: Vec<Vec<u8>> = self.blocks.iter().filter_map(|h| unhex(h)).collect();
+        if level.is_empty() {
+            return hex(&Sha256::digest(b""))
+        }
+        while level.len() > 1 {
+            level = level.chunks(2).map(|pair| match pair.len() {
+                2 => {
+                    let mut hasher = Sha256::new();
+                    hasher.update(&pair[0]);
+                    hasher.update(&pair[1]);
+                    hasher.finalize().to_vec()
+                },
+                _ => pair[0].clone(),
+            }).collect();
+        }
+        hex(&level[0])
+    }
+
+    /// Byte range `[start, end)` of block `index`
+    pub fn block(&self, index: usize) -> (u64, u64)
+    {
+        let start = index as u64 * self.block_size;
+        (start, (start + self.block_size).min(self.length))
+    }
+
+    /// Write the manifest to `path` with `permissions`. It is written to a
+    /// new file renamed into place, so a file or symlink already at `path`
+    /// is replaced rather than written through.
+    pub fn write<P: AsRef<Path>>(&self, path: P, permissions: Permissions) -> io::Result<()>
+    {
+        let path = path.as_ref();
+        let (staging, file) = try!(create_staging(path, "blocks"));
+        let written = self.write_to(file, permissions).and_then(|_| fs::rename(&staging, path));
+        if written.is_err() {
+            let _ = fs::remove_file(&staging);
+        }
+        written
+    }
+
+    fn write_to(&self, file: File, permissions: Permissions) -> io::Result<()>
+    {
+        let mut file = BufWriter::new(file);
+        try!(writeln!(file, "{}", HEADER));
+        try!(writeln!(file, "length {}", self.length));
+        try!(writeln!(file, "block-size {}", self.block_size));
+        try!(writeln!(file, "root {}", self.root()));
+        for block in &self.blocks {
+            try!(writeln!(file, "{}", block));
+        }
+        let file = try!(file.into_inner().map_err(|e| e.into_error()));
+        try!(file.set_permissions(permissions));
+        file.sync_all()
+    }
+
+    /// Read a manifest, checking that its blocks match its root
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<BlockHashes>
+    {
+        let file = try!(File::open(path));
+        let mut lines = BufReader::new(file).lines();
+        let mut next = || lines.next().unwrap_or_else(|| Err(invalid("truncated manifest")));
+        if try!(next()) != HEADER {
+            return Err(invalid("not a block manifest"))
+        }
+        let length = try!(field(&*try!(next()), "length"));
+        let block_size = try!(field(&*try!(next()), "block-size"));
+        let root_line = try!(next());
+        if !root_line.starts_with("root ") {
+            return Err(invalid("missing root"))
+        }
+        let root = root_line[5..].to_owned();
+        let mut blocks = vec![];
+        while let Ok(line) = next() {
+            blocks.push(line);
+        }
+
+        let hashes = BlockHashes { length: length, block_size: block_size, blocks: blocks };
+        let expected_blocks = if block_size == 0 { 0 } else { (length + block_size - 1) / block_size };
+        if hashes.blocks.len() as u64 != expected_blocks {
+            return Err(invalid("block count does not match the length"))
+        }
+        if hashes.root() != root {
+            return Err(invalid("block hashes do not match the root"))
+        }
+        Ok(hashes)
+    }
+}
+
+/// Fill `buf` unless the end of the file comes first
+fn read_block<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize>
+{
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn field(line: &str, name: &str) -> io::Result<u64>
+{
+    let mut parts = line.splitn(2, ' ');
+    match (parts.next(), parts.next().map(|v| v.parse())) {
+        (Some(key), Some(Ok(value))) if key == name => Ok(value),
+        _ => Err(invalid(&*format!("invalid {}", name))),
+    }
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>>
+{
+    if s.len() % 2 != 0 {
+        return None
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok()).collect()
+}
+
+fn invalid(message: &str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockHashes;
+    use std::env;
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::process;
+
+    /// The manifest of `data` in blocks of four bytes
+    fn hashes(name: &str, data: &[u8]) -> BlockHashes
+    {
+        let path = env::temp_dir().join(format!("sledge-blocks-{}-{}", name, process::id()));
+        fs::write(&path, data).unwrap();
+        let hashes = BlockHashes::compute(&path, 4).unwrap();
+        let _ = fs::remove_file(&path);
+        hashes
+    }
+
+    #[test]
+    fn empty_file()
+    {
+        let hashes = hashes("empty", b"");
+        assert_eq!(hashes.length, 0);
+        assert!(hashes.blocks.is_empty());
+        // The hash of nothing
+        assert_eq!(hashes.root(), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+
+    #[test]
+    fn single_block_is_the_root()
+    {
+        let hashes = hashes("single", b"abcd");
+        assert_eq!(hashes.blocks, vec!["88d4266fd4e6338d13b845fcf289579d209c897823b9217da3e161936f031589"]);
+        assert_eq!(hashes.root(), hashes.blocks[0]);
+    }
+
+    #[test]
+    fn pins_roots()
+    {
+        assert_eq!(hashes("even", b"abcdefgh").root(),
+                   "7d5473712172f9ec1494baa03da3d8734d12d385d1ca6340856771c3d93382e6");
+        // Three blocks, the last short and unpaired
+        let odd = hashes("odd", b"abcdefghij");
+        assert_eq!(odd.length, 10);
+        assert_eq!(odd.blocks[2], "c9df9c3f2963b19b9b95f58c4d33b053fa9f8586dd6ee04126e52a868f882108");
+        assert_eq!(odd.root(), "790b7200cb067d0e671dd8bdbfd6c362a4b7be3d558e77832254d3235735297b");
+        assert_eq!(odd.block(2), (8, 10));
+        // Five blocks, the last unpaired on every level
+        assert_eq!(hashes("five", b"abcdefghijklmnopq").root(),
+                   "da5a12195748a7bb28682b00e1837a4a6ecd3e74ef0a14b4fc66e9789962c88b");
+    }
+
+    #[test]
+    fn manifest_round_trip()
+    {
+        let hashes = hashes("manifest", b"abcdefghij");
+        let path = env::temp_dir().join(format!("sledge-blocks-manifest-{}.blocks", process::id()));
+        // Replacing a manifest already there
+        fs::write(&path, b"stale").unwrap();
+        let permissions = fs::metadata(&path).unwrap().permissions();
+        hashes.write(&path, permissions).unwrap();
+        assert_eq!(BlockHashes::read(&path).unwrap(), hashes);
+
+        // A block that doesn't match the root
+        let text = fs::read_to_string(&path).unwrap();
+        fs::write(&path, text.replace(&*hashes.blocks[1], &*hashes.blocks[0])).unwrap();
+        assert_eq!(BlockHashes::read(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+        // A missing block
+        let lines: Vec<&str> = text.lines().collect();
+        fs::write(&path, lines[..lines.len() - 1].join("\n")).unwrap();
+        assert_eq!(BlockHashes::read(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,366 @@
+//! Recording and replaying downloads
+//!
+//! When recording, the headers and body bytes of every response a download
+//! streams are saved to a cassette directory. When replaying, an embedded
+//! local server answers from the cassette, serving any range of the bytes
+//! recorded, so a download can be repeated offline (e.g. to compare how it
+//! is segmented with different settings) without the origin server.
+//!
+//! Each url is stored as `<md5 of url>.head` (status, length, url and
+//! headers), `.body` (a sparse file of the bytes received) and `.ranges`
+//! (the byte ranges of `.body` that were received).
+
+use hyper::client::Response;
+use hyper::header::{
+    ContentLength,
+    Headers,
+};
+use hyper::status::StatusCode;
+use md5;
+use selftest::parse_range;
+use std::fs::{
+    self,
+    File,
+    OpenOptions,
+};
+use std::io::{
+    self,
+    BufRead,
+    BufReader,
+    Read,
+    Seek,
+    SeekFrom,
+    Write,
+};
+use std::net::{
+    SocketAddr,
+    TcpListener,
+    TcpStream,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::Arc;
+use std::thread;
+use urls::normalize_url;
+
+
+/// Headers that describe a single response rather than the file
+const RESPONSE_HEADERS: &'static [&'static str] = &[
+    "accept-ranges", "connection", "content-length", "content-range", "keep-alive",
+    "transfer-encoding",
+];
+
+pub struct Cassette {
+    dir: PathBuf,
+}
+
+/// What was recorded for a url
+struct Entry {
+    status: u16,
+    len: u64,
+    /// The url itself, or where it redirects to
+    url: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Cassette {
+
+    /// Open the cassette in `dir`, creating it if needed
+    pub fn open<P: Into<PathBuf>>(dir: P) -> io::Result<Cassette>
+    {
+        let dir = dir.into();
+        try!(fs::create_dir_all(&dir));
+        Ok(Cassette { dir: dir })
+    }
+
+    fn path(&self, url: &str, extension: &str) -> PathBuf
+    {
+        self.dir.join(format!("{:x}.{}", md5::compute(url.as_bytes()), extension))
+    }
+
+    /// Record that `from` redirects to `to`
+    pub fn record_redirect(&self, from: &str, to: &str) -> io::Result<()>
+    {
+        self.write_entry(from, &Entry {
+            status: 302,
+            len: 0,
+            url: to.to_owned(),
+            headers: vec![],
+        })
+    }
+
+    /// Record the headers of `response`, returning a reader that records
+    /// its body, which starts at `offset` of the file
+    pub fn record(&self, response: Response, offset: u64) -> io::Result<Recording<Response>>
+    {
+        let url = response.url.to_string();
+        let len = match response.status {
+            StatusCode::PartialContent => content_range_len(&response.headers),
+            _ => response.headers.get::<ContentLength>().map(|&ContentLength(len)| len),
+        };
+        let headers = response.headers.iter()
+            .filter(|h| {
+                let name = h.name().to_lowercase();
+                !RESPONSE_HEADERS.iter().any(|&response_header| response_header == name)
+            })
+            .map(|h| (h.name().to_owned(), h.value_string()))
+            .collect();
+        try!(self.write_entry(&*url, &Entry {
+            status: 200,
+            len: len.unwrap_or(0),
+            url: url.clone(),
+            headers: headers,
+        }));
+
+        let mut body = try!(OpenOptions::new().write(true).create(true)
+                            .open(self.path(&*url, "body")));
+        try!(body.seek(SeekFrom::Start(offset)));
+        let ranges = try!(OpenOptions::new().append(true).create(true)
+                          .open(self.path(&*url, "ranges")));
+        Ok(Recording {
+            inner: response,
+            body: body,
+            ranges: ranges,
+            start: offset,
+            pos: offset,
+        })
+    }
+
+    /// Write an entry unless one was recorded already
+    fn write_entry(&self, url: &str, entry: &Entry) -> io::Result<()>
+    {
+        let mut file = match OpenOptions::new().write(true).create_new(true)
+            .open(self.path(url, "head")) {
+                Ok(file) => file,
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(()),
+                Err(e) => return Err(e),
+            };
+        try!(writeln!(file, "{}\n{}\n{}", entry.status, entry.len, entry.url));
+        for &(ref name, ref value) in &entry.headers {
+            try!(writeln!(file, "{}: {}", name, value));
+        }
+        Ok(())
+    }
+
+    /// Whether bytes `start` to `end` of `url` were all recorded
+    fn covers(&self, url: &str, start: u64, end: u64) -> bool
+    {
+        let file = match File::open(self.path(url, "ranges")) {
+            Ok(file) => file,
+            Err(_) => return false,
+        };
+        let mut ranges: Vec<(u64, u64)> = BufReader::new(file).lines()
+            .filter_map(|line| line.ok())
+            .filter_map(|line| {
+                let mut bounds = line.split(' ').map(|b| b.parse().ok());
+                match (bounds.next(), bounds.next()) {
+                    (Some(Some(start)), Some(Some(end))) => Some((start, end)),
+                    _ => None,
+                }
+            })
+            .collect();
+        ranges.sort();
+        let mut covered = start;
+        for (range_start, range_end) in ranges {
+            if range_start <= covered && range_end > covered {
+                covered = range_end;
+            }
+        }
+        covered >= end
+    }
+}
+
+/// A response body that is saved to the cassette as it is read
+pub struct Recording<R> {
+    inner: R,
+    body: File,
+    ranges: File,
+    start: u64,
+    pos: u64,
+}
+
+impl<R: Read> Read for Recording<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let n = try!(self.inner.read(buf));
+        try!(self.body.write_all(&buf[..n]));
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R> Drop for Recording<R> {
+    fn drop(&mut self)
+    {
+        if self.pos > self.start {
+            let _ = writeln!(self.ranges, "{} {}", self.start, self.pos);
+        }
+    }
+}
+
+/// The total length in a `Content-Range: bytes a-b/total` header
+fn content_range_len(headers: &Headers) -> Option<u64>
+{
+    headers.get_raw("Content-Range")
+        .and_then(|lines| lines.first())
+        .and_then(|line| {
+            let line = String::from_utf8_lossy(line);
+            line.rsplit('/').next().and_then(|len| len.trim().parse().ok())
+        })
+}
+
+/// Serves a cassette on a local port until the process exits
+pub struct ReplayServer {
+    addr: SocketAddr,
+    cassette: Arc<Cassette>,
+}
+
+impl ReplayServer {
+
+    pub fn start(cassette: Cassette) -> io::Result<ReplayServer>
+    {
+        let listener = try!(TcpListener::bind("127.0.0.1:0"));
+        let addr = try!(listener.local_addr());
+        let cassette = Arc::new(cassette);
+
+        let server_cassette = cassette.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let cassette = server_cassette.clone();
+                match stream {
+                    Ok(stream) => {
+                        thread::spawn(move || {
+                            if let Err(e) = serve(stream, &cassette, addr) {
+                                debug!("replay server: {}", e);
+                            }
+                        });
+                    },
+                    Err(e) => debug!("replay server: {}", e),
+                }
+            }
+        });
+        Ok(ReplayServer { addr: addr, cassette: cassette })
+    }
+
+    /// The local url replaying `url`, or None if it was not recorded
+    pub fn url(&self, url: &str) -> Option<String>
+    {
+        let url = match normalize_url(url) {
+            Ok(normalized) => normalized.url.to_string(),
+            Err(_) => return None,
+        };
+        if self.cassette.path(&*url, "head").exists() {
+            Some(local_url(self.addr, &*url))
+        } else {
+            None
+        }
+    }
+}
+
+/// The local url of a recorded url, keeping its file name
+fn local_url(addr: SocketAddr, url: &str) -> String
+{
+    let name = url.split(|c| c == '?' || c == '#').next().unwrap_or("")
+        .rsplit('/').next().unwrap_or("");
+    format!("http://{}/{:x}/{}", addr, md5::compute(url.as_bytes()), name)
+}
+
+/// Answer a single request from the cassette, then close the connection
+fn serve(stream: TcpStream, cassette: &Cassette, addr: SocketAddr) -> io::Result<()>
+{
+    let mut reader = BufReader::new(try!(stream.try_clone()));
+    let mut request_line = String::new();
+    try!(reader.read_line(&mut request_line));
+    let mut range = None;
+    loop {
+        let mut line = String::new();
+        if try!(reader.read_line(&mut line)) == 0 || line.trim().is_empty() {
+            break;
+        }
+        if line.to_lowercase().starts_with("range:") {
+            range = Some(line[6..].trim().to_owned());
+        }
+    }
+
+    // Requests are for /<id>/<name>, where the id names the cassette files
+    let id = request_line.split(' ').nth(1).unwrap_or("")
+        .trim_start_matches('/').split('/').next().unwrap_or("").to_owned();
+    let mut stream = stream;
+    let entry = match find_entry(cassette, &*id) {
+        Some(entry) => entry,
+        None => {
+            return write!(stream, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\
+                                   Connection: close\r\n\r\n")
+        },
+    };
+
+    if entry.status != 200 {
+        return write!(stream, "HTTP/1.1 {} Found\r\nLocation: {}\r\nContent-Length: 0\r\n\
+                               Connection: close\r\n\r\n",
+                      entry.status, local_url(addr, &*entry.url))
+    }
+
+    let (start, end, partial) = match range.and_then(|r| parse_range(&*r, entry.len)) {
+        Some((start, end)) => (start, end, true),
+        None => (0, entry.len, false),
+    };
+    if !cassette.covers(&*entry.url, start, end) {
+        warn!("Bytes {} - {} of {} were not recorded", start, end, entry.url);
+        return write!(stream, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\
+                               Connection: close\r\n\r\n")
+    }
+
+    try!(write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\n\
+                         Connection: close\r\n",
+                if partial { "206 Partial Content" } else { "200 OK" }, end - start));
+    if partial {
+        try!(write!(stream, "Content-Range: bytes {}-{}/{}\r\n", start, end - 1, entry.len));
+    }
+    // Mirrors advertised in Link headers would lead back to the network
+    for &(ref name, ref value) in entry.headers.iter().filter(|h| h.0.to_lowercase() != "link") {
+        try!(write!(stream, "{}: {}\r\n", name, value));
+    }
+    try!(write!(stream, "\r\n"));
+
+    if request_line.starts_with("HEAD") {
+        return Ok(())
+    }
+    let mut body = try!(File::open(cassette.path(&*entry.url, "body")));
+    try!(body.seek(SeekFrom::Start(start)));
+    try!(io::copy(&mut body.take(end - start), &mut stream));
+    Ok(())
+}
+
+/// The entry recorded under `id` (the md5 of its url)
+fn find_entry(cassette: &Cassette, id: &str) -> Option<Entry>
+{
+    if id.is_empty() || !id.chars().all(|c| c.is_digit(16)) {
+        return None
+    }
+    read_entry(&cassette.dir.join(format!("{}.head", id))).ok()
+}
+
+fn read_entry(path: &Path) -> io::Result<Entry>
+{
+    let file = try!(File::open(path));
+    let mut lines = BufReader::new(file).lines();
+    let mut next = || lines.next().unwrap_or_else(|| Err(invalid("truncated head")));
+    let status = try!(try!(next()).parse().map_err(|_| invalid("invalid status")));
+    let len = try!(try!(next()).parse().map_err(|_| invalid("invalid length")));
+    let url = try!(next());
+    let mut headers = vec![];
+    while let Ok(line) = next() {
+        let mut parts = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            headers.push((name.to_owned(), value.trim().to_owned()));
+        }
+    }
+    Ok(Entry { status: status, len: len, url: url, headers: headers })
+}
+
+fn invalid(message: &str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,226 @@
+//! Verifying a download against an expected digest
+//!
+//! Bytes written in order (serial and streaming downloads) are hashed as
+//! they are written, so verifying doesn't take a second pass over the file.
+//! Parallel downloads write out of order and are hashed once complete.
+//! Without an expected digest, one advertised by the server (RFC 3230
+//! `Digest` or `Content-MD5`) is checked instead.
+
+use md5;
+use metalink::Digest as Advertised;
+use sha2::{
+    Digest,
+    Sha256,
+};
+use std::fs::File;
+use std::io::{
+    self,
+    Read,
+    Write,
+};
+use std::path::Path;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+
+
+/// The expected hex digest of a file
+#[derive(Clone,Debug,PartialEq)]
+pub enum Checksum {
+    Md5(String),
+    Sha256(String),
+}
+
+impl Checksum {
+
+    pub fn algorithm(&self) -> &'static str
+    {
+        match *self {
+            Checksum::Md5(_) => "md5",
+            Checksum::Sha256(_) => "sha256",
+        }
+    }
+
+    /// The expected digest, in lowercase hex
+    pub fn expected(&self) -> String
+    {
+        match *self {
+            Checksum::Md5(ref hex) | Checksum::Sha256(ref hex) => hex.to_lowercase(),
+        }
+    }
+
+    /// The checksum of a digest advertised by the server, if its
+    /// algorithm is supported
+    pub fn from_digest(digest: &Advertised) -> Option<Checksum>
+    {
+        let hex = match decode_base64(&*digest.value) {
+            Some(bytes) => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+            None => return None,
+        };
+        match &*digest.algorithm {
+            "md5" => Some(Checksum::Md5(hex)),
+            "sha-256" => Some(Checksum::Sha256(hex)),
+            _ => None,
+        }
+    }
+
+    /// The strongest supported checksum of the digests advertised by the
+    /// server
+    pub fn advertised(digests: &[Advertised]) -> Option<Checksum>
+    {
+        let checksums: Vec<Checksum> = digests.iter().filter_map(Checksum::from_digest).collect();
+        checksums.iter().find(|c| c.algorithm() == "sha256")
+            .or_else(|| checksums.first())
+            .cloned()
+    }
+
+    /// A hasher for this checksum's algorithm
+    pub fn hasher(&self) -> Hasher
+    {
+        match *self {
+            Checksum::Md5(_) => Hasher::Md5(md5::Context::new()),
+            Checksum::Sha256(_) => Hasher::Sha256(Sha256::new()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Hasher {
+    Md5(md5::Context),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+
+    pub fn update(&mut self, buf: &[u8])
+    {
+        match *self {
+            Hasher::Md5(ref mut context) => context.consume(buf),
+            Hasher::Sha256(ref mut hasher) => hasher.update(buf),
+        }
+    }
+
+    /// The digest of the bytes so far, in lowercase hex
+    pub fn hex(&self) -> String
+    {
+        match *self {
+            Hasher::Md5(ref context) => format!("{:x}", context.clone().compute()),
+            Hasher::Sha256(ref hasher) => {
+                hasher.clone().finalize().iter().map(|b| format!("{:02x}", b)).collect()
+            },
+        }
+    }
+
+    /// Hash the first `len` bytes of the file at `path`
+    pub fn update_from_file<P: AsRef<Path>>(&mut self, path: P, len: u64) -> io::Result<()>
+    {
+        let mut file = try!(File::open(path)).take(len);
+        let mut buf = vec![0; 64 * 1024];
+        let mut read = 0;
+        loop {
+            let n = try!(file.read(&mut buf));
+            if n == 0 {
+                break
+            }
+            self.update(&buf[..n]);
+            read += n as u64;
+        }
+        if read < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file is shorter than expected"))
+        }
+        Ok(())
+    }
+}
+
+/// Encode bytes as standard base64
+pub fn encode_base64(bytes: &[u8]) -> String
+{
+    const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decode standard base64, as used by `Digest` and `Content-MD5`
+pub fn decode_base64(value: &str) -> Option<Vec<u8>>
+{
+    let mut bytes = vec![];
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in value.trim().trim_end_matches('=').bytes() {
+        let sextet = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | sextet as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+/// Hashes bytes as they are read through it
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Arc<Mutex<Hasher>>,
+}
+
+impl<R> HashingReader<R> {
+    pub fn new(inner: R, hasher: Arc<Mutex<Hasher>>) -> HashingReader<R>
+    {
+        HashingReader { inner: inner, hasher: hasher }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let n = try!(self.inner.read(buf));
+        self.hasher.lock().unwrap().update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Hashes bytes as they are written through it
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Arc<Mutex<Hasher>>,
+}
+
+impl<W> HashingWriter<W> {
+    pub fn new(inner: W, hasher: Arc<Mutex<Hasher>>) -> HashingWriter<W>
+    {
+        HashingWriter { inner: inner, hasher: hasher }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        let n = try!(self.inner.write(buf));
+        self.hasher.lock().unwrap().update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,171 @@
+//! Secure removal of the working files of cancelled downloads
+//!
+//! With `--secure-cleanup` the part file and journal of a download are
+//! registered while it runs. If the download fails they are overwritten
+//! with zeros and removed, and so are those of every running download when
+//! the process is interrupted or terminated. Overwriting in place can't
+//! reach copies kept by copy-on-write filesystems, snapshots or SSD wear
+//! leveling, so it narrows rather than closes the window for recovery.
+
+use paths::no_follow;
+use std::fs::{
+    self,
+    OpenOptions,
+};
+use std::cmp;
+use std::io::{
+    self,
+    Write,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::Mutex;
+
+
+/// Working files of running downloads, shredded if the process is cancelled
+static PENDING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Shred `path` if the process is cancelled before it is released
+pub fn register(path: &Path)
+{
+    let mut pending = PENDING.lock().unwrap();
+    if !pending.iter().any(|p| p == path) {
+        pending.push(path.to_path_buf());
+    }
+}
+
+/// Stop tracking `path`, e.g. because its download completed
+pub fn release(path: &Path)
+{
+    PENDING.lock().unwrap().retain(|p| p != path);
+}
+
+/// Overwrite the file at `path` with zeros, sync it to disk and remove it.
+/// A file that doesn't exist is left alone. Anything but a regular file,
+/// such as a symlink planted at the path, is refused and left in place.
+pub fn shred(path: &Path) -> io::Result<()>
+{
+    // Checked before opening too, as opening e.g. a FIFO would block
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if metadata.file_type().is_file() => (),
+        Ok(_) => return Err(not_regular(path)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    let mut open_options = OpenOptions::new();
+    open_options.write(true);
+    no_follow(&mut open_options);
+    let mut file = match open_options.open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    // Whatever was opened, in case the path was replaced after the check
+    let metadata = try!(file.metadata());
+    if !metadata.file_type().is_file() {
+        return Err(not_regular(path))
+    }
+    let mut remaining = metadata.len();
+    let zeros = vec![0; ::DEFAULT_BUFF_SIZE];
+    while remaining > 0 {
+        let len = cmp::min(remaining, zeros.len() as u64) as usize;
+        try!(file.write_all(&zeros[..len]));
+        remaining -= len as u64;
+    }
+    try!(file.sync_all());
+    drop(file);
+    debug!("shredded {}", path.display());
+    fs::remove_file(path)
+}
+
+fn not_regular(path: &Path) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidInput,
+                   format!("{} is not a regular file, leaving it alone", path.display()))
+}
+
+/// Shred the working files of every running download
+pub fn shred_pending()
+{
+    let pending = PENDING.lock().unwrap().drain(..).collect::<Vec<_>>();
+    for path in pending {
+        if let Err(e) = shred(&path) {
+            error!("Unable to shred {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Shred the working files of running downloads and exit when the process
+/// receives SIGINT, SIGTERM or SIGHUP. Must be called before any other
+/// thread is started, so that every thread leaves the signals to the one
+/// waiting for them.
+#[cfg(unix)]
+pub fn shred_on_signal() -> io::Result<()>
+{
+    use libc;
+    use std::mem;
+    use std::process;
+    use std::ptr;
+    use std::thread;
+
+    let mut signals: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut signals);
+        for &signal in &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            libc::sigaddset(&mut signals, signal);
+        }
+        let err = libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut());
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err))
+        }
+    }
+    thread::spawn(move || {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+            return
+        }
+        warn!("Cancelled, shredding partial files");
+        shred_pending();
+        process::exit(128 + signal);
+    });
+    Ok(())
+}
+
+/// Cancelling isn't intercepted on this platform, only failed downloads
+/// are shredded
+#[cfg(not(unix))]
+pub fn shred_on_signal() -> io::Result<()>
+{
+    Err(io::Error::new(io::ErrorKind::Other, "signals can't be intercepted on this platform"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::shred;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    #[test]
+    fn shreds_regular_files_and_refuses_symlinks()
+    {
+        let dir = env::temp_dir().join(format!("sledge-shred-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (part, victim, link) = (dir.join("a.part"), dir.join("victim"), dir.join("b.part"));
+        fs::write(&part, b"partial").unwrap();
+        fs::write(&victim, b"precious").unwrap();
+        symlink(&victim, &link).unwrap();
+
+        shred(&part).unwrap();
+        assert!(!part.exists());
+        assert!(shred(&link).is_err());
+        assert_eq!(fs::read(&victim).unwrap(), b"precious");
+        assert!(fs::symlink_metadata(&link).is_ok());
+        // Nothing to shred
+        shred(&part).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
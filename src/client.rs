@@ -0,0 +1,458 @@
+//! Construction of the HTTP clients used by the requests of a download
+
+use config::HostPolicy;
+use cookies::CookieStore;
+use dns::{
+    CachingConnector,
+    DnsCache,
+    Resolver,
+};
+use hyper;
+use hyper::{
+    Client,
+    Url,
+};
+use hyper::client::RedirectPolicy;
+use hyper::header::Headers;
+use hyper::client::pool::{
+    Config,
+    Pool,
+};
+use hyper::net::{
+    HttpStream,
+    HttpsStream,
+    NetworkConnector,
+};
+use metadata::MetadataCache;
+use proxy::{
+    ProxyConnector,
+    ProxySettings,
+};
+use stats::as_secs_f64;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
+use token::BearerToken;
+
+
+/// Maximum number of idle connections kept for reuse per host
+const MAX_IDLE_CONNECTIONS: usize = 32;
+
+/// Default number of bytes of an error response's body kept in the error
+pub const DEFAULT_ERROR_BODY_LIMIT: usize = 64 * 1024;  // 64 KB
+
+/// Default maximum number of redirects followed for a single request
+pub const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+type Connector = ProxyConnector;
+
+/// Whether credentials (`Authorization` and `Cookie` headers) are sent on
+/// when a request is redirected
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum RedirectAuth {
+    /// Only while redirects stay on the same host
+    SameHost,
+    /// To whichever host the request is redirected
+    Keep,
+    /// Never, even to the same host
+    Strip,
+}
+
+impl Default for RedirectAuth {
+    fn default() -> RedirectAuth
+    {
+        RedirectAuth::SameHost
+    }
+}
+
+impl FromStr for RedirectAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RedirectAuth, String>
+    {
+        match s {
+            "same-host" => Ok(RedirectAuth::SameHost),
+            "keep" => Ok(RedirectAuth::Keep),
+            "strip" => Ok(RedirectAuth::Strip),
+            _ => Err(format!("expected 'same-host', 'keep' or 'strip', not '{}'", s)),
+        }
+    }
+}
+
+impl RedirectAuth {
+
+    /// Whether credentials sent to `from` are sent on to `to`
+    pub fn keeps(&self, from: &Url, to: &Url) -> bool
+    {
+        match *self {
+            RedirectAuth::SameHost => from.host_str() == to.host_str(),
+            RedirectAuth::Keep => true,
+            RedirectAuth::Strip => false,
+        }
+    }
+}
+
+
+/// Authenticates the requests made to a storage service, e.g. an S3
+/// bucket's endpoint, by adding headers to each
+pub trait RequestSigner: Send + Sync {
+
+    /// Whether requests to `url` are signed. Redirects elsewhere, e.g. to
+    /// a presigned url, aren't.
+    fn signs(&self, url: &Url) -> bool;
+
+    /// Add the headers authenticating a `method` request to `url`, made now
+    fn sign(&self, method: &str, url: &Url, headers: &mut Headers);
+}
+
+
+/// Connection settings shared by every request of a download
+#[derive(Clone)]
+pub struct ClientConfig {
+    /// Addresses resolved by earlier requests
+    dns: Arc<DnsCache>,
+    /// Open connections shared by every client created from this config
+    pool: Arc<Pool<Connector>>,
+    /// Proxies to connect through
+    proxies: ProxySettings,
+    /// Results of probing urls
+    metadata: Arc<MetadataCache>,
+    /// Cookies sent with and set by requests, if kept
+    cookies: Option<Arc<CookieStore>>,
+    /// Bearer token sent with requests that have no other credentials
+    token: Option<BearerToken>,
+    /// Signs requests to a storage service
+    signer: Option<Arc<dyn RequestSigner>>,
+    /// Refuse to make any request over plain http
+    https_only: bool,
+    /// Hosts requests may be made to
+    hosts: HostPolicy,
+    /// Maximum number of redirects followed for a single request
+    max_redirects: usize,
+    /// Whether credentials are sent on when a request is redirected
+    redirect_auth: RedirectAuth,
+    /// How long to wait for a connection to be accepted
+    connect_timeout: Option<Duration>,
+    /// How long to wait for the next bytes of a response
+    read_timeout: Option<Duration>,
+    /// When requests stop being made, and the time allowed until then
+    deadline: Option<(Instant, Duration)>,
+    /// Number of bytes of an error response's body kept in the error
+    error_body_limit: usize,
+    /// Where to save the body of error responses
+    error_body_path: Option<PathBuf>,
+}
+
+/// Lets every client created from a config draw from the same pool
+struct SharedPool(Arc<Pool<Connector>>);
+
+impl NetworkConnector for SharedPool {
+    type Stream = <Pool<Connector> as NetworkConnector>::Stream;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<Self::Stream>
+    {
+        self.0.connect(host, port, scheme)
+    }
+}
+
+fn new_pool(dns: Arc<DnsCache>, proxies: ProxySettings, connect_timeout: Option<Duration>)
+            -> Arc<Pool<Connector>>
+{
+    let connector = ProxyConnector::new(
+        CachingConnector::new(dns.clone()).timeout(connect_timeout),
+        CachingConnector::new(dns).timeout(connect_timeout),
+        proxies);
+    Arc::new(Pool::with_connector(Config { max_idle: MAX_IDLE_CONNECTIONS }, connector))
+}
+
+impl ClientConfig {
+
+    /// Create a config that caches resolved addresses for its lifetime and
+    /// uses the proxies set in the environment
+    pub fn new() -> ClientConfig
+    {
+        let dns = Arc::new(DnsCache::new(None));
+        let proxies = ProxySettings::from_env();
+        ClientConfig {
+            pool: new_pool(dns.clone(), proxies.clone(), None),
+            dns: dns,
+            proxies: proxies,
+            metadata: Arc::new(MetadataCache::new()),
+            cookies: None,
+            token: None,
+            signer: None,
+            https_only: false,
+            hosts: HostPolicy::default(),
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            redirect_auth: RedirectAuth::default(),
+            connect_timeout: None,
+            read_timeout: None,
+            deadline: None,
+            error_body_limit: DEFAULT_ERROR_BODY_LIMIT,
+            error_body_path: None,
+        }
+    }
+
+    /// Expire cached addresses after `ttl`
+    pub fn dns_ttl(mut self, ttl: Duration) -> ClientConfig
+    {
+        self.dns = Arc::new(DnsCache::new(Some(ttl)).resolver(self.dns.current_resolver()));
+        self.pool = new_pool(self.dns.clone(), self.proxies.clone(), self.connect_timeout);
+        self
+    }
+
+    /// Resolve the hosts connected to with `resolver` instead of the
+    /// system's
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> ClientConfig
+    {
+        self.dns = Arc::new(DnsCache::new(self.dns.ttl()).resolver(resolver));
+        self.pool = new_pool(self.dns.clone(), self.proxies.clone(), self.connect_timeout);
+        self
+    }
+
+    /// Connect through `proxies` instead of those set in the environment
+    pub fn proxies(mut self, proxies: ProxySettings) -> ClientConfig
+    {
+        self.proxies = proxies;
+        self.pool = new_pool(self.dns.clone(), self.proxies.clone(), self.connect_timeout);
+        self
+    }
+
+    /// Share probe results through `cache` rather than a cache of its own
+    pub fn metadata_cache(mut self, cache: Arc<MetadataCache>) -> ClientConfig
+    {
+        self.metadata = cache;
+        self
+    }
+
+    /// Results of probing urls with this config
+    pub fn metadata(&self) -> &MetadataCache
+    {
+        &self.metadata
+    }
+
+    /// Send the cookies in `store` that match each request, and record the
+    /// cookies responses set in it
+    pub fn cookie_store(mut self, store: Arc<CookieStore>) -> ClientConfig
+    {
+        self.cookies = Some(store);
+        self
+    }
+
+    /// The cookies kept for requests with this config, if any
+    pub fn cookies(&self) -> Option<&CookieStore>
+    {
+        self.cookies.as_ref().map(|store| &**store)
+    }
+
+    /// Authenticate requests with a bearer token, read again for each
+    /// request. An `Authorization` header given with a request is sent
+    /// instead.
+    pub fn bearer_token(mut self, token: BearerToken) -> ClientConfig
+    {
+        self.token = Some(token);
+        self
+    }
+
+    /// The bearer token requests are authenticated with, if any
+    pub fn token(&self) -> Option<&BearerToken>
+    {
+        self.token.as_ref()
+    }
+
+    /// Sign the requests `signer` signs, e.g. those to a bucket's endpoint
+    pub fn request_signer(mut self, signer: Arc<dyn RequestSigner>) -> ClientConfig
+    {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// The signer of requests to a storage service, if any
+    pub fn signer(&self) -> Option<&dyn RequestSigner>
+    {
+        self.signer.as_ref().map(|signer| &**signer)
+    }
+
+    /// Refuse to make any request (including redirect hops) over plain http
+    pub fn https_only(mut self, https_only: bool) -> ClientConfig
+    {
+        self.https_only = https_only;
+        self
+    }
+
+    /// Refuse to make any request (including redirect hops) to hosts the
+    /// policy doesn't permit
+    pub fn host_policy(mut self, hosts: HostPolicy) -> ClientConfig
+    {
+        self.hosts = hosts;
+        self
+    }
+
+    /// Follow at most `limit` redirects for a single request
+    pub fn max_redirects(mut self, limit: usize) -> ClientConfig
+    {
+        self.max_redirects = limit;
+        self
+    }
+
+    /// Maximum number of redirects followed for a single request
+    pub fn redirect_limit(&self) -> usize
+    {
+        self.max_redirects
+    }
+
+    /// Set whether credentials are sent on when a request is redirected
+    pub fn redirect_auth(mut self, policy: RedirectAuth) -> ClientConfig
+    {
+        self.redirect_auth = policy;
+        self
+    }
+
+    /// Whether credentials are sent on when a request is redirected
+    pub fn redirect_auth_policy(&self) -> RedirectAuth
+    {
+        self.redirect_auth
+    }
+
+    /// Give up on connections that haven't been accepted after `timeout`
+    pub fn connect_timeout(mut self, timeout: Duration) -> ClientConfig
+    {
+        self.connect_timeout = Some(timeout);
+        self.pool = new_pool(self.dns.clone(), self.proxies.clone(), self.connect_timeout);
+        self
+    }
+
+    /// Give up on responses that send nothing for `timeout`
+    pub fn read_timeout(mut self, timeout: Duration) -> ClientConfig
+    {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Make no requests after `max_time` from now
+    pub fn max_time(mut self, max_time: Duration) -> ClientConfig
+    {
+        self.deadline = Some((Instant::now() + max_time, max_time));
+        self
+    }
+
+    /// How long to wait for a connection, and for the next bytes of a
+    /// response (forever if None)
+    pub fn timeouts(&self) -> (Option<Duration>, Option<Duration>)
+    {
+        (self.connect_timeout, self.read_timeout)
+    }
+
+    /// When requests stop being made, and the time allowed until then
+    pub fn deadline(&self) -> Option<(Instant, Duration)>
+    {
+        self.deadline
+    }
+
+    /// Keep at most `limit` bytes of an error response's body in the error
+    pub fn error_body_limit(mut self, limit: usize) -> ClientConfig
+    {
+        self.error_body_limit = limit;
+        self
+    }
+
+    /// Save the full body of error responses to `path`
+    pub fn save_error_body(mut self, path: PathBuf) -> ClientConfig
+    {
+        self.error_body_path = Some(path);
+        self
+    }
+
+    /// How much of an error response's body to keep, and where to save
+    /// the full body (if anywhere)
+    pub fn error_capture(&self) -> (usize, Option<&Path>)
+    {
+        (self.error_body_limit, self.error_body_path.as_ref().map(|p| p.as_path()))
+    }
+
+    /// Check that these settings permit a request to `url`
+    pub fn check_url(&self, url: &Url) -> Result<(), String>
+    {
+        if self.https_only && url.scheme() != "https" {
+            return Err(format!("refusing to request {} over {} (https only)", url, url.scheme()))
+        }
+        try!(self.hosts.check(url.host_str().unwrap_or("")));
+        Ok(())
+    }
+
+    /// Create a client that connects using these settings
+    ///
+    /// Redirects are not followed by the client so that each hop can be
+    /// recorded by the caller.
+    pub fn client(&self) -> Client
+    {
+        let mut client = Client::with_connector(SharedPool(self.pool.clone()));
+        client.set_redirect_policy(RedirectPolicy::FollowNone);
+        client.set_read_timeout(self.read_timeout);
+        client.set_write_timeout(self.read_timeout);
+        client
+    }
+
+    /// Check that a connection (including any TLS handshake) can be made
+    /// to the host of `url`
+    pub fn check_connection(&self, url: &Url) -> hyper::Result<()>
+    {
+        let host = url.host_str().unwrap_or("");
+        let port = url.port_or_known_default().unwrap_or(80);
+        self.pool.connect(host, port, url.scheme()).map(|_| ())
+    }
+
+    /// Open a connection to `host:port` for a protocol other than http
+    /// (e.g. FTP), through the proxy of http urls if one applies. It isn't
+    /// pooled.
+    pub fn connect(&self, host: &str, port: u16) -> hyper::Result<HttpStream>
+    {
+        let connector = ProxyConnector::new(
+            CachingConnector::new(self.dns.clone()).timeout(self.connect_timeout),
+            CachingConnector::new(self.dns.clone()).timeout(self.connect_timeout),
+            self.proxies.clone());
+        match try!(connector.connect(host, port, "http")) {
+            HttpsStream::Http(stream) => Ok(stream),
+            HttpsStream::Https(_) => unreachable!("only https connections use TLS"),
+        }
+    }
+
+    /// Concurrently open `n` connections (DNS, TCP and TLS) to the host of
+    /// `url` and return them to the pool, so that requests made afterwards
+    /// start transferring without waiting on handshakes
+    pub fn preconnect(&self, url: &Url, n: usize)
+    {
+        let host = match url.host_str() {
+            Some(host) => host.to_owned(),
+            None => return,
+        };
+        let port = url.port_or_known_default().unwrap_or(80);
+        let scheme = url.scheme().to_owned();
+
+        let children: Vec<_> = (0..n).map(|i| {
+            let pool = self.pool.clone();
+            let (host, scheme) = (host.clone(), scheme.clone());
+            thread::spawn(move || {
+                let start = Instant::now();
+                match pool.connect(&*host, port, &*scheme) {
+                    // Dropping the stream returns it to the idle pool
+                    Ok(_) => debug!("preconnect {}: handshake with {} took {:.3}s",
+                                    i, host, as_secs_f64(start.elapsed())),
+                    Err(e) => debug!("preconnect {}: unable to connect to {}: {}", i, host, e),
+                }
+            })
+        }).collect();
+
+        for child in children {
+            let _ = child.join();
+        }
+    }
+}
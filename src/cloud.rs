@@ -0,0 +1,211 @@
+//! Helpers shared by the cloud storage sources (`s3://`, `gs://` and `az://`)
+//!
+//! They address objects as `<scheme>://bucket/key`. S3 and Azure sign
+//! requests with HMAC-SHA256, and S3 and GCS look for credentials in their
+//! instance metadata service, which answers at a link-local address on
+//! their VMs, and read the flat JSON documents those services and
+//! credential files hold.
+
+use hyper::Url;
+use sha2::{
+    Digest,
+    Sha256,
+};
+use std::env;
+use std::io::{
+    self,
+    Read,
+    Write,
+};
+use std::net::{
+    SocketAddr,
+    TcpStream,
+};
+use std::time::Duration;
+
+
+/// Address of the instance metadata service of EC2 and Compute Engine
+const METADATA_ADDR: &'static str = "169.254.169.254:80";
+
+/// How long the metadata service is waited on, short since it is only
+/// reachable on cloud VMs
+const METADATA_TIMEOUT: u64 = 1;
+
+/// Split a `<scheme>://bucket/key` url into its bucket and key. The key
+/// is taken as written, not decoded, and is empty for the bucket itself.
+pub fn split_bucket_url(url: &str, scheme: &str) -> Result<(String, String), String>
+{
+    let rest = match url.find("://") {
+        Some(i) if url[..i].eq_ignore_ascii_case(scheme) => &url[i + 3..],
+        _ => return Err(format!("'{}' is not a {}:// url", url, scheme)),
+    };
+    let (bucket, key) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i + 1..]),
+        None => (rest, ""),
+    };
+    if bucket.is_empty() {
+        return Err(format!("'{}' has no bucket", url))
+    }
+    Ok((bucket.to_owned(), key.to_owned()))
+}
+
+/// Percent encode all but unreserved characters, and `/` unless
+/// `encode_slash`
+pub fn uri_encode(s: &str, encode_slash: bool) -> String
+{
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        b'/' if !encode_slash => "/".to_owned(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+/// The `Host` header of requests to `url`
+pub fn host_header(url: &Url) -> String
+{
+    let host = url.host_str().unwrap_or("");
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_owned(),
+    }
+}
+
+/// The HMAC-SHA256 of `message` with `key`
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8>
+{
+    const BLOCK_SIZE: usize = 64;
+    let mut block = if key.len() > BLOCK_SIZE { Sha256::digest(key).to_vec() } else { key.to_vec() };
+    block.resize(BLOCK_SIZE, 0);
+    let mut inner = Sha256::new();
+    inner.update(&block.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(&block.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    outer.update(&inner.finalize());
+    outer.finalize().to_vec()
+}
+
+/// Lowercase hex of `bytes`
+pub fn hex(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A non-empty environment variable
+pub fn env_var(name: &str) -> Option<String>
+{
+    env::var(name).ok().and_then(|value| if value.trim().is_empty() { None } else { Some(value) })
+}
+
+/// Make a request of the instance metadata service, sending `host` as the
+/// `Host` and `headers` (each a `Name: value` line), and return the body
+/// of a successful response
+pub fn metadata_request(method: &str, host: &str, path: &str, headers: &[&str])
+                        -> io::Result<String>
+{
+    let addr: SocketAddr = METADATA_ADDR.parse().unwrap();
+    let timeout = Duration::from_secs(METADATA_TIMEOUT);
+    let mut stream = try!(TcpStream::connect_timeout(&addr, timeout));
+    try!(stream.set_read_timeout(Some(timeout)));
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", method, path, host);
+    for header in headers {
+        request.push_str(&*format!("{}\r\n", header));
+    }
+    request.push_str("Content-Length: 0\r\nConnection: close\r\n\r\n");
+    try!(stream.write_all(request.as_bytes()));
+    let mut response = String::new();
+    try!(stream.read_to_string(&mut response));
+    let (head, body) = match response.find("\r\n\r\n") {
+        Some(i) => (&response[..i], &response[i + 4..]),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed response")),
+    };
+    let status = head.lines().next().unwrap_or("");
+    match status.split(' ').nth(1) {
+        Some("200") => Ok(body.to_owned()),
+        _ => Err(io::Error::new(io::ErrorKind::Other, status.to_owned())),
+    }
+}
+
+/// The text following `"field":` in a flat JSON object
+fn json_value<'a>(json: &'a str, field: &str) -> Option<&'a str>
+{
+    let start = match json.find(&*format!("\"{}\"", field)) {
+        Some(i) => i + field.len() + 2,
+        None => return None,
+    };
+    let rest = json[start..].trim_start();
+    if !rest.starts_with(':') {
+        return None
+    }
+    Some(rest[1..].trim_start())
+}
+
+/// The string value of `"field"` in a flat JSON object
+pub fn json_string(json: &str, field: &str) -> Option<String>
+{
+    let rest = match json_value(json, field) {
+        Some(rest) if rest.starts_with('"') => &rest[1..],
+        _ => return None,
+    };
+    let mut value = String::new();
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(value),
+            '\\' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some('u') => {
+                    let code: String = chars.by_ref().take(4).collect();
+                    match u32::from_str_radix(&*code, 16).ok().and_then(::std::char::from_u32) {
+                        Some(c) => value.push(c),
+                        None => return None,
+                    }
+                },
+                Some(c) => value.push(c),
+                None => return None,
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// The unsigned integer value of `"field"` in a flat JSON object
+pub fn json_number(json: &str, field: &str) -> Option<u64>
+{
+    json_value(json, field).and_then(|rest| {
+        let digits: String = rest.chars().take_while(|c| c.is_digit(10)).collect();
+        digits.parse().ok()
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        hex,
+        hmac_sha256,
+        uri_encode,
+    };
+
+    #[test]
+    fn hmac_sha256_rfc4231()
+    {
+        // Test cases 2 and 6 of RFC 4231, the second with a key longer
+        // than the block size
+        assert_eq!(hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+                   "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert_eq!(hex(&hmac_sha256(&[0xaa; 131],
+                                    b"Test Using Larger Than Block-Size Key - Hash Key First")),
+                   "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+    }
+
+    #[test]
+    fn uri_encode_unreserved()
+    {
+        assert_eq!(uri_encode("a b/c~d+e", false), "a%20b/c~d%2Be");
+        assert_eq!(uri_encode("a b/c", true), "a%20b%2Fc");
+    }
+}
@@ -0,0 +1,221 @@
+//! User configuration file
+//!
+//! The file holds `key = value` lines providing defaults for command line
+//! options; `#` starts a comment. It is read from `$SLEDGE_CONFIG` if set,
+//! otherwise from `$XDG_CONFIG_HOME/sledge/config` or
+//! `~/.config/sledge/config`. `sledge init` writes one interactively.
+//!
+//! `allow_hosts` and `deny_hosts` take comma separated hosts, each matching
+//! itself and its subdomains, to constrain where sledge may connect:
+//!
+//! ```text
+//! directory = /data/downloads
+//! threads = 4
+//! limit_rate = 2M
+//! proxy = http://proxy.example.com:3128
+//! https_only = true
+//! allow_hosts = example.com, mirror.example.org
+//! deny_hosts = internal.example.com
+//! ```
+
+use idna;
+use proxy::Proxy;
+use std::env;
+use std::fs::File;
+use std::io::{
+    self,
+    Read,
+};
+use std::path::PathBuf;
+use units::parse_bytes;
+
+
+#[derive(Clone,Debug,Default)]
+pub struct Config {
+    /// Directory to save downloads in
+    pub directory: Option<String>,
+    /// Number of threads to download with
+    pub threads: Option<u8>,
+    /// Bytes per second all connections are limited to
+    pub limit_rate: Option<u64>,
+    /// Url of the proxy to connect through
+    pub proxy: Option<String>,
+    /// Refuse to make any request over plain http
+    pub https_only: bool,
+    /// Hosts requests may be made to
+    pub hosts: HostPolicy,
+}
+
+/// Hosts requests may be made to, checked for the initial url and every
+/// redirect hop
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct HostPolicy {
+    /// If not empty, only these hosts (and their subdomains) are allowed
+    pub allow: Vec<String>,
+    /// These hosts (and their subdomains) are refused, even if allowed
+    pub deny: Vec<String>,
+}
+
+impl HostPolicy {
+
+    /// Check that a request to `host` is permitted
+    pub fn check(&self, host: &str) -> Result<(), String>
+    {
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_lowercase();
+        if let Some(entry) = self.deny.iter().find(|entry| matches_host(entry, &*host)) {
+            return Err(format!("refusing to connect to {} (denied by '{}')", host, entry))
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|entry| matches_host(entry, &*host)) {
+            return Err(format!("refusing to connect to {} (not an allowed host)", host))
+        }
+        Ok(())
+    }
+}
+
+/// Whether `host` is `entry` or one of its subdomains
+fn matches_host(entry: &str, host: &str) -> bool
+{
+    host == entry || host.ends_with(&*format!(".{}", entry))
+}
+
+impl Config {
+
+    /// Location of the configuration file
+    pub fn path() -> Option<PathBuf>
+    {
+        if let Ok(path) = env::var("SLEDGE_CONFIG") {
+            return Some(PathBuf::from(path))
+        }
+        let dir = match env::var("XDG_CONFIG_HOME") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => match env::var("HOME") {
+                Ok(home) => PathBuf::from(home).join(".config"),
+                Err(_) => return None,
+            },
+        };
+        Some(dir.join("sledge").join("config"))
+    }
+
+    /// Load the configuration file, or the defaults if there isn't one
+    pub fn load() -> Result<Config, String>
+    {
+        let path = match Config::path() {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
+
+        let mut contents = String::new();
+        match File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(format!("unable to read {}: {}", path.display(), e)),
+        };
+
+        debug!("loading configuration from {}", path.display());
+        Config::parse(&*contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Parse the contents of a configuration file
+    pub fn parse(contents: &str) -> Result<Config, String>
+    {
+        let mut config = Config::default();
+
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(eq) => (line[..eq].trim(), line[eq + 1..].trim()),
+                None => return Err(format!("line {}: expected 'key = value'", i + 1)),
+            };
+
+            let err = |e: String| format!("line {}: {}", i + 1, e);
+            match key {
+                "directory" => config.directory = Some(value.to_owned()),
+                "threads" => config.threads = Some(try!(parse_threads(value).map_err(err))),
+                "limit_rate" => config.limit_rate = Some(try!(parse_rate(value).map_err(err))),
+                "proxy" => {
+                    try!(Proxy::parse(value).map_err(err));
+                    config.proxy = Some(value.to_owned());
+                },
+                "https_only" => config.https_only = try!(parse_bool(value).map_err(err)),
+                "allow_hosts" => config.hosts.allow = parse_hosts(value),
+                "deny_hosts" => config.hosts.deny = parse_hosts(value),
+                _ => return Err(err(format!("unknown setting '{}'", key))),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// The contents of a configuration file holding these settings
+    pub fn contents(&self) -> String
+    {
+        let mut lines = vec![];
+        if let Some(ref directory) = self.directory {
+            lines.push(format!("directory = {}", directory));
+        }
+        if let Some(threads) = self.threads {
+            lines.push(format!("threads = {}", threads));
+        }
+        if let Some(rate) = self.limit_rate {
+            lines.push(format!("limit_rate = {}", rate));
+        }
+        if let Some(ref proxy) = self.proxy {
+            lines.push(format!("proxy = {}", proxy));
+        }
+        if self.https_only {
+            lines.push("https_only = true".to_owned());
+        }
+        if !self.hosts.allow.is_empty() {
+            lines.push(format!("allow_hosts = {}", self.hosts.allow.join(", ")));
+        }
+        if !self.hosts.deny.is_empty() {
+            lines.push(format!("deny_hosts = {}", self.hosts.deny.join(", ")));
+        }
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+}
+
+/// Parse a number of threads, which must be positive
+pub fn parse_threads(value: &str) -> Result<u8, String>
+{
+    match value.parse::<u8>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("expected a number of threads from 1 to 255, not '{}'", value)),
+    }
+}
+
+/// Parse a rate in bytes per second, which must be positive
+pub fn parse_rate(value: &str) -> Result<u64, String>
+{
+    match try!(parse_bytes(value)) {
+        0 => Err("expected a positive rate".to_owned()),
+        rate => Ok(rate),
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String>
+{
+    match value {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(format!("expected true or false, not '{}'", value)),
+    }
+}
+
+/// Parse a comma separated list of hosts; a leading `.` or `*.` is allowed
+/// as subdomains always match
+fn parse_hosts(value: &str) -> Vec<String>
+{
+    value.split(',')
+        .map(|host| host.trim().trim_start_matches("*.").trim_start_matches('.'))
+        .map(|host| match idna::domain_to_ascii(host) {
+            Ok(ascii) => ascii,
+            Err(_) => host.to_lowercase(),
+        })
+        .filter(|host| !host.is_empty())
+        .collect()
+}
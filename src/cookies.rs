@@ -0,0 +1,398 @@
+//! Cookies kept across the requests of a run
+//!
+//! Some download portals set a session cookie on a login redirect or the
+//! first response and refuse requests without it. A `CookieStore` shared
+//! by a `ClientConfig` records every `Set-Cookie` and sends the matching
+//! cookies with each request, including the probe, redirect hops and the
+//! range requests of every worker. Stores are loaded from and saved to
+//! Netscape cookie jar files, as written by curl, wget and browser
+//! extensions.
+
+use hyper::Url;
+use hyper::header::{
+    Headers,
+    HttpDate,
+};
+use std::fs::File;
+use std::io::{
+    self,
+    BufRead,
+    BufReader,
+    Write,
+};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    /// Host the cookie was set by or for, without a leading dot
+    pub domain: String,
+    /// Whether subdomains of `domain` receive it too
+    pub include_subdomains: bool,
+    pub path: String,
+    /// Only sent over https
+    pub secure: bool,
+    /// Seconds since the epoch after which it is discarded, 0 for a
+    /// session cookie
+    pub expires: u64,
+    pub http_only: bool,
+}
+
+impl Cookie {
+
+    /// Whether the cookie is sent with a request to `url`
+    fn matches(&self, url: &Url, now: u64) -> bool
+    {
+        let host = url.host_str().unwrap_or("").to_lowercase();
+        let domain_matches = host == self.domain
+            || (self.include_subdomains && host.ends_with(&*format!(".{}", self.domain)));
+        let path = url.path();
+        let path_matches = path == self.path
+            || (path.starts_with(&*self.path)
+                && (self.path.ends_with('/') || path[self.path.len()..].starts_with('/')));
+        domain_matches && path_matches && (!self.secure || url.scheme() == "https")
+            && !self.expired(now)
+    }
+
+    fn expired(&self, now: u64) -> bool
+    {
+        self.expires != 0 && self.expires <= now
+    }
+}
+
+#[derive(Debug,Default)]
+pub struct CookieStore {
+    cookies: Mutex<Vec<Cookie>>,
+}
+
+impl CookieStore {
+
+    pub fn new() -> CookieStore
+    {
+        CookieStore::default()
+    }
+
+    /// Load the cookies of a Netscape cookie jar file. Expired cookies and
+    /// malformed lines are skipped.
+    pub fn load(path: &Path) -> io::Result<CookieStore>
+    {
+        let store = CookieStore::new();
+        let now = now();
+        for line in BufReader::new(try!(File::open(path))).lines() {
+            let line = try!(line);
+            let (line, http_only) = if line.starts_with("#HttpOnly_") {
+                (&line["#HttpOnly_".len()..], true)
+            } else {
+                (&*line, false)
+            };
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 7 {
+                warn!("Skipping malformed line of {}: {}", path.display(), line);
+                continue
+            }
+            let expires = match fields[4].parse::<u64>() {
+                Ok(expires) => expires,
+                Err(_) => {
+                    warn!("Skipping malformed line of {}: {}", path.display(), line);
+                    continue
+                },
+            };
+            let cookie = Cookie {
+                name: fields[5].to_owned(),
+                value: fields[6].to_owned(),
+                domain: fields[0].trim_start_matches('.').to_lowercase(),
+                include_subdomains: fields[1] == "TRUE" || fields[0].starts_with('.'),
+                path: fields[2].to_owned(),
+                secure: fields[3] == "TRUE",
+                expires: expires,
+                http_only: http_only,
+            };
+            if !cookie.expired(now) {
+                store.add(cookie);
+            }
+        }
+        Ok(store)
+    }
+
+    /// Write the cookies that haven't expired to a Netscape cookie jar file
+    pub fn save(&self, path: &Path) -> io::Result<()>
+    {
+        let mut file = try!(File::create(path));
+        try!(writeln!(file, "# Netscape HTTP Cookie File"));
+        let now = now();
+        for cookie in self.cookies.lock().unwrap().iter().filter(|c| !c.expired(now)) {
+            let domain = if cookie.include_subdomains {
+                format!(".{}", cookie.domain)
+            } else {
+                cookie.domain.clone()
+            };
+            try!(writeln!(file, "{}{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                          if cookie.http_only { "#HttpOnly_" } else { "" }, domain,
+                          flag(cookie.include_subdomains), cookie.path, flag(cookie.secure),
+                          cookie.expires, cookie.name, cookie.value));
+        }
+        Ok(())
+    }
+
+    /// Add `cookie`, replacing one with the same domain, path and name
+    pub fn add(&self, cookie: Cookie)
+    {
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|c| {
+            !(c.domain == cookie.domain && c.path == cookie.path && c.name == cookie.name)
+        });
+        cookies.push(cookie);
+    }
+
+    /// Add session cookies for the host of `url` from a `name=value`
+    /// list, e.g. `session=abc; lang=en`
+    pub fn add_pairs(&self, url: &Url, pairs: &str) -> Result<(), String>
+    {
+        for pair in pairs.split(';').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (name, value) = match pair.find('=') {
+                Some(i) if i > 0 => (pair[..i].trim(), pair[i + 1..].trim()),
+                _ => return Err(format!("expected 'name=value', not '{}'", pair)),
+            };
+            self.add(Cookie {
+                name: name.to_owned(),
+                value: value.to_owned(),
+                domain: url.host_str().unwrap_or("").to_lowercase(),
+                include_subdomains: false,
+                path: "/".to_owned(),
+                secure: false,
+                expires: 0,
+                http_only: false,
+            });
+        }
+        Ok(())
+    }
+
+    /// The `Cookie` header value for a request to `url`, if any cookies
+    /// match. Cookies with longer paths come first.
+    pub fn header(&self, url: &Url) -> Option<String>
+    {
+        let now = now();
+        let cookies = self.cookies.lock().unwrap();
+        let mut matching: Vec<&Cookie> = cookies.iter().filter(|c| c.matches(url, now)).collect();
+        if matching.is_empty() {
+            return None
+        }
+        matching.sort_by(|a, b| b.path.len().cmp(&a.path.len()));
+        let pairs: Vec<String> = matching.iter()
+            .map(|c| format!("{}={}", c.name, c.value))
+            .collect();
+        Some(pairs.join("; "))
+    }
+
+    /// Record the cookies set by a response to `url`. Cookies for another
+    /// domain are ignored, and a cookie already expired removes any it
+    /// replaces.
+    pub fn store_response(&self, url: &Url, headers: &Headers)
+    {
+        let lines = match headers.get_raw("Set-Cookie") {
+            Some(lines) => lines,
+            None => return,
+        };
+        let now = now();
+        for line in lines {
+            let line = String::from_utf8_lossy(line);
+            match parse_set_cookie(url, &*line, now) {
+                Some(cookie) => {
+                    debug!("{} set cookie {} for {}", url, cookie.name, cookie.domain);
+                    self.add(cookie)
+                },
+                None => debug!("ignoring cookie set by {}: {}", url, line),
+            }
+        }
+        self.cookies.lock().unwrap().retain(|c| !c.expired(now));
+    }
+}
+
+/// Parse a `Set-Cookie` header received from `url`
+fn parse_set_cookie(url: &Url, line: &str, now: u64) -> Option<Cookie>
+{
+    let host = url.host_str().unwrap_or("").to_lowercase();
+    let mut parts = line.split(';');
+    let pair = parts.next().unwrap_or("");
+    let (name, value) = match pair.find('=') {
+        Some(i) if i > 0 => (pair[..i].trim(), pair[i + 1..].trim()),
+        _ => return None,
+    };
+    // The default path is the directory of the request's path
+    let default_path = match url.path().rfind('/') {
+        Some(i) if i > 0 => url.path()[..i].to_owned(),
+        _ => "/".to_owned(),
+    };
+    let mut cookie = Cookie {
+        name: name.to_owned(),
+        value: value.trim_matches('"').to_owned(),
+        domain: host.clone(),
+        include_subdomains: false,
+        path: default_path,
+        secure: false,
+        expires: 0,
+        http_only: false,
+    };
+    let mut max_age = None;
+    for attribute in parts {
+        let (key, value) = match attribute.find('=') {
+            Some(i) => (attribute[..i].trim(), attribute[i + 1..].trim()),
+            None => (attribute.trim(), ""),
+        };
+        match &*key.to_lowercase() {
+            "domain" if !value.is_empty() => {
+                let domain = value.trim_start_matches('.').to_lowercase();
+                // A server may only set cookies for itself or a parent domain
+                if host != domain && !host.ends_with(&*format!(".{}", domain)) {
+                    return None
+                }
+                cookie.domain = domain;
+                cookie.include_subdomains = true;
+            },
+            "path" if value.starts_with('/') => cookie.path = value.to_owned(),
+            "secure" => cookie.secure = true,
+            "httponly" => cookie.http_only = true,
+            "max-age" => max_age = value.parse::<i64>().ok(),
+            "expires" => if let Ok(date) = value.parse::<HttpDate>() {
+                cookie.expires = date.0.to_timespec().sec.max(1) as u64;
+            },
+            _ => (),
+        }
+    }
+    // Max-Age takes precedence over Expires
+    match max_age {
+        Some(secs) if secs <= 0 => cookie.expires = 1,
+        Some(secs) => cookie.expires = now + secs as u64,
+        None => (),
+    }
+    Some(cookie)
+}
+
+fn flag(value: bool) -> &'static str
+{
+    if value { "TRUE" } else { "FALSE" }
+}
+
+/// Seconds since the epoch
+fn now() -> u64
+{
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hyper::Url;
+    use hyper::header::Headers;
+    use super::{
+        Cookie,
+        CookieStore,
+        parse_set_cookie,
+    };
+
+    const NOW: u64 = 1_400_000_000;
+
+    fn url(url: &str) -> Url
+    {
+        Url::parse(url).unwrap()
+    }
+
+    fn parse(line: &str) -> Option<Cookie>
+    {
+        parse_set_cookie(&url("https://www.example.com/files/a.iso"), line, NOW)
+    }
+
+    #[test]
+    fn parses_attributes()
+    {
+        let cookie = parse("SID=31d4d96e407aad42; Path=/; Domain=.Example.com; Secure; HttpOnly; \
+                            Expires=Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        assert_eq!(cookie, Cookie {
+            name: "SID".to_owned(),
+            value: "31d4d96e407aad42".to_owned(),
+            domain: "example.com".to_owned(),
+            include_subdomains: true,
+            path: "/".to_owned(),
+            secure: true,
+            expires: 1445412480,
+            http_only: true,
+        });
+    }
+
+    #[test]
+    fn defaults_to_host_and_directory()
+    {
+        let cookie = parse("lang=\"en-US\"").unwrap();
+        assert_eq!(cookie.value, "en-US");
+        assert_eq!(cookie.domain, "www.example.com");
+        assert!(!cookie.include_subdomains);
+        assert_eq!(cookie.path, "/files");
+        assert_eq!(cookie.expires, 0);
+        // A path not starting with / is ignored
+        assert_eq!(parse("a=b; Path=files").unwrap().path, "/files");
+    }
+
+    #[test]
+    fn max_age_overrides_expires()
+    {
+        let expires = "Expires=Wed, 21 Oct 2015 07:28:00 GMT";
+        assert_eq!(parse(&*format!("a=b; Max-Age=60; {}", expires)).unwrap().expires, NOW + 60);
+        assert_eq!(parse(&*format!("a=b; {}; Max-Age=0", expires)).unwrap().expires, 1);
+        assert_eq!(parse("a=b; Max-Age=-5").unwrap().expires, 1);
+        assert_eq!(parse("a=b; Max-Age=soon").unwrap().expires, 0);
+        assert_eq!(parse("a=b; Expires=tomorrow").unwrap().expires, 0);
+    }
+
+    #[test]
+    fn refuses_other_domains_and_malformed()
+    {
+        assert_eq!(parse("a=b; Domain=evil.com"), None);
+        assert_eq!(parse("a=b; Domain=ample.com"), None);
+        assert_eq!(parse("a=b; Domain=sub.www.example.com"), None);
+        assert!(parse("a=b; Domain=www.example.com").is_some());
+        assert_eq!(parse("novalue"), None);
+        assert_eq!(parse("=value"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn sends_matching_cookies()
+    {
+        let store = CookieStore::new();
+        let mut headers = Headers::new();
+        headers.set_raw("Set-Cookie", vec![
+            b"site=1; Domain=example.com; Path=/".to_vec(),
+            b"files=2; Path=/files".to_vec(),
+            b"secure=3; Path=/; Secure".to_vec(),
+            b"gone=4; Path=/; Max-Age=0".to_vec(),
+        ]);
+        store.store_response(&url("https://www.example.com/files/a.iso"), &headers);
+
+        assert_eq!(store.header(&url("https://www.example.com/files/b.iso")),
+                   Some("files=2; site=1; secure=3".to_owned()));
+        assert_eq!(store.header(&url("http://www.example.com/filesystem")),
+                   Some("site=1".to_owned()));
+        assert_eq!(store.header(&url("https://cdn.example.com/files/a.iso")),
+                   Some("site=1".to_owned()));
+        assert_eq!(store.header(&url("https://example.org/")), None);
+    }
+
+    #[test]
+    fn pairs_need_names()
+    {
+        let store = CookieStore::new();
+        assert!(store.add_pairs(&url("https://example.com/"), "a=1; b=2").is_ok());
+        assert_eq!(store.header(&url("https://example.com/x")), Some("a=1; b=2".to_owned()));
+        assert!(store.add_pairs(&url("https://example.com/"), "a=1; oops").is_err());
+    }
+}
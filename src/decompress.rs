@@ -0,0 +1,316 @@
+//! Decompressing downloads by their magic bytes
+//!
+//! Some servers and CDNs send a compressed body without saying so, or with
+//! a `Content-Encoding` the client didn't ask for, leaving a gzip, zstd or
+//! xz stream where the file was expected. With `--auto-decompress` a
+//! completed file starting with one of their magic numbers is decompressed
+//! in place, unless its name says it is meant to be compressed (e.g.
+//! `.tar.gz`).
+//!
+//! Streamed to stdout there is no file to decompress afterwards, so the
+//! ordered chunk stream is decoded as it arrives on a thread of its own,
+//! keeping the CPU-bound decoder from holding up the fetching workers.
+
+use flate2::read::MultiGzDecoder;
+use paths::create_staging;
+use std::cmp;
+use std::fs::{
+    self,
+    File,
+};
+use std::io::{
+    self,
+    Read,
+    Write,
+};
+use std::path::Path;
+use std::sync::mpsc::{
+    sync_channel,
+    Receiver,
+    SyncSender,
+};
+use std::thread::{
+    self,
+    JoinHandle,
+};
+use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+
+/// Longest magic number recognized
+const MAX_MAGIC: usize = 6;
+
+/// Pieces queued for the decoding thread before writes block
+const STREAM_QUEUE: usize = 16;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+
+    /// The compression whose magic number `bytes` start with, if any
+    pub fn sniff(bytes: &[u8]) -> Option<Compression>
+    {
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else if bytes.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(&self) -> &'static str
+    {
+        match *self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+        }
+    }
+
+    /// Extensions of files expected to hold this compression
+    fn extensions(&self) -> &'static [&'static str]
+    {
+        match *self {
+            Compression::Gzip => &["gz", "tgz", "gzip"],
+            Compression::Zstd => &["zst", "zstd", "tzst"],
+            Compression::Xz => &["xz", "txz"],
+        }
+    }
+
+    /// Whether the name of `path` says it holds this compression
+    fn named_in(&self, path: &Path) -> bool
+    {
+        let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+        extension.map_or(false, |e| self.extensions().contains(&&*e))
+    }
+
+    /// A reader of the decompressed contents of `reader`
+    pub fn decoder<'a, R: Read + 'a>(&self, reader: R) -> io::Result<Box<dyn Read + 'a>>
+    {
+        Ok(match *self {
+            Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Compression::Zstd => Box::new(try!(ZstdDecoder::new(reader))),
+            Compression::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+        })
+    }
+}
+
+/// Decompress the file at `path` in place if it starts with a known magic
+/// number and its name doesn't say it is compressed, keeping its
+/// permissions and modification time. Returns the compression removed.
+pub fn decompress_file(path: &Path) -> io::Result<Option<Compression>>
+{
+    let mut magic = vec![];
+    try!(try!(File::open(path)).take(MAX_MAGIC as u64).read_to_end(&mut magic));
+    let compression = match Compression::sniff(&magic) {
+        Some(compression) => compression,
+        None => return Ok(None),
+    };
+    if compression.named_in(path) {
+        debug!("{} is meant to be {} compressed, keeping it", path.display(), compression.name());
+        return Ok(None)
+    }
+
+    let metadata = try!(fs::metadata(path));
+    let (tmp, out) = try!(create_staging(path, "decompress"));
+    let result = File::open(path)
+        .and_then(|file| compression.decoder(file))
+        .and_then(|mut decoder| {
+            // Closed before it is renamed
+            let mut out = out;
+            try!(io::copy(&mut decoder, &mut out));
+            try!(out.set_permissions(metadata.permissions()));
+            if let Ok(modified) = metadata.modified() {
+                try!(out.set_modified(modified));
+            }
+            out.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(io::Error::new(e.kind(), format!("unable to decompress {} contents: {}",
+                                                    compression.name(), e)))
+    }
+    Ok(Some(compression))
+}
+
+/// Decompress what is written to the returned writer on a thread of its
+/// own, writing the result to `out`. Input starting with a known magic
+/// number is decoded unless `name` says it is compressed, other input is
+/// passed through. The thread finishes once the writer is dropped,
+/// returning the compression removed.
+pub fn decompress_stream<W>(mut out: W, name: &str)
+                            -> (StreamWriter, JoinHandle<io::Result<Option<Compression>>>)
+    where W: Write + Send + 'static
+{
+    let (tx, rx) = sync_channel(STREAM_QUEUE);
+    let name = name.to_owned();
+    let decoder = thread::spawn(move || -> io::Result<Option<Compression>> {
+        let mut input = StreamReader { rx: rx, piece: vec![], pos: 0 };
+        let mut magic = vec![];
+        try!((&mut input).take(MAX_MAGIC as u64).read_to_end(&mut magic));
+        let compression = Compression::sniff(&magic).and_then(|compression| {
+            if compression.named_in(Path::new(&*name)) {
+                debug!("{} is meant to be {} compressed, keeping it", name, compression.name());
+                None
+            } else {
+                Some(compression)
+            }
+        });
+        let mut input = io::Cursor::new(magic).chain(input);
+        let result = match compression {
+            Some(compression) => compression.decoder(input)
+                .and_then(|mut decoder| io::copy(&mut decoder, &mut out)),
+            None => io::copy(&mut input, &mut out),
+        };
+        try!(result.and_then(|_| out.flush()).map_err(|e| match compression {
+            Some(compression) => io::Error::new(e.kind(), format!(
+                "unable to decompress {} contents: {}", compression.name(), e)),
+            None => e,
+        }));
+        Ok(compression)
+    });
+    (StreamWriter { tx: tx }, decoder)
+}
+
+/// Hands what is written to the thread started by `decompress_stream`
+pub struct StreamWriter {
+    tx: SyncSender<Vec<u8>>,
+}
+
+impl Write for StreamWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        // The decoding thread only hangs up when it fails, with the reason
+        // returned when it is joined
+        try!(self.tx.send(buf.to_vec()).map_err(|_| io::Error::new(
+            io::ErrorKind::BrokenPipe, "the decompressing thread stopped")));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        Ok(())
+    }
+}
+
+/// Reads the pieces sent by a `StreamWriter` until it is dropped
+struct StreamReader {
+    rx: Receiver<Vec<u8>>,
+    piece: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        while self.pos == self.piece.len() {
+            match self.rx.recv() {
+                Ok(piece) => {
+                    self.piece = piece;
+                    self.pos = 0;
+                },
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = cmp::min(buf.len(), self.piece.len() - self.pos);
+        buf[..n].copy_from_slice(&self.piece[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decompress_stream,
+        Compression,
+    };
+    use flate2::Compression as Level;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    use std::sync::{
+        Arc,
+        Mutex,
+    };
+
+    /// Collects what the decoding thread writes
+    #[derive(Clone,Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize>
+        {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> ::std::io::Result<()>
+        {
+            Ok(())
+        }
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8>
+    {
+        let mut encoder = GzEncoder::new(vec![], Level::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn stream(input: &[u8], name: &str, piece: usize) -> (Option<Compression>, Vec<u8>)
+    {
+        let sink = Sink::default();
+        let (mut writer, decoder) = decompress_stream(sink.clone(), name);
+        for chunk in input.chunks(piece) {
+            writer.write_all(chunk).unwrap();
+        }
+        drop(writer);
+        let compression = decoder.join().unwrap().unwrap();
+        let out = sink.0.lock().unwrap().clone();
+        (compression, out)
+    }
+
+    #[test]
+    fn streams_are_decoded_as_they_arrive()
+    {
+        let data: Vec<u8> = (0..100000u32).map(|i| (i % 251) as u8).collect();
+        // Pieces smaller than the magic number are put back together
+        for &piece in &[1, 5, 4096] {
+            assert_eq!(stream(&gzip(&data), "/data.bin", piece),
+                       (Some(Compression::Gzip), data.clone()));
+        }
+    }
+
+    #[test]
+    fn other_streams_pass_through()
+    {
+        let data = b"plain text".to_vec();
+        assert_eq!(stream(&data, "/a.txt", 3), (None, data));
+        assert_eq!(stream(b"", "/empty", 1), (None, vec![]));
+        let compressed = gzip(b"kept");
+        assert_eq!(stream(&compressed, "/archive.tar.gz", 7), (None, compressed));
+    }
+
+    #[test]
+    fn corrupt_streams_fail_when_joined()
+    {
+        let mut corrupt = gzip(&[7; 10000]);
+        let len = corrupt.len();
+        corrupt[len / 2..].iter_mut().for_each(|b| *b = !*b);
+        let (mut writer, decoder) = decompress_stream(Sink::default(), "/data.bin");
+        // Writing may or may not notice, depending on when the thread gives up
+        let _ = writer.write_all(&corrupt);
+        drop(writer);
+        assert!(decoder.join().unwrap().is_err());
+    }
+}
@@ -0,0 +1,571 @@
+//! Resolution and caching of host addresses
+//!
+//! Every segment of a parallel download (and every retry) opens its own
+//! connection. Resolving the host once and sharing the result avoids a
+//! burst of identical DNS lookups.
+//!
+//! Names are resolved by a `Resolver`: the system's (the default), a
+//! `StaticResolver` of fixed addresses like curl's `--resolve`, a
+//! `DohResolver` asking a DNS over HTTPS server, or one provided by an
+//! embedder, e.g. to pin the addresses of a service mesh or test harness.
+
+use client::ClientConfig;
+use errors::{
+    Elapsed,
+    TimeoutKind,
+};
+use hyper;
+use idna;
+use hyper::header::Headers;
+use hyper::net::{
+    HttpStream,
+    NetworkConnector,
+};
+use std::collections::HashMap;
+use std::io::{
+    self,
+    Read,
+};
+use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    Ipv6Addr,
+    SocketAddr,
+    TcpStream,
+    ToSocketAddrs,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+
+
+/// Describe a host by both its ASCII (punycode) and Unicode forms, e.g.
+/// `xn--bcher-kva.de (bücher.de)`, or just the host if they are the same
+pub fn display_host(host: &str) -> String
+{
+    let (unicode, result) = idna::domain_to_unicode(host);
+    if result.is_err() || unicode == host {
+        host.to_owned()
+    } else {
+        format!("{} ({})", host, unicode)
+    }
+}
+
+/// DNS record type of IPv4 addresses
+const TYPE_A: u16 = 1;
+
+/// DNS record type of IPv6 addresses
+const TYPE_AAAA: u16 = 28;
+
+/// Finds the addresses connections to a host are made to
+pub trait Resolver: Send + Sync {
+
+    /// The addresses of `host` (never an IP literal) to connect to on
+    /// `port`, in the order they are tried
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves names as the system does, e.g. with /etc/hosts and the
+/// configured name servers
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>
+    {
+        Ok(try!((host, port).to_socket_addrs()).collect())
+    }
+}
+
+/// Resolves hosts to fixed addresses, passing others to a fallback
+pub struct StaticResolver {
+    /// Addresses by host and port, or by host alone for any port
+    entries: HashMap<(String, Option<u16>), Vec<IpAddr>>,
+    /// Resolves hosts without an entry, which fail if None
+    fallback: Option<Arc<dyn Resolver>>,
+}
+
+impl StaticResolver {
+
+    /// Create a resolver of no hosts, resolving others with the system's
+    pub fn new() -> StaticResolver
+    {
+        StaticResolver {
+            entries: HashMap::new(),
+            fallback: Some(Arc::new(SystemResolver)),
+        }
+    }
+
+    /// Resolve `host` to `addrs` for connections to `port`, or to any
+    /// port if None
+    pub fn add(mut self, host: &str, port: Option<u16>, addrs: Vec<IpAddr>) -> StaticResolver
+    {
+        self.entries.insert((host.to_lowercase(), port), addrs);
+        self
+    }
+
+    /// Resolve hosts without an entry with `fallback`, or fail if None
+    pub fn fallback(mut self, fallback: Option<Arc<dyn Resolver>>) -> StaticResolver
+    {
+        self.fallback = fallback;
+        self
+    }
+
+    /// Parse an entry written like curl's `--resolve`,
+    /// `HOST:PORT:ADDR[,ADDR...]`, where the port may be `*` for any port
+    /// and IPv6 addresses may be in brackets
+    pub fn parse_entry(entry: &str) -> Result<(String, Option<u16>, Vec<IpAddr>), String>
+    {
+        let parts: Vec<&str> = entry.splitn(3, ':').collect();
+        if parts.len() < 3 || parts[0].is_empty() {
+            return Err(format!("'{}' isn't HOST:PORT:ADDR", entry))
+        }
+        let port = match parts[1] {
+            "*" => None,
+            port => Some(try!(port.parse::<u16>().map_err(|_| {
+                format!("invalid port '{}' in '{}'", port, entry)
+            }))),
+        };
+        let mut addrs = vec![];
+        for addr in parts[2].split(',') {
+            let bare = addr.trim().trim_start_matches('[').trim_end_matches(']');
+            addrs.push(try!(bare.parse::<IpAddr>().map_err(|_| {
+                format!("invalid address '{}' in '{}'", addr, entry)
+            })));
+        }
+        Ok((parts[0].to_owned(), port, addrs))
+    }
+}
+
+impl Resolver for StaticResolver {
+
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>
+    {
+        let host = host.to_lowercase();
+        let entry = self.entries.get(&(host.clone(), Some(port)))
+            .or_else(|| self.entries.get(&(host.clone(), None)));
+        match (entry, &self.fallback) {
+            (Some(addrs), _) => Ok(addrs.iter().map(|&ip| SocketAddr::new(ip, port)).collect()),
+            (None, &Some(ref fallback)) => fallback.resolve(&*host, port),
+            (None, &None) => Err(io::Error::new(
+                io::ErrorKind::NotFound, format!("no address is given for {}", host))),
+        }
+    }
+}
+
+/// Resolves names by asking a DNS over HTTPS (RFC 8484) server, e.g.
+/// `https://cloudflare-dns.com/dns-query`, for their A and AAAA records
+pub struct DohResolver {
+    /// Url queries are posted to
+    url: String,
+    /// Config of the requests to the server, which resolves its host with
+    /// the system's resolver
+    config: ClientConfig,
+}
+
+impl DohResolver {
+
+    pub fn new(url: &str) -> DohResolver
+    {
+        DohResolver { url: url.to_owned(), config: ClientConfig::new() }
+    }
+
+    /// Make the queries with `config`, e.g. for its timeouts or proxies
+    pub fn config(mut self, config: ClientConfig) -> DohResolver
+    {
+        self.config = config;
+        self
+    }
+
+    /// The addresses of the records of `record_type` for `host`
+    fn query(&self, host: &str, record_type: u16) -> io::Result<Vec<IpAddr>>
+    {
+        let query = try!(encode_query(host, record_type));
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Type", vec![b"application/dns-message".to_vec()]);
+        headers.set_raw("Accept", vec![b"application/dns-message".to_vec()]);
+        let mut response = try!(self.config.client().post(&*self.url).headers(headers)
+                                .body(&query[..]).send()
+                                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())));
+        let mut answer = vec![];
+        try!(response.read_to_end(&mut answer));
+        if !response.status.is_success() {
+            return Err(io::Error::new(io::ErrorKind::Other, format!(
+                "{} refused to resolve {} ({})", self.url, host, response.status)))
+        }
+        decode_answer(&answer).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} sent an invalid answer for {}", self.url, host)))
+    }
+}
+
+impl Resolver for DohResolver {
+
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>
+    {
+        let mut addrs = try!(self.query(host, TYPE_A));
+        addrs.extend(try!(self.query(host, TYPE_AAAA)));
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound, format!("{} has no addresses for {}", self.url, host)))
+        }
+        Ok(addrs.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+    }
+}
+
+/// A recursive DNS query for the records of `record_type` for `host`
+fn encode_query(host: &str, record_type: u16) -> io::Result<Vec<u8>>
+{
+    // Id 0 (as RFC 8484 recommends, for caching), recursion desired, and
+    // one question
+    let mut query = vec![0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput, format!("invalid host name {}", host)))
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&[(record_type >> 8) as u8, record_type as u8, 0, 1]);
+    Ok(query)
+}
+
+/// The A and AAAA addresses answering a DNS query, none if the name
+/// doesn't exist, or None if the message isn't a successful answer
+fn decode_answer(message: &[u8]) -> Option<Vec<IpAddr>>
+{
+    let u16_at = |i: usize| (message[i] as u16) << 8 | message[i + 1] as u16;
+    // Past the name starting at `i`, which may end with a pointer
+    let skip_name = |mut i: usize| loop {
+        match message.get(i) {
+            Some(&0) => return Some(i + 1),
+            Some(&len) if len & 0xc0 == 0xc0 => return Some(i + 2),
+            Some(&len) => i += len as usize + 1,
+            None => return None,
+        }
+    };
+
+    if message.len() < 12 {
+        return None
+    }
+    match u16_at(2) & 0xf {
+        0 => (),
+        // The name doesn't exist
+        3 => return Some(vec![]),
+        _ => return None,
+    }
+    let mut i = 12;
+    for _ in 0..u16_at(4) {
+        i = match skip_name(i) {
+            Some(end) => end + 4,
+            None => return None,
+        };
+    }
+    let mut addrs = vec![];
+    for _ in 0..u16_at(6) {
+        i = match skip_name(i) {
+            Some(end) if end + 10 <= message.len() => end,
+            _ => return None,
+        };
+        let (record_type, len) = (u16_at(i), u16_at(i + 8) as usize);
+        let data = match message.get(i + 10..i + 10 + len) {
+            Some(data) => data,
+            None => return None,
+        };
+        match (record_type, len) {
+            (TYPE_A, 4) => addrs.push(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data);
+                addrs.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            },
+            // e.g. the CNAMEs leading to the addresses
+            _ => (),
+        }
+        i += 10 + len;
+    }
+    Some(addrs)
+}
+
+pub struct DnsCache {
+    /// How long resolved addresses are reused (forever if None)
+    ttl: Option<Duration>,
+    /// Resolves hosts not in the cache
+    resolver: Arc<dyn Resolver>,
+    entries: Mutex<HashMap<(String, u16), (Instant, Vec<SocketAddr>)>>,
+}
+
+impl DnsCache {
+
+    /// Create an empty cache whose entries expire after `ttl`, resolving
+    /// hosts as the system does
+    pub fn new(ttl: Option<Duration>) -> DnsCache
+    {
+        DnsCache {
+            ttl: ttl,
+            resolver: Arc::new(SystemResolver),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Resolve hosts with `resolver` instead of the system's
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> DnsCache
+    {
+        self.resolver = resolver;
+        self
+    }
+
+    /// How long resolved addresses are reused (forever if None)
+    pub fn ttl(&self) -> Option<Duration>
+    {
+        self.ttl
+    }
+
+    /// The resolver of hosts not in the cache
+    pub fn current_resolver(&self) -> Arc<dyn Resolver>
+    {
+        self.resolver.clone()
+    }
+
+    /// Resolve `host`, reusing a previous result if it has not expired
+    pub fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>
+    {
+        let key = (host.to_owned(), port);
+        if let Some(&(resolved_at, ref addrs)) = self.entries.lock().unwrap().get(&key) {
+            if self.ttl.map_or(true, |ttl| resolved_at.elapsed() < ttl) {
+                trace!("using cached addresses for {}: {:?}", host, addrs);
+                return Ok(addrs.clone())
+            }
+        }
+
+        // IPv6 literals arrive in brackets, which the resolver rejects
+        let bare = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = bare.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)])
+        }
+        let addrs = try!(self.resolver.resolve(bare, port));
+        debug!("resolved {} to {:?}", display_host(host), addrs);
+
+        self.entries.lock().unwrap().insert(key, (Instant::now(), addrs.clone()));
+        Ok(addrs)
+    }
+}
+
+/// Opens plain TCP connections using addresses from a shared `DnsCache`
+pub struct CachingConnector {
+    cache: Arc<DnsCache>,
+    /// How long to wait for each address to accept (forever if None)
+    timeout: Option<Duration>,
+}
+
+impl CachingConnector {
+    pub fn new(cache: Arc<DnsCache>) -> CachingConnector
+    {
+        CachingConnector { cache: cache, timeout: None }
+    }
+
+    /// Give up on an address that hasn't accepted after `timeout`
+    pub fn timeout(mut self, timeout: Option<Duration>) -> CachingConnector
+    {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The cache addresses are resolved with
+    pub fn cache(&self) -> &DnsCache
+    {
+        &self.cache
+    }
+}
+
+impl NetworkConnector for CachingConnector {
+    type Stream = HttpStream;
+
+    fn connect(&self, host: &str, port: u16, _: &str) -> hyper::Result<HttpStream>
+    {
+        let mut last_err = io::Error::new(
+            io::ErrorKind::Other, format!("no addresses found for {}", host));
+
+        for addr in try!(self.cache.resolve(host, port)) {
+            let connected = match self.timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout).map_err(|e| {
+                    match e.kind() {
+                        io::ErrorKind::TimedOut => Elapsed::new(TimeoutKind::Connect, timeout).into_io(),
+                        _ => e,
+                    }
+                }),
+                None => TcpStream::connect(addr),
+            };
+            match connected {
+                Ok(stream) => return Ok(HttpStream(stream)),
+                Err(e) => {
+                    debug!("unable to connect to {}: {}", addr, e);
+                    last_err = e;
+                },
+            }
+        }
+
+        Err(hyper::Error::from(last_err))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::{
+        IpAddr,
+        SocketAddr,
+    };
+    use std::sync::Arc;
+    use std::sync::atomic::{
+        AtomicUsize,
+        Ordering,
+    };
+    use super::{
+        DnsCache,
+        Resolver,
+        StaticResolver,
+        TYPE_A,
+        TYPE_AAAA,
+        decode_answer,
+        encode_query,
+    };
+
+    /// The answer to a query for www.example.com, a CNAME to example.com
+    /// and its address, the names compressed as servers send them
+    const CNAME_AND_A: &'static [u8] = &[
+        0x00, 0x00, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00,
+        // www.example.com A IN
+        0x03, b'w', b'w', b'w', 0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e',
+        0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01,
+        // www.example.com CNAME example.com, ttl 300
+        0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x02, 0xc0, 0x10,
+        // example.com A 93.184.216.34
+        0xc0, 0x10, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x04,
+        93, 184, 216, 34,
+    ];
+
+    /// The answer to a query for example.com AAAA
+    const AAAA: &'static [u8] = &[
+        0x00, 0x00, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00,
+        0x00, 0x1c, 0x00, 0x01,
+        0xc0, 0x0c, 0x00, 0x1c, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x10,
+        0x26, 0x06, 0x28, 0x00, 0x02, 0x20, 0x00, 0x01, 0x02, 0x48, 0x18, 0x93, 0x25, 0xc8, 0x19, 0x46,
+    ];
+
+    fn ip(ip: &str) -> IpAddr
+    {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn encodes_queries()
+    {
+        // The example query of RFC 8484, section 4.1
+        assert_eq!(encode_query("www.example.com", TYPE_A).unwrap(), vec![
+            0x00, 0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x03, 0x77, 0x77, 0x77, 0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65,
+            0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00, 0x01,
+        ]);
+        assert_eq!(encode_query("example.com.", TYPE_AAAA).unwrap()[25..], [0x00, 0x1c, 0x00, 0x01]);
+        assert!(encode_query("a..example.com", TYPE_A).is_err());
+        assert!(encode_query(&*format!("{}.com", "a".repeat(64)), TYPE_A).is_err());
+    }
+
+    #[test]
+    fn decodes_answers()
+    {
+        assert_eq!(decode_answer(CNAME_AND_A), Some(vec![ip("93.184.216.34")]));
+        assert_eq!(decode_answer(AAAA), Some(vec![ip("2606:2800:220:1:248:1893:25c8:1946")]));
+    }
+
+    #[test]
+    fn decodes_failures()
+    {
+        // NXDOMAIN is an answer with no addresses, SERVFAIL no answer
+        let mut nxdomain = CNAME_AND_A[..33].to_vec();
+        nxdomain[3] = 0x83;
+        nxdomain[7] = 0;
+        assert_eq!(decode_answer(&nxdomain), Some(vec![]));
+        let mut servfail = nxdomain.clone();
+        servfail[3] = 0x82;
+        assert_eq!(decode_answer(&servfail), None);
+    }
+
+    #[test]
+    fn refuses_truncated_answers()
+    {
+        for len in 0..CNAME_AND_A.len() {
+            assert_eq!(decode_answer(&CNAME_AND_A[..len]), None, "truncated to {} bytes", len);
+        }
+        // A record claiming more data than the message holds
+        let mut overlong = AAAA.to_vec();
+        overlong[40] = 0x20;
+        assert_eq!(decode_answer(&overlong), None);
+    }
+
+    #[test]
+    fn parses_resolve_entries()
+    {
+        assert_eq!(StaticResolver::parse_entry("example.com:443:127.0.0.1"),
+                   Ok(("example.com".to_owned(), Some(443), vec![ip("127.0.0.1")])));
+        assert_eq!(StaticResolver::parse_entry("example.com:*:[::1],10.0.0.1"),
+                   Ok(("example.com".to_owned(), None, vec![ip("::1"), ip("10.0.0.1")])));
+        assert_eq!(StaticResolver::parse_entry("example.com:443:::1"),
+                   Ok(("example.com".to_owned(), Some(443), vec![ip("::1")])));
+        assert!(StaticResolver::parse_entry("example.com:443").is_err());
+        assert!(StaticResolver::parse_entry(":443:127.0.0.1").is_err());
+        assert!(StaticResolver::parse_entry("example.com:https:127.0.0.1").is_err());
+        assert!(StaticResolver::parse_entry("example.com:443:localhost").is_err());
+        assert!(StaticResolver::parse_entry("example.com:443:127.0.0.1,").is_err());
+    }
+
+    #[test]
+    fn resolves_static_entries()
+    {
+        let resolver = StaticResolver::new()
+            .add("Example.com", Some(443), vec![ip("10.0.0.1")])
+            .add("example.com", None, vec![ip("10.0.0.2")])
+            .fallback(None);
+        let addrs = |port| resolver.resolve("EXAMPLE.com", port).unwrap();
+        assert_eq!(addrs(443), vec!["10.0.0.1:443".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(addrs(80), vec!["10.0.0.2:80".parse::<SocketAddr>().unwrap()]);
+        assert!(resolver.resolve("example.org", 443).is_err());
+    }
+
+    /// Resolves every host to 10.0.0.1, counting the lookups
+    struct Counting(AtomicUsize);
+
+    impl Resolver for Counting {
+        fn resolve(&self, _: &str, port: u16) -> io::Result<Vec<SocketAddr>>
+        {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![SocketAddr::new(ip("10.0.0.1"), port)])
+        }
+    }
+
+    #[test]
+    fn caches_provided_resolver()
+    {
+        let counting = Arc::new(Counting(AtomicUsize::new(0)));
+        let cache = DnsCache::new(None).resolver(counting.clone());
+        for _ in 0..3 {
+            assert_eq!(cache.resolve("example.com", 80).unwrap(),
+                       vec!["10.0.0.1:80".parse::<SocketAddr>().unwrap()]);
+        }
+        assert_eq!(counting.0.load(Ordering::SeqCst), 1);
+
+        // IP literals aren't looked up
+        assert_eq!(cache.resolve("[::1]", 80).unwrap(), vec!["[::1]:80".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(counting.0.load(Ordering::SeqCst), 1);
+    }
+}
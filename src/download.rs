@@ -1,28 +1,163 @@
 //! Download files
 
 use ::DEFAULT_BUFF_SIZE;
-use ::errors::DownloadError;
-use hyper::Client;
+use ::errors::{DownloadError, Elapsed, ErrorContext, TimeoutKind};
+use azure;
+use cleanup;
+use client::{
+    ClientConfig,
+    RedirectAuth,
+};
+use config::HostPolicy;
+use cookies::CookieStore;
+use decompress::{
+    decompress_file,
+    decompress_stream,
+};
+use dns::{
+    Resolver,
+    display_host,
+};
+use ftp::{
+    self,
+    FtpHandler,
+    FtpMode,
+};
+use gcs::{
+    self,
+    GsUrl,
+};
+use journal::{
+    self,
+    Journal,
+    JournaledWriter,
+    VerifyChunks,
+};
+use md5;
+use metadata::{
+    Metadata,
+    MetadataCache,
+};
+use metalink::{
+    self,
+    Digest,
+};
+use netrc::Netrc;
+use owner::Owner;
+use paths::{
+    create_staging,
+    no_follow,
+    os_path,
+};
+use proxy::ProxySettings;
+use s3::{
+    self,
+    S3Url,
+};
+use urls::normalize_url;
+use hyper;
 use hyper::client::response::Response;
+use hyper::Url;
+use hyper::method::Method;
 use hyper::status::StatusCode;
-use std::cmp::min;
-use std::fs::File;
+#[cfg(unix)]
+use libc;
+use names::{
+    FileNames,
+    numbered,
+};
+use netfs::{
+    NetworkWrites,
+    is_network_fs,
+};
+use std::fs::{
+    self,
+    File,
+    OpenOptions,
+};
 use std::io::prelude::Seek;
-use std::path::Path;
+use std::str::FromStr;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use ratelimit;
+use retry::RetryPolicy;
+use plan::{
+    self,
+    Planner,
+    WholeFile,
+};
+use schedule::{
+    Chunk,
+    FIXED_CHUNK_SIZE,
+    FixedScheduler,
+    Schedule,
+    ScheduleFactory,
+    Scheduler,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use blocks::BlockHashes;
+use cassette::Cassette;
+use checksum::{
+    Checksum,
+    Hasher,
+    HashingReader,
+    HashingWriter,
+};
+use ordered::{
+    Backpressure,
+    fetch_in_order,
+};
+use stats::as_secs_f64;
+use throttle::RateLimiter;
+use schemes::{
+    self,
+    Connection,
+    SchemeHandler,
+};
+use shaping::{
+    Link,
+    ShapedReader,
+};
+use token::BearerToken;
+use torrent::PieceHashes;
+#[cfg(feature = "testing")]
+use fault::{
+    FaultInjector,
+    FaultyReader,
+};
+use std::time::{
+    Duration,
+    Instant,
+    SystemTime,
+};
 
 use std::{
+    cmp,
     io,
+    mem,
     str,
     thread,
 };
 
 use hyper::header::{
+    AcceptRanges,
+    Authorization,
+    Basic,
     ByteRangeSpec,
     ContentDisposition,
     ContentLength,
+    ContentRange,
+    ContentRangeSpec,
     DispositionParam,
     Headers,
+    Location,
     Range,
+    RangeUnit,
 };
 
 use reporter::{
@@ -30,12 +165,19 @@ use reporter::{
     Reporter,
 };
 
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
 use std::sync::mpsc::{
+    Receiver,
+    RecvTimeoutError,
     Sender,
     channel,
 };
 
 use std::io::{
+    BufWriter,
     Read,
     Write,
 };
@@ -52,12 +194,196 @@ pub enum DownloadTarget {
     Default,
 }
 
-#[derive(Clone)]
+/// A hop in the chain of redirects followed to reach the file
+#[derive(Clone,Debug)]
+pub struct Redirect {
+    /// The url that responded with a redirect
+    pub url: String,
+    /// The redirect status it responded with
+    pub status: StatusCode,
+}
+
+/// The outcome of a successful download
+#[derive(Clone,Debug)]
+pub struct DownloadResult {
+    /// Number of bytes written to the target
+    pub bytes: u64,
+    /// The url the file was finally downloaded from
+    pub url: String,
+    /// The redirects followed to reach `url`, in order
+    pub redirects: Vec<Redirect>,
+    /// Hashes of the file advertised by the server (RFC 3230 `Digest` and
+    /// `Content-MD5`)
+    pub digests: Vec<Digest>,
+    /// Where the file was saved, with any default name resolved
+    pub target: DownloadTarget,
+    /// The target already existed and was kept (`OverwritePolicy::Skip`),
+    /// so nothing was downloaded
+    pub skipped: bool,
+}
+
+#[derive(Clone,Debug)]
 pub enum DownloadMode {
     /// Download the file serially
     Serial,
     /// Download the file in parallel (not implemented)
     Parallel(u8),
+    /// Download the file in order, with parallel connections fetching
+    /// ahead of the write position so the target can be consumed (e.g. by
+    /// a media player) as it downloads
+    Streaming(u8),
+}
+
+/// How the size of the source, and whether it supports ranges, is found
+/// before downloading
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Probe {
+    /// A HEAD request, or a range request if the server rejects HEAD
+    Head,
+    /// A request for the first byte, whose Content-Range gives the size,
+    /// for servers whose HEAD responses are wrong
+    Range,
+    /// No request, for servers that bill per request: the download is
+    /// serial and sized by its response
+    None,
+}
+
+impl Default for Probe {
+    fn default() -> Probe
+    {
+        Probe::Head
+    }
+}
+
+impl FromStr for Probe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Probe, String>
+    {
+        match s {
+            "head" => Ok(Probe::Head),
+            "range" => Ok(Probe::Range),
+            "none" => Ok(Probe::None),
+            _ => Err(format!("expected 'head', 'range' or 'none', not '{}'", s)),
+        }
+    }
+}
+
+/// What happens when a file target already exists
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum OverwritePolicy {
+    /// Fail the download
+    Error,
+    /// Replace the file
+    Overwrite,
+    /// Keep the file, downloading nothing
+    Skip,
+    /// Save to the first free numbered name instead, e.g. `file-1.iso`
+    RenameNumbered,
+    /// Continue the file if it is partial (see `Download::resume`),
+    /// otherwise replace it
+    Resume,
+}
+
+impl Default for OverwritePolicy {
+    fn default() -> OverwritePolicy
+    {
+        OverwritePolicy::Error
+    }
+}
+
+/// Whether to write a destination or keep the file already there
+enum Existing {
+    Write(DownloadTarget),
+    Keep(DownloadTarget),
+}
+
+/// Where a resumed download continues from
+enum Resume {
+    /// The start, as there is nothing usable to resume
+    Restart,
+    /// This many bytes into the file
+    From(u64),
+    /// Nowhere, the target already has the whole file from this url
+    Complete(String),
+}
+
+/// Number of bytes before the end of a partial file compared with the
+/// source before resuming it
+const RESUME_SAMPLE_SIZE: u64 = 64 * 1024;  // 64 KB
+
+/// Size of the chunks fetched ahead of the write position when streaming
+const STREAM_CHUNK_SIZE: u64 = 1024 * 1024;  // 1 MB
+
+/// How long a segment of a parallel download may receive nothing before
+/// it is fetched again by another worker
+pub const DEFAULT_STALL_TIMEOUT: u64 = 60;
+
+/// Headers dropped when a redirect leads to another host (by default)
+const CREDENTIAL_HEADERS: &'static [&'static str] = &["Authorization", "Cookie"];
+
+/// Permissions of a target file while it is being downloaded
+#[cfg(unix)]
+const PARTIAL_FILE_MODE: u32 = 0o600;
+
+/// The process umask, once read
+#[cfg(unix)]
+static UMASK: Mutex<Option<u32>> = Mutex::new(None);
+
+/// Options applied when opening the target for writing
+#[derive(Clone)]
+struct WriteOptions {
+    /// Follow symlinks that lead out of the target's directory
+    trust_symlinks: bool,
+    /// Permissions applied once the download completes (derived from
+    /// the umask if None)
+    file_mode: Option<u32>,
+    /// Journal of the ranges durably written to the target
+    journal: Option<Arc<Journal>>,
+    /// Cassette response headers and bodies are recorded to
+    cassette: Option<Arc<Cassette>>,
+    /// Simulated link response bodies are read over
+    link: Option<Arc<Link>>,
+    /// Limits the combined rate of every response body
+    limiter: Option<Arc<RateLimiter>>,
+    /// How long a response body may send nothing
+    read_timeout: Option<Duration>,
+    /// When the download must be complete, and the time it was allowed
+    deadline: Option<(Instant, Duration)>,
+    /// Slowest rate (bytes per second) a response body may receive for
+    /// how long
+    speed_limit: Option<(u64, Duration)>,
+    /// Modification time set on a completed file target
+    mtime: Option<SystemTime>,
+    /// Hashes the bytes written in order, to verify the checksum
+    hasher: Option<Arc<Mutex<Hasher>>>,
+    /// Report the md5 of each completed segment
+    segment_md5: bool,
+    /// Bytes buffered before writing to the target, if batched
+    write_batch: Option<usize>,
+    /// Bytes written between syncs of a journaled target, if not the
+    /// journal's default
+    sync_interval: Option<u64>,
+    /// Faults injected into response bodies
+    #[cfg(feature = "testing")]
+    faults: Option<Arc<dyn FaultInjector>>,
+}
+
+/// What the downloaded file is checked against
+enum Expected {
+    /// The checksum the download was given
+    Given(Checksum),
+    /// A digest advertised by the server
+    Advertised(Checksum),
+}
+
+impl Expected {
+    fn checksum(&self) -> &Checksum
+    {
+        match *self {
+            Expected::Given(ref checksum) | Expected::Advertised(ref checksum) => checksum,
+        }
+    }
 }
 
 pub struct Download<R>
@@ -73,6 +399,75 @@ pub struct Download<R>
     mode: DownloadMode,
     /// Reporter for reporting download progress
     reporter: R,
+    /// Registry of default file names claimed by other downloads in a batch
+    file_names: Option<Arc<FileNames>>,
+    /// Options applied when opening the target for writing
+    write_options: WriteOptions,
+    /// Connection settings shared by every request
+    client: ClientConfig,
+    /// Number of connections to open before transferring
+    preconnect: usize,
+    /// Use http if no scheme was given and https is unreachable
+    allow_http_fallback: bool,
+    /// Refuse to make any request over plain http
+    https_only: bool,
+    /// Journal durably written ranges next to file targets
+    journal: bool,
+    /// Continue a partially downloaded file target from its length
+    resume: bool,
+    /// Which journaled chunks are checked before resuming
+    verify_resume: VerifyChunks,
+    /// Directory file targets are written in until complete
+    tmp_dir: Option<PathBuf>,
+    /// Write file targets to a part file next to them until complete
+    part_file: bool,
+    /// Cookies given as `name=value` lists for the host of the url
+    cookies: Vec<String>,
+    /// Credentials for the host of the url when none are given
+    netrc: Option<Arc<Netrc>>,
+    /// Shred the working files of a failed download
+    secure_cleanup: bool,
+    /// Working files of the download, registered for secure cleanup
+    working_files: Vec<PathBuf>,
+    /// How the source is probed before downloading
+    probe: Probe,
+    /// Size of the source, for servers that don't send a Content-Length
+    expected_size: Option<u64>,
+    /// Time allowed for the whole download
+    max_time: Option<Duration>,
+    /// How long a segment may receive nothing in parallel mode before it
+    /// is re-dispatched, if at all
+    stall_timeout: Option<Duration>,
+    /// What happens when a file target already exists
+    overwrite: OverwritePolicy,
+    /// How file targets on a network filesystem are written, if detected
+    network_writes: Option<NetworkWrites>,
+    /// Hook to obtain a fresh url when the current one expires
+    url_refresher: Option<UrlRefresher>,
+    /// Which failed requests are retried
+    retry: RetryPolicy,
+    /// Directory default targets are saved in
+    directory: Option<PathBuf>,
+    /// Authoritative hashes of the file's pieces
+    piece_hashes: Option<Arc<PieceHashes>>,
+    /// Digest the downloaded file must have
+    checksum: Option<Checksum>,
+    /// Check the file against a digest advertised by the server, if there
+    /// is no checksum
+    verify_digest: bool,
+    /// Block size of the hash manifest written next to a completed file
+    /// target, if any
+    block_manifest: Option<u64>,
+    /// Decompress a completed file target that turns out to be compressed
+    auto_decompress: bool,
+    /// Who a completed file target (and its block manifest) is given to
+    owner: Option<Owner>,
+    /// Which end opens the data connections of FTP downloads
+    ftp_mode: FtpMode,
+    /// Order in which regions of the file are fetched in parallel mode
+    planner: Arc<dyn Planner>,
+    /// Creates the schedule handing chunks to workers in parallel mode
+    scheduler: ScheduleFactory,
 }
 
 impl<R> Download<R>
@@ -80,13 +475,70 @@ impl<R> Download<R>
 {
 
     /// Create a new Download
+    ///
+    /// The url may omit its scheme (e.g. `example.com/file.iso`), in which
+    /// case https is assumed. It is validated when the download starts.
     pub fn new(url: String) -> Download<R> {
+        // Read while no other thread of the download creates files
+        umask();
         Download {
             headers: Headers::new(),
             mode: DownloadMode::Serial,
             url: url,
             target: DownloadTarget::Default,
             reporter: R::new(),
+            file_names: None,
+            write_options: WriteOptions {
+                trust_symlinks: false,
+                file_mode: None,
+                journal: None,
+                cassette: None,
+                link: None,
+                limiter: None,
+                read_timeout: None,
+                deadline: None,
+                speed_limit: None,
+                mtime: None,
+                hasher: None,
+                segment_md5: true,
+                write_batch: None,
+                sync_interval: None,
+                #[cfg(feature = "testing")]
+                faults: None,
+            },
+            client: ClientConfig::new(),
+            preconnect: 0,
+            allow_http_fallback: false,
+            https_only: false,
+            journal: false,
+            resume: false,
+            verify_resume: VerifyChunks::default(),
+            tmp_dir: None,
+            part_file: true,
+            cookies: vec![],
+            netrc: None,
+            secure_cleanup: false,
+            working_files: vec![],
+            probe: Probe::default(),
+            expected_size: None,
+            max_time: None,
+            stall_timeout: Some(Duration::from_secs(DEFAULT_STALL_TIMEOUT)),
+            overwrite: OverwritePolicy::default(),
+            network_writes: Some(NetworkWrites::default()),
+            url_refresher: None,
+            retry: RetryPolicy::default(),
+            directory: None,
+            piece_hashes: None,
+            checksum: None,
+            verify_digest: true,
+            block_manifest: None,
+            auto_decompress: false,
+            owner: None,
+            ftp_mode: FtpMode::default(),
+            planner: Arc::new(WholeFile),
+            scheduler: Arc::new(|regions: Vec<Chunk>, workers: u64| {
+                Box::new(Scheduler::with_plan(regions, workers)) as Box<dyn Schedule>
+            }),
         }
     }
 
@@ -104,234 +556,3097 @@ impl<R> Download<R>
         self
     }
 
-    /// Set the mode of the Download
-    pub fn mode(mut self, mode: DownloadMode) -> Download<R>
+    /// Set the reporter used to report download progress
+    pub fn reporter(mut self, reporter: R) -> Download<R>
     {
-        self.mode = mode;
+        self.reporter = reporter;
         self
     }
 
-    /// Download the source to target base on the download mode
-    pub fn download(&mut self) -> Result<u64, DownloadError>
+    /// Share a registry of default file names with other downloads so
+    /// that downloads resolving to the same name don't overwrite each other
+    pub fn file_names(mut self, file_names: Arc<FileNames>) -> Download<R>
     {
-        match self.mode {
-            DownloadMode::Serial => self.download_serial(),
-            DownloadMode::Parallel(n) => self.download_parallel(n),
-        }
+        self.file_names = Some(file_names);
+        self
     }
 
-    /// Download the source to the target serially
-    fn download_serial(&mut self) -> Result<u64, DownloadError>
+    /// Allow writing through symlinks that lead out of the target's
+    /// directory (refused by default)
+    pub fn trust_symlinks(mut self, trust: bool) -> Download<R>
     {
-        info!("Downloading serially");
-        let response  = try!(get(&*self.url, self.headers.clone()));
-        let size = try!(parse_content_length(&response));
+        self.write_options.trust_symlinks = trust;
+        self
+    }
 
-        match set_target_len(&self.target, size, &response) {
-            Err(e) => warn!("{}", e), Ok(_) => ()
-        };
+    /// Set the permissions applied to the target once the download
+    /// completes (until then it is only readable by the owner)
+    pub fn file_mode(mut self, mode: u32) -> Download<R>
+    {
+        self.write_options.file_mode = Some(mode);
+        self
+    }
 
-        let (tx, rx) = channel();
-        let target = self.target.clone();
+    /// Expire cached host addresses after `ttl` (by default they are
+    /// reused for the whole download)
+    pub fn dns_ttl(mut self, ttl: Duration) -> Download<R>
+    {
+        self.client = self.client.dns_ttl(ttl);
+        self
+    }
 
-        let downloader = thread::spawn(move|| {
-            stream(&target, 0, response, tx)
-        });
+    /// Resolve the hosts connected to with `resolver`, e.g. to pin their
+    /// addresses, instead of the system's
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> Download<R>
+    {
+        self.client = self.client.resolver(resolver);
+        self
+    }
 
-        self.reporter.listen(size, rx);
-        downloader.join().unwrap()
+    /// Open `n` connections concurrently before transferring so that all
+    /// segments can start at once
+    pub fn preconnect(mut self, n: usize) -> Download<R>
+    {
+        self.preconnect = n;
+        self
     }
 
-    /// Download the source to the target in parallel
-    fn download_parallel(&mut self, n: u8) -> Result<u64, DownloadError>
+    /// Fall back to http if the url has no scheme and the host can't be
+    /// reached over https
+    pub fn allow_http_fallback(mut self, allow: bool) -> Download<R>
     {
-        info!("Downloading with {} threads", n);
+        self.allow_http_fallback = allow;
+        self
+    }
 
-        let head = try!(head(&*self.url, self.headers.clone()));
-        let size = try!(parse_content_length(&head));
-        let block_size = size / (n as u64);
-        let mut children = vec![];
+    /// Refuse to make any request over plain http, including redirects
+    /// that downgrade from https
+    pub fn https_only(mut self, https_only: bool) -> Download<R>
+    {
+        self.https_only = https_only;
+        self.client = self.client.https_only(https_only);
+        self
+    }
 
-        match set_target_len(&self.target, size, &head) {
-            Err(e) => warn!("{}", e), Ok(_) => ()
-        };
+    /// Share probe results with other downloads (and readers) using `cache`,
+    /// so a url is only probed once
+    pub fn metadata_cache(mut self, cache: Arc<MetadataCache>) -> Download<R>
+    {
+        self.client = self.client.metadata_cache(cache);
+        self
+    }
 
-        let (tx, rx) = channel();
-        for i in 0..n {
-            let mut headers = self.headers.clone();
-            let target = self.target.clone();
-            let url = self.url.clone();
-            let reporter = tx.clone();
-
-            let start = min(i as u64 * block_size, size);
-            let end = min((i as u64 + 1) * block_size, size);
-            headers.set(Range::Bytes(vec![ByteRangeSpec::FromTo(start, end)]));
-
-            children.push(thread::spawn(move || {
-                debug!("Making request for segment ({} - {})", start, end);
-                let response = try!(get(&*url, headers));
-                debug!("returned");
-                stream(&target, start, response, reporter)
-            }))
-        };
+    /// Connect through `proxies` instead of those set in the environment
+    pub fn proxies(mut self, proxies: ProxySettings) -> Download<R>
+    {
+        self.client = self.client.proxies(proxies);
+        self
+    }
 
-        self.reporter.listen(size, rx);
+    /// Give up on connections that haven't been accepted after `timeout`
+    pub fn connect_timeout(mut self, timeout: Duration) -> Download<R>
+    {
+        self.client = self.client.connect_timeout(timeout);
+        self
+    }
+
+    /// Give up on responses (and retry them, as the policy allows) that
+    /// send nothing for `timeout`
+    pub fn read_timeout(mut self, timeout: Duration) -> Download<R>
+    {
+        self.client = self.client.read_timeout(timeout);
+        self.write_options.read_timeout = Some(timeout);
+        self
+    }
 
-        for child in children {
-            println!("{:?}", child.join());
+    /// Abort (and retry, as the policy allows) a response receiving less
+    /// than `rate` bytes per second for `time`. Each segment of a parallel
+    /// download is checked on its own, so one stalled segment is retried
+    /// without holding up the rest. A response receiving nothing at all
+    /// for `time` times out reading, unless a read timeout is set.
+    pub fn speed_limit(mut self, rate: u64, time: Duration) -> Download<R>
+    {
+        if self.write_options.read_timeout.is_none() {
+            self.client = self.client.read_timeout(time);
+            self.write_options.read_timeout = Some(time);
         }
+        self.write_options.speed_limit = Some((rate, time));
+        self
+    }
 
-        Ok(size)
+    /// Fail the download if it hasn't completed within `max_time`,
+    /// including any retries
+    pub fn max_time(mut self, max_time: Duration) -> Download<R>
+    {
+        self.max_time = Some(max_time);
+        self
     }
-}
 
-/// Construct and execute GET request against API
-fn get(url: &str, headers: Headers) -> Result<Response, DownloadError>
-{
-    debug!("GET: {}", url);
-    let client = Client::new();
-    let request = client.get(&*url).headers(headers);
-    raise_for_status(try!(request.send()))
-}
+    /// Re-dispatch a segment of a parallel download that receives nothing
+    /// for `timeout`: the rest of its range is requested again by a new
+    /// worker, from the last byte written, and the stalled connection is
+    /// abandoned. None never re-dispatches.
+    pub fn stall_timeout(mut self, timeout: Option<Duration>) -> Download<R>
+    {
+        self.stall_timeout = timeout;
+        self
+    }
 
-/// Construct and execute HEAD request against API
-fn head(url: &str, headers: Headers) -> Result<Response, DownloadError>
-{
-    debug!("HEAD: {}", url);
-    let client = Client::new();
-    let request = client.head(url).headers(headers);
-    raise_for_status(try!(request.send()))
-}
+    /// Follow at most `limit` redirects for a single request
+    pub fn max_redirects(mut self, limit: usize) -> Download<R>
+    {
+        self.client = self.client.max_redirects(limit);
+        self
+    }
 
-/// Returns error if request unsuccessful
-fn raise_for_status(mut response: Response) -> Result<Response, DownloadError>
-{
-    if response.status != StatusCode::Ok {
-        let mut body = String::new();
-        try!(response.read_to_string(&mut body));
-        Err(DownloadError(format!("{:}: {}", response.status, body)))
-    } else {
-        debug!("Request to {} successful", response.url);
-        Ok(response)
+    /// Send and record cookies in `store`, which can be shared with other
+    /// downloads and saved once they are done
+    pub fn cookie_store(mut self, store: Arc<CookieStore>) -> Download<R>
+    {
+        self.client = self.client.cookie_store(store);
+        self
     }
-}
 
-/// Set the expected length of the target (if applicable)
-fn set_target_len(target: &DownloadTarget, size: u64, response: &Response)
-                  -> Result<(), DownloadError>
-{
-    info!("Setting the length of target {:?} to {} bytes", target, size);
-    match *target {
-        DownloadTarget::Default => {
-            let file = try!(open_default_file_target(response));
-            Ok(try!(file.set_len(size)))
-        },
-        DownloadTarget::File(ref path) => {
-            let file = try!(File::open(path));
-            Ok(try!(file.set_len(size)))
-        },
-        DownloadTarget::StdOut => {
-            Err(DownloadError("Cannot take offset on stdout".to_owned()))
-        }
+    /// Authenticate every request (the probe and each range request) with
+    /// HTTP Basic credentials, taking precedence over any in the url
+    pub fn basic_auth(mut self, username: &str, password: Option<&str>) -> Download<R>
+    {
+        self.headers.set(Authorization(Basic {
+            username: username.to_owned(),
+            password: password.map(|p| p.to_owned()),
+        }));
+        self
     }
-}
 
-/// Stream the response to the download target at a given offset (if applicable)
-fn stream(
-    target: &DownloadTarget,
-    offset: u64,
-    mut response: Response,
-    reporter: Sender<CompletedSegment>
-) -> Result<u64, DownloadError>
-{
-    let size = try!(parse_content_length(&response));
-    Ok(match *target {
-        DownloadTarget::Default => {
-            let mut file = try!(open_default_file_target(&response));
-            try!(file.seek(io::SeekFrom::Start(offset)));
-            try!(copy_with_reporter(size, &mut response, &mut file, reporter))
-        },
-        DownloadTarget::File(ref path) => {
-            let mut file = try!(File::open(path));
-            try!(file.seek(io::SeekFrom::Start(offset)));
-            try!(copy_with_reporter(size, &mut response, &mut file, reporter))
-        },
-        DownloadTarget::StdOut => {
-            try!(copy_with_reporter(size, &mut response, &mut io::stdout(), reporter))
-        }
-    })
-}
+    /// Authenticate every request with a bearer token. A token file is read
+    /// again for each request, so a token refreshed on disk is used by the
+    /// requests that follow.
+    pub fn bearer_token(mut self, token: BearerToken) -> Download<R>
+    {
+        self.client = self.client.bearer_token(token);
+        self
+    }
 
+    /// Authenticate with the credentials `netrc` has for the host of the
+    /// url, if no others are given
+    pub fn netrc(mut self, netrc: Arc<Netrc>) -> Download<R>
+    {
+        self.netrc = Some(netrc);
+        self
+    }
 
-/// Vendored io::copy() to report progress because <Write>.broadcast() was
-/// deprecated in 1.6
-pub fn copy_with_reporter<R: ?Sized, W: ?Sized>(
-    size: u64,
-    reader: &mut R,
-    writer: &mut W,
-    reporter: Sender<CompletedSegment>,
-) -> io::Result<u64>
-    where R: io::Read, W: io::Write
-{
-    debug!("Stream is {} bytes", size);
+    /// Send cookies from a `name=value` list (e.g. `session=abc; lang=en`)
+    /// to the host of the url
+    pub fn cookie(mut self, pairs: &str) -> Download<R>
+    {
+        self.cookies.push(pairs.to_owned());
+        self
+    }
 
-    let mut buf = vec![0; DEFAULT_BUFF_SIZE];
-    let mut written = 0;
+    /// Set whether credentials are sent on when a request is redirected
+    pub fn redirect_auth(mut self, policy: RedirectAuth) -> Download<R>
+    {
+        self.client = self.client.redirect_auth(policy);
+        self
+    }
 
-    loop {
-        let len = match reader.read(&mut buf) {
-            Ok(0) => return Ok(written),
-            Ok(len) => len,
-            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-            Err(e) => return Err(e),
-        } as u64;
+    /// Refuse to make any request (including redirect hops) to hosts the
+    /// policy doesn't permit
+    pub fn host_policy(mut self, hosts: HostPolicy) -> Download<R>
+    {
+        self.client = self.client.host_policy(hosts);
+        self
+    }
 
-        try!(writer.write_all(&buf[..len as usize]));
-        written += len;
+    /// Sync the target to disk every few megabytes and record each synced
+    /// range in a journal (`<target>.sledge`), which is removed once the
+    /// download completes. A parallel download finding the journal of an
+    /// interrupted run only fetches the ranges it is missing.
+    pub fn journal(mut self, journal: bool) -> Download<R>
+    {
+        self.journal = journal;
+        self
+    }
 
-        let _ = reporter.send(CompletedSegment {
-            start: written,
-            len: len,
-            md5: "".to_string(),
-        });
+    /// Set how file targets detected to be on a network filesystem are
+    /// written, or None to write them like local files
+    pub fn network_writes(mut self, writes: Option<NetworkWrites>) -> Download<R>
+    {
+        self.network_writes = writes;
+        self
     }
-}
 
-/// Reads the file size from the Content-Length if possible
-fn parse_content_length(response: &Response) -> Result<u64, DownloadError>
-{
-    match response.headers.get::<ContentLength>() {
-        Some(size) => Ok(size.0),
-        None => Err(DownloadError(format!("server did not provide a content length!"))),
+    /// Continue a file target that is shorter than the remote file from its
+    /// current length instead of starting over. Serial downloads are then
+    /// not preallocated, so an interrupted file's length marks where it
+    /// stopped. With journaling, only the verified journaled start of the
+    /// file is kept. The end of the kept bytes is compared with the source
+    /// first, so a file can be continued from another url serving the same
+    /// content.
+    pub fn resume(mut self, resume: bool) -> Download<R>
+    {
+        self.resume = resume;
+        self
     }
-}
 
-/// Parse the file name (or use default name) and return an opened file
-fn open_default_file_target(response: &Response) -> Result<File, DownloadError>
-{
-    let file_name = match parse_file_name(&response) {
-        Ok(name) => name,
-        Err(e) => {
-            let default = response.url.path_segments()
-                .unwrap().collect::<Vec<_>>().last().unwrap().to_string();
-            debug!("no filename ({}) downloading to {}", e, default);
-            default
-        }
-    };
+    /// Set which journaled chunks of an interrupted download are checked
+    /// against their md5 before resuming (every chunk by default). Checking
+    /// a sample catches on-disk corruption early without reading the whole
+    /// file.
+    pub fn verify_resume(mut self, check: VerifyChunks) -> Download<R>
+    {
+        self.verify_resume = check;
+        self
+    }
+
+    /// What to do when a file target already exists (by default the
+    /// download fails rather than replace it)
+    pub fn overwrite(mut self, policy: OverwritePolicy) -> Download<R>
+    {
+        self.overwrite = policy;
+        self
+    }
+
+    /// The size of the source, used when the server doesn't send a
+    /// Content-Length so the download can still be parallel and report
+    /// its progress. A different size sent by the server is an error.
+    pub fn expect_size(mut self, size: u64) -> Download<R>
+    {
+        self.expected_size = Some(size);
+        self
+    }
 
-    let path = Path::new(&*file_name).file_name().unwrap();
-    debug!("opening {}", file_name);
+    /// How to find the size of the source and whether it supports ranges
+    /// before downloading
+    pub fn probe(mut self, probe: Probe) -> Download<R>
+    {
+        self.probe = probe;
+        self
+    }
 
-    match File::create(path) {
-        Ok(f) => Ok(f),
-        Err(e) => Err(DownloadError(
-            format!("unable to open file {} for writing: {}", file_name, e))),
+    /// Overwrite and remove the working files (the part file and journal)
+    /// of a failed download, and register them to be shredded if the
+    /// process is cancelled (see `cleanup::shred_on_signal`)
+    pub fn secure_cleanup(mut self, enabled: bool) -> Download<R>
+    {
+        self.secure_cleanup = enabled;
+        self
     }
-}
 
+    /// Write file targets to `<file>.part`, renaming them into place only
+    /// once their size and checksum are verified (on by default), so a
+    /// half-written file is never mistaken for a complete one
+    pub fn part_file(mut self, enabled: bool) -> Download<R>
+    {
+        self.part_file = enabled;
+        self
+    }
+
+    /// Write file targets (and their journals) as part files in `dir`, e.g.
+    /// on a fast scratch disk, moving them to their destination once
+    /// complete
+    pub fn tmp_dir<P: Into<PathBuf>>(mut self, dir: P) -> Download<R>
+    {
+        self.tmp_dir = Some(dir.into());
+        self
+    }
+
+    /// Set a hook that is called with the url when a request is refused
+    /// with 403 (e.g. a presigned url expired mid-transfer). If it returns a
+    /// fresh url, the request and any remaining ranges use it instead.
+    pub fn url_refresher<F>(mut self, refresher: F) -> Download<R>
+        where F: Fn(&str) -> Option<String> + Send + Sync + 'static
+    {
+        self.url_refresher = Some(Arc::new(refresher));
+        self
+    }
+
+    /// Capture at most `limit` bytes of the body of unsuccessful responses
+    /// in the returned error
+    pub fn error_body_limit(mut self, limit: usize) -> Download<R>
+    {
+        self.client = self.client.error_body_limit(limit);
+        self
+    }
+
+    /// Save the full body of unsuccessful responses to `path`, for
+    /// debugging API error payloads
+    pub fn save_error_body<P: Into<PathBuf>>(mut self, path: P) -> Download<R>
+    {
+        self.client = self.client.save_error_body(path.into());
+        self
+    }
+
+    /// Save the default target in `dir` rather than the working directory
+    pub fn directory<P: Into<PathBuf>>(mut self, dir: P) -> Download<R>
+    {
+        self.directory = Some(dir.into());
+        self
+    }
+
+    /// Verify the downloaded file against the piece hashes of a torrent,
+    /// fetching corrupt pieces again
+    pub fn piece_hashes(mut self, hashes: PieceHashes) -> Download<R>
+    {
+        self.piece_hashes = Some(Arc::new(hashes));
+        self
+    }
+
+    /// Fail the download unless the file has this digest. Bytes are hashed
+    /// as they are written, except by parallel downloads (which write out
+    /// of order) and when torrent pieces may be refetched.
+    pub fn verify(mut self, checksum: Checksum) -> Download<R>
+    {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    /// Check the file against a `Digest` or `Content-MD5` advertised by the
+    /// server when no checksum is set (on by default)
+    pub fn verify_digest(mut self, enabled: bool) -> Download<R>
+    {
+        self.verify_digest = enabled;
+        self
+    }
+
+    /// Report the md5 of each completed segment to the reporter (on by
+    /// default). Turning it off saves hashing every byte received.
+    pub fn segment_md5(mut self, enabled: bool) -> Download<R>
+    {
+        self.write_options.segment_md5 = enabled;
+        self
+    }
+
+    /// Write the SHA-256 of every `block_size` block of a completed file
+    /// target, and the root of their hash tree, to `<file>.blocks`
+    pub fn block_manifest(mut self, block_size: u64) -> Download<R>
+    {
+        self.block_manifest = Some(block_size);
+        self
+    }
+
+    /// Decompress a completed file target starting with a gzip, zstd or xz
+    /// magic number, unless its name says it is compressed. Streamed to
+    /// stdout, the contents are decompressed as they arrive.
+    pub fn auto_decompress(mut self, auto_decompress: bool) -> Download<R>
+    {
+        self.auto_decompress = auto_decompress;
+        self
+    }
+
+    /// Have the server connect back for the data connections of an FTP
+    /// download, rather than connecting to it
+    pub fn ftp_mode(mut self, mode: FtpMode) -> Download<R>
+    {
+        self.ftp_mode = mode;
+        self
+    }
+
+    /// Give a completed file target, and its block manifest, to `owner`,
+    /// e.g. the user who ran sledge through sudo
+    pub fn owner(mut self, owner: Owner) -> Download<R>
+    {
+        self.owner = Some(owner);
+        self
+    }
+
+    /// Record the headers and bodies of responses to a cassette, for
+    /// replaying the download later
+    pub fn record(mut self, cassette: Arc<Cassette>) -> Download<R>
+    {
+        self.write_options.cassette = Some(cassette);
+        self
+    }
+
+    /// Limit the download to `rate` bytes per second, shared by all of
+    /// its connections
+    pub fn limit_rate(mut self, rate: u64) -> Download<R>
+    {
+        self.write_options.limiter = Some(Arc::new(RateLimiter::new(rate)));
+        self
+    }
+
+    /// Read response bodies as if over a link with this bandwidth (bytes
+    /// per second, shared by all connections) and latency, to reproduce
+    /// slow links locally
+    pub fn simulate_link(mut self, bandwidth: Option<u64>, latency: Duration) -> Download<R>
+    {
+        self.write_options.link = Some(Arc::new(Link::new(bandwidth, latency)));
+        self
+    }
+
+    /// Inject faults into response bodies, for testing retry and resume
+    /// configurations
+    #[cfg(feature = "testing")]
+    pub fn fault_injector(mut self, injector: Arc<dyn FaultInjector>) -> Download<R>
+    {
+        self.write_options.faults = Some(injector);
+        self
+    }
+
+    /// Set the order in which regions of the file are fetched in parallel
+    /// mode
+    pub fn planner<P: Planner + 'static>(mut self, planner: P) -> Download<R>
+    {
+        self.planner = Arc::new(planner);
+        self
+    }
+
+    /// Set how chunks of the planned regions are handed to workers in
+    /// parallel mode
+    pub fn scheduler<F>(mut self, factory: F) -> Download<R>
+        where F: Fn(Vec<Chunk>, u64) -> Box<dyn Schedule> + Send + Sync + 'static
+    {
+        self.scheduler = Arc::new(factory);
+        self
+    }
+
+    /// Set the modification time of the completed file
+    pub fn mtime(mut self, mtime: SystemTime) -> Download<R>
+    {
+        self.write_options.mtime = Some(mtime);
+        self
+    }
+
+    /// Make runs reproducible: chunks have a fixed size rather than one
+    /// adapted to throughput, and retries wait the full backoff without
+    /// jitter, so the requests made (and logged) are the same every run
+    pub fn deterministic(mut self, deterministic: bool) -> Download<R>
+    {
+        if deterministic {
+            self.scheduler = Arc::new(|regions: Vec<Chunk>, _| {
+                Box::new(FixedScheduler::with_plan(regions, FIXED_CHUNK_SIZE)) as Box<dyn Schedule>
+            });
+        }
+        self.retry = self.retry.jitter(!deterministic);
+        self
+    }
+
+    /// Retry failed requests and interrupted transfers up to `retries`
+    /// times
+    pub fn retries(mut self, retries: u32) -> Download<R>
+    {
+        self.retry = self.retry.retries(retries);
+        self
+    }
+
+    /// Set the delay before the first retry, which doubles for each one
+    /// after
+    pub fn retry_delay(mut self, delay: Duration) -> Download<R>
+    {
+        self.retry = self.retry.base_delay(delay);
+        self
+    }
+
+    /// Set which failed requests are retried
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Download<R>
+    {
+        self.retry = retry;
+        self
+    }
+
+    /// Set the mode of the Download
+    pub fn mode(mut self, mode: DownloadMode) -> Download<R>
+    {
+        self.mode = mode;
+        self
+    }
+
+    /// Download the source to target base on the download mode
+    pub fn download(&mut self) -> Result<DownloadResult, DownloadError>
+    {
+        if let Some(max_time) = self.max_time {
+            self.client = self.client.clone().max_time(max_time);
+            self.write_options.deadline = self.client.deadline();
+        }
+        // s3://, gs:// and az:// urls are fetched from the bucket's endpoint
+        // over https, with signed or authenticated requests, as are Azure
+        // blob urls
+        let bucket_object = if self.url.starts_with("s3://") {
+            Some(S3Url::parse(&*self.url).and_then(|s3_url| {
+                if s3_url.key.is_empty() { return Err("no key to download".to_owned()) }
+                s3::resolve(&self.client, &s3_url)
+            }))
+        } else if self.url.starts_with("gs://") {
+            Some(GsUrl::parse(&*self.url).and_then(|gs_url| {
+                if gs_url.object.is_empty() { return Err("no object to download".to_owned()) }
+                gcs::resolve(&self.client, &gs_url)
+            }))
+        } else if self.url.starts_with("az://") || azure::is_blob_url(&*self.url) {
+            Some(azure::resolve(&self.client, &*self.url))
+        } else {
+            None
+        };
+        if let Some(resolved) = bucket_object {
+            let (url, client) = try!(resolved.map_err(|e| DownloadError::invalid_url(&*self.url, e)));
+            debug!("{} is at {}", self.url, url);
+            self.client = client;
+            self.url = url.to_string();
+        }
+        // Parsing converts internationalized domain names to punycode
+        let normalized = try!(normalize_url(&*self.url).map_err(|e| {
+            DownloadError::invalid_url(&*self.url, e)
+        }));
+        let mut url = normalized.url;
+        // Credentials in the url, unless given explicitly, or else those
+        // of the netrc entry for the host
+        let given = self.headers.get_raw("Authorization").is_some() || self.client.token().is_some();
+        let credentials = normalized.credentials.or_else(|| {
+            match (&self.netrc, url.host_str()) {
+                (&Some(ref netrc), Some(host)) => netrc.credentials(host),
+                _ => None,
+            }
+        });
+        if let (Some((username, password)), false) = (credentials, given) {
+            self.headers.set(Authorization(Basic { username: username, password: password }));
+        }
+        if let Some(handler) = schemes::handler(url.scheme()) {
+            self.url = url.to_string();
+            let result = self.download_custom(handler, url);
+            self.clean_up(result.is_ok());
+            return result
+        }
+        if ftp::is_ftp_scheme(url.scheme()) {
+            try!(self.client.check_url(&url).map_err(DownloadError::refused));
+            // Logged in as the user given for Basic authentication, if any
+            let credentials = self.headers.get::<Authorization<Basic>>().map(|auth| {
+                (auth.0.username.clone(), auth.0.password.clone())
+            });
+            try!(ftp::check_arguments(&url, &credentials)
+                 .map_err(|e| DownloadError::invalid_url(url.to_string(), e)));
+            let handler = FtpHandler::new(&self.client, credentials, self.ftp_mode);
+            self.url = url.to_string();
+            let result = self.download_custom(Arc::new(handler), url);
+            self.clean_up(result.is_ok());
+            return result
+        }
+        // Before connecting at all, e.g. to check https or preconnect
+        try!(self.client.check_url(&url).map_err(DownloadError::refused));
+        if !self.cookies.is_empty() {
+            if self.client.cookies().is_none() {
+                self.client = self.client.clone().cookie_store(Arc::new(CookieStore::new()));
+            }
+            for pairs in mem::replace(&mut self.cookies, vec![]) {
+                try!(self.client.cookies().unwrap().add_pairs(&url, &*pairs).map_err(|e| {
+                    DownloadError::other(format!("invalid cookie: {}", e))
+                }));
+            }
+        }
+
+        if normalized.inferred_scheme && self.allow_http_fallback && !self.https_only {
+            if let Err(e) = self.client.check_connection(&url) {
+                warn!("Unable to connect to {} over https ({}), falling back to http", url, e);
+                let _ = url.set_scheme("http");
+            }
+        }
+        self.url = url.to_string();
+
+        if let Some(host) = url.host_str() {
+            info!("Connecting to {}", display_host(host));
+        }
+
+        if self.preconnect > 0 {
+            self.client.preconnect(&url, self.preconnect);
+        }
+
+        if self.overwrite == OverwritePolicy::Resume {
+            self.resume = true;
+        }
+        match (self.probe, &self.mode) {
+            (Probe::None, &DownloadMode::Parallel(_))
+            | (Probe::None, &DownloadMode::Streaming(_)) => {
+                warn!("Downloading serially, as the size is unknown without probing");
+                self.mode = DownloadMode::Serial;
+            },
+            _ => (),
+        }
+        match (self.resume, &self.mode) {
+            (true, &DownloadMode::Parallel(_)) if !self.journal => {
+                warn!("Parallel downloads only resume a partial file from its journal \
+                       (--journal), so it is fetched again");
+            },
+            (true, &DownloadMode::Streaming(_)) => {
+                warn!("Streamed downloads don't resume a partial file, so it is fetched again");
+            },
+            (true, _) if self.probe == Probe::None => {
+                warn!("Partial files aren't resumed without probing the remote size");
+            },
+            _ => (),
+        }
+
+        let result = match self.mode {
+            DownloadMode::Serial => self.download_serial(),
+            DownloadMode::Parallel(n) => self.download_parallel(n),
+            DownloadMode::Streaming(n) => self.download_streaming(n),
+        };
+        self.clean_up(result.is_ok());
+        if let (&Ok(ref result), &Some(ref cassette)) = (&result, &self.write_options.cassette) {
+            if result.url != self.url {
+                if let Err(e) = cassette.record_redirect(&*self.url, &*result.url) {
+                    warn!("Unable to record the redirect to {}: {}", result.url, e);
+                }
+            }
+        }
+        if let (&Ok(ref result), true) = (&result, self.auto_decompress) {
+            if !result.skipped {
+                try!(decompress_target(&result.target).map_err(|e| e.with_url(&*self.url)));
+            }
+        }
+        if let (&Ok(ref result), Some(block_size)) = (&result, self.block_manifest) {
+            try!(write_block_manifest(&result.target, block_size)
+                 .map_err(|e| e.with_url(&*self.url)));
+        }
+        if let (&Ok(ref result), Some(owner)) = (&result, self.owner) {
+            if !result.skipped {
+                try!(chown_target(&result.target, &owner, self.block_manifest.is_some())
+                     .map_err(|e| e.with_url(&*self.url)));
+            }
+        }
+        result.map_err(|e| e.with_url(&*self.url))
+    }
+
+    /// Download the source to the target serially
+    fn download_serial(&mut self) -> Result<DownloadResult, DownloadError>
+    {
+        info!("Downloading serially");
+        let mut headers = self.headers.clone();
+        let mut offset = 0;
+        if self.resume && self.probe != Probe::None {
+            match try!(self.partial_len()) {
+                Resume::From(len) => {
+                    info!("Resuming from byte {}", len);
+                    headers.set(Range::Bytes(vec![ByteRangeSpec::AllFrom(len)]));
+                    offset = len;
+                },
+                Resume::Complete(url) => {
+                    info!("{} is already downloaded completely", url);
+                    return Ok(kept(self.target.clone(), url, vec![], vec![]))
+                },
+                Resume::Restart => (),
+            }
+        }
+
+        let refreshable = self.url_refresher.is_some();
+        let (response, redirects) = try!(with_retries(&self.retry, "GET", || {
+            let (response, redirects) = try!(request(&self.client, Method::Get, &*self.url,
+                                                     headers.clone()));
+            if response.status.is_success()
+                || (response.status == StatusCode::Forbidden && refreshable) {
+                return Ok((response, redirects))
+            }
+            raise_for_status(&self.client, response).map(|response| (response, redirects))
+        }));
+        let response = match (response.status, &self.url_refresher) {
+            (StatusCode::Forbidden, &Some(ref refresher)) => match refresher(&*self.url) {
+                Some(fresh) => {
+                    info!("Refreshing expired url {}", self.url);
+                    self.url = fresh;
+                    try!(get(&self.client, &*self.url, headers.clone())).0
+                },
+                None => try!(raise_for_status(&self.client, response)),
+            },
+            _ => try!(raise_for_status(&self.client, response)),
+        };
+        if offset > 0 && response.status != StatusCode::PartialContent {
+            warn!("{} ignored the range request, starting over", response.url);
+            offset = 0;
+        }
+        let url = response.url.to_string();
+        let metadata = Metadata::of(&response);
+        let digests = advertised_digests(&metadata);
+        // Chunked responses are read until the connection closes
+        let size = match parse_content_length(&metadata) {
+            Ok(size) => Some(size),
+            Err(_) => self.expected_size.map(|expected| expected.saturating_sub(offset)),
+        };
+        match size {
+            Some(size) => try!(self.check_piece_length(offset + size)),
+            None => info!("{} did not provide a content length, size unknown", url),
+        }
+        let resolved = self.resolve_target(&metadata);
+        let destination = match try!(self.check_existing(resolved, size.map(|size| offset + size))) {
+            Existing::Write(destination) => destination,
+            Existing::Keep(existing) => return Ok(kept(existing, url, redirects, digests)),
+        };
+        let target = self.working_target(&destination);
+        self.claim(&target);
+
+        if offset == 0 {
+            let len = if self.resume { 0 } else { size.unwrap_or(0) };
+            match set_target_len(&target, len, &self.write_options) {
+                Err(e) => warn!("{}", e), Ok(_) => ()
+            };
+        }
+
+        // Resuming continues the journal of the bytes kept
+        let mut options = try!(self.target_options(&target, offset > 0));
+        let expected = self.expected_digest(&digests);
+        let hasher = self.checksum_hasher(&expected);
+        if let (Some(ref hasher), &DownloadTarget::File(ref path)) = (&hasher, &target) {
+            // The bytes kept are hashed before those received
+            try!(hasher.lock().unwrap().update_from_file(os_path(path), offset)
+                 .map_err(|e| DownloadError::io(e, &**path)));
+        }
+        options.hasher = hasher.clone();
+        let (tx, rx) = channel();
+        let thread_options = options.clone();
+        let (client, source, retry) = (self.client.clone(), url.clone(), self.retry.clone());
+        let thread_headers = self.headers.clone();
+        let thread_target = target.clone();
+
+        let downloader = thread::spawn(move|| {
+            let mut response = response;
+            let mut pos = offset;
+            let mut attempt = 1;
+            let reopen = |pos: u64| -> Result<Response, DownloadError> {
+                let mut headers = thread_headers.clone();
+                headers.set(Range::Bytes(vec![ByteRangeSpec::AllFrom(pos)]));
+                let response = try!(get(&client, &*source, headers)).0;
+                if response.status != StatusCode::PartialContent {
+                    return Err(DownloadError::unsupported_range(response.status)
+                               .with_url(&*source))
+                }
+                Ok(response)
+            };
+            loop {
+                let mut err = match stream(&thread_target, pos, response, tx.clone(), &thread_options) {
+                    Ok(written) => return Ok(pos + written - offset),
+                    Err(e) => e,
+                };
+                // Failing to reconnect uses up attempts just as interruptions do
+                response = loop {
+                    if !retry.should_retry(&err, attempt) {
+                        return Err(err.with_attempt(attempt))
+                    }
+                    pos = err.context().offset.unwrap_or(pos);
+                    warn!("Transfer interrupted at byte {} ({}), resuming", pos, err);
+                    wait_before_retry(&retry, &err, attempt);
+                    attempt = retry.next_attempt(&err, attempt);
+                    match reopen(pos) {
+                        Ok(response) => break response,
+                        Err(e) => err = e,
+                    }
+                };
+            }
+        });
+
+        match size {
+            Some(size) => self.reporter.listen(size, rx),
+            None => self.reporter.listen_unsized(rx),
+        }
+        let written = try!(downloader.join().unwrap());
+        try!(self.repair_pieces(&target, &options));
+        if let Some(size) = size {
+            try!(check_size(&target, offset + size));
+        }
+        try!(self.verify_checksum(&expected, &target, hasher));
+        try!(finalize_target(&target, &options));
+        try!(place_target(&target, &destination));
+        Ok(DownloadResult {
+            bytes: written,
+            url: url,
+            redirects: redirects,
+            digests: digests,
+            target: destination,
+            skipped: false,
+        })
+    }
+
+    /// Download the source with the handler for its scheme: serially, or
+    /// in parallel segments if the handler tells the size up front
+    fn download_custom(&mut self, handler: Arc<dyn SchemeHandler>, url: Url)
+                       -> Result<DownloadResult, DownloadError>
+    {
+        info!("Downloading {} with the handler for {}:// urls", url, url.scheme());
+        let known = try!(with_retries(&self.retry, "size", || {
+            handler.size(&url).map_err(|e| DownloadError::from(e).with_url(url.as_str()))
+        }));
+        // Otherwise the file is opened to find out
+        let (body, size) = match known {
+            Some(size) => (None, Some(size)),
+            None => {
+                let (body, size) = try!(with_retries(&self.retry, "open", || {
+                    open_custom(&*handler, &mut None, &url, 0)
+                }));
+                (Some(body), size)
+            },
+        };
+        let size = size.or(self.expected_size);
+        if let Some(size) = size {
+            try!(self.check_piece_length(size));
+        }
+        // There are no response headers to name the file or advertise digests
+        let metadata = Metadata { url: url.clone(), status: StatusCode::Ok, headers: Headers::new() };
+        let destination = match try!(self.check_existing(self.resolve_target(&metadata), size)) {
+            Existing::Write(destination) => destination,
+            Existing::Keep(existing) => return Ok(kept(existing, url.to_string(), vec![], vec![])),
+        };
+        let target = self.working_target(&destination);
+        self.claim(&target);
+
+        let written = match (&self.mode, known) {
+            (&DownloadMode::Parallel(n), Some(size)) if n > 1 => {
+                try!(self.fetch_custom_segments(handler, &url, &target, size, n))
+            },
+            _ => try!(self.fetch_custom_serially(handler, &url, &target, body, size)),
+        };
+        try!(place_target(&target, &destination));
+        Ok(DownloadResult {
+            bytes: written,
+            url: url.to_string(),
+            redirects: vec![],
+            digests: vec![],
+            target: destination,
+            skipped: false,
+        })
+    }
+
+    /// Fetch a custom scheme's file into the target from `body`, opened at
+    /// the start, resuming a partial file of known size instead if asked
+    fn fetch_custom_serially(&mut self, handler: Arc<dyn SchemeHandler>, url: &Url,
+                             target: &DownloadTarget, body: Option<Box<dyn io::Read + Send>>,
+                             size: Option<u64>) -> Result<u64, DownloadError>
+    {
+        let offset = match (self.resume, size, target) {
+            (true, Some(size), &DownloadTarget::File(ref path)) => self.partial_file_len(path, size),
+            _ => 0,
+        };
+        if offset > 0 {
+            info!("Resuming from byte {}", offset);
+        } else {
+            let len = if self.resume { 0 } else { size.unwrap_or(0) };
+            if let Err(e) = set_target_len(target, len, &self.write_options) {
+                warn!("{}", e);
+            }
+        }
+        let body = match body {
+            Some(body) if offset == 0 => body,
+            _ => try!(with_retries(&self.retry, "open", || {
+                open_custom(&*handler, &mut None, url, offset)
+            })).0,
+        };
+
+        // Resuming continues the journal of the bytes kept
+        let mut options = try!(self.target_options(target, offset > 0));
+        let expected = self.expected_digest(&[]);
+        let hasher = self.checksum_hasher(&expected);
+        if let (Some(ref hasher), &DownloadTarget::File(ref path)) = (&hasher, target) {
+            // The bytes kept are hashed before those received
+            try!(hasher.lock().unwrap().update_from_file(os_path(path), offset)
+                 .map_err(|e| DownloadError::io(e, &**path)));
+        }
+        options.hasher = hasher.clone();
+        let (tx, rx) = channel();
+        let thread_options = options.clone();
+        let (retry, thread_target, thread_url) = (self.retry.clone(), target.clone(), url.clone());
+
+        let downloader = thread::spawn(move|| {
+            stream_custom(&*handler, &mut None, &thread_url, &thread_target, offset, size, body,
+                          tx, &thread_options, &retry)
+        });
+
+        match size {
+            Some(size) => self.reporter.listen(size - offset, rx),
+            None => self.reporter.listen_unsized(rx),
+        }
+        let written = try!(downloader.join().unwrap());
+        if let Some(size) = size {
+            try!(check_size(target, size));
+        }
+        try!(self.verify_checksum(&expected, target, hasher));
+        try!(finalize_target(target, &options));
+        Ok(written)
+    }
+
+    /// Fetch a custom scheme's file of `size` bytes into the target with
+    /// `n` workers, each opening the file at the start of the chunks it
+    /// takes. Ranges journaled by an interrupted run aren't fetched again.
+    fn fetch_custom_segments(&mut self, handler: Arc<dyn SchemeHandler>, url: &Url,
+                             target: &DownloadTarget, size: u64, n: u8)
+                             -> Result<u64, DownloadError>
+    {
+        info!("Downloading with {} threads", n);
+        let journaled = self.journaled_ranges(target, size);
+        if journaled.is_empty() {
+            if let Err(e) = set_target_len(target, size, &self.write_options) {
+                warn!("Unable to preallocate {} bytes ({}), downloading serially", size, e);
+                return self.fetch_custom_serially(handler, url, target, None, Some(size))
+            }
+        }
+        let options = try!(self.target_options(target, !journaled.is_empty()));
+        let regions = self.planner.plan(size);
+        try!(plan::validate(&regions, size).map_err(DownloadError::other));
+        let regions = plan::subtract(regions, &journaled);
+        let schedule = Arc::new(Mutex::new((self.scheduler)(regions, n as u64)));
+        // Chunks arrive out of order, so the file is hashed once complete
+        let expected = self.expected_digest(&[]);
+        let failed = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = channel();
+
+        let done: u64 = journaled.iter().map(|&(start, end)| end - start).sum();
+        if done > 0 {
+            info!("Resuming with {} of {} bytes already journaled", done, size);
+            let _ = tx.send(CompletedSegment { start: 0, len: done, md5: String::new() });
+        }
+
+        let workers: Vec<_> = (0..n).map(|_| {
+            let (handler, url, target) = (handler.clone(), url.clone(), target.clone());
+            let (options, retry) = (options.clone(), self.retry.clone());
+            let (schedule, failed, tx) = (schedule.clone(), failed.clone(), tx.clone());
+            thread::spawn(move|| {
+                // Kept from chunk to chunk, e.g. so FTP logs in once a worker
+                let mut connection = handler.connect(&url);
+                let mut written = 0;
+                while !failed.load(Ordering::SeqCst) {
+                    let chunk = match schedule.lock().unwrap().next_chunk() {
+                        Some(chunk) => chunk,
+                        None => break,
+                    };
+                    let start = Instant::now();
+                    let result = with_retries(&retry, "open", || {
+                        open_custom(&*handler, &mut connection, &url, chunk.start)
+                    }).and_then(|(body, _)| {
+                        let rtt = start.elapsed();
+                        let len = try!(stream_custom(&*handler, &mut connection, &url, &target,
+                                                     chunk.start, Some(chunk.end), body,
+                                                     tx.clone(), &options, &retry));
+                        schedule.lock().unwrap().report(&chunk, rtt, start.elapsed());
+                        Ok(len)
+                    });
+                    match result {
+                        Ok(len) => written += len,
+                        Err(e) => {
+                            // The other workers stop after their chunk
+                            failed.store(true, Ordering::SeqCst);
+                            return Err(e)
+                        },
+                    }
+                }
+                Ok(written)
+            })
+        }).collect();
+        drop(tx);
+
+        self.reporter.listen(size, rx);
+        let mut written = 0;
+        for worker in workers {
+            written += try!(worker.join().unwrap_or_else(|_| {
+                Err(DownloadError::other(format!("a worker fetching chunks of {} panicked", url)))
+            }));
+        }
+        try!(check_size(target, size));
+        try!(self.verify_checksum(&expected, target, None));
+        try!(finalize_target(target, &options));
+        Ok(written)
+    }
+
+    /// Request the headers of the source, retrying failures the policy
+    /// allows. Servers rejecting HEAD, or probed by range, are sent a GET
+    /// for the first byte instead, whose Content-Range gives the size (see
+    /// `remote_size`).
+    ///
+    /// The result is cached, so the source is only probed once a run.
+    fn head_with_retries(&self) -> Result<(Metadata, Vec<Redirect>), DownloadError>
+    {
+        let cache = self.client.metadata();
+        if let Some(cached) = cache.get(&*self.url) {
+            debug!("using cached metadata of {}", self.url);
+            return Ok(cached)
+        }
+        let (response, redirects) = try!(self.probe_source());
+        let metadata = Metadata::of(&response);
+        cache.insert(&*self.url, metadata.clone(), redirects.clone());
+        Ok((metadata, redirects))
+    }
+
+    /// Probe the source as `head_with_retries` describes
+    fn probe_source(&self) -> Result<(Response, Vec<Redirect>), DownloadError>
+    {
+        if self.probe == Probe::Range {
+            return self.probe_range()
+        }
+        let result = with_retries(&self.retry, "HEAD", || {
+            head(&self.client, &*self.url, self.headers.clone())
+        });
+        match result {
+            Err(DownloadError::Http { status, .. })
+                if status == StatusCode::MethodNotAllowed
+                    || status == StatusCode::NotImplemented => {
+                info!("{} rejected HEAD ({}), probing with a range request", self.url, status);
+                self.probe_range()
+            },
+            result => result,
+        }
+    }
+
+    /// The size of the source from a probe response, or the expected size
+    /// if the server didn't send it
+    fn remote_size(&self, head: &Metadata) -> Result<u64, DownloadError>
+    {
+        match (remote_size(head), self.expected_size) {
+            (Err(DownloadError::MissingContentLength { .. }), Some(expected)) => {
+                info!("{} did not provide a content length, expecting {} bytes",
+                      head.url, expected);
+                Ok(expected)
+            },
+            (Ok(size), Some(expected)) if size != expected => {
+                Err(DownloadError::other(format!(
+                    "{} is {} bytes, but {} were expected", head.url, size, expected)))
+            },
+            (result, _) => result,
+        }
+    }
+
+    /// Request the first byte of the source
+    fn probe_range(&self) -> Result<(Response, Vec<Redirect>), DownloadError>
+    {
+        let mut headers = self.headers.clone();
+        headers.set(Range::Bytes(vec![ByteRangeSpec::FromTo(0, 0)]));
+        with_retries(&self.retry, "GET", || get(&self.client, &*self.url, headers.clone()))
+    }
+
+    /// Where to resume a partially downloaded file target from. Resolves
+    /// the target so the name is claimed only once.
+    fn partial_len(&mut self) -> Result<Resume, DownloadError>
+    {
+        let (head, _) = try!(self.head_with_retries());
+        self.target = self.resolve_target(&head);
+        // Without a size there's no telling whether the file is partial
+        let size = match self.remote_size(&head) {
+            Ok(size) => size,
+            Err(DownloadError::MissingContentLength { .. }) => return Ok(Resume::Restart),
+            Err(e) => return Err(e),
+        };
+        if self.already_complete(&self.target, size) {
+            return Ok(Resume::Complete(head.url.to_string()))
+        }
+        let path = match self.working_target(&self.target) {
+            DownloadTarget::File(path) => path,
+            _ => return Ok(Resume::Restart),
+        };
+        let len = self.partial_file_len(&*path, size);
+        if len == 0 {
+            return Ok(Resume::Restart)
+        }
+        // The partial file may have been started from another url (e.g. an
+        // expired presigned url or another mirror)
+        if !try!(self.serves_partial_file(&*path, (0, len))) {
+            warn!("{} does not serve the bytes already in {}, starting over", self.url, path);
+            return Ok(Resume::Restart)
+        }
+        Ok(Resume::From(len))
+    }
+
+    /// Whether the source serves the same bytes as the last (up to
+    /// `RESUME_SAMPLE_SIZE`) of the bytes `kept.0` to `kept.1` of the
+    /// partial file at `path`. A server ignoring the range is left for the
+    /// download to handle.
+    fn serves_partial_file(&self, path: &str, kept: (u64, u64)) -> Result<bool, DownloadError>
+    {
+        let (start, len) = (cmp::max(kept.0, kept.1.saturating_sub(RESUME_SAMPLE_SIZE)), kept.1);
+        let mut local = vec![0; (len - start) as usize];
+        try!(File::open(os_path(path))
+             .and_then(|mut file| file.seek(io::SeekFrom::Start(start)).map(|_| file))
+             .and_then(|mut file| file.read_exact(&mut local))
+             .map_err(|e| DownloadError::io(e, path)));
+
+        let mut headers = self.headers.clone();
+        headers.set(Range::Bytes(vec![ByteRangeSpec::FromTo(start, len - 1)]));
+        let (response, _) = try!(with_retries(&self.retry, "GET", || {
+            get(&self.client, &*self.url, headers.clone())
+        }));
+        if response.status != StatusCode::PartialContent {
+            return Ok(true)
+        }
+        let mut remote = Vec::with_capacity(local.len());
+        try!(response.take(local.len() as u64).read_to_end(&mut remote));
+        if remote != local {
+            debug!("bytes {} - {} of {} differ from the source", start, len, path);
+        }
+        Ok(remote == local)
+    }
+
+    /// Whether a file target being resumed is already complete: it has the
+    /// length of the remote file of `size` bytes and, if it has a journal,
+    /// all of it is journaled
+    fn already_complete(&self, target: &DownloadTarget, size: u64) -> bool
+    {
+        let path = match *target {
+            DownloadTarget::File(ref path) => path,
+            _ => return false,
+        };
+        match fs::metadata(os_path(path)) {
+            Ok(metadata) if metadata.len() == size => {
+                self.journaled_prefix(path).map_or(true, |journaled| journaled == size)
+            },
+            _ => false,
+        }
+    }
+
+    /// The length of the partial working file at `path` of a remote file
+    /// of `size` bytes, or 0 to start over
+    fn partial_file_len(&self, path: &str, size: u64) -> u64
+    {
+        let len = match fs::metadata(os_path(path)) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return 0,
+        };
+        if len > size {
+            info!("{} is longer than the remote file, starting over", path);
+            return 0
+        }
+        match self.journaled_prefix(path) {
+            Some(journaled) if journaled < len => {
+                info!("Only {} bytes of {} are journaled", journaled, path);
+                journaled
+            },
+            // A complete target was kept, so this is a part file that was
+            // never moved into place
+            _ if len == size => {
+                info!("{} is not shorter than the remote file, starting over", path);
+                0
+            },
+            _ => len,
+        }
+    }
+
+    /// The length of the verified start of a file target, if journaling is
+    /// enabled and it has a journal
+    fn journaled_prefix(&self, path: &str) -> Option<u64>
+    {
+        if !self.journal {
+            return None
+        }
+        let entries = match Journal::read(path) {
+            Ok(entries) => entries,
+            Err(_) => return None,
+        };
+        match journal::verify_chunks(path, entries, self.verify_resume) {
+            Ok(entries) => match journal::valid_ranges(&entries).first() {
+                Some(&(0, end)) => Some(end),
+                _ => Some(0),
+            },
+            Err(e) => {
+                warn!("Unable to verify the journal of {} ({}), starting over", path, e);
+                Some(0)
+            },
+        }
+    }
+
+    /// What the file is checked against: the checksum if one is set, or
+    /// else a digest the server advertised
+    fn expected_digest(&self, digests: &[Digest]) -> Option<Expected>
+    {
+        match self.checksum {
+            Some(ref checksum) => Some(Expected::Given(checksum.clone())),
+            None if self.verify_digest => Checksum::advertised(digests).map(Expected::Advertised),
+            None => None,
+        }
+    }
+
+    /// A hasher to verify the expected digest as bytes are written in
+    /// order, if there is one and no pieces will be refetched out of order
+    fn checksum_hasher(&self, expected: &Option<Expected>) -> Option<Arc<Mutex<Hasher>>>
+    {
+        match (expected, &self.piece_hashes) {
+            (&Some(ref expected), &None) => {
+                Some(Arc::new(Mutex::new(expected.checksum().hasher())))
+            },
+            _ => None,
+        }
+    }
+
+    /// Check the target against the expected digest, if there is one,
+    /// using the digest of the bytes hashed as they were written or else
+    /// hashing the whole file
+    fn verify_checksum(&self, expected: &Option<Expected>, target: &DownloadTarget,
+                       hashed: Option<Arc<Mutex<Hasher>>>) -> Result<(), DownloadError>
+    {
+        let checksum = match *expected {
+            Some(ref expected) => expected.checksum(),
+            None => return Ok(()),
+        };
+        let actual = match (hashed, target) {
+            (Some(hasher), _) => hasher.lock().unwrap().hex(),
+            (None, &DownloadTarget::File(ref path)) => {
+                info!("Verifying the {} of {}", checksum.algorithm(), path);
+                let len = try!(fs::metadata(os_path(path)).map_err(|e| DownloadError::io(e, &**path)))
+                    .len();
+                let mut hasher = checksum.hasher();
+                try!(hasher.update_from_file(os_path(path), len)
+                     .map_err(|e| DownloadError::io(e, &**path)));
+                hasher.hex()
+            },
+            (None, _) => {
+                warn!("Unable to verify the {} of output that can't be read back",
+                      checksum.algorithm());
+                return Ok(())
+            },
+        };
+        if actual != checksum.expected() {
+            return Err(match *expected {
+                Some(Expected::Advertised(_)) => DownloadError::digest_mismatch(
+                    checksum.algorithm(), checksum.expected(), actual),
+                _ => DownloadError::checksum_mismatch(
+                    checksum.algorithm(), checksum.expected(), actual),
+            })
+        }
+        info!("The {} matches", checksum.algorithm());
+        Ok(())
+    }
+
+    /// Download the source to the target in parallel
+    fn download_parallel(&mut self, n: u8) -> Result<DownloadResult, DownloadError>
+    {
+        info!("Downloading with {} threads", n);
+
+        let (head, redirects) = try!(self.head_with_retries());
+        let effective_url = head.url.to_string();
+        let size = match self.remote_size(&head) {
+            Ok(size) => size,
+            Err(DownloadError::MissingContentLength { .. }) => {
+                warn!("{} did not provide a content length, downloading serially", effective_url);
+                self.target = self.resolve_target(&head);
+                return self.download_serial()
+            },
+            Err(e) => return Err(e),
+        };
+        try!(self.check_piece_length(size));
+        let digests = advertised_digests(&head);
+        let destination = match try!(self.check_existing(self.resolve_target(&head), Some(size))) {
+            Existing::Write(destination) => destination,
+            Existing::Keep(existing) => {
+                return Ok(kept(existing, effective_url, redirects, digests))
+            },
+        };
+        let target = self.working_target(&destination);
+        self.claim(&target);
+        let sources = Arc::new(Sources::new(source_urls(&head), self.url_refresher.clone()));
+
+        // Segments written from the start of a whole response would
+        // corrupt the file
+        if !try!(self.supports_ranges(&head)) {
+            warn!("{} does not support range requests, downloading serially", effective_url);
+            // Keep the name already claimed for the target
+            self.target = destination;
+            return self.download_serial()
+        }
+
+        // Ranges journaled by an interrupted run don't need fetching again,
+        // unless they came from another url serving other bytes
+        let mut journaled = self.journaled_ranges(&target, size);
+        if let (Some(&last), &DownloadTarget::File(ref path)) = (journaled.last(), &target) {
+            if !try!(self.serves_partial_file(path, last)) {
+                warn!("{} does not serve the bytes journaled in {}, starting over", self.url, path);
+                journaled.clear();
+            }
+        }
+        if journaled.is_empty() {
+            let created = match target {
+                DownloadTarget::File(ref path) if !exists(path) => Some(path.clone()),
+                _ => None,
+            };
+            // Some filesystems can't hold the file's full length up front
+            // (e.g. FAT32 beyond 4 GB, a full quota), so grow it in order
+            if let Err(e) = set_target_len(&target, size, &self.write_options) {
+                warn!("Unable to preallocate {} bytes ({}), writing in order instead, so \
+                       workers can only fetch a window ahead of the write position", size, e);
+                // Leave only the files that were there before, for the
+                // overwrite policy to apply to as it did here
+                if let Some(ref path) = created {
+                    let _ = fs::remove_file(os_path(path));
+                }
+                self.target = destination;
+                return self.download_streaming(n)
+            }
+        }
+
+        let write_options = try!(self.target_options(&target, !journaled.is_empty()));
+        let regions = self.planner.plan(size);
+        try!(plan::validate(&regions, size).map_err(DownloadError::other));
+        let regions = plan::subtract(regions, &journaled);
+        let crew = Arc::new(Crew {
+            scheduler: Mutex::new((self.scheduler)(regions, n as u64)),
+            stall_timeout: self.stall_timeout,
+            workers: Mutex::new(vec![]),
+        });
+        let (tx, rx) = channel();
+
+        let done: u64 = journaled.iter().map(|&(start, end)| end - start).sum();
+        if done > 0 {
+            info!("Resuming with {} of {} bytes already journaled", done, size);
+            let _ = tx.send(CompletedSegment { start: 0, len: done, md5: String::new() });
+        }
+
+        for i in 0..n {
+            // Spread workers across mirrors, falling back to the others
+            let worker = Worker {
+                id: i,
+                retry: self.retry.clone(),
+                sources: sources.clone(),
+                preferred: i as usize,
+                headers: self.headers.clone(),
+                client: self.client.clone(),
+                target: target.clone(),
+                options: write_options.clone(),
+            };
+            spawn_worker(worker, None, &crew, tx.clone());
+        };
+
+        // Only the workers hold senders now, so listening ends with them
+        drop(tx);
+        self.reporter.listen(size, rx);
+
+        // Replacements took over the ranges of abandoned workers, which
+        // may never return
+        let workers = mem::replace(&mut *crew.workers.lock().unwrap(), vec![]);
+        for (seat, child) in workers {
+            if !seat.abandoned.load(Ordering::SeqCst) {
+                try!(child.join().unwrap());
+            }
+        }
+
+        try!(self.repair_pieces(&target, &write_options));
+        try!(check_size(&target, size));
+        // Written out of order, so hashed once complete
+        try!(self.verify_checksum(&self.expected_digest(&digests), &target, None));
+        try!(finalize_target(&target, &write_options));
+        try!(place_target(&target, &destination));
+        Ok(DownloadResult {
+            bytes: size,
+            url: effective_url,
+            redirects: redirects,
+            digests: digests,
+            target: destination,
+            skipped: false,
+        })
+    }
+
+    /// Whether the server honors range requests: it must not advertise
+    /// `Accept-Ranges: none`, and must answer a request for the first byte
+    /// with 206 Partial Content
+    fn supports_ranges(&self, head: &Metadata) -> Result<bool, DownloadError>
+    {
+        // Already answered a range request in place of HEAD
+        if head.status == StatusCode::PartialContent {
+            return Ok(true)
+        }
+        if let Some(&AcceptRanges(ref units)) = head.headers.get::<AcceptRanges>() {
+            if !units.contains(&RangeUnit::Bytes) {
+                return Ok(false)
+            }
+        }
+        let cache = self.client.metadata();
+        if let Some(ranges) = cache.ranges(&*self.url) {
+            return Ok(ranges)
+        }
+        let mut headers = self.headers.clone();
+        headers.set(Range::Bytes(vec![ByteRangeSpec::FromTo(0, 0)]));
+        let (response, _) = try!(with_retries(&self.retry, "GET", || {
+            get(&self.client, head.url.as_str(), headers.clone())
+        }));
+        let ranges = response.status == StatusCode::PartialContent;
+        cache.set_ranges(&*self.url, ranges);
+        Ok(ranges)
+    }
+
+    /// Download the source to the target in order, fetching ahead in
+    /// parallel
+    fn download_streaming(&mut self, n: u8) -> Result<DownloadResult, DownloadError>
+    {
+        info!("Streaming with {} threads", n);
+
+        let (head, redirects) = try!(self.head_with_retries());
+        let effective_url = head.url.to_string();
+        let size = match self.remote_size(&head) {
+            Ok(size) => size,
+            Err(DownloadError::MissingContentLength { .. }) => {
+                warn!("{} did not provide a content length, downloading serially", effective_url);
+                self.target = self.resolve_target(&head);
+                return self.download_serial()
+            },
+            Err(e) => return Err(e),
+        };
+        try!(self.check_piece_length(size));
+        let digests = advertised_digests(&head);
+        let destination = match try!(self.check_existing(self.resolve_target(&head), Some(size))) {
+            Existing::Write(destination) => destination,
+            Existing::Keep(existing) => {
+                return Ok(kept(existing, effective_url, redirects, digests))
+            },
+        };
+        let target = self.working_target(&destination);
+        self.claim(&target);
+        let sources = Arc::new(Sources::new(source_urls(&head), self.url_refresher.clone()));
+
+        // Small chunks keep the time to first byte and the memory held
+        // ahead of the write position low
+        let chunks: Vec<Chunk> = (0..(size + STREAM_CHUNK_SIZE - 1) / STREAM_CHUNK_SIZE)
+            .map(|i| Chunk {
+                start: i * STREAM_CHUNK_SIZE,
+                end: cmp::min((i + 1) * STREAM_CHUNK_SIZE, size),
+            })
+            .collect();
+        let worker = Worker {
+            id: 0,
+            retry: self.retry.clone(),
+            sources: sources,
+            preferred: 0,
+            headers: self.headers.clone(),
+            client: self.client.clone(),
+            target: target.clone(),
+            options: self.write_options.clone(),
+        };
+
+        let (tx, rx) = channel();
+        let (thread_target, options) = (target.clone(), self.write_options.clone());
+        let expected = self.expected_digest(&digests);
+        let hasher = self.checksum_hasher(&expected);
+        let thread_hasher = hasher.clone();
+        let segment_md5 = self.write_options.segment_md5;
+        // Files are decompressed in place once complete, as the size,
+        // pieces and checksums describe the bytes served
+        let decompress = match target {
+            DownloadTarget::StdOut => self.auto_decompress,
+            _ => false,
+        };
+        let name = head.url.path().to_owned();
+        let writer = thread::spawn(move || -> Result<u64, DownloadError> {
+            let mut decoder = None;
+            let out: Box<dyn io::Write> = match thread_target {
+                DownloadTarget::File(ref path) => Box::new(try!(create_file(path, &options))),
+                DownloadTarget::StdOut if decompress => {
+                    let (stream, thread) = decompress_stream(io::stdout(), &*name);
+                    decoder = Some(thread);
+                    Box::new(stream)
+                },
+                DownloadTarget::StdOut => Box::new(io::stdout()),
+                DownloadTarget::Default => {
+                    return Err(DownloadError::other("Default target has not been resolved"))
+                },
+            };
+            let mut out = match thread_hasher {
+                Some(hasher) => Box::new(HashingWriter::new(out, hasher)),
+                None => out,
+            };
+            let mut done = 0;
+            let written = fetch_in_order(chunks, n as usize, move |index, chunk: &Chunk,
+                                                                   backpressure| {
+                // Spread workers across mirrors, falling back to the others
+                let id = (index % n as usize) as u8;
+                let worker = Worker { id: id, preferred: index, ..worker.clone() };
+                debug!("worker {} requesting bytes {} - {}", id, chunk.start, chunk.end);
+                worker.fetch(index, chunk, backpressure).map_err(|e| e.with_segment(id))
+            }, &mut out, |piece| {
+                let _ = tx.send(CompletedSegment {
+                    start: done,
+                    len: piece.len() as u64,
+                    md5: if segment_md5 { segment_hash(piece) } else { String::new() },
+                });
+                done += piece.len() as u64;
+            });
+            // Hanging up lets the decoder finish, and its error explains a
+            // write that failed because it stopped
+            drop(out);
+            match decoder.map(|decoder| decoder.join()) {
+                Some(Ok(Ok(Some(compression)))) => {
+                    info!("Decompressed {} while streaming", compression.name())
+                },
+                Some(Ok(Err(e))) => return Err(DownloadError::from(e)),
+                Some(Err(_)) => {
+                    return Err(DownloadError::other("the decompressing thread panicked"))
+                },
+                _ => (),
+            }
+            written
+        });
+
+        self.reporter.listen(size, rx);
+        let written = try!(writer.join().unwrap());
+        try!(self.repair_pieces(&target, &self.write_options));
+        try!(check_size(&target, size));
+        try!(self.verify_checksum(&expected, &target, hasher));
+        try!(finalize_target(&target, &self.write_options));
+        try!(place_target(&target, &destination));
+        Ok(DownloadResult {
+            bytes: written,
+            url: effective_url,
+            redirects: redirects,
+            digests: digests,
+            target: destination,
+            skipped: false,
+        })
+    }
+
+    /// Make sure the piece hashes describe a file of this size
+    fn check_piece_length(&self, size: u64) -> Result<(), DownloadError>
+    {
+        match self.piece_hashes {
+            Some(ref hashes) if hashes.length != size => Err(DownloadError::checksum(format!(
+                "the torrent describes a {} byte file but the server has {} bytes",
+                hashes.length, size))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Verify a file target against the piece hashes, fetching any corrupt
+    /// pieces again
+    fn repair_pieces(&self, target: &DownloadTarget, options: &WriteOptions)
+                     -> Result<(), DownloadError>
+    {
+        let (hashes, path) = match (&self.piece_hashes, target) {
+            (&Some(ref hashes), &DownloadTarget::File(ref path)) => (hashes, path),
+            _ => return Ok(()),
+        };
+
+        let corrupt = try!(hashes.corrupt_pieces(path));
+        if corrupt.is_empty() {
+            info!("All {} pieces match the torrent", hashes.hashes.len());
+            return Ok(())
+        }
+
+        warn!("{} of {} pieces don't match the torrent, fetching them again",
+              corrupt.len(), hashes.hashes.len());
+        for index in corrupt {
+            let (start, len) = hashes.piece(index);
+            let mut headers = self.headers.clone();
+            headers.set(range_header(&Chunk { start: start, end: start + len }));
+            let (response, _) = try!(get(&self.client, &*self.url, headers));
+            // A server ignoring the range would overwrite the file from `start`
+            if response.status != StatusCode::PartialContent {
+                return Err(DownloadError::unsupported_range(response.status))
+            }
+            let (tx, _) = channel();
+            try!(stream(target, start, response, tx, options).map_err(|e| e.with_offset(start)));
+        }
+
+        let corrupt = try!(hashes.corrupt_pieces(path));
+        if !corrupt.is_empty() {
+            return Err(DownloadError::checksum(format!(
+                "{} pieces still don't match the torrent after fetching them again",
+                corrupt.len())))
+        }
+        info!("Repaired the file, all pieces now match the torrent");
+        Ok(())
+    }
+
+    /// The options for writing a target: starting a journal for file
+    /// targets if journaling is enabled (or continuing the existing one),
+    /// and batching writes to a network filesystem
+    fn target_options(&self, target: &DownloadTarget, resume: bool)
+                    -> Result<WriteOptions, DownloadError>
+    {
+        let mut options = self.write_options.clone();
+        if let (true, &DownloadTarget::File(ref path)) = (self.journal, target) {
+            let journal = if resume { Journal::open(path) } else { Journal::create(path) };
+            options.journal = Some(Arc::new(try!(journal)));
+        }
+        if let (Some(writes), &DownloadTarget::File(ref path)) = (self.network_writes, target) {
+            if is_network_fs(Path::new(path)) {
+                info!("{} is on a network filesystem, writing in batches of {} bytes",
+                      path, writes.batch);
+                options.write_batch = Some(writes.batch);
+                options.sync_interval = Some(writes.sync_interval);
+            }
+        }
+        Ok(options)
+    }
+
+    /// The verified ranges of a file target journaled by an interrupted
+    /// download, if resuming with journaling enabled and the target has the
+    /// length of the remote file
+    fn journaled_ranges(&self, target: &DownloadTarget, size: u64) -> Vec<(u64, u64)>
+    {
+        let path = match (self.resume && self.journal, target) {
+            (true, &DownloadTarget::File(ref path)) => path,
+            _ => return vec![],
+        };
+        let entries = match Journal::read(path) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        match fs::metadata(os_path(path)) {
+            Ok(ref metadata) if metadata.len() == size => (),
+            _ => {
+                warn!("{} does not match its journal, starting over", path);
+                return vec![]
+            },
+        }
+        match journal::verify_chunks(path, entries, self.verify_resume) {
+            Ok(entries) => journal::valid_ranges(&entries),
+            Err(e) => {
+                warn!("Unable to verify the journal of {} ({}), starting over", path, e);
+                vec![]
+            },
+        }
+    }
+
+    /// Apply the overwrite policy to a destination that may already exist,
+    /// for a remote file of `size` bytes if known
+    fn check_existing(&self, destination: DownloadTarget, size: Option<u64>)
+                      -> Result<Existing, DownloadError>
+    {
+        let path = match destination {
+            DownloadTarget::File(ref path) if exists(path) => path.clone(),
+            _ => return Ok(Existing::Write(destination)),
+        };
+        match self.overwrite {
+            // As with `wget -c`, a complete file is left as it is
+            _ if self.resume && size.map_or(false, |size| self.already_complete(&destination, size)) => {
+                info!("{} is already complete, skipping", path);
+                Ok(Existing::Keep(destination))
+            },
+            // A partial file is the one being continued
+            _ if self.resume => Ok(Existing::Write(destination)),
+            OverwritePolicy::Overwrite | OverwritePolicy::Resume => {
+                info!("Replacing {}", path);
+                Ok(Existing::Write(destination))
+            },
+            OverwritePolicy::Skip => {
+                info!("{} already exists, skipping", path);
+                Ok(Existing::Keep(destination))
+            },
+            OverwritePolicy::Error => Err(DownloadError::refused(format!(
+                "{} already exists (use --force to replace it or --no-clobber to keep it)",
+                path))),
+            OverwritePolicy::RenameNumbered => {
+                let (dir, name) = {
+                    let path = Path::new(&*path);
+                    let name = path.file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                        .unwrap_or_default();
+                    (path.parent().map(Path::to_path_buf).unwrap_or_default(), name)
+                };
+                let mut n = 1;
+                loop {
+                    let candidate = dir.join(numbered(&*name, n)).to_string_lossy().into_owned();
+                    if !exists(&*candidate) {
+                        info!("{} already exists, saving as {}", path, candidate);
+                        return Ok(Existing::Write(DownloadTarget::File(candidate)))
+                    }
+                    n += 1;
+                }
+            },
+        }
+    }
+
+    /// Where a file target is written while downloading: a part file in the
+    /// temp directory if one is set, otherwise next to the target unless
+    /// part files are disabled
+    fn working_target(&self, target: &DownloadTarget) -> DownloadTarget
+    {
+        match (&self.tmp_dir, target) {
+            (&Some(ref dir), &DownloadTarget::File(ref path)) => {
+                let name = Path::new(path).file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                DownloadTarget::File(dir.join(format!("{}.part", name))
+                                     .to_string_lossy().into_owned())
+            },
+            (&None, &DownloadTarget::File(ref path)) if self.part_file => {
+                DownloadTarget::File(format!("{}.part", path))
+            },
+            _ => target.clone(),
+        }
+    }
+
+    /// Register the working files of a file target for secure cleanup
+    fn claim(&mut self, target: &DownloadTarget)
+    {
+        let path = match (self.secure_cleanup, target) {
+            (true, &DownloadTarget::File(ref path)) => path,
+            _ => return,
+        };
+        for path in vec![os_path(path), Journal::path_for(path)] {
+            cleanup::register(&path);
+            if !self.working_files.contains(&path) {
+                self.working_files.push(path);
+            }
+        }
+    }
+
+    /// Release the working files registered for secure cleanup, shredding
+    /// them unless the download succeeded
+    fn clean_up(&mut self, succeeded: bool)
+    {
+        for path in mem::replace(&mut self.working_files, vec![]) {
+            cleanup::release(&path);
+            if succeeded {
+                continue
+            }
+            match cleanup::shred(&path) {
+                Ok(_) => (),
+                Err(e) => warn!("Unable to shred {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Resolve the default target to a file name based on the response
+    fn resolve_target(&self, response: &Metadata) -> DownloadTarget
+    {
+        match self.target {
+            DownloadTarget::Default => {
+                let name = default_file_name(response);
+                let name = match self.file_names {
+                    Some(ref names) => {
+                        names.claim(&*self.url, response.url.host_str(), &*name)
+                    },
+                    None => name,
+                };
+                DownloadTarget::File(match self.directory {
+                    Some(ref dir) => dir.join(name).to_string_lossy().into_owned(),
+                    None => name,
+                })
+            },
+            ref target => target.clone(),
+        }
+    }
+}
+
+/// State shared by the workers of a parallel download
+struct Crew {
+    scheduler: Mutex<Box<dyn Schedule>>,
+    /// How long a segment may receive nothing before it is re-dispatched
+    stall_timeout: Option<Duration>,
+    /// Every worker started, including replacements
+    workers: Mutex<Vec<(Arc<Seat>, thread::JoinHandle<Result<(), DownloadError>>)>>,
+}
+
+/// A worker's connection to the reporter, taken away if it stalls
+struct Seat {
+    reporter: Mutex<Option<Sender<CompletedSegment>>>,
+    abandoned: AtomicBool,
+}
+
+impl Seat {
+
+    /// Abandon the worker, handing over its reporter unless it was already
+    /// abandoned
+    fn abandon(&self) -> Option<Sender<CompletedSegment>>
+    {
+        self.abandoned.store(true, Ordering::SeqCst);
+        self.reporter.lock().unwrap().take()
+    }
+}
+
+/// Start `worker` on the `first` chunk (if any), then on the chunks the
+/// crew's scheduler hands out
+fn spawn_worker(worker: Worker, first: Option<Chunk>, crew: &Arc<Crew>,
+                reporter: Sender<CompletedSegment>)
+{
+    let seat = Arc::new(Seat { reporter: Mutex::new(Some(reporter)), abandoned: AtomicBool::new(false) });
+    let (crew_, seat_) = (crew.clone(), seat.clone());
+    let child = thread::spawn(move || worker.run(first, crew_, seat_));
+    crew.workers.lock().unwrap().push((seat, child));
+}
+
+/// Fails reads once the worker is abandoned, so nothing it receives
+/// afterwards is written
+struct AbandonableReader {
+    inner: Box<dyn io::Read>,
+    seat: Arc<Seat>,
+}
+
+impl io::Read for AbandonableReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let n = try!(self.inner.read(buf));
+        if self.seat.abandoned.load(Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::Other, "segment was re-dispatched"))
+        }
+        Ok(n)
+    }
+}
+
+/// Downloads chunks handed out by a shared scheduler until none remain
+#[derive(Clone)]
+struct Worker {
+    id: u8,
+    /// Which failed requests are retried
+    retry: RetryPolicy,
+    /// Urls serving the file
+    sources: Arc<Sources>,
+    /// Index of the source this worker prefers
+    preferred: usize,
+    headers: Headers,
+    client: ClientConfig,
+    target: DownloadTarget,
+    options: WriteOptions,
+}
+
+/// Gives up a worker's seat when the worker ends, however it ends. The
+/// crew keeps every seat, so a sender left in one would keep the reporter
+/// listening forever.
+struct Vacate<'a>(&'a Seat);
+
+impl<'a> Drop for Vacate<'a> {
+    fn drop(&mut self)
+    {
+        // Also while a panicking worker unwinds
+        let mut reporter = self.0.reporter.lock().unwrap_or_else(|e| e.into_inner());
+        reporter.take();
+    }
+}
+
+impl Worker {
+    fn run(self, first: Option<Chunk>, crew: Arc<Crew>, seat: Arc<Seat>)
+           -> Result<(), DownloadError>
+    {
+        let _vacate = Vacate(&seat);
+        let mut first = first;
+        loop {
+            if seat.abandoned.load(Ordering::SeqCst) {
+                return Ok(())
+            }
+            let chunk = match first.take().or_else(|| crew.scheduler.lock().unwrap().next_chunk()) {
+                Some(chunk) => chunk,
+                None => return Ok(()),
+            };
+
+            debug!("worker {} requesting bytes {} - {}", self.id, chunk.start, chunk.end);
+            let requested = Instant::now();
+
+            let mut rtt = None;
+            let mut remaining = chunk;
+            let mut attempt = 1;
+            loop {
+                let mut headers = self.headers.clone();
+                headers.set(range_header(&remaining));
+                let response = try!(self.get_with_retries(headers).map_err(|e| {
+                    e.with_segment(self.id).with_offset(remaining.start)
+                }));
+                rtt = rtt.or(Some(requested.elapsed()));
+                // A mirror ignoring the range would send the whole file
+                if response.status != StatusCode::PartialContent {
+                    return Err(DownloadError::unsupported_range(response.status)
+                               .with_url(response.url.as_str())
+                               .with_segment(self.id)
+                               .with_offset(remaining.start))
+                }
+
+                let err = match self.stream_watched(remaining, response, &crew, &seat) {
+                    Ok(_) => break,
+                    // A replacement is fetching the rest of the chunk
+                    Err(_) if seat.abandoned.load(Ordering::SeqCst) => return Ok(()),
+                    Err(e) => e.with_segment(self.id),
+                };
+                // Request only the bytes not yet written
+                let reached = err.context().offset.unwrap_or(remaining.start);
+                if reached >= remaining.end || !self.retry.should_retry(&err, attempt) {
+                    return Err(err.with_attempt(attempt))
+                }
+                remaining.start = reached;
+                warn!("worker {} interrupted at byte {} ({}), resuming", self.id, remaining.start, err);
+                wait_before_retry(&self.retry, &err, attempt);
+                attempt = self.retry.next_attempt(&err, attempt);
+            }
+            crew.scheduler.lock().unwrap().report(&chunk, rtt.unwrap(), requested.elapsed());
+        }
+    }
+
+    /// Stream the response for the `remaining` bytes of a chunk to the
+    /// target, relaying its progress to the reporter. If it receives
+    /// nothing for the stall timeout the worker is abandoned.
+    fn stream_watched(&self, remaining: Chunk, response: Response, crew: &Arc<Crew>,
+                      seat: &Arc<Seat>) -> Result<u64, DownloadError>
+    {
+        let reporter = match seat.reporter.lock().unwrap().clone() {
+            Some(reporter) => reporter,
+            None => return Err(DownloadError::other("segment was re-dispatched".to_owned())),
+        };
+        let timeout = match crew.stall_timeout {
+            Some(timeout) => timeout,
+            None => return stream(&self.target, remaining.start, response, reporter, &self.options),
+        };
+
+        let (tx, rx) = channel();
+        let (worker, crew_, seat_) = (self.clone(), crew.clone(), seat.clone());
+        thread::spawn(move || worker.relay(remaining, timeout, rx, reporter, crew_, seat_));
+
+        let size = response.headers.get::<ContentLength>().map_or(0, |len| len.0);
+        let body = try!(body_reader(response, remaining.start, &self.options));
+        let body = Box::new(AbandonableReader { inner: body, seat: seat.clone() });
+        stream_body(&self.target, remaining.start, size, body, tx, &self.options)
+    }
+
+    /// Forward the progress of a segment to the reporter until it ends. A
+    /// segment receiving nothing for `timeout` is stalled: its worker is
+    /// abandoned and a replacement requests the rest of the range from the
+    /// last byte written.
+    fn relay(self, segment: Chunk, timeout: Duration, progress: Receiver<CompletedSegment>,
+             reporter: Sender<CompletedSegment>, crew: Arc<Crew>, seat: Arc<Seat>)
+    {
+        let mut confirmed = segment.start;
+        loop {
+            match progress.recv_timeout(timeout) {
+                Ok(completed) => {
+                    confirmed += completed.len;
+                    let _ = reporter.send(completed);
+                },
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+        let reporter = match seat.abandon() {
+            Some(reporter) => reporter,
+            None => return,
+        };
+        warn!("worker {} received nothing for {:?} at byte {} of bytes {} - {}, re-dispatching \
+               the rest", self.id, timeout, confirmed, segment.start, segment.end);
+        // Stalled after writing everything, e.g. while flushing
+        let rest = if confirmed < segment.end {
+            Some(Chunk { start: confirmed, end: segment.end })
+        } else {
+            None
+        };
+        spawn_worker(self, rest, &crew, reporter);
+    }
+
+    /// Fetch piece `index` of an ordered download into memory, pausing
+    /// while `backpressure` says the output is behind. A body interrupted
+    /// (e.g. closed by the server while paused) is requested again from
+    /// the bytes already read, as the retry policy allows.
+    fn fetch(&self, index: usize, chunk: &Chunk, backpressure: &Backpressure)
+             -> Result<Vec<u8>, DownloadError>
+    {
+        let mut piece = Vec::with_capacity(chunk.len() as usize);
+        let mut attempt = 1;
+        loop {
+            let remaining = Chunk { start: chunk.start + piece.len() as u64, end: chunk.end };
+            let err = match self.fetch_into(index, &remaining, backpressure, &mut piece) {
+                Ok(_) => return Ok(piece),
+                Err(e) => e.with_offset(chunk.start + piece.len() as u64),
+            };
+            if !self.retry.should_retry(&err, attempt) {
+                return Err(err.with_attempt(attempt))
+            }
+            warn!("worker {} interrupted at byte {} ({}), resuming", self.id,
+                  chunk.start + piece.len() as u64, err);
+            wait_before_retry(&self.retry, &err, attempt);
+            attempt = self.retry.next_attempt(&err, attempt);
+        }
+    }
+
+    /// Read the bytes of `chunk` onto the end of `piece`
+    fn fetch_into(&self, index: usize, chunk: &Chunk, backpressure: &Backpressure,
+                  piece: &mut Vec<u8>) -> Result<(), DownloadError>
+    {
+        let mut headers = self.headers.clone();
+        headers.set(range_header(chunk));
+        let response = try!(self.get_with_retries(headers));
+        // A server ignoring the range would send the whole file
+        if response.status != StatusCode::PartialContent {
+            return Err(DownloadError::unsupported_range(response.status)
+                       .with_url(response.url.as_str()))
+        }
+        let mut body = try!(body_reader(response, chunk.start, &self.options)).take(chunk.len());
+        let mut buf = vec![0; cmp::min(DEFAULT_BUFF_SIZE as u64, chunk.len()) as usize];
+        let mut read = 0;
+        loop {
+            let len = match body.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(ref limiter) = self.options.limiter {
+                limiter.take(len);
+            }
+            if !backpressure.reserve(index, len) {
+                return Err(DownloadError::other("the output failed"))
+            }
+            piece.extend_from_slice(&buf[..len]);
+            read += len as u64;
+        }
+        if read != chunk.len() {
+            return Err(DownloadError::incomplete(read, chunk.len()))
+        }
+        Ok(())
+    }
+
+    /// Request from the sources, retrying failures the policy allows
+    fn get_with_retries(&self, headers: Headers) -> Result<Response, DownloadError>
+    {
+        with_retries(&self.retry, &*format!("worker {}", self.id),
+                     || self.get_from_any(headers.clone()))
+    }
+
+    /// Request from each url in turn until one succeeds
+    fn get_from_any(&self, headers: Headers) -> Result<Response, DownloadError>
+    {
+        let mut last_err = None;
+        for url in self.sources.ordered(self.preferred) {
+            match self.sources.get(&self.client, &*url, headers.clone()) {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    warn!("worker {} unable to fetch from {}: {}", self.id, url, e);
+                    last_err = Some(e.with_url(url));
+                },
+            }
+        }
+        Err(last_err.unwrap_or_else(|| DownloadError::other("no urls to download from".to_owned())))
+    }
+}
+
+/// Called with an expired url to obtain a fresh one (e.g. a new presigned
+/// url), or None if it can't be refreshed
+pub type UrlRefresher = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// The urls a file is served from, the first being the primary url
+struct Sources {
+    urls: Mutex<Vec<String>>,
+    refresher: Option<UrlRefresher>,
+}
+
+impl Sources {
+    fn new(urls: Vec<String>, refresher: Option<UrlRefresher>) -> Sources
+    {
+        Sources { urls: Mutex::new(urls), refresher: refresher }
+    }
+
+    /// All urls, starting from the one at index `preferred`
+    fn ordered(&self, preferred: usize) -> Vec<String>
+    {
+        let mut urls = self.urls.lock().unwrap().clone();
+        let len = urls.len();
+        if len > 0 {
+            urls.rotate_left(preferred % len);
+        }
+        urls
+    }
+
+    /// GET from `url`, refreshing it if the server says it has expired
+    fn get(&self, client: &ClientConfig, url: &str, headers: Headers)
+           -> Result<Response, DownloadError>
+    {
+        let (response, _) = try!(request(client, Method::Get, url, headers.clone()));
+        if response.status == StatusCode::Forbidden {
+            if let Some(fresh) = self.refresh(url) {
+                let (response, _) = try!(request(client, Method::Get, &*fresh, headers));
+                return raise_for_status(client, response)
+            }
+        }
+        raise_for_status(client, response)
+    }
+
+    /// Obtain a fresh url to replace an expired one
+    fn refresh(&self, expired: &str) -> Option<String>
+    {
+        let refresher = match self.refresher {
+            Some(ref refresher) => refresher,
+            None => return None,
+        };
+
+        let mut urls = self.urls.lock().unwrap();
+        let index = match urls.iter().position(|u| u == expired) {
+            Some(index) => index,
+            // Another worker already replaced it
+            None => return urls.first().cloned(),
+        };
+
+        info!("Refreshing expired url {}", expired);
+        let fresh = refresher(expired);
+        if let Some(ref fresh) = fresh {
+            urls[index] = fresh.clone();
+        }
+        fresh
+    }
+}
+
+/// The effective url of a response followed by any mirrors it advertises
+fn source_urls(response: &Metadata) -> Vec<String>
+{
+    let mut urls = vec![response.url.to_string()];
+    for mirror in metalink::mirrors(&response.headers) {
+        match response.url.join(&*mirror.url) {
+            Ok(url) => {
+                info!("Server advertised mirror {}", url);
+                urls.push(url.to_string());
+            },
+            Err(e) => warn!("Ignoring invalid mirror {}: {}", mirror.url, e),
+        }
+    }
+    urls
+}
+
+/// The hashes of the file advertised by the response. The `Content-MD5`
+/// of a partial response only covers its part, so is ignored.
+fn advertised_digests(response: &Metadata) -> Vec<Digest>
+{
+    let mut digests = metalink::digests(&response.headers);
+    if response.status != StatusCode::PartialContent {
+        if let Some(lines) = response.headers.get_raw("Content-MD5") {
+            for line in lines {
+                digests.push(Digest {
+                    algorithm: "md5".to_owned(),
+                    value: String::from_utf8_lossy(line).trim().to_owned(),
+                });
+            }
+        }
+    }
+    for digest in &digests {
+        info!("Server advertised {} digest {}", digest.algorithm, digest.value);
+    }
+    digests
+}
+
+/// The Range header requesting a chunk (whose end is exclusive)
+fn range_header(chunk: &Chunk) -> Range
+{
+    Range::Bytes(vec![ByteRangeSpec::FromTo(chunk.start, chunk.end - 1)])
+}
+
+/// Construct and execute GET request against API
+pub fn get(client: &ClientConfig, url: &str, headers: Headers)
+           -> Result<(Response, Vec<Redirect>), DownloadError>
+{
+    let (response, redirects) = try!(request(client, Method::Get, url, headers));
+    Ok((try!(raise_for_status(client, response)), redirects))
+}
+
+/// Construct and execute HEAD request against API
+pub fn head(client: &ClientConfig, url: &str, headers: Headers)
+            -> Result<(Response, Vec<Redirect>), DownloadError>
+{
+    let (response, redirects) = try!(request(client, Method::Head, url, headers));
+    Ok((try!(raise_for_status(client, response)), redirects))
+}
+
+/// Execute a request, following and recording any redirects (the final
+/// response's status is not checked)
+fn request(config: &ClientConfig, method: Method, url: &str, headers: Headers)
+           -> Result<(Response, Vec<Redirect>), DownloadError>
+{
+    let client = config.client();
+    let mut headers = headers;
+    let mut redirects = vec![];
+    let mut url = try!(Url::parse(url).map_err(|e| {
+        DownloadError::invalid_url(url, e.to_string())
+    }));
+    // Dropped with the other credentials on a redirect the policy doesn't
+    // send them on for
+    let mut token = config.token();
+    // Each url requested with the cookies sent, to detect loops
+    let mut requested: Vec<(String, Option<Vec<Vec<u8>>>)> = vec![];
+
+    loop {
+        try!(config.check_url(&url).map_err(DownloadError::refused));
+        if let Some((deadline, max_time)) = config.deadline() {
+            if Instant::now() >= deadline {
+                return Err(DownloadError::timeout(TimeoutKind::Total, max_time).with_url(url.as_str()))
+            }
+        }
+        let mut sent = headers.clone();
+        if let Some(cookies) = config.cookies().and_then(|store| store.header(&url)) {
+            // Sent after any given with the request's headers
+            let cookies = match headers.get_raw("Cookie") {
+                Some(given) => {
+                    let given: Vec<String> = given.iter()
+                        .map(|line| String::from_utf8_lossy(line).into_owned())
+                        .collect();
+                    format!("{}; {}", given.join("; "), cookies)
+                },
+                None => cookies,
+            };
+            sent.set_raw("Cookie", vec![cookies.into_bytes()]);
+        }
+        if let Some(token) = token.filter(|_| headers.get_raw("Authorization").is_none()) {
+            // Read for each request, so a refreshed token file is picked up
+            let value = try!(token.read().map_err(|e| match token.path() {
+                Some(path) => DownloadError::io(e, path.to_string_lossy()),
+                None => DownloadError::from(e),
+            }));
+            sent.set_raw("Authorization", vec![format!("Bearer {}", value).into_bytes()]);
+        }
+        if let Some(signer) = config.signer().filter(|signer| signer.signs(&url)) {
+            signer.sign(&*method.to_string(), &url, &mut sent);
+        }
+        let key = (url.to_string(), sent.get_raw("Cookie").map(|lines| lines.to_vec()));
+        if requested.contains(&key) {
+            let mut chain: Vec<String> = requested.into_iter().map(|(url, _)| url).collect();
+            chain.push(url.to_string());
+            return Err(DownloadError::redirect_loop(chain).with_url(url.as_str()))
+        }
+        requested.push(key);
+        debug!("{}: {}", method, url);
+        let request = client.request(method.clone(), url.clone()).headers(sent);
+        let response = try!(request.send().map_err(|e| {
+            timed_out(config, e).with_url(url.as_str())
+        }));
+        if let Some(store) = config.cookies() {
+            store.store_response(&url, &response.headers);
+        }
+
+        let location = match response.headers.get::<Location>() {
+            Some(location) if is_redirect(response.status) => location.0.clone(),
+            _ => return Ok((response, redirects)),
+        };
+
+        if redirects.len() >= config.redirect_limit() {
+            let chain = requested.into_iter().map(|(url, _)| url).collect();
+            return Err(DownloadError::too_many_redirects(config.redirect_limit(), chain)
+                       .with_url(url.as_str()))
+        }
+
+        let next = try!(url.join(&*location).map_err(|e| {
+            DownloadError::invalid_url(&*location, e.to_string())
+        }));
+
+        info!("{} redirected ({}) to {}", url, response.status, next);
+        // Credentials given for one host aren't sent on to another, unless
+        // the policy says otherwise
+        if !config.redirect_auth_policy().keeps(&url, &next) {
+            for name in CREDENTIAL_HEADERS {
+                headers.remove_raw(name);
+            }
+            token = None;
+        }
+        redirects.push(Redirect { url: url.to_string(), status: response.status });
+        url = next;
+    }
+}
+
+/// Decompress a completed file target if it was sent compressed
+fn decompress_target(target: &DownloadTarget) -> Result<(), DownloadError>
+{
+    let path = match *target {
+        DownloadTarget::File(ref path) => path,
+        _ => return Ok(()),
+    };
+    match decompress_file(&os_path(path)) {
+        Ok(Some(compression)) => info!("Decompressed {} ({})", path, compression.name()),
+        Ok(None) => (),
+        Err(e) => return Err(DownloadError::io(e, &**path)),
+    }
+    Ok(())
+}
+
+/// Hash a completed file target in blocks and write the manifest next to it
+fn write_block_manifest(target: &DownloadTarget, block_size: u64) -> Result<(), DownloadError>
+{
+    let path = match *target {
+        DownloadTarget::File(ref path) => path,
+        _ => return Ok(()),
+    };
+    let manifest = BlockHashes::path_for(path);
+    let hashes = try!(BlockHashes::compute(os_path(path), block_size)
+                      .map_err(|e| DownloadError::io(e, &**path)));
+    // Readable by whoever can read the file it describes
+    let permissions = try!(fs::metadata(os_path(path)).map_err(|e| DownloadError::io(e, &**path)))
+        .permissions();
+    try!(hashes.write(os_path(&manifest), permissions).map_err(|e| DownloadError::io(e, &*manifest)));
+    info!("Wrote {} block hashes (root {}) to {}", hashes.blocks.len(), hashes.root(), manifest);
+    Ok(())
+}
+
+/// Give a completed file target, and its block manifest if one was
+/// written, to `owner`
+fn chown_target(target: &DownloadTarget, owner: &Owner, block_manifest: bool)
+                -> Result<(), DownloadError>
+{
+    let path = match *target {
+        DownloadTarget::File(ref path) => path,
+        _ => return Ok(()),
+    };
+    try!(owner.chown(&os_path(path)).map_err(|e| DownloadError::io(e, &**path)));
+    if block_manifest {
+        let manifest = BlockHashes::path_for(path);
+        try!(owner.chown(&os_path(&manifest)).map_err(|e| DownloadError::io(e, &*manifest)));
+    }
+    Ok(())
+}
+
+/// Open a custom scheme's file at `offset`, over `connection` if the
+/// handler keeps one
+fn open_custom(handler: &dyn SchemeHandler, connection: &mut Option<Box<dyn Connection>>,
+               url: &Url, offset: u64)
+               -> Result<(Box<dyn io::Read + Send>, Option<u64>), DownloadError>
+{
+    let opened = match *connection {
+        Some(ref mut connection) => connection.open(url, offset),
+        None => handler.open(url, offset),
+    };
+    opened.map_err(|e| DownloadError::from(e).with_url(url.as_str()))
+}
+
+/// Stream a custom scheme's file into the target from `body`, opened at
+/// `start`, up to `end` (or the end of the body if unknown), opening it
+/// again where it was interrupted if the retry policy allows. Returns the
+/// number of bytes written.
+fn stream_custom(handler: &dyn SchemeHandler, connection: &mut Option<Box<dyn Connection>>,
+                 url: &Url, target: &DownloadTarget, start: u64,
+                 end: Option<u64>, body: Box<dyn io::Read + Send>,
+                 reporter: Sender<CompletedSegment>, options: &WriteOptions, retry: &RetryPolicy)
+                 -> Result<u64, DownloadError>
+{
+    let mut body = body;
+    let mut pos = start;
+    let mut attempt = 1;
+    loop {
+        let remaining = end.map_or(0, |end| end.saturating_sub(pos));
+        // A chunk's body is cut off at its end
+        let limited: Box<dyn io::Read> = match end {
+            Some(_) => Box::new(body.take(remaining)),
+            None => body,
+        };
+        let err = match stream_body(target, pos, remaining, limited, reporter.clone(), options) {
+            Ok(written) if end.map_or(true, |end| pos + written >= end) => {
+                return Ok(pos + written - start)
+            },
+            // The body ended early
+            Ok(written) => DownloadError::incomplete(pos + written - start, remaining + pos - start)
+                .with_offset(pos + written),
+            Err(e) => e,
+        };
+        if !retry.should_retry(&err, attempt) {
+            return Err(err.with_attempt(attempt))
+        }
+        pos = err.context().offset.unwrap_or(pos);
+        warn!("Transfer interrupted at byte {} ({}), resuming", pos, err);
+        wait_before_retry(retry, &err, attempt);
+        attempt = retry.next_attempt(&err, attempt);
+        body = try!(open_custom(handler, connection, url, pos)).0;
+    }
+}
+
+/// Sleep before retrying a failed attempt, backing off or for as long as
+/// a rate limiting server asked
+fn wait_before_retry(retry: &RetryPolicy, err: &DownloadError, attempt: u32)
+{
+    let wait = retry.delay(err, attempt);
+    debug!("Waiting {:?} before retrying", wait);
+    thread::sleep(wait);
+}
+
+/// Call `f` until it succeeds or the policy gives up on its failures
+pub fn with_retries<T, F>(retry: &RetryPolicy, what: &str, mut f: F) -> Result<T, DownloadError>
+    where F: FnMut() -> Result<T, DownloadError>
+{
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(ref e) if retry.should_retry(e, attempt) => {
+                warn!("{} attempt {} failed ({}), retrying", what, attempt, e);
+                wait_before_retry(retry, e, attempt);
+                attempt = retry.next_attempt(e, attempt);
+            },
+            result => return result.map_err(|e| e.with_attempt(attempt)),
+        }
+    }
+}
+
+/// A failed request, as a read `Timeout` if it timed out waiting for the
+/// response
+fn timed_out(config: &ClientConfig, err: hyper::Error) -> DownloadError
+{
+    let read_timeout = match (&err, config.timeouts().1) {
+        (&hyper::Error::Io(ref e), Some(timeout)) if Elapsed::from_io(e).is_none() => {
+            match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Some(timeout),
+                _ => None,
+            }
+        },
+        _ => None,
+    };
+    match read_timeout {
+        Some(timeout) => DownloadError::timeout(TimeoutKind::Read, timeout),
+        None => DownloadError::from(err),
+    }
+}
+
+/// Whether the status asks the client to look elsewhere
+fn is_redirect(status: StatusCode) -> bool
+{
+    match status.to_u16() {
+        301 | 302 | 303 | 307 | 308 => true,
+        _ => false,
+    }
+}
+
+/// Returns error if request unsuccessful
+fn raise_for_status(config: &ClientConfig, mut response: Response)
+                    -> Result<Response, DownloadError>
+{
+    if response.status.is_success() {
+        debug!("Request to {} successful", response.url);
+        match ratelimit::detect(response.status, &response.headers, "") {
+            Some(ref limit) if limit.is_exhausted() => {
+                warn!("Rate limit for {} is exhausted: {}", response.url, limit)
+            },
+            Some(ref limit) => debug!("Rate limit for {}: {}", response.url, limit),
+            None => (),
+        }
+        return Ok(response)
+    }
+
+    // Capture at most the limit (plus a byte to detect truncation)
+    let (limit, save_path) = config.error_capture();
+    let limit = limit as u64;
+    let mut captured = vec![];
+    try!((&mut response).take(limit + 1).read_to_end(&mut captured));
+    let truncated = captured.len() as u64 > limit;
+
+    if let Some(path) = save_path {
+        match save_error_body(path, &captured, &mut response) {
+            Ok(_) => info!("Saved body of {} response to {}", response.status, path.display()),
+            Err(e) => warn!("Unable to save error body to {}: {}", path.display(), e),
+        }
+    }
+
+    captured.truncate(limit as usize);
+    let body = String::from_utf8_lossy(&captured).into_owned();
+    if let Some(limit) = ratelimit::detect(response.status, &response.headers, &*body) {
+        warn!("Rate limited by {}: {}", response.url, limit);
+    }
+    Err(DownloadError::Http {
+        status: response.status,
+        headers: response.headers.clone(),
+        body: body,
+        truncated: truncated,
+        context: ErrorContext { url: Some(response.url.to_string()), ..Default::default() },
+    })
+}
+
+/// Write the captured start of an error body and the rest of it to a file
+fn save_error_body(path: &Path, captured: &[u8], rest: &mut Response) -> io::Result<()>
+{
+    let mut file = try!(File::create(path));
+    try!(file.write_all(captured));
+    try!(io::copy(rest, &mut file));
+    Ok(())
+}
+
+/// Create the target and set its expected length (if applicable)
+fn set_target_len(target: &DownloadTarget, size: u64, options: &WriteOptions)
+                  -> Result<(), DownloadError>
+{
+    info!("Setting the length of target {:?} to {} bytes", target, size);
+    match *target {
+        DownloadTarget::File(ref path) => {
+            // Not truncated, so that a file already there is left as it
+            // was if its length can't be set
+            let mut open_options = OpenOptions::new();
+            open_options.write(true).create(true);
+            restrict_permissions(&mut open_options);
+            let file = try!(open_checked(path, &mut open_options, options));
+            try!(restrict_existing_permissions(&file, path));
+            Ok(try!(file.set_len(size).map_err(|e| DownloadError::io(e, &**path))))
+        },
+        DownloadTarget::StdOut => {
+            Err(DownloadError::other("Cannot take offset on stdout".to_owned()))
+        },
+        DownloadTarget::Default => {
+            Err(DownloadError::other("Default target has not been resolved".to_owned()))
+        },
+    }
+}
+
+/// Apply the final permissions and modification time to a completed
+/// target (if applicable)
+fn finalize_target(target: &DownloadTarget, options: &WriteOptions)
+                   -> Result<(), DownloadError>
+{
+    if let Some(ref journal) = options.journal {
+        try!(journal.remove());
+    }
+    match *target {
+        DownloadTarget::File(ref path) => {
+            try!(set_final_mode(path, options));
+            set_mtime(path, options)
+        },
+        _ => Ok(()),
+    }
+}
+
+fn set_mtime(path: &str, options: &WriteOptions) -> Result<(), DownloadError>
+{
+    let mtime = match options.mtime {
+        Some(mtime) => mtime,
+        None => return Ok(()),
+    };
+    let file = try!(OpenOptions::new().write(true).open(os_path(path))
+                    .map_err(|e| DownloadError::io(e, path)));
+    Ok(try!(file.set_modified(mtime).map_err(|e| DownloadError::io(e, path))))
+}
+
+#[cfg(unix)]
+fn set_final_mode(path: &str, options: &WriteOptions) -> Result<(), DownloadError>
+{
+    use std::os::unix::fs::PermissionsExt;
+    let mode = options.file_mode.unwrap_or_else(|| 0o666 & !umask());
+    debug!("setting permissions of {} to {:o}", path, mode);
+    Ok(try!(fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(|e| DownloadError::io(e, path))))
+}
+
+#[cfg(not(unix))]
+fn set_final_mode(_: &str, _: &WriteOptions) -> Result<(), DownloadError>
+{
+    Ok(())
+}
+
+/// Whether a file (or anything else) exists at `path`
+fn exists(path: &str) -> bool
+{
+    fs::symlink_metadata(os_path(path)).is_ok()
+}
+
+/// The result of a download that kept the existing file at its target
+fn kept(target: DownloadTarget, url: String, redirects: Vec<Redirect>, digests: Vec<Digest>)
+        -> DownloadResult
+{
+    DownloadResult {
+        bytes: 0,
+        url: url,
+        redirects: redirects,
+        digests: digests,
+        target: target,
+        skipped: true,
+    }
+}
+
+/// Check that a completed file target has the length of the remote file
+fn check_size(target: &DownloadTarget, size: u64) -> Result<(), DownloadError>
+{
+    let path = match *target {
+        DownloadTarget::File(ref path) => path,
+        _ => return Ok(()),
+    };
+    let len = try!(fs::metadata(os_path(path)).map_err(|e| DownloadError::io(e, &**path))).len();
+    if len != size {
+        return Err(DownloadError::incomplete(len, size))
+    }
+    Ok(())
+}
+
+/// Move a completed working target to its destination
+fn place_target(working: &DownloadTarget, destination: &DownloadTarget)
+                -> Result<(), DownloadError>
+{
+    match (working, destination) {
+        (&DownloadTarget::File(ref from), &DownloadTarget::File(ref to)) if from != to => {
+            info!("Moving {} to {}", from, to);
+            move_file(from, to).map_err(|e| DownloadError::io(e, &**to))
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Rename a file, falling back to copying it next to its destination,
+/// syncing the copy and renaming that into place when the two are on
+/// different devices (e.g. a local disk and an NFS volume)
+fn move_file(from: &str, to: &str) -> io::Result<()>
+{
+    let (from, to) = (os_path(from), os_path(to));
+    match fs::rename(&from, &to) {
+        Err(ref e) if is_cross_device(e) => (),
+        result => return result,
+    }
+    debug!("{} and {} are on different devices, copying", from.display(), to.display());
+    let (staging, mut copy) = try!(create_staging(&to, "move"));
+    // The copy takes the permissions the finalized target was given
+    let copied = File::open(&from)
+        .and_then(|mut source| io::copy(&mut source, &mut copy).and(source.metadata()))
+        .and_then(|meta| copy.set_permissions(meta.permissions()))
+        .and_then(|_| copy.sync_all())
+        .and_then(|_| fs::rename(&staging, &to));
+    if let Err(e) = copied {
+        let _ = fs::remove_file(&staging);
+        return Err(e)
+    }
+    fs::remove_file(&from)
+}
+
+#[cfg(unix)]
+fn is_cross_device(err: &io::Error) -> bool
+{
+    err.raw_os_error() == Some(libc::EXDEV)
+}
+
+/// ERROR_NOT_SAME_DEVICE
+#[cfg(windows)]
+fn is_cross_device(err: &io::Error) -> bool
+{
+    err.raw_os_error() == Some(17)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_cross_device(_: &io::Error) -> bool
+{
+    true
+}
+
+/// The process umask, read once. Where `/proc` doesn't show it, it can
+/// only be read by setting it, which would give files other threads create
+/// meanwhile the wrong mode, so `Download::new` reads it before there are
+/// any workers.
+#[cfg(unix)]
+fn umask() -> u32
+{
+    let mut umask = UMASK.lock().unwrap();
+    *umask.get_or_insert_with(|| proc_umask().unwrap_or_else(|| unsafe {
+        let mask = libc::umask(0o022);
+        libc::umask(mask);
+        mask as u32
+    }))
+}
+
+#[cfg(not(unix))]
+fn umask() -> u32
+{
+    0
+}
+
+/// The umask shown by `/proc/self/status` (Linux 4.7 and later)
+#[cfg(unix)]
+fn proc_umask() -> Option<u32>
+{
+    let mut status = String::new();
+    if File::open("/proc/self/status").and_then(|mut f| f.read_to_string(&mut status)).is_err() {
+        return None
+    }
+    status.lines()
+        .find(|line| line.starts_with("Umask:"))
+        .and_then(|line| u32::from_str_radix(line["Umask:".len()..].trim(), 8).ok())
+}
+
+/// The body of a response starting at `offset` of the file, recorded,
+/// shaped, timed or with faults injected as configured
+fn body_reader(response: Response, offset: u64, options: &WriteOptions)
+               -> Result<Box<dyn io::Read>, DownloadError>
+{
+    #[cfg(feature = "testing")]
+    let url = response.url.to_string();
+    let response: Box<dyn io::Read> = match options.cassette {
+        Some(ref cassette) => Box::new(try!(cassette.record(response, offset))),
+        None => Box::new(response),
+    };
+    let response: Box<dyn io::Read> = match options.link {
+        Some(ref link) => Box::new(ShapedReader::new(response, link.clone())),
+        None => response,
+    };
+    let timing = (options.read_timeout, options.deadline, options.speed_limit);
+    let response: Box<dyn io::Read> = match timing {
+        (None, None, None) => response,
+        (read_timeout, deadline, speed_limit) => Box::new(TimeoutReader {
+            inner: response,
+            read_timeout: read_timeout,
+            deadline: deadline,
+            speed_limit: speed_limit,
+            window: (Instant::now(), 0),
+        }),
+    };
+    #[cfg(feature = "testing")]
+    let response: Box<dyn io::Read> = match options.faults {
+        Some(ref faults) => Box::new(FaultyReader::new(response, url, offset, faults.clone())),
+        None => response,
+    };
+    Ok(response)
+}
+
+/// Stream the response to the download target at a given offset (if applicable)
+fn stream(
+    target: &DownloadTarget,
+    offset: u64,
+    response: Response,
+    reporter: Sender<CompletedSegment>,
+    options: &WriteOptions,
+) -> Result<u64, DownloadError>
+{
+    let size = response.headers.get::<ContentLength>().map_or(0, |len| len.0);
+    let body = try!(body_reader(response, offset, options));
+    stream_body(target, offset, size, body, reporter, options)
+}
+
+/// Stream a body of `size` bytes (0 if unknown) to the download target at
+/// a given offset
+fn stream_body(
+    target: &DownloadTarget,
+    offset: u64,
+    size: u64,
+    body: Box<dyn io::Read>,
+    reporter: Sender<CompletedSegment>,
+    options: &WriteOptions,
+) -> Result<u64, DownloadError>
+{
+    let body = match options.hasher {
+        Some(ref hasher) => Box::new(HashingReader::new(body, hasher.clone())),
+        None => body,
+    };
+    let mut body = CountingReader { inner: body, count: 0 };
+    let result = copy_to_target(target, offset, size, &mut body, reporter, options);
+    // Record how far the transfer got, so it can be resumed from there
+    result.map_err(|e| e.with_offset(offset + body.count))
+}
+
+/// Copy a response body to the download target at a given offset
+fn copy_to_target(
+    target: &DownloadTarget,
+    offset: u64,
+    size: u64,
+    response: &mut dyn io::Read,
+    reporter: Sender<CompletedSegment>,
+    options: &WriteOptions,
+) -> Result<u64, DownloadError>
+{
+    Ok(match *target {
+        DownloadTarget::File(ref path) => {
+            let mut file = try!(open_file(path, options));
+            try!(file.seek(io::SeekFrom::Start(offset)).map_err(|e| DownloadError::io(e, &**path)));
+            let writer: Box<dyn Write> = match options.journal {
+                Some(ref journal) => {
+                    let writer = JournaledWriter::new(file, journal.clone(), offset);
+                    Box::new(match options.sync_interval {
+                        Some(interval) => writer.sync_every(interval),
+                        None => writer,
+                    })
+                },
+                None => Box::new(file),
+            };
+            let writer: Box<dyn Write> = match options.write_batch {
+                Some(batch) => Box::new(BufWriter::with_capacity(batch, writer)),
+                None => writer,
+            };
+            let mut writer = TargetWriter { inner: writer, failed: false };
+            let copied = copy_with_segments(size, response, &mut writer, reporter,
+                                            options.segment_md5,
+                                            options.limiter.as_ref().map(|l| &**l));
+            let written = match copied {
+                Ok(written) => written,
+                // Writing to the file failed (e.g. the disk is full), not the transfer
+                Err(e) if writer.failed => return Err(DownloadError::io(e, &**path)),
+                Err(e) => return Err(e.into()),
+            };
+            try!(writer.flush().map_err(|e| DownloadError::io(e, &**path)));
+            written
+        },
+        DownloadTarget::StdOut => {
+            try!(copy_with_segments(size, response, &mut io::stdout(), reporter,
+                                    options.segment_md5, options.limiter.as_ref().map(|l| &**l)))
+        },
+        DownloadTarget::Default => {
+            return Err(DownloadError::other("Default target has not been resolved".to_owned()))
+        },
+    })
+}
+
+/// Notes whether writing to the target failed, to tell local file errors
+/// apart from network errors in the same copy
+struct TargetWriter<W> {
+    inner: W,
+    failed: bool,
+}
+
+impl<W: Write> Write for TargetWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        let result = self.inner.write(buf);
+        self.failed |= result.is_err();
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        let result = self.inner.flush();
+        self.failed |= result.is_err();
+        result
+    }
+}
+
+/// Reports reads that time out, run past the deadline or fall below the
+/// speed limit as `Elapsed`
+struct TimeoutReader<R> {
+    inner: R,
+    read_timeout: Option<Duration>,
+    deadline: Option<(Instant, Duration)>,
+    speed_limit: Option<(u64, Duration)>,
+    /// When the current speed limit window started, and the bytes read since
+    window: (Instant, u64),
+}
+
+impl<R: io::Read> io::Read for TimeoutReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        if let Some((deadline, max_time)) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(Elapsed::new(TimeoutKind::Total, max_time).into_io())
+            }
+        }
+        let n = try!(self.inner.read(buf).map_err(|e| match (e.kind(), self.read_timeout) {
+            // Sockets report a read timeout as either, depending on the OS
+            (io::ErrorKind::WouldBlock, Some(timeout))
+            | (io::ErrorKind::TimedOut, Some(timeout)) => {
+                Elapsed::new(TimeoutKind::Read, timeout).into_io()
+            },
+            _ => e,
+        }));
+        if let Some((rate, time)) = self.speed_limit {
+            let (start, bytes) = self.window;
+            let bytes = bytes + n as u64;
+            let elapsed = start.elapsed();
+            if elapsed < time {
+                self.window = (start, bytes);
+            } else if (bytes as f64) < rate as f64 * as_secs_f64(elapsed) {
+                return Err(Elapsed::new(TimeoutKind::LowSpeed(rate), time).into_io())
+            } else {
+                self.window = (Instant::now(), 0);
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Counts the bytes read through it
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: io::Read> io::Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let n = try!(self.inner.read(buf));
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Vendored io::copy() to report progress because <Write>.broadcast() was
+/// deprecated in 1.6
+pub fn copy_with_reporter<R: ?Sized, W: ?Sized>(
+    size: u64,
+    reader: &mut R,
+    writer: &mut W,
+    reporter: Sender<CompletedSegment>,
+) -> io::Result<u64>
+    where R: io::Read, W: io::Write
+{
+    copy_with_segments(size, reader, writer, reporter, true, None)
+}
+
+/// Like copy_with_reporter(), hashing each segment only if `md5` is set
+/// and taking each read from `limiter` (if any)
+pub fn copy_with_segments<R: ?Sized, W: ?Sized>(
+    size: u64,
+    reader: &mut R,
+    writer: &mut W,
+    reporter: Sender<CompletedSegment>,
+    md5: bool,
+    limiter: Option<&RateLimiter>,
+) -> io::Result<u64>
+    where R: io::Read, W: io::Write
+{
+    debug!("Stream is {} bytes", size);
+
+    let mut buf = vec![0; DEFAULT_BUFF_SIZE];
+    let mut written = 0;
+
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => return Ok(written),
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        } as u64;
+        if let Some(limiter) = limiter {
+            limiter.take(len as usize);
+        }
+
+        try!(writer.write_all(&buf[..len as usize]));
+        written += len;
+
+        let _ = reporter.send(CompletedSegment {
+            start: written,
+            len: len,
+            md5: if md5 { segment_hash(&buf[..len as usize]) } else { String::new() },
+        });
+    }
+}
+
+/// Hex md5 of a completed segment
+fn segment_hash(segment: &[u8]) -> String
+{
+    format!("{:x}", md5::compute(segment))
+}
+
+/// The size of the remote file from a HEAD response, or from the
+/// Content-Range of a response to a range request
+pub fn remote_size(response: &Metadata) -> Result<u64, DownloadError>
+{
+    if response.status != StatusCode::PartialContent {
+        return parse_content_length(response)
+    }
+    match response.headers.get::<ContentRange>() {
+        Some(&ContentRange(ContentRangeSpec::Bytes { instance_length: Some(len), .. })) => Ok(len),
+        _ => Err(DownloadError::missing_content_length()),
+    }
+}
+
+/// Reads the file size from the Content-Length if possible
+fn parse_content_length(response: &Metadata) -> Result<u64, DownloadError>
+{
+    match response.headers.get::<ContentLength>() {
+        Some(size) => Ok(size.0),
+        None => Err(DownloadError::missing_content_length()),
+    }
+}
+
+/// Parse the file name from the response, or use the last segment of the
+/// url's path, or its host (`index.html` if it has neither)
+fn default_file_name(response: &Metadata) -> String
+{
+    let from_header = match parse_file_name(response) {
+        Ok(name) => Some(name),
+        Err(e) => {
+            debug!("no filename ({}), naming the download after its url", e);
+            None
+        },
+    };
+    let from_path = response.url.path_segments()
+        .and_then(|segments| segments.filter(|s| !s.is_empty()).last())
+        .map(|segment| segment.to_owned());
+
+    // A name like `..` or `/` has no file name to take
+    let file_name = from_header.into_iter().chain(from_path)
+        .filter_map(|name| Path::new(&*name).file_name().map(|n| n.to_string_lossy().into_owned()))
+        .next();
+    file_name
+        .or_else(|| response.url.host_str().map(|host| host.to_owned()))
+        .unwrap_or_else(|| "index.html".to_owned())
+}
+
+/// Create (or truncate) a file for writing, readable only by the owner
+/// until the download is finalized
+fn create_file(path: &str, options: &WriteOptions) -> Result<File, DownloadError>
+{
+    debug!("opening {}", path);
+    let mut open_options = OpenOptions::new();
+    open_options.write(true).create(true).truncate(true);
+    restrict_permissions(&mut open_options);
+
+    let f = try!(open_checked(path, &mut open_options, options));
+    try!(restrict_existing_permissions(&f, path));
+    Ok(f)
+}
+
+#[cfg(unix)]
+fn restrict_permissions(open_options: &mut OpenOptions)
+{
+    use std::os::unix::fs::OpenOptionsExt;
+    open_options.mode(PARTIAL_FILE_MODE);
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_: &mut OpenOptions)
+{
+}
+
+/// The mode given at creation doesn't apply to files that already exist
+#[cfg(unix)]
+fn restrict_existing_permissions(file: &File, path: &str) -> Result<(), DownloadError>
+{
+    use std::os::unix::fs::PermissionsExt;
+    Ok(try!(file.set_permissions(fs::Permissions::from_mode(PARTIAL_FILE_MODE))
+            .map_err(|e| DownloadError::io(e, path))))
+}
+
+#[cfg(not(unix))]
+fn restrict_existing_permissions(_: &File, _: &str) -> Result<(), DownloadError>
+{
+    Ok(())
+}
+
+/// Open an existing file for writing without truncating it
+fn open_file(path: &str, options: &WriteOptions) -> Result<File, DownloadError>
+{
+    open_checked(path, OpenOptions::new().write(true), options)
+}
+
+/// Open `path` with `open_options`, refusing (unless symlinks are trusted)
+/// to write through a symlink that leads out of the directory containing
+/// it, which untrusted processes may have planted there. The open itself
+/// doesn't follow a symlink, so one can't be swapped in after the check.
+fn open_checked(path: &str, open_options: &mut OpenOptions, options: &WriteOptions)
+                -> Result<File, DownloadError>
+{
+    let os = os_path(path);
+    if options.trust_symlinks {
+        return open_options.open(&os).map_err(|e| DownloadError::io(e, path))
+    }
+    try!(check_parents(&os));
+    no_follow(open_options);
+    // Elsewhere the open would follow a symlink, so it is looked for first
+    if cfg!(not(unix)) && is_symlink(&os) {
+        let resolved = try!(check_symlink(&os));
+        return open_options.open(&resolved).map_err(|e| DownloadError::io(e, path))
+    }
+    match open_options.open(&os) {
+        Err(ref e) if is_symlink_error(e) => {
+            // Write to where a symlink staying in its directory led when
+            // checked, still not following any symlink found there now
+            let resolved = try!(check_symlink(&os));
+            open_options.open(&resolved).map_err(|e| DownloadError::io(e, path))
+        },
+        result => result.map_err(|e| DownloadError::io(e, path)),
+    }
+}
+
+/// Whether an open with `O_NOFOLLOW` failed because the path is a symlink
+#[cfg(unix)]
+fn is_symlink_error(err: &io::Error) -> bool
+{
+    err.raw_os_error() == Some(libc::ELOOP)
+}
+
+#[cfg(not(unix))]
+fn is_symlink_error(_: &io::Error) -> bool
+{
+    false
+}
+
+fn is_symlink(path: &Path) -> bool
+{
+    fs::symlink_metadata(path).map(|meta| meta.file_type().is_symlink()).unwrap_or(false)
+}
+
+/// Refuse to write below a directory symlink leading out of the directory
+/// containing it
+fn check_parents(path: &Path) -> Result<(), DownloadError>
+{
+    for dir in path.ancestors().skip(1).take_while(|dir| !dir.as_os_str().is_empty()) {
+        if is_symlink(dir) {
+            try!(check_symlink(dir));
+        }
+    }
+    Ok(())
+}
+
+/// Where the symlink at `path` leads, refusing one that leads out of the
+/// directory containing it or nowhere
+fn check_symlink(path: &Path) -> Result<PathBuf, DownloadError>
+{
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+
+    let refuse = |reason: String| Err(DownloadError::refused(format!(
+        "refusing to write to {}: {} (use --trust-symlinks to override)",
+        path.display(), reason)));
+
+    let dir = try!(fs::canonicalize(dir));
+    match fs::canonicalize(path) {
+        Ok(resolved) => if resolved.starts_with(&dir) {
+            Ok(resolved)
+        } else {
+            refuse(format!("symlink leads to {}", resolved.display()))
+        },
+        Err(_) => refuse("symlink is dangling".to_owned()),
+    }
+}
 
 /// Reads the filename from the Content-Disposition if possible
-fn parse_file_name(response: &Response) -> Result<String, DownloadError>
+fn parse_file_name(response: &Metadata) -> Result<String, DownloadError>
 {
     if let Some(disposition) = response.headers.get::<ContentDisposition>() {
         let file_name_param = disposition.parameters.iter()
@@ -351,5 +3666,5 @@ fn parse_file_name(response: &Response) -> Result<String, DownloadError>
         }
     }
 
-    Err(DownloadError(format!("server did not provide a file name")))
+    Err(DownloadError::other(format!("server did not provide a file name")))
 }
@@ -1,24 +1,452 @@
+use std::error;
 use std::io;
 use std::fmt;
+use std::time::Duration;
 use hyper;
+use stats::as_secs_f64;
+use hyper::header::Headers;
+use hyper::status::StatusCode;
+
+/// Where in a download an error occurred
+#[derive(Clone,Debug,Default)]
+pub struct ErrorContext {
+    /// The url being requested
+    pub url: Option<String>,
+    /// The id of the worker downloading the segment
+    pub segment: Option<u8>,
+    /// The byte offset into the file
+    pub offset: Option<u64>,
+    /// The attempt number, starting from 1
+    pub attempt: Option<u32>,
+}
+
+impl ErrorContext {
+    fn is_empty(&self) -> bool
+    {
+        self.url.is_none() && self.segment.is_none()
+            && self.offset.is_none() && self.attempt.is_none()
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(ref url) = self.url {
+            parts.push(format!("url {}", url));
+        }
+        if let Some(segment) = self.segment {
+            parts.push(format!("segment {}", segment));
+        }
+        if let Some(offset) = self.offset {
+            parts.push(format!("offset {}", offset));
+        }
+        if let Some(attempt) = self.attempt {
+            parts.push(format!("attempt {}", attempt));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Which deadline a request or download missed
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum TimeoutKind {
+    /// Opening a connection
+    Connect,
+    /// Waiting for the next bytes of a response
+    Read,
+    /// Completing the whole download
+    Total,
+    /// Receiving at least this many bytes per second
+    LowSpeed(u64),
+}
+
+/// A missed deadline, carried inside an `io::Error` until it becomes a
+/// `DownloadError::Timeout`
+#[derive(Clone,Copy,Debug)]
+pub struct Elapsed {
+    pub kind: TimeoutKind,
+    pub after: Duration,
+}
+
+impl Elapsed {
+    pub fn new(kind: TimeoutKind, after: Duration) -> Elapsed
+    {
+        Elapsed { kind: kind, after: after }
+    }
+
+    /// An `io::Error` carrying this deadline
+    pub fn into_io(self) -> io::Error
+    {
+        io::Error::new(io::ErrorKind::TimedOut, self)
+    }
+
+    /// The deadline carried by `err`, if any
+    pub fn from_io(err: &io::Error) -> Option<Elapsed>
+    {
+        err.get_ref().and_then(|inner| inner.downcast_ref::<Elapsed>()).cloned()
+    }
+}
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = as_secs_f64(self.after);
+        match self.kind {
+            TimeoutKind::Connect => write!(f, "timed out connecting after {}s", secs),
+            TimeoutKind::Read => write!(f, "no data received for {}s", secs),
+            TimeoutKind::Total => write!(f, "did not complete within {}s", secs),
+            TimeoutKind::LowSpeed(rate) => {
+                write!(f, "received less than {} bytes/s for {}s", rate, secs)
+            },
+        }
+    }
+}
+
+impl error::Error for Elapsed {}
 
 #[derive(Debug)]
-pub struct DownloadError(pub String);
+pub enum DownloadError {
+    /// The server responded with an unsuccessful status
+    Http {
+        status: StatusCode,
+        headers: Headers,
+        /// The start of the response body (see `truncated`)
+        body: String,
+        /// Whether the body was longer than the capture limit
+        truncated: bool,
+        context: ErrorContext,
+    },
+    /// Connecting to the server or exchanging a request failed
+    Network {
+        source: hyper::Error,
+        context: ErrorContext,
+    },
+    /// Reading a response body, or reading or writing a file, failed
+    Io {
+        source: io::Error,
+        /// The file being accessed, if any
+        path: Option<String>,
+        context: ErrorContext,
+    },
+    /// The url could not be parsed
+    InvalidUrl {
+        url: String,
+        reason: String,
+        context: ErrorContext,
+    },
+    /// A request or write was refused by policy (e.g. https only)
+    Refused {
+        reason: String,
+        context: ErrorContext,
+    },
+    /// Redirects were followed this many times without reaching the file
+    TooManyRedirects {
+        limit: usize,
+        /// Every url requested, in order
+        chain: Vec<String>,
+        context: ErrorContext,
+    },
+    /// A redirect led back to a url already requested (with the same
+    /// cookies), so following it would never end
+    RedirectLoop {
+        /// Every url requested, in order, ending with the repeated one
+        chain: Vec<String>,
+        context: ErrorContext,
+    },
+    /// A connect, read or total deadline was missed
+    Timeout {
+        elapsed: Elapsed,
+        context: ErrorContext,
+    },
+    /// The response did not have a Content-Length
+    MissingContentLength {
+        context: ErrorContext,
+    },
+    /// The server answered a range request with this status instead of
+    /// 206 Partial Content
+    UnsupportedRange {
+        status: StatusCode,
+        context: ErrorContext,
+    },
+    /// A response body ended early
+    Incomplete {
+        received: u64,
+        expected: u64,
+        context: ErrorContext,
+    },
+    /// The downloaded bytes do not match their expected hashes
+    Checksum {
+        message: String,
+        context: ErrorContext,
+    },
+    /// The downloaded file does not have the expected digest
+    ChecksumMismatch {
+        /// The hash algorithm, e.g. `sha256`
+        algorithm: &'static str,
+        expected: String,
+        actual: String,
+        context: ErrorContext,
+    },
+    /// The downloaded file doesn't have the digest the server advertised
+    DigestMismatch {
+        /// The hash algorithm, e.g. `sha256`
+        algorithm: &'static str,
+        advertised: String,
+        actual: String,
+        context: ErrorContext,
+    },
+    /// Any other failure
+    Other {
+        message: String,
+        context: ErrorContext,
+    },
+}
+
+impl DownloadError {
+    /// An error without a more specific variant
+    pub fn other<S: Into<String>>(message: S) -> DownloadError
+    {
+        DownloadError::Other { message: message.into(), context: ErrorContext::default() }
+    }
+
+    /// A failure accessing the file at `path`
+    pub fn io<S: Into<String>>(source: io::Error, path: S) -> DownloadError
+    {
+        DownloadError::Io {
+            source: source,
+            path: Some(path.into()),
+            context: ErrorContext::default(),
+        }
+    }
+
+    pub fn invalid_url<S: Into<String>, T: Into<String>>(url: S, reason: T) -> DownloadError
+    {
+        DownloadError::InvalidUrl {
+            url: url.into(),
+            reason: reason.into(),
+            context: ErrorContext::default(),
+        }
+    }
+
+    pub fn refused<S: Into<String>>(reason: S) -> DownloadError
+    {
+        DownloadError::Refused { reason: reason.into(), context: ErrorContext::default() }
+    }
+
+    pub fn too_many_redirects(limit: usize, chain: Vec<String>) -> DownloadError
+    {
+        DownloadError::TooManyRedirects {
+            limit: limit,
+            chain: chain,
+            context: ErrorContext::default(),
+        }
+    }
+
+    pub fn redirect_loop(chain: Vec<String>) -> DownloadError
+    {
+        DownloadError::RedirectLoop { chain: chain, context: ErrorContext::default() }
+    }
+
+    pub fn timeout(kind: TimeoutKind, after: Duration) -> DownloadError
+    {
+        DownloadError::Timeout { elapsed: Elapsed::new(kind, after), context: ErrorContext::default() }
+    }
+
+    pub fn missing_content_length() -> DownloadError
+    {
+        DownloadError::MissingContentLength { context: ErrorContext::default() }
+    }
+
+    pub fn unsupported_range(status: StatusCode) -> DownloadError
+    {
+        DownloadError::UnsupportedRange { status: status, context: ErrorContext::default() }
+    }
+
+    pub fn incomplete(received: u64, expected: u64) -> DownloadError
+    {
+        DownloadError::Incomplete {
+            received: received,
+            expected: expected,
+            context: ErrorContext::default(),
+        }
+    }
+
+    pub fn checksum<S: Into<String>>(message: S) -> DownloadError
+    {
+        DownloadError::Checksum { message: message.into(), context: ErrorContext::default() }
+    }
+
+    pub fn checksum_mismatch(algorithm: &'static str, expected: String, actual: String)
+                             -> DownloadError
+    {
+        DownloadError::ChecksumMismatch {
+            algorithm: algorithm,
+            expected: expected,
+            actual: actual,
+            context: ErrorContext::default(),
+        }
+    }
+
+    pub fn digest_mismatch(algorithm: &'static str, advertised: String, actual: String)
+                           -> DownloadError
+    {
+        DownloadError::DigestMismatch {
+            algorithm: algorithm,
+            advertised: advertised,
+            actual: actual,
+            context: ErrorContext::default(),
+        }
+    }
+
+    pub fn context(&self) -> &ErrorContext
+    {
+        match *self {
+            DownloadError::Http { ref context, .. }
+            | DownloadError::Network { ref context, .. }
+            | DownloadError::Io { ref context, .. }
+            | DownloadError::InvalidUrl { ref context, .. }
+            | DownloadError::Refused { ref context, .. }
+            | DownloadError::TooManyRedirects { ref context, .. }
+            | DownloadError::RedirectLoop { ref context, .. }
+            | DownloadError::Timeout { ref context, .. }
+            | DownloadError::MissingContentLength { ref context, .. }
+            | DownloadError::UnsupportedRange { ref context, .. }
+            | DownloadError::Incomplete { ref context, .. }
+            | DownloadError::Checksum { ref context, .. }
+            | DownloadError::ChecksumMismatch { ref context, .. }
+            | DownloadError::DigestMismatch { ref context, .. }
+            | DownloadError::Other { ref context, .. } => context,
+        }
+    }
+
+    fn context_mut(&mut self) -> &mut ErrorContext
+    {
+        match *self {
+            DownloadError::Http { ref mut context, .. }
+            | DownloadError::Network { ref mut context, .. }
+            | DownloadError::Io { ref mut context, .. }
+            | DownloadError::InvalidUrl { ref mut context, .. }
+            | DownloadError::Refused { ref mut context, .. }
+            | DownloadError::TooManyRedirects { ref mut context, .. }
+            | DownloadError::RedirectLoop { ref mut context, .. }
+            | DownloadError::Timeout { ref mut context, .. }
+            | DownloadError::MissingContentLength { ref mut context, .. }
+            | DownloadError::UnsupportedRange { ref mut context, .. }
+            | DownloadError::Incomplete { ref mut context, .. }
+            | DownloadError::Checksum { ref mut context, .. }
+            | DownloadError::ChecksumMismatch { ref mut context, .. }
+            | DownloadError::DigestMismatch { ref mut context, .. }
+            | DownloadError::Other { ref mut context, .. } => context,
+        }
+    }
+
+    // The context closest to the failure is the most specific, so the
+    // following only fill in what hasn't already been recorded
+
+    /// Record the url being requested, unless already set
+    pub fn with_url<S: Into<String>>(mut self, url: S) -> DownloadError
+    {
+        self.context_mut().url.get_or_insert(url.into());
+        self
+    }
+
+    /// Record the segment being downloaded, unless already set
+    pub fn with_segment(mut self, segment: u8) -> DownloadError
+    {
+        self.context_mut().segment.get_or_insert(segment);
+        self
+    }
+
+    /// Record the byte offset into the file, unless already set
+    pub fn with_offset(mut self, offset: u64) -> DownloadError
+    {
+        self.context_mut().offset.get_or_insert(offset);
+        self
+    }
+
+    /// Record the attempt number, unless already set
+    pub fn with_attempt(mut self, attempt: u32) -> DownloadError
+    {
+        self.context_mut().attempt.get_or_insert(attempt);
+        self
+    }
+}
 
 impl fmt::Display for DownloadError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+        try!(match *self {
+            DownloadError::Http { ref status, ref body, truncated, .. } => {
+                write!(f, "{:}: {}{}", status, body, if truncated { "..." } else { "" })
+            },
+            DownloadError::Network { ref source, .. } => write!(f, "{}", source),
+            DownloadError::Io { ref source, path: Some(ref path), .. } => {
+                write!(f, "{}: {}", path, source)
+            },
+            DownloadError::Io { ref source, path: None, .. } => write!(f, "{}", source),
+            DownloadError::InvalidUrl { ref url, ref reason, .. } => {
+                write!(f, "invalid url {}: {}", url, reason)
+            },
+            DownloadError::Refused { ref reason, .. } => write!(f, "{}", reason),
+            DownloadError::TooManyRedirects { limit, ref chain, .. } => {
+                write!(f, "stopped after {} redirects: {}", limit, chain.join(" -> "))
+            },
+            DownloadError::RedirectLoop { ref chain, .. } => {
+                write!(f, "redirect loop: {}", chain.join(" -> "))
+            },
+            DownloadError::Timeout { ref elapsed, .. } => write!(f, "{}", elapsed),
+            DownloadError::MissingContentLength { .. } => {
+                write!(f, "server did not provide a content length")
+            },
+            DownloadError::UnsupportedRange { ref status, .. } => {
+                write!(f, "server does not support range requests ({})", status)
+            },
+            DownloadError::Incomplete { received, expected, .. } => {
+                write!(f, "received {} of {} bytes", received, expected)
+            },
+            DownloadError::Checksum { ref message, .. } => write!(f, "{}", message),
+            DownloadError::ChecksumMismatch { algorithm, ref expected, ref actual, .. } => {
+                write!(f, "{} is {}, expected {}", algorithm, actual, expected)
+            },
+            DownloadError::DigestMismatch { algorithm, ref advertised, ref actual, .. } => {
+                write!(f, "{} is {}, but the server advertised {}", algorithm, actual, advertised)
+            },
+            DownloadError::Other { ref message, .. } => write!(f, "{}", message),
+        });
+        let context = self.context();
+        if context.is_empty() {
+            Ok(())
+        } else {
+            write!(f, " ({})", context)
+        }
+    }
+}
+
+impl error::Error for DownloadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)>
+    {
+        match *self {
+            DownloadError::Network { ref source, .. } => Some(source),
+            DownloadError::Io { ref source, .. } => Some(source),
+            _ => None,
+        }
     }
 }
 
 impl From<io::Error> for DownloadError {
     fn from(err: io::Error) -> DownloadError {
-        DownloadError(err.to_string())
+        if let Some(elapsed) = Elapsed::from_io(&err) {
+            return DownloadError::timeout(elapsed.kind, elapsed.after)
+        }
+        DownloadError::Io { source: err, path: None, context: ErrorContext::default() }
     }
 }
 
 impl From<hyper::Error> for DownloadError {
     fn from(err: hyper::Error) -> DownloadError {
-        DownloadError(err.to_string())
+        if let hyper::Error::Io(ref e) = err {
+            if let Some(elapsed) = Elapsed::from_io(e) {
+                return DownloadError::timeout(elapsed.kind, elapsed.after)
+            }
+        }
+        DownloadError::Network { source: err, context: ErrorContext::default() }
     }
 }
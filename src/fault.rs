@@ -0,0 +1,138 @@
+//! Deterministic fault injection for testing retry and resume behavior
+//!
+//! Only compiled with the `testing` feature. A `FaultInjector` set with
+//! `Download::fault_injector` is consulted before every read of a response
+//! body and may drop the connection, delay the read or corrupt the data.
+//! Faults are chosen by byte offset, so a test sees the same failures on
+//! every run.
+
+use std::io::{
+    self,
+    Read,
+};
+use std::sync::Arc;
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+use std::thread;
+use std::time::Duration;
+
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum Fault {
+    /// Read normally
+    None,
+    /// Fail the read as if the connection was reset
+    Drop,
+    /// Wait before reading
+    Delay(Duration),
+    /// Flip the bits of the byte at this offset if the read includes it
+    Corrupt(u64),
+}
+
+pub trait FaultInjector: Send + Sync {
+    /// The fault to apply to a read of up to `len` bytes at `offset` of the
+    /// file downloaded from `url`
+    fn on_read(&self, url: &str, offset: u64, len: usize) -> Fault;
+}
+
+/// Drops the connection on the first read starting at or after `offset`
+pub struct DropAfter {
+    offset: u64,
+    fired: AtomicBool,
+}
+
+impl DropAfter {
+    pub fn new(offset: u64) -> DropAfter
+    {
+        DropAfter { offset: offset, fired: AtomicBool::new(false) }
+    }
+}
+
+impl FaultInjector for DropAfter {
+    fn on_read(&self, _: &str, offset: u64, _: usize) -> Fault
+    {
+        if offset >= self.offset && !self.fired.swap(true, Ordering::SeqCst) {
+            Fault::Drop
+        } else {
+            Fault::None
+        }
+    }
+}
+
+/// Delays every read
+pub struct DelayReads(pub Duration);
+
+impl FaultInjector for DelayReads {
+    fn on_read(&self, _: &str, _: u64, _: usize) -> Fault
+    {
+        Fault::Delay(self.0)
+    }
+}
+
+/// Corrupts the byte at `offset` the first time it is read
+pub struct CorruptAt {
+    offset: u64,
+    fired: AtomicBool,
+}
+
+impl CorruptAt {
+    pub fn new(offset: u64) -> CorruptAt
+    {
+        CorruptAt { offset: offset, fired: AtomicBool::new(false) }
+    }
+}
+
+impl FaultInjector for CorruptAt {
+    fn on_read(&self, _: &str, offset: u64, len: usize) -> Fault
+    {
+        let includes = offset <= self.offset && self.offset < offset + len as u64;
+        if includes && !self.fired.swap(true, Ordering::SeqCst) {
+            Fault::Corrupt(self.offset)
+        } else {
+            Fault::None
+        }
+    }
+}
+
+/// Wraps a response body, applying the injector's faults
+pub struct FaultyReader<R> {
+    inner: R,
+    url: String,
+    offset: u64,
+    injector: Arc<dyn FaultInjector>,
+}
+
+impl<R: Read> FaultyReader<R> {
+    pub fn new(inner: R, url: String, offset: u64, injector: Arc<dyn FaultInjector>)
+               -> FaultyReader<R>
+    {
+        FaultyReader { inner: inner, url: url, offset: offset, injector: injector }
+    }
+}
+
+impl<R: Read> Read for FaultyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let n = match self.injector.on_read(&*self.url, self.offset, buf.len()) {
+            Fault::None => try!(self.inner.read(buf)),
+            Fault::Drop => return Err(io::Error::new(io::ErrorKind::ConnectionReset,
+                                                     "connection dropped by fault injector")),
+            Fault::Delay(delay) => {
+                thread::sleep(delay);
+                try!(self.inner.read(buf))
+            },
+            Fault::Corrupt(at) => {
+                let n = try!(self.inner.read(buf));
+                if at >= self.offset && at < self.offset + n as u64 {
+                    let i = (at - self.offset) as usize;
+                    buf[i] = !buf[i];
+                }
+                n
+            },
+        };
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
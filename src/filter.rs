@@ -0,0 +1,206 @@
+//! Glob filters selecting which files bulk operations act on
+//!
+//! Patterns match paths relative to the directory, prefix or manifest
+//! being processed. `*` matches within a path segment, `**` matches across
+//! segments and `?` matches a single character. A pattern without a `/`
+//! matches the file name alone, so `*.parquet` selects parquet files at
+//! any depth.
+
+use std::str::FromStr;
+
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct Glob {
+    pattern: String,
+}
+
+impl FromStr for Glob {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Glob, String>
+    {
+        if s.is_empty() {
+            return Err("glob pattern is empty".to_owned())
+        }
+        Ok(Glob { pattern: s.trim_start_matches('/').to_owned() })
+    }
+}
+
+impl Glob {
+    /// Whether the `/` separated relative `path` matches
+    pub fn matches(&self, path: &str) -> bool
+    {
+        let path = path.trim_start_matches('/');
+        let subject = if self.pattern.contains('/') {
+            path
+        } else {
+            path.rsplit('/').next().unwrap_or(path)
+        };
+        let pattern: Vec<char> = self.pattern.chars().collect();
+        let subject: Vec<char> = subject.chars().collect();
+        glob_match(&pattern, &subject)
+    }
+}
+
+fn glob_match(pattern: &[char], subject: &[char]) -> bool
+{
+    // Whether the rest matches from each pair of positions is remembered,
+    // as patterns with many stars would otherwise take exponential time
+    let mut memo = vec![None; (pattern.len() + 1) * (subject.len() + 1)];
+    match_from(pattern, subject, 0, 0, &mut memo)
+}
+
+/// Whether `pattern` from index `p` matches `subject` from index `s`
+fn match_from(pattern: &[char], subject: &[char], p: usize, s: usize,
+              memo: &mut [Option<bool>]) -> bool
+{
+    let key = p * (subject.len() + 1) + s;
+    if let Some(matched) = memo[key] {
+        return matched
+    }
+    let matched = match_step(pattern, subject, p, s, memo);
+    memo[key] = Some(matched);
+    matched
+}
+
+fn match_step(pattern: &[char], subject: &[char], p: usize, s: usize,
+              memo: &mut [Option<bool>]) -> bool
+{
+    match pattern.get(p) {
+        None => s == subject.len(),
+        Some(&'*') if pattern.get(p + 1) == Some(&'*') => {
+            // `**/` may also match no directories at all
+            let rest = p + 2;
+            if pattern.get(rest) == Some(&'/') && match_from(pattern, subject, rest + 1, s, memo) {
+                return true
+            }
+            (s..subject.len() + 1).any(|i| match_from(pattern, subject, rest, i, memo))
+        },
+        Some(&'*') => {
+            for i in s..subject.len() + 1 {
+                if match_from(pattern, subject, p + 1, i, memo) {
+                    return true
+                }
+                if subject.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        },
+        Some(&'?') => match subject.get(s) {
+            Some(&c) if c != '/' => match_from(pattern, subject, p + 1, s + 1, memo),
+            _ => false,
+        },
+        Some(&c) => match subject.get(s) {
+            Some(&x) if x == c => match_from(pattern, subject, p + 1, s + 1, memo),
+            _ => false,
+        },
+    }
+}
+
+#[derive(Clone,Debug,Default)]
+pub struct Filter {
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+}
+
+impl Filter {
+
+    pub fn new() -> Filter
+    {
+        Filter::default()
+    }
+
+    /// Only select paths matching one of the included patterns
+    pub fn include(mut self, glob: Glob) -> Filter
+    {
+        self.include.push(glob);
+        self
+    }
+
+    /// Never select paths matching this pattern
+    pub fn exclude(mut self, glob: Glob) -> Filter
+    {
+        self.exclude.push(glob);
+        self
+    }
+
+    /// Whether `path` is selected: it matches an included pattern (if any
+    /// were given) and no excluded pattern
+    pub fn allows(&self, path: &str) -> bool
+    {
+        let included = self.include.is_empty() || self.include.iter().any(|g| g.matches(path));
+        included && !self.exclude.iter().any(|g| g.matches(path))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Filter,
+        Glob,
+    };
+
+    fn glob(pattern: &str) -> Glob
+    {
+        pattern.parse().unwrap()
+    }
+
+    #[test]
+    fn star_matches_within_a_segment()
+    {
+        assert!(glob("data/*.csv").matches("data/a.csv"));
+        assert!(glob("data/*.csv").matches("/data/.csv"));
+        assert!(!glob("data/*.csv").matches("data/sub/a.csv"));
+        assert!(!glob("data/*.csv").matches("data/a.csv.gz"));
+    }
+
+    #[test]
+    fn double_star_matches_across_segments()
+    {
+        assert!(glob("data/**/*.csv").matches("data/a/b/c.csv"));
+        // `**/` may match no directories at all
+        assert!(glob("data/**/*.csv").matches("data/c.csv"));
+        assert!(glob("**").matches("a/b/c"));
+        assert!(!glob("data/**/*.csv").matches("other/c.csv"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character()
+    {
+        assert!(glob("part-?.bin").matches("part-1.bin"));
+        assert!(!glob("part-?.bin").matches("part-10.bin"));
+        assert!(!glob("a?b").matches("a/b"));
+    }
+
+    #[test]
+    fn patterns_without_a_slash_match_the_name()
+    {
+        assert!(glob("*.parquet").matches("x/y/z.parquet"));
+        assert!(glob("z.parquet").matches("z.parquet"));
+        assert!(!glob("*.parquet").matches("x.parquet/z.csv"));
+        assert!("".parse::<Glob>().is_err());
+    }
+
+    #[test]
+    fn many_stars_match_quickly()
+    {
+        // Without memoizing, each star retries every split of the subject
+        let subject: String = (0..200).map(|_| 'a').collect();
+        let pattern = format!("{}b", (0..20).map(|_| "*a").collect::<String>());
+        assert!(!glob(&*pattern).matches(&*subject));
+        assert!(glob(&*format!("{}*", pattern)).matches(&*format!("{}b", subject)));
+        assert!(!glob("**a**a**a**a**a**a**a**a**b").matches(&*subject));
+    }
+
+    #[test]
+    fn filters_include_then_exclude()
+    {
+        let filter = Filter::new().include(glob("*.csv")).exclude(glob("tmp/**"));
+        assert!(filter.allows("data/a.csv"));
+        assert!(!filter.allows("tmp/a.csv"));
+        assert!(!filter.allows("data/a.json"));
+        assert!(Filter::new().allows("anything"));
+    }
+}
@@ -0,0 +1,381 @@
+//! Frontends that draw download progress to the terminal
+//!
+//! The progress bar library is selected with cargo features: `pbr`
+//! (default) or `indicatif`. The template frontend has no dependencies
+//! and is always available.
+
+use std::env;
+use std::io::{
+    Write,
+    stderr,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+use stats::{
+    BatchStats,
+    Stats,
+    format_clock,
+};
+use template::ProgressTemplate;
+use units::{
+    Units,
+    format_bytes,
+    format_rate,
+};
+
+#[cfg(feature = "pbr")]
+use pbr;
+
+#[cfg(feature = "indicatif")]
+use indicatif;
+
+
+/// Minimum time between redraws of a templated progress line
+const REDRAW_INTERVAL_MS: u64 = 100;
+
+/// Width used for templated progress lines when $COLUMNS is not set
+const DEFAULT_WIDTH: usize = 80;
+
+/// Frames drawn in turn while the size of a transfer is unknown
+const SPINNER_FRAMES: &'static [char] = &['|', '/', '-', '\\'];
+
+pub trait Frontend {
+    /// Draw the current progress of the transfer (and its batch, if any)
+    fn update(&mut self, stats: &Stats, batch: Option<&BatchStats>);
+    /// Draw the final state of the transfer (and its batch, if any)
+    fn finish(&mut self, stats: &Stats, batch: Option<&BatchStats>);
+}
+
+#[derive(Clone,Debug)]
+pub enum FrontendKind {
+    /// Draw progress with the pbr crate
+    #[cfg(feature = "pbr")]
+    Pbr,
+    /// Draw progress with the indicatif crate
+    #[cfg(feature = "indicatif")]
+    Indicatif,
+    /// Draw progress with a custom template
+    Template(ProgressTemplate),
+}
+
+impl Default for FrontendKind {
+    #[cfg(feature = "pbr")]
+    fn default() -> FrontendKind
+    {
+        FrontendKind::Pbr
+    }
+
+    #[cfg(all(feature = "indicatif", not(feature = "pbr")))]
+    fn default() -> FrontendKind
+    {
+        FrontendKind::Indicatif
+    }
+
+    #[cfg(not(any(feature = "pbr", feature = "indicatif")))]
+    fn default() -> FrontendKind
+    {
+        FrontendKind::Template(ProgressTemplate::default())
+    }
+}
+
+impl FrontendKind {
+
+    /// Create a frontend of this kind for a transfer of `size` bytes
+    pub fn create(&self, size: u64, units: Units) -> Box<dyn Frontend>
+    {
+        match *self {
+            #[cfg(feature = "pbr")]
+            FrontendKind::Pbr => Box::new(PbrFrontend::new(size, units)),
+            #[cfg(feature = "indicatif")]
+            FrontendKind::Indicatif => Box::new(IndicatifFrontend::new(size, units)),
+            FrontendKind::Template(ref template) => {
+                Box::new(TemplateFrontend::new(template.clone(), units))
+            },
+        }
+    }
+}
+
+/// A spinner with the bytes transferred and rate, for transfers whose
+/// size isn't known until they complete
+pub struct SpinnerFrontend {
+    units: Units,
+    frame: usize,
+    last_draw: Option<Instant>,
+    batch_line: BatchLine,
+}
+
+impl SpinnerFrontend {
+    pub fn new(units: Units) -> SpinnerFrontend
+    {
+        SpinnerFrontend {
+            units: units,
+            frame: 0,
+            last_draw: None,
+            batch_line: BatchLine::new(units),
+        }
+    }
+
+    fn draw(&mut self, stats: &Stats, batch: Option<&BatchStats>, spinner: &str, end: &str)
+    {
+        if let Some(batch) = batch {
+            self.batch_line.draw(batch, true);
+        }
+        let _ = write!(stderr(), "\r{}{} {}\x1b[K{}", spinner,
+                       format_bytes(stats.done, self.units),
+                       format_rate(stats.rate(), self.units), end);
+        self.last_draw = Some(Instant::now());
+    }
+}
+
+impl Frontend for SpinnerFrontend {
+    fn update(&mut self, stats: &Stats, batch: Option<&BatchStats>)
+    {
+        let interval = Duration::from_millis(REDRAW_INTERVAL_MS);
+        if self.last_draw.map_or(true, |t| t.elapsed() >= interval) {
+            self.frame = (self.frame + 1) % SPINNER_FRAMES.len();
+            let spinner = format!("{} ", SPINNER_FRAMES[self.frame]);
+            self.draw(stats, batch, &*spinner, "");
+        }
+    }
+
+    fn finish(&mut self, stats: &Stats, batch: Option<&BatchStats>)
+    {
+        self.draw(stats, batch, "", "\n");
+    }
+}
+
+/// The size, total and rate text shared by the library frontends
+fn counter(stats: &Stats, units: Units) -> String
+{
+    let mut counter = format!("{} / {} {}",
+                              format_bytes(stats.done, units),
+                              format_bytes(stats.total, units),
+                              format_rate(stats.rate(), units));
+    if let Some(time) = stats.finishes_at() {
+        counter.push_str(&format!(" ~ finishes {}", format_clock(&time)));
+    }
+    counter
+}
+
+/// Width of the terminal from $COLUMNS, or `DEFAULT_WIDTH`
+fn terminal_width() -> usize
+{
+    env::var("COLUMNS").ok()
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(DEFAULT_WIDTH)
+}
+
+/// The aggregate progress of a batch rendered with `template`, e.g.
+/// `[2/5] 1.2 GiB / 3.4 GiB [===>    ]  35.0% ...`. Until the size of every
+/// file is known there is no total to draw a bar against, so only the
+/// bytes and rate are shown.
+fn render_batch(batch: &BatchStats, template: &ProgressTemplate, units: Units, width: usize)
+                -> String
+{
+    let prefix = format!("[{}/{}] ", batch.file, batch.files);
+    let line = match batch.total() {
+        Some(_) => template.render(&batch.stats, units, width.saturating_sub(prefix.len())),
+        None => format!("{} / ? {}", format_bytes(batch.stats.done, units),
+                        format_rate(batch.stats.rate(), units)),
+    };
+    prefix + &*line
+}
+
+/// Draws the aggregate progress of a batch on the line above the bar of
+/// the file downloading, for the frontends drawing that bar themselves
+struct BatchLine {
+    template: ProgressTemplate,
+    units: Units,
+    width: usize,
+    last_draw: Option<Instant>,
+}
+
+impl BatchLine {
+    fn new(units: Units) -> BatchLine
+    {
+        BatchLine {
+            template: ProgressTemplate::default(),
+            units: units,
+            width: terminal_width(),
+            last_draw: None,
+        }
+    }
+
+    /// Whether the line is due to be drawn again
+    fn due(&self) -> bool
+    {
+        let interval = Duration::from_millis(REDRAW_INTERVAL_MS);
+        self.last_draw.map_or(true, |t| t.elapsed() >= interval)
+    }
+
+    /// Draw the line, if due (or `always`), leaving the cursor at the
+    /// start of the line below
+    fn draw(&mut self, batch: &BatchStats, always: bool)
+    {
+        if !always && !self.due() {
+            return
+        }
+        let mut err = stderr();
+        // Move back up over the batch line after the first draw
+        if self.last_draw.is_some() {
+            let _ = write!(err, "\x1b[1A");
+        }
+        let line = render_batch(batch, &self.template, self.units, self.width);
+        let _ = write!(err, "\r{}\x1b[K\n", line);
+        self.last_draw = Some(Instant::now());
+    }
+}
+
+#[cfg(feature = "pbr")]
+pub struct PbrFrontend {
+    pb: pbr::ProgressBar<::std::io::Stderr>,
+    units: Units,
+    batch_line: BatchLine,
+}
+
+#[cfg(feature = "pbr")]
+impl PbrFrontend {
+    pub fn new(size: u64, units: Units) -> PbrFrontend
+    {
+        // pbr always formats in binary units, so the counter and
+        // speed are rendered into the message instead. Drawing to stderr
+        // keeps stdout free for a streamed download
+        let mut pb = pbr::ProgressBar::on(stderr(), size);
+        pb.set_units(pbr::Units::Bytes);
+        pb.show_counter = false;
+        pb.show_speed = false;
+        PbrFrontend { pb: pb, units: units, batch_line: BatchLine::new(units) }
+    }
+}
+
+#[cfg(feature = "pbr")]
+impl Frontend for PbrFrontend {
+    fn update(&mut self, stats: &Stats, batch: Option<&BatchStats>)
+    {
+        if let Some(batch) = batch {
+            self.batch_line.draw(batch, false);
+        }
+        self.pb.message(&format!("{} ", counter(stats, self.units)));
+        self.pb.set(stats.done);
+    }
+
+    fn finish(&mut self, stats: &Stats, batch: Option<&BatchStats>)
+    {
+        if let Some(batch) = batch {
+            self.batch_line.draw(batch, true);
+        }
+        self.update(stats, None);
+        self.pb.finish();
+    }
+}
+
+#[cfg(feature = "indicatif")]
+pub struct IndicatifFrontend {
+    pb: indicatif::ProgressBar,
+    units: Units,
+    batch_line: BatchLine,
+}
+
+#[cfg(feature = "indicatif")]
+impl IndicatifFrontend {
+    pub fn new(size: u64, units: Units) -> IndicatifFrontend
+    {
+        let pb = indicatif::ProgressBar::new(size);
+        if let Ok(style) = indicatif::ProgressStyle::default_bar()
+            .template("{msg} {wide_bar} {percent}% {eta}")
+        {
+            pb.set_style(style);
+        }
+        IndicatifFrontend { pb: pb, units: units, batch_line: BatchLine::new(units) }
+    }
+
+    /// Draw the batch line above the bar, hiding the bar meanwhile
+    fn draw_batch(&mut self, batch: &BatchStats, always: bool)
+    {
+        if always || self.batch_line.due() {
+            let batch_line = &mut self.batch_line;
+            self.pb.suspend(|| batch_line.draw(batch, true));
+        }
+    }
+}
+
+#[cfg(feature = "indicatif")]
+impl Frontend for IndicatifFrontend {
+    fn update(&mut self, stats: &Stats, batch: Option<&BatchStats>)
+    {
+        if let Some(batch) = batch {
+            self.draw_batch(batch, false);
+        }
+        self.pb.set_message(counter(stats, self.units));
+        self.pb.set_position(stats.done);
+    }
+
+    fn finish(&mut self, stats: &Stats, batch: Option<&BatchStats>)
+    {
+        if let Some(batch) = batch {
+            self.draw_batch(batch, true);
+        }
+        self.update(stats, None);
+        self.pb.finish();
+    }
+}
+
+/// Draws a line rendered from a template, with an aggregate line for the
+/// batch above it when downloading several files
+pub struct TemplateFrontend {
+    template: ProgressTemplate,
+    units: Units,
+    width: usize,
+    last_draw: Option<Instant>,
+}
+
+impl TemplateFrontend {
+    pub fn new(template: ProgressTemplate, units: Units) -> TemplateFrontend
+    {
+        TemplateFrontend {
+            template: template,
+            units: units,
+            width: terminal_width(),
+            last_draw: None,
+        }
+    }
+}
+
+impl TemplateFrontend {
+    fn draw(&mut self, stats: &Stats, batch: Option<&BatchStats>, end: &str)
+    {
+        let mut err = stderr();
+        let line = self.template.render(stats, self.units, self.width);
+        match batch {
+            Some(batch) => {
+                let total = render_batch(batch, &self.template, self.units, self.width);
+                // Move back up over the batch line after the first draw
+                if self.last_draw.is_some() {
+                    let _ = write!(err, "\x1b[1A");
+                }
+                let _ = write!(err, "\r{}\x1b[K\n{}\x1b[K{}", total, line, end);
+            },
+            None => {
+                let _ = write!(err, "\r{}{}", line, end);
+            },
+        }
+        self.last_draw = Some(Instant::now());
+    }
+}
+
+impl Frontend for TemplateFrontend {
+    fn update(&mut self, stats: &Stats, batch: Option<&BatchStats>)
+    {
+        let interval = Duration::from_millis(REDRAW_INTERVAL_MS);
+        if self.last_draw.map_or(true, |t| t.elapsed() >= interval) {
+            self.draw(stats, batch, "");
+        }
+    }
+
+    fn finish(&mut self, stats: &Stats, batch: Option<&BatchStats>)
+    {
+        self.draw(stats, batch, "\n");
+    }
+}
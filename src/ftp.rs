@@ -0,0 +1,749 @@
+//! Downloading `ftp://` urls
+//!
+//! FTP urls are downloaded by the same `Download` as http ones, through an
+//! `FtpHandler` (see `schemes`). The size of a file is asked with `SIZE`
+//! and each transfer starts at its offset with `REST`, so files are fetched
+//! in parallel segments and partial files are resumed. Each worker logs in
+//! once and fetches the chunks it takes over the same control connection,
+//! aborting the transfer of the rest of the file at the end of each. Data
+//! connections are passive (`EPSV`, or else `PASV`) unless active mode has
+//! the server connect back (`EPRT`, or else `PORT`), which can't work
+//! through a proxy.
+//!
+//! `ftps://` urls speak TLS from the start (implicit FTPS, port 990) and
+//! `ftpes://` urls upgrade the connection with `AUTH TLS` (explicit FTPS);
+//! either way data connections are protected too. Servers requiring data
+//! connections to reuse the control connection's TLS session aren't
+//! supported. Without credentials (from the url, `--user` or netrc) the
+//! server is logged into anonymously.
+
+use client::ClientConfig;
+use hyper;
+use hyper::Url;
+use hyper::net::{
+    HttpStream,
+    Openssl,
+    SslClient,
+};
+use schemes::{
+    Connection,
+    SchemeHandler,
+};
+use std::io::{
+    self,
+    Read,
+    Write,
+};
+use std::net::{
+    IpAddr,
+    SocketAddr,
+    TcpListener,
+    TcpStream,
+};
+use std::str::FromStr;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
+use urls::percent_decode;
+
+
+/// Schemes downloaded over FTP
+pub const FTP_SCHEMES: &'static [&'static str] = &["ftp", "ftps", "ftpes"];
+
+/// Password of anonymous logins, by convention an email address
+const ANONYMOUS_PASSWORD: &'static str = "sledge@";
+
+/// How long the server is waited on to connect back in active mode, unless
+/// the connect timeout is set
+const ACCEPT_TIMEOUT: u64 = 30;
+
+/// Which end opens data connections
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum FtpMode {
+    /// The client, to a port the server listens on
+    Passive,
+    /// The server, to a port the client listens on
+    Active,
+}
+
+impl Default for FtpMode {
+    fn default() -> FtpMode
+    {
+        FtpMode::Passive
+    }
+}
+
+impl FromStr for FtpMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FtpMode, String>
+    {
+        match s {
+            "passive" => Ok(FtpMode::Passive),
+            "active" => Ok(FtpMode::Active),
+            _ => Err(format!("expected 'passive' or 'active', not '{}'", s)),
+        }
+    }
+}
+
+/// Whether `scheme` is downloaded over FTP
+pub fn is_ftp_scheme(scheme: &str) -> bool
+{
+    FTP_SCHEMES.contains(&scheme)
+}
+
+/// How connections are protected with TLS
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Security {
+    Plain,
+    /// From the start
+    Implicit,
+    /// After `AUTH TLS`
+    Explicit,
+}
+
+/// Downloads `ftp://`, `ftps://` and `ftpes://` urls
+#[derive(Clone)]
+pub struct FtpHandler {
+    /// Settings connections are made with
+    config: ClientConfig,
+    /// The user and password to log in with, or None for anonymous
+    credentials: Option<(String, Option<String>)>,
+    mode: FtpMode,
+}
+
+impl FtpHandler {
+
+    pub fn new(config: &ClientConfig, credentials: Option<(String, Option<String>)>, mode: FtpMode)
+               -> FtpHandler
+    {
+        FtpHandler { config: config.clone(), credentials: credentials, mode: mode }
+    }
+
+    /// Connect and log in to the server of `url`, for binary transfers
+    fn login(&self, url: &Url) -> io::Result<Session>
+    {
+        let host = try!(url.host_str().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("{} has no host", url))
+        }));
+        let security = match url.scheme() {
+            "ftps" => Security::Implicit,
+            "ftpes" => Security::Explicit,
+            _ => Security::Plain,
+        };
+        let port = url.port().unwrap_or(if security == Security::Implicit { 990 } else { 21 });
+        let mut session = try!(Session::connect(&self.config, host, port, security));
+        let (user, password) = match self.credentials {
+            Some((ref user, ref password)) => (&**user, password.as_ref().map_or("", |p| &**p)),
+            None => ("anonymous", ANONYMOUS_PASSWORD),
+        };
+        try!(session.login(user, password));
+        try!(session.expect("TYPE I", &[200]));
+        Ok(session)
+    }
+}
+
+impl SchemeHandler for FtpHandler {
+
+    fn open(&self, url: &Url, offset: u64) -> io::Result<(Box<dyn Read + Send>, Option<u64>)>
+    {
+        let path = file_path(url);
+        let mut session = try!(self.login(url));
+        let size = try!(session.size(&*path));
+        let transfer = try!(session.retrieve(&*path, offset, self.mode));
+        Ok((Box::new(transfer), size))
+    }
+
+    fn size(&self, url: &Url) -> io::Result<Option<u64>>
+    {
+        let mut session = try!(self.login(url));
+        let size = try!(session.size(&*file_path(url)));
+        let _ = session.command("QUIT");
+        Ok(size)
+    }
+
+    fn connect(&self, _: &Url) -> Option<Box<dyn Connection>>
+    {
+        Some(Box::new(FtpConnection {
+            handler: self.clone(),
+            idle: Arc::new(Mutex::new(None)),
+            size: None,
+        }))
+    }
+}
+
+/// A session logged in once and kept between transfers, so that each only
+/// takes a `REST` and a `RETR`
+struct FtpConnection {
+    handler: FtpHandler,
+    /// The session between transfers, None while one is running or after
+    /// one failed
+    idle: Arc<Mutex<Option<Session>>>,
+    /// The size told when logging in first
+    size: Option<Option<u64>>,
+}
+
+impl FtpConnection {
+
+    /// Start a transfer over `session`, giving it back once it's done
+    fn retrieve(&self, session: Session, path: &str, offset: u64)
+                -> io::Result<Box<dyn Read + Send>>
+    {
+        let transfer = try!(session.retrieve(path, offset, self.handler.mode));
+        Ok(Box::new(Reusable { transfer: Some(transfer), idle: self.idle.clone() }))
+    }
+}
+
+impl Connection for FtpConnection {
+
+    fn open(&mut self, url: &Url, offset: u64) -> io::Result<(Box<dyn Read + Send>, Option<u64>)>
+    {
+        let path = file_path(url);
+        let idle = self.idle.lock().unwrap().take();
+        if let Some(session) = idle {
+            match self.retrieve(session, &*path, offset) {
+                Ok(transfer) => return Ok((transfer, self.size.unwrap_or(None))),
+                // e.g. the server closed an idle control connection
+                Err(e) => debug!("logging in again ({})", e),
+            }
+        }
+        let mut session = try!(self.handler.login(url));
+        if self.size.is_none() {
+            self.size = Some(try!(session.size(&*path)));
+        }
+        let transfer = try!(self.retrieve(session, &*path, offset));
+        Ok((transfer, self.size.unwrap_or(None)))
+    }
+}
+
+/// Check that the path of `url` and the credentials can be sent as the
+/// arguments of commands: a decoded CR or LF would end the command early,
+/// letting the url send commands of its own
+pub fn check_arguments(url: &Url, credentials: &Option<(String, Option<String>)>)
+                       -> Result<(), String>
+{
+    if !is_safe_argument(&*file_path(url)) {
+        return Err("the path has a line break or NUL".to_owned())
+    }
+    if let Some((ref user, ref password)) = *credentials {
+        if !is_safe_argument(user) || !password.as_ref().map_or(true, |p| is_safe_argument(p)) {
+            return Err("the credentials have a line break or NUL".to_owned())
+        }
+    }
+    Ok(())
+}
+
+/// Whether `argument` has none of the characters ending a command
+fn is_safe_argument(argument: &str) -> bool
+{
+    !argument.contains(|c| c == '\r' || c == '\n' || c == '\0')
+}
+
+/// The path of the file `url` names. As RFC 1738 has it, the path is
+/// relative to the login directory, and one starting with `%2F` absolute.
+fn file_path(url: &Url) -> String
+{
+    percent_decode(url.path().trim_start_matches('/'))
+}
+
+type TlsStream = <Openssl as SslClient>::Stream;
+
+/// A control or data connection
+enum Stream {
+    Plain(HttpStream),
+    Tls(TlsStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.read(buf),
+            Stream::Tls(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.write(buf),
+            Stream::Tls(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.flush(),
+            Stream::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+/// A logged in control connection
+struct Session {
+    control: Stream,
+    config: ClientConfig,
+    host: String,
+    /// Address of this end of the control connection, which the server
+    /// connects back to in active mode
+    local: SocketAddr,
+    security: Security,
+}
+
+impl Session {
+
+    /// Connect to the server and read its greeting, protecting the
+    /// connection as `security` says
+    fn connect(config: &ClientConfig, host: &str, port: u16, security: Security)
+               -> io::Result<Session>
+    {
+        let stream = try!(config.connect(host, port).map_err(io_error));
+        try!(set_timeouts(&stream, config));
+        let local = try!(stream.0.local_addr());
+        let control = match security {
+            Security::Implicit => Stream::Tls(try!(wrap_tls(stream, host))),
+            _ => Stream::Plain(stream),
+        };
+        let mut session = Session {
+            control: control,
+            config: config.clone(),
+            host: host.to_owned(),
+            local: local,
+            security: security,
+        };
+        let (code, text) = try!(session.reply());
+        if code != 220 {
+            return Err(ftp_error("connect", code, &*text))
+        }
+        if security == Security::Explicit {
+            try!(session.expect("AUTH TLS", &[234]));
+            session.control = match session.control {
+                Stream::Plain(stream) => Stream::Tls(try!(wrap_tls(stream, host))),
+                tls => tls,
+            };
+        }
+        if security != Security::Plain {
+            try!(session.expect("PBSZ 0", &[200]));
+            try!(session.expect("PROT P", &[200]));
+        }
+        Ok(session)
+    }
+
+    fn login(&mut self, user: &str, password: &str) -> io::Result<()>
+    {
+        let (code, text) = try!(self.command(&*format!("USER {}", user)));
+        let (code, text) = match code {
+            331 | 332 => try!(self.command(&*format!("PASS {}", password))),
+            _ => (code, text),
+        };
+        match code {
+            230 | 202 => Ok(()),
+            _ => Err(ftp_error("login", code, &*text)),
+        }
+    }
+
+    /// The size of the file at `path`, if the server tells
+    fn size(&mut self, path: &str) -> io::Result<Option<u64>>
+    {
+        let (code, text) = try!(self.command(&*format!("SIZE {}", path)));
+        if code != 213 {
+            debug!("no size of {} ({} {})", path, code, text);
+            return Ok(None)
+        }
+        Ok(text.trim().parse().ok())
+    }
+
+    /// Start a transfer of the file at `path` from byte `offset`
+    fn retrieve(mut self, path: &str, offset: u64, mode: FtpMode) -> io::Result<Transfer>
+    {
+        let pending = match mode {
+            FtpMode::Passive => Pending::Connected(try!(self.passive())),
+            FtpMode::Active => Pending::Listening(try!(self.active())),
+        };
+        if offset > 0 {
+            try!(self.expect(&*format!("REST {}", offset), &[350]));
+        }
+        try!(self.expect(&*format!("RETR {}", path), &[125, 150]));
+        let stream = match pending {
+            Pending::Connected(stream) => stream,
+            Pending::Listening(listener) => {
+                let timeout = self.config.timeouts().0.unwrap_or(Duration::from_secs(ACCEPT_TIMEOUT));
+                let stream = HttpStream(try!(accept(&listener, timeout)));
+                try!(set_timeouts(&stream, &self.config));
+                stream
+            },
+        };
+        // The server starts the TLS handshake once it has the command
+        let data = match self.security {
+            Security::Plain => Stream::Plain(stream),
+            _ => Stream::Tls(try!(wrap_tls(stream, &*self.host))),
+        };
+        Ok(Transfer { data: data, session: self, finished: false })
+    }
+
+    /// Open a passive data connection
+    fn passive(&mut self) -> io::Result<HttpStream>
+    {
+        let port = match try!(self.command("EPSV")) {
+            (229, text) => parse_epsv(&*text),
+            _ => None,
+        };
+        let port = match port {
+            Some(port) => port,
+            None => {
+                let text = try!(self.expect("PASV", &[227]));
+                try!(parse_pasv(&*text).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("unable to parse '{}'", text))
+                }))
+            },
+        };
+        // The address in a PASV reply is often a private one behind NAT, so
+        // the data connection goes to the host of the control connection
+        let stream = try!(self.config.connect(&*self.host, port).map_err(io_error));
+        try!(set_timeouts(&stream, &self.config));
+        Ok(stream)
+    }
+
+    /// Listen for an active data connection, and tell the server where
+    fn active(&mut self) -> io::Result<TcpListener>
+    {
+        let listener = try!(TcpListener::bind(SocketAddr::new(self.local.ip(), 0)));
+        let addr = try!(listener.local_addr());
+        let family = if addr.is_ipv4() { 1 } else { 2 };
+        let (code, text) = try!(self.command(&*format!("EPRT |{}|{}|{}|", family, addr.ip(),
+                                                       addr.port())));
+        if code == 200 {
+            return Ok(listener)
+        }
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                let octets = ip.octets();
+                try!(self.expect(&*format!("PORT {},{},{},{},{},{}", octets[0], octets[1],
+                                           octets[2], octets[3], addr.port() >> 8,
+                                           addr.port() & 0xff), &[200]));
+                Ok(listener)
+            },
+            IpAddr::V6(_) => Err(ftp_error("EPRT", code, &*text)),
+        }
+    }
+
+    /// Stop a transfer whose data connection was closed before it ended,
+    /// leaving the session ready for the next command
+    fn abort(&mut self) -> io::Result<()>
+    {
+        // Which replies RETR and ABOR get depends on whether the server was
+        // done sending, so the reply to NOOP marks the end of them
+        try!(self.send("ABOR"));
+        try!(self.send("NOOP"));
+        for _ in 0..4 {
+            if try!(self.reply()).0 == 200 {
+                return Ok(())
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidData, "no reply to NOOP after ABOR"))
+    }
+
+    /// Send `command` and return the server's reply
+    fn command(&mut self, command: &str) -> io::Result<(u32, String)>
+    {
+        try!(self.send(command));
+        self.reply()
+    }
+
+    /// Send `command` without waiting for the reply
+    fn send(&mut self, command: &str) -> io::Result<()>
+    {
+        if !is_safe_argument(command) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "FTP command has a line break or NUL"))
+        }
+        if command.starts_with("PASS ") {
+            debug!("ftp > PASS ****");
+        } else {
+            debug!("ftp > {}", command);
+        }
+        try!(self.control.write_all(format!("{}\r\n", command).as_bytes()));
+        self.control.flush()
+    }
+
+    /// Send `command`, returning the text of the reply if its code is one
+    /// of `expected`
+    fn expect(&mut self, command: &str, expected: &[u32]) -> io::Result<String>
+    {
+        let (code, text) = try!(self.command(command));
+        if !expected.contains(&code) {
+            return Err(ftp_error(command.split(' ').next().unwrap_or(command), code, &*text))
+        }
+        Ok(text)
+    }
+
+    /// Read a reply, joining the lines of a multiline one
+    fn reply(&mut self) -> io::Result<(u32, String)>
+    {
+        let first = try!(self.read_line());
+        let code = match first.get(..3).and_then(|code| code.parse().ok()) {
+            Some(code) => code,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("malformed reply '{}'", first))),
+        };
+        let mut text = first.get(4..).unwrap_or("").to_owned();
+        if first.as_bytes().get(3) == Some(&b'-') {
+            let last = format!("{} ", code);
+            loop {
+                let line = try!(self.read_line());
+                let done = line.starts_with(&*last);
+                text.push('\n');
+                text.push_str(if done { &line[4..] } else { &*line });
+                if done {
+                    break
+                }
+            }
+        }
+        debug!("ftp < {} {}", code, text);
+        Ok((code, text))
+    }
+
+    /// Read a line a byte at a time, so nothing past it is consumed before
+    /// a TLS upgrade
+    fn read_line(&mut self) -> io::Result<String>
+    {
+        let mut line = vec![];
+        let mut byte = [0; 1];
+        while line.last() != Some(&b'\n') {
+            if try!(self.control.read(&mut byte)) == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          "the server closed the control connection"))
+            }
+            line.push(byte[0]);
+        }
+        Ok(String::from_utf8_lossy(&line).trim_end().to_owned())
+    }
+}
+
+/// A transfer giving its session back once dropped, aborting it first if
+/// it was cut short
+struct Reusable {
+    transfer: Option<Transfer>,
+    idle: Arc<Mutex<Option<Session>>>,
+}
+
+impl Read for Reusable {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        match self.transfer {
+            Some(ref mut transfer) => transfer.read(buf),
+            None => Ok(0),
+        }
+    }
+}
+
+impl Drop for Reusable {
+    fn drop(&mut self)
+    {
+        let Transfer { data, mut session, finished } = match self.transfer.take() {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        drop(data);
+        if !finished {
+            if let Err(e) = session.abort() {
+                debug!("unable to abort the transfer ({}), closing the session", e);
+                return
+            }
+        }
+        *self.idle.lock().unwrap() = Some(session);
+    }
+}
+
+/// A data connection before the transfer starts
+enum Pending {
+    Connected(HttpStream),
+    Listening(TcpListener),
+}
+
+/// The data connection of a transfer, read until the server closes it
+pub struct Transfer {
+    data: Stream,
+    /// Kept open for the reply confirming the transfer
+    session: Session,
+    finished: bool,
+}
+
+impl Read for Transfer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let len = try!(self.data.read(buf));
+        if len == 0 && !buf.is_empty() && !self.finished {
+            self.finished = true;
+            // A transfer cut short ends just like a complete one, but for
+            // the reply
+            let (code, text) = try!(self.session.reply());
+            if code / 100 != 2 {
+                return Err(ftp_error("RETR", code, &*text))
+            }
+        }
+        Ok(len)
+    }
+}
+
+/// Wait up to `timeout` for the server to open an active data connection
+fn accept(listener: &TcpListener, timeout: Duration) -> io::Result<TcpStream>
+{
+    try!(listener.set_nonblocking(true));
+    let start = Instant::now();
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                try!(stream.set_nonblocking(false));
+                return Ok(stream)
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && start.elapsed() < timeout => {
+                thread::sleep(Duration::from_millis(10));
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                          "the server didn't open the data connection"))
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn set_timeouts(stream: &HttpStream, config: &ClientConfig) -> io::Result<()>
+{
+    let (_, read_timeout) = config.timeouts();
+    try!(stream.0.set_read_timeout(read_timeout));
+    stream.0.set_write_timeout(read_timeout)
+}
+
+fn wrap_tls(stream: HttpStream, host: &str) -> io::Result<TlsStream>
+{
+    Openssl::default().wrap_client(stream, host).map_err(io_error)
+}
+
+fn io_error(err: hyper::Error) -> io::Error
+{
+    match err {
+        hyper::Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+    }
+}
+
+/// The error of a reply refusing `command`
+fn ftp_error(command: &str, code: u32, text: &str) -> io::Error
+{
+    let kind = match code {
+        530 => io::ErrorKind::PermissionDenied,
+        550 => io::ErrorKind::NotFound,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("FTP {} refused: {} {}", command, code, text.trim()))
+}
+
+/// The port of an `EPSV` reply, e.g. `Entering Extended Passive Mode (|||6446|)`
+fn parse_epsv(text: &str) -> Option<u16>
+{
+    let start = match text.find('(') {
+        Some(i) => i + 1,
+        None => return None,
+    };
+    let inner = match text[start..].find(')') {
+        Some(len) => &text[start..start + len],
+        None => return None,
+    };
+    inner.chars().next()
+        .and_then(|delimiter| inner.split(delimiter).nth(3))
+        .and_then(|port| port.parse().ok())
+}
+
+/// The port of a `PASV` reply, e.g. `Entering Passive Mode (192,168,1,2,19,137)`
+fn parse_pasv(text: &str) -> Option<u16>
+{
+    let start = match text.find(|c: char| c.is_digit(10)) {
+        Some(i) => i,
+        None => return None,
+    };
+    let numbers: Vec<u16> = text[start..].split(|c: char| !c.is_digit(10) && c != ',')
+        .next().unwrap_or("")
+        .split(',')
+        .filter_map(|n| n.parse().ok())
+        .collect();
+    if numbers.len() != 6 || numbers[4] > 255 || numbers[5] > 255 {
+        return None
+    }
+    Some(numbers[4] << 8 | numbers[5])
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hyper::Url;
+    use super::{
+        FtpMode,
+        check_arguments,
+        parse_epsv,
+        parse_pasv,
+    };
+
+    #[test]
+    fn pasv_replies()
+    {
+        // RFC 959 leaves the form of the address open
+        assert_eq!(parse_pasv("Entering Passive Mode (192,168,1,2,19,137)"), Some(19 * 256 + 137));
+        assert_eq!(parse_pasv("Entering Passive Mode 192,168,1,2,19,137"), Some(5001));
+        assert_eq!(parse_pasv("=192,168,1,2,0,21"), Some(21));
+        assert_eq!(parse_pasv("Entering Passive Mode (192,168,1,2,19)"), None);
+        assert_eq!(parse_pasv("Entering Passive Mode (192,168,1,2,256,1)"), None);
+        assert_eq!(parse_pasv("Entering Passive Mode"), None);
+        assert_eq!(parse_pasv(""), None);
+    }
+
+    #[test]
+    fn epsv_replies()
+    {
+        // RFC 2428, with the usual and another delimiter
+        assert_eq!(parse_epsv("Entering Extended Passive Mode (|||6446|)"), Some(6446));
+        assert_eq!(parse_epsv("Entering Extended Passive Mode (!!!6446!)"), Some(6446));
+        assert_eq!(parse_epsv("Entering Extended Passive Mode (|||6446"), None);
+        assert_eq!(parse_epsv("Entering Extended Passive Mode (|||port|)"), None);
+        assert_eq!(parse_epsv("Entering Extended Passive Mode (|||70000|)"), None);
+        assert_eq!(parse_epsv("Entering Extended Passive Mode ()"), None);
+        assert_eq!(parse_epsv("Entering Extended Passive Mode"), None);
+    }
+
+    #[test]
+    fn refuses_injected_commands()
+    {
+        let url = Url::parse("ftp://example.com/pub/file.iso").unwrap();
+        assert!(check_arguments(&url, &None).is_ok());
+        assert!(check_arguments(&url, &Some(("user".to_owned(), Some("pass".to_owned())))).is_ok());
+
+        let url = Url::parse("ftp://example.com/file%0D%0ADELE%20other").unwrap();
+        assert!(check_arguments(&url, &None).is_err());
+        let url = Url::parse("ftp://example.com/file%00").unwrap();
+        assert!(check_arguments(&url, &None).is_err());
+
+        let url = Url::parse("ftp://example.com/file").unwrap();
+        let injected = Some(("user\r\nDELE file".to_owned(), None));
+        assert!(check_arguments(&url, &injected).is_err());
+        let injected = Some(("user".to_owned(), Some("pass\nQUIT".to_owned())));
+        assert!(check_arguments(&url, &injected).is_err());
+    }
+
+    #[test]
+    fn modes()
+    {
+        assert_eq!("passive".parse::<FtpMode>(), Ok(FtpMode::Passive));
+        assert_eq!("active".parse::<FtpMode>(), Ok(FtpMode::Active));
+        assert!("pasv".parse::<FtpMode>().is_err());
+    }
+}
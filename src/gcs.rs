@@ -0,0 +1,323 @@
+//! Downloading `gs://bucket/object` urls from Google Cloud Storage
+//!
+//! A GCS url is downloaded through the XML API at `storage.googleapis.com`,
+//! which honors `Range` requests like any other server, so objects are
+//! downloaded in parallel segments. Requests are authenticated with an
+//! OAuth access token from the application default credentials, looked up
+//! in the standard order:
+//!
+//! 1. the service account key or user credentials file named by
+//!    `$GOOGLE_APPLICATION_CREDENTIALS`
+//! 2. the user credentials saved by `gcloud auth application-default login`
+//! 3. the service account of the Compute Engine VM, from its metadata server
+//!
+//! Tokens are refreshed shortly before they expire. Without credentials,
+//! requests are sent unauthenticated, which is enough for public objects.
+
+use client::ClientConfig;
+use cloud::{
+    json_number,
+    json_string,
+    metadata_request,
+    split_bucket_url,
+    uri_encode,
+};
+use hyper::Url;
+use hyper::header::Headers;
+use rsa::RsaPrivateKey;
+use rsa::pkcs1v15::SigningKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::sha2::Sha256;
+use rsa::signature::{
+    SignatureEncoding,
+    Signer,
+};
+use std::env;
+use std::fs::File;
+use std::io::{
+    self,
+    Read,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::time::{
+    Duration,
+    Instant,
+    SystemTime,
+    UNIX_EPOCH,
+};
+use token::{
+    BearerToken,
+    TokenSource,
+};
+
+
+/// Endpoint of the XML API
+const STORAGE_ENDPOINT: &'static str = "https://storage.googleapis.com";
+
+/// Where tokens are obtained when a credentials file doesn't say
+const TOKEN_URI: &'static str = "https://oauth2.googleapis.com/token";
+
+/// Access asked of service account tokens
+const READ_ONLY_SCOPE: &'static str = "https://www.googleapis.com/auth/devstorage.read_only";
+
+/// Host of the Compute Engine metadata server
+const METADATA_HOST: &'static str = "metadata.google.internal";
+
+/// Seconds before a token expires that it is replaced
+const REFRESH_MARGIN: u64 = 300;
+
+/// Credentials found, once looked for
+static CREDENTIALS: Mutex<Option<Option<Arc<GcsCredentials>>>> = Mutex::new(None);
+
+/// The bucket and object of a `gs://` url
+#[derive(Clone,Debug,PartialEq)]
+pub struct GsUrl {
+    pub bucket: String,
+    pub object: String,
+}
+
+impl GsUrl {
+
+    /// Parse `gs://bucket/object`. The object is taken as written, not
+    /// decoded, and is empty for the bucket itself.
+    pub fn parse(url: &str) -> Result<GsUrl, String>
+    {
+        let (bucket, object) = try!(split_bucket_url(url, "gs"));
+        Ok(GsUrl { bucket: bucket, object: object })
+    }
+
+    /// The XML API url of the object
+    pub fn https_url(&self) -> String
+    {
+        format!("{}/{}/{}", STORAGE_ENDPOINT, self.bucket, uri_encode(&*self.object, false))
+    }
+}
+
+/// How access tokens are obtained
+enum Grant {
+    /// A JWT signed with a service account's key
+    ServiceAccount { email: String, private_key: String, token_uri: String },
+    /// A user's refresh token
+    User { client_id: String, client_secret: String, refresh_token: String, token_uri: String },
+    /// The metadata server of a Compute Engine VM
+    Metadata,
+}
+
+/// Application default credentials, handing out access tokens
+pub struct GcsCredentials {
+    grant: Grant,
+    /// Settings tokens are requested with
+    config: ClientConfig,
+    /// The current token and when it expires
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl GcsCredentials {
+
+    /// The application default credentials, if any. They are only looked
+    /// for the first time.
+    pub fn find(config: &ClientConfig) -> Option<Arc<GcsCredentials>>
+    {
+        let mut found = CREDENTIALS.lock().unwrap();
+        if found.is_none() {
+            let credentials = GcsCredentials::lookup(config).map(Arc::new);
+            if credentials.is_none() {
+                info!("No Google Cloud credentials found, requesting GCS unauthenticated");
+            }
+            *found = Some(credentials);
+        }
+        found.clone().unwrap()
+    }
+
+    fn lookup(config: &ClientConfig) -> Option<GcsCredentials>
+    {
+        if let Ok(path) = env::var("GOOGLE_APPLICATION_CREDENTIALS") {
+            match GcsCredentials::from_file(Path::new(&path), config) {
+                Ok(credentials) => return Some(credentials),
+                Err(e) => warn!("Ignoring $GOOGLE_APPLICATION_CREDENTIALS: {}", e),
+            }
+        }
+        let gcloud = env::var("CLOUDSDK_CONFIG").map(PathBuf::from).ok()
+            .or_else(|| env::var("HOME").ok().map(|home| PathBuf::from(home).join(".config/gcloud")));
+        if let Some(path) = gcloud.map(|dir| dir.join("application_default_credentials.json")) {
+            if path.exists() {
+                match GcsCredentials::from_file(&path, config) {
+                    Ok(credentials) => return Some(credentials),
+                    Err(e) => warn!("Ignoring {}: {}", path.display(), e),
+                }
+            }
+        }
+        let credentials = GcsCredentials::new(Grant::Metadata, config);
+        match credentials.fetch() {
+            Ok((token, expires_in)) => {
+                credentials.keep(token, expires_in);
+                Some(credentials)
+            },
+            Err(e) => {
+                debug!("no Compute Engine metadata server: {}", e);
+                None
+            },
+        }
+    }
+
+    /// Load a service account key or user credentials file
+    pub fn from_file(path: &Path, config: &ClientConfig) -> Result<GcsCredentials, String>
+    {
+        let mut json = String::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut json))
+             .map_err(|e| e.to_string()));
+        let field = |name: &str| json_string(&*json, name).ok_or_else(|| format!("no {}", name));
+        let token_uri = json_string(&*json, "token_uri").unwrap_or_else(|| TOKEN_URI.to_owned());
+        let grant = match &*try!(field("type")) {
+            "service_account" => Grant::ServiceAccount {
+                email: try!(field("client_email")),
+                private_key: try!(field("private_key")),
+                token_uri: token_uri,
+            },
+            "authorized_user" => Grant::User {
+                client_id: try!(field("client_id")),
+                client_secret: try!(field("client_secret")),
+                refresh_token: try!(field("refresh_token")),
+                token_uri: token_uri,
+            },
+            kind => return Err(format!("unsupported credentials type '{}'", kind)),
+        };
+        Ok(GcsCredentials::new(grant, config))
+    }
+
+    fn new(grant: Grant, config: &ClientConfig) -> GcsCredentials
+    {
+        GcsCredentials { grant: grant, config: config.clone(), token: Mutex::new(None) }
+    }
+
+    fn keep(&self, token: String, expires_in: u64)
+    {
+        let lifetime = Duration::from_secs(expires_in.saturating_sub(REFRESH_MARGIN));
+        *self.token.lock().unwrap() = Some((token, Instant::now() + lifetime));
+    }
+
+    /// Obtain a new access token and the seconds until it expires
+    fn fetch(&self) -> Result<(String, u64), String>
+    {
+        let response = match self.grant {
+            Grant::ServiceAccount { ref email, ref private_key, ref token_uri } => {
+                let jwt = try!(signed_jwt(email, private_key, token_uri));
+                try!(post_form(&self.config, token_uri, &[
+                    ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                    ("assertion", &*jwt),
+                ]))
+            },
+            Grant::User { ref client_id, ref client_secret, ref refresh_token, ref token_uri } => {
+                try!(post_form(&self.config, token_uri, &[
+                    ("grant_type", "refresh_token"),
+                    ("client_id", &**client_id),
+                    ("client_secret", &**client_secret),
+                    ("refresh_token", &**refresh_token),
+                ]))
+            },
+            Grant::Metadata => {
+                let path = "/computeMetadata/v1/instance/service-accounts/default/token";
+                try!(metadata_request("GET", METADATA_HOST, path, &["Metadata-Flavor: Google"])
+                     .map_err(|e| e.to_string()))
+            },
+        };
+        match json_string(&*response, "access_token") {
+            Some(token) => Ok((token, json_number(&*response, "expires_in").unwrap_or(3600))),
+            None => Err("the token response has no access_token".to_owned()),
+        }
+    }
+}
+
+impl TokenSource for GcsCredentials {
+
+    fn token(&self) -> io::Result<String>
+    {
+        if let Some((ref token, refresh_at)) = *self.token.lock().unwrap() {
+            if Instant::now() < refresh_at {
+                return Ok(token.clone())
+            }
+        }
+        let (token, expires_in) = try!(self.fetch().map_err(|e| {
+            io::Error::new(io::ErrorKind::Other, format!("unable to get a GCS access token: {}", e))
+        }));
+        debug!("obtained a GCS access token valid for {}s", expires_in);
+        self.keep(token.clone(), expires_in);
+        Ok(token)
+    }
+}
+
+/// The XML API url of an object (or of the bucket, for an empty object),
+/// and `config` authenticating requests with the application default
+/// credentials if there are any and no other token is set
+pub fn resolve(config: &ClientConfig, gs_url: &GsUrl) -> Result<(Url, ClientConfig), String>
+{
+    let https_url = gs_url.https_url();
+    let url = try!(Url::parse(&*https_url).map_err(|e| format!("invalid url {}: {}", https_url, e)));
+    if config.token().is_some() {
+        return Ok((url, config.clone()))
+    }
+    let config = match GcsCredentials::find(config) {
+        Some(credentials) => config.clone().bearer_token(BearerToken::Source(credentials)),
+        None => config.clone(),
+    };
+    Ok((url, config))
+}
+
+/// A JWT asking `token_uri` for a read only token, signed with a service
+/// account's private key
+fn signed_jwt(email: &str, private_key: &str, token_uri: &str) -> Result<String, String>
+{
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let header = encode_base64url(br#"{"alg":"RS256","typ":"JWT"}"#);
+    let claims = format!(r#"{{"iss":"{}","scope":"{}","aud":"{}","iat":{},"exp":{}}}"#,
+                         email, READ_ONLY_SCOPE, token_uri, now, now + 3600);
+    let input = format!("{}.{}", header, encode_base64url(claims.as_bytes()));
+    let key = try!(RsaPrivateKey::from_pkcs8_pem(private_key).map_err(|e| {
+        format!("invalid private_key: {}", e)
+    }));
+    let signature = try!(SigningKey::<Sha256>::new(key).try_sign(input.as_bytes()).map_err(|e| {
+        format!("unable to sign a token: {}", e)
+    }));
+    Ok(format!("{}.{}", input, encode_base64url(&signature.to_vec())))
+}
+
+/// POST a form to `url`, returning the body of a successful response
+fn post_form(config: &ClientConfig, url: &str, fields: &[(&str, &str)]) -> Result<String, String>
+{
+    let body = fields.iter()
+        .map(|&(name, value)| format!("{}={}", uri_encode(name, true), uri_encode(value, true)))
+        .collect::<Vec<_>>().join("&");
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"application/x-www-form-urlencoded".to_vec()]);
+    let mut response = try!(config.client().post(url).headers(headers).body(&*body).send()
+                            .map_err(|e| e.to_string()));
+    let mut text = String::new();
+    try!(response.read_to_string(&mut text).map_err(|e| e.to_string()));
+    if !response.status.is_success() {
+        let reason = json_string(&*text, "error_description")
+            .or_else(|| json_string(&*text, "error"))
+            .unwrap_or_else(|| text.trim().to_owned());
+        return Err(format!("{} refused ({}): {}", url, response.status, reason))
+    }
+    Ok(text)
+}
+
+fn encode_base64url(bytes: &[u8]) -> String
+{
+    const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..chunk.len() + 1 {
+            out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    out
+}
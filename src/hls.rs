@@ -0,0 +1,143 @@
+//! Downloading HLS streams
+//!
+//! An m3u8 media playlist lists the segments of a stream in order. The
+//! segments are fetched in parallel and written to a single output in
+//! playlist order (see `ordered`).
+//! Given a master playlist, the variant with the highest bandwidth is used.
+
+use client::ClientConfig;
+use download::{
+    get,
+    with_retries,
+};
+use errors::DownloadError;
+use hyper::Url;
+use hyper::header::Headers;
+use ordered::fetch_in_order;
+use retry::RetryPolicy;
+use std::io::{
+    Read,
+    Write,
+};
+
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct Variant {
+    pub url: String,
+    pub bandwidth: u64,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum Playlist {
+    /// Alternative renditions of the stream
+    Master(Vec<Variant>),
+    /// The segments of the stream, in order
+    Media(Vec<String>),
+}
+
+/// Parse an m3u8 playlist, resolving its urls against `base`
+pub fn parse(base: &Url, text: &str) -> Result<Playlist, String>
+{
+    let mut lines = text.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+    if lines.next() != Some("#EXTM3U") {
+        return Err("not an m3u8 playlist (missing #EXTM3U)".to_owned())
+    }
+
+    let resolve = |uri: &str| base.join(uri).map(|u| u.to_string()).map_err(|e| {
+        format!("invalid uri {} in playlist: {}", uri, e)
+    });
+
+    let mut variants = vec![];
+    let mut segments = vec![];
+    let mut bandwidth = None;
+    for line in lines {
+        if line.starts_with("#EXT-X-STREAM-INF:") {
+            let parsed = attribute(line, "BANDWIDTH").and_then(|b| b.parse().ok());
+            bandwidth = Some(parsed.unwrap_or(0));
+        } else if line.starts_with("#EXT-X-KEY:") && attribute(line, "METHOD") != Some("NONE") {
+            return Err("encrypted streams are not supported".to_owned())
+        } else if line.starts_with("#EXT-X-BYTERANGE") || line.starts_with("#EXT-X-MAP") {
+            return Err(format!("{} is not supported", line.split(':').next().unwrap_or(line)))
+        } else if line.starts_with('#') {
+            continue;
+        } else if let Some(bandwidth) = bandwidth.take() {
+            variants.push(Variant { url: try!(resolve(line)), bandwidth: bandwidth });
+        } else {
+            segments.push(try!(resolve(line)));
+        }
+    }
+
+    if !variants.is_empty() {
+        Ok(Playlist::Master(variants))
+    } else {
+        Ok(Playlist::Media(segments))
+    }
+}
+
+/// The value of `name` in a tag's attribute list, e.g. `BANDWIDTH=1280000`
+fn attribute<'a>(line: &'a str, name: &str) -> Option<&'a str>
+{
+    let list = match line.find(':') {
+        Some(i) => &line[i + 1..],
+        None => return None,
+    };
+    list.split(',')
+        .filter_map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) if key.trim() == name => {
+                    Some(value.trim().trim_matches('"'))
+                },
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Fetch and parse the playlist at `url`, following a master playlist to
+/// its highest bandwidth variant, and return the segment urls
+pub fn segments(client: &ClientConfig, retry: &RetryPolicy, url: &str)
+                -> Result<Vec<String>, DownloadError>
+{
+    let mut url = url.to_owned();
+    // A master playlist points at media playlists, which don't nest further
+    for _ in 0..2 {
+        let (text, base) = try!(with_retries(retry, "GET", || {
+            let (mut response, _) = try!(get(client, &*url, Headers::new()));
+            let mut text = String::new();
+            try!(response.read_to_string(&mut text));
+            Ok((text, response.url.clone()))
+        }));
+
+        match try!(parse(&base, &*text).map_err(DownloadError::other)) {
+            Playlist::Media(segments) => return Ok(segments),
+            Playlist::Master(variants) => {
+                let best = variants.into_iter().max_by_key(|v| v.bandwidth).unwrap();
+                info!("Using the {} bps variant {}", best.bandwidth, best.url);
+                url = best.url;
+            },
+        }
+    }
+    Err(DownloadError::other(format!("{} does not lead to a media playlist", url)))
+}
+
+/// Download the segments with `workers` threads, retrying each as `retry`
+/// allows, writing them to `out` in order, and return the number of bytes
+/// written
+pub fn download<W: Write>(client: &ClientConfig, retry: &RetryPolicy, segments: Vec<String>,
+                          workers: usize, out: &mut W) -> Result<u64, DownloadError>
+{
+    let (client, retry) = (client.clone(), retry.clone());
+    fetch_in_order(segments, workers, move |index, url: &String, _| {
+        debug!("Fetching segment {} {}", index, url);
+        with_retries(&retry, "GET", || fetch(&client, &*url))
+    }, out, |_| ())
+}
+
+fn fetch(client: &ClientConfig, url: &str) -> Result<Vec<u8>, DownloadError>
+{
+    let (mut response, _) = try!(get(client, url, Headers::new()));
+    let mut buf = vec![];
+    try!(response.read_to_end(&mut buf));
+    Ok(buf)
+}
@@ -0,0 +1,400 @@
+//! Random access to a remote file over ranged requests
+//!
+//! `HttpReader` implements `Read` and `Seek`, fetching the bytes under the
+//! cursor (plus some readahead) with `Range` requests, so formats with an
+//! index (e.g. a zip's central directory) can be read without downloading
+//! the whole file.
+//!
+//! Readers of many scattered pages, such as a remote database file, can
+//! instead fetch a batch of ranges at once with `fetch_ranges`, which
+//! coalesces nearby ranges into one request and makes the requests in
+//! parallel.
+
+use client::ClientConfig;
+use download::{
+    get,
+    head,
+    remote_size,
+    with_retries,
+};
+use errors::DownloadError;
+use hyper::header::{
+    ByteRangeSpec,
+    ContentRange,
+    ContentRangeSpec,
+    Headers,
+    Range,
+};
+use hyper::status::StatusCode;
+use metadata::Metadata;
+use retry::RetryPolicy;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{
+    self,
+    Read,
+    Seek,
+    SeekFrom,
+};
+use std::ops;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::thread;
+
+
+/// Bytes fetched beyond those requested by a read
+pub const DEFAULT_READAHEAD: u64 = 64 * 1024;  // 64 KB
+
+/// Ranges of a batch closer together than this are fetched by one request,
+/// the bytes between them costing less than another round trip
+pub const COALESCE_GAP: u64 = 32 * 1024;  // 32 KB
+
+/// Ranges aren't coalesced into requests larger than this, so that a large
+/// batch is still spread across workers
+const MAX_COALESCED: u64 = 8 * 1024 * 1024;  // 8 MB
+
+/// Number of requests of a batch made at the same time
+pub const DEFAULT_BATCH_WORKERS: usize = 4;
+
+pub struct HttpReader {
+    client: ClientConfig,
+    url: String,
+    headers: Headers,
+    len: u64,
+    pos: u64,
+    readahead: u64,
+    /// Number of requests of a batch made at the same time
+    workers: usize,
+    /// Retries of the requests of a batch
+    retry: RetryPolicy,
+    /// The most recently fetched bytes, starting at `buf_start`
+    buf: Vec<u8>,
+    buf_start: u64,
+}
+
+impl HttpReader {
+
+    /// Open `url`, which must be served by a server supporting ranges
+    pub fn open(client: ClientConfig, url: &str, headers: Headers)
+                -> Result<HttpReader, DownloadError>
+    {
+        // Reuse a probe of the url by a download (or another reader)
+        let metadata = match client.metadata().get(url) {
+            Some((metadata, _)) => metadata,
+            None => {
+                let (response, redirects) = try!(head(&client, url, headers.clone()));
+                let metadata = Metadata::of(&response);
+                client.metadata().insert(url, metadata.clone(), redirects);
+                metadata
+            },
+        };
+        let len = try!(remote_size(&metadata).map_err(|e| e.with_url(url)));
+        let ranges = metadata.status == StatusCode::PartialContent
+            || client.metadata().ranges(url).unwrap_or(false)
+            || metadata.headers.get_raw("Accept-Ranges")
+                .map_or(false, |lines| lines.iter().any(|l| l.starts_with(b"bytes")));
+        if !ranges {
+            warn!("{} does not advertise range support", url);
+        }
+
+        Ok(HttpReader {
+            client: client,
+            url: metadata.url.to_string(),
+            headers: headers,
+            len: len,
+            pos: 0,
+            readahead: DEFAULT_READAHEAD,
+            workers: DEFAULT_BATCH_WORKERS,
+            retry: RetryPolicy::default(),
+            buf: vec![],
+            buf_start: 0,
+        })
+    }
+
+    /// Set the number of bytes fetched beyond those requested by a read
+    pub fn readahead(mut self, readahead: u64) -> HttpReader
+    {
+        self.readahead = readahead;
+        self
+    }
+
+    /// Set the number of requests of a batch made at the same time
+    pub fn workers(mut self, workers: usize) -> HttpReader
+    {
+        self.workers = cmp::max(workers, 1);
+        self
+    }
+
+    /// Retry the failed requests of a batch as `retry` allows
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> HttpReader
+    {
+        self.retry = retry;
+        self
+    }
+
+    /// Length of the remote file
+    pub fn len(&self) -> u64
+    {
+        self.len
+    }
+
+    /// Fetch the bytes of each of `ranges`, which may be in any order and
+    /// overlap, returning them in the same order. Nearby ranges are
+    /// coalesced into one request, the requests are made in parallel, and
+    /// each response is checked to hold exactly the bytes asked for.
+    pub fn fetch_ranges(&self, ranges: &[ops::Range<u64>]) -> Result<Vec<Vec<u8>>, DownloadError>
+    {
+        if let Some(range) = ranges.iter().find(|r| r.start > r.end || r.end > self.len) {
+            return Err(DownloadError::other(format!(
+                "range {}..{} is outside the {} bytes of {}", range.start, range.end, self.len,
+                self.url)))
+        }
+        let spans = coalesce(ranges);
+        debug!("Fetching {} ranges of {} in {} requests", ranges.len(), self.url, spans.len());
+
+        let queue: VecDeque<(usize, ops::Range<u64>)> = spans.iter().cloned().enumerate().collect();
+        let queue = Arc::new(Mutex::new(queue));
+        let workers: Vec<_> = (0..cmp::min(self.workers, spans.len())).map(|_| {
+            let (queue, client, url) = (queue.clone(), self.client.clone(), self.url.clone());
+            let (headers, retry) = (self.headers.clone(), self.retry.clone());
+            thread::spawn(move|| {
+                let mut fetched = vec![];
+                loop {
+                    let (i, span) = match queue.lock().unwrap().pop_front() {
+                        Some(next) => next,
+                        None => return Ok(fetched),
+                    };
+                    let result = with_retries(&retry, "GET", || {
+                        fetch_range(&client, &*url, &headers, span.clone())
+                    });
+                    match result {
+                        Ok(bytes) => fetched.push((i, bytes)),
+                        Err(e) => {
+                            // Leave the other workers nothing more to fetch
+                            queue.lock().unwrap().clear();
+                            return Err(e)
+                        },
+                    }
+                }
+            })
+        }).collect();
+        let mut bodies = vec![vec![]; spans.len()];
+        for worker in workers {
+            let fetched = worker.join().unwrap_or_else(|_| {
+                Err(DownloadError::other(format!("a worker fetching ranges of {} panicked", self.url)))
+            });
+            for (i, bytes) in try!(fetched) {
+                bodies[i] = bytes;
+            }
+        }
+
+        Ok(slice(ranges, &spans, &bodies))
+    }
+
+    /// Fetch `len` bytes starting at `start` into the buffer
+    fn fill(&mut self, start: u64, len: u64) -> Result<(), DownloadError>
+    {
+        let end = cmp::min(start + len, self.len);
+        debug!("Fetching bytes {} - {} of {}", start, end, self.url);
+        self.buf = try!(fetch_range(&self.client, &*self.url, &self.headers, start..end));
+        self.buf_start = start;
+        Ok(())
+    }
+}
+
+/// The requests to make for `ranges`: the non-empty ranges sorted, with
+/// those that overlap merged, as are those less than `COALESCE_GAP` apart
+/// unless the request would grow beyond `MAX_COALESCED`
+fn coalesce(ranges: &[ops::Range<u64>]) -> Vec<ops::Range<u64>>
+{
+    let mut sorted: Vec<ops::Range<u64>> = ranges.iter().filter(|r| r.start < r.end).cloned().collect();
+    sorted.sort_by_key(|range| range.start);
+    let mut spans: Vec<ops::Range<u64>> = vec![];
+    for range in sorted {
+        if let Some(last) = spans.last_mut() {
+            let end = cmp::max(last.end, range.end);
+            let near = range.start <= last.end + COALESCE_GAP && end - last.start <= MAX_COALESCED;
+            // Overlapping ranges must share a request to be sliced from it
+            if range.start < last.end || near {
+                last.end = end;
+                continue
+            }
+        }
+        spans.push(range);
+    }
+    spans
+}
+
+/// The bytes of each of `ranges`, cut from the `bodies` fetched for the
+/// `spans` they were coalesced into
+fn slice(ranges: &[ops::Range<u64>], spans: &[ops::Range<u64>], bodies: &[Vec<u8>])
+         -> Vec<Vec<u8>>
+{
+    ranges.iter().map(|range| {
+        match spans.iter().position(|span| span.start <= range.start && range.end <= span.end) {
+            Some(i) if range.start < range.end => {
+                let offset = (range.start - spans[i].start) as usize;
+                bodies[i][offset..offset + (range.end - range.start) as usize].to_vec()
+            },
+            _ => vec![],
+        }
+    }).collect()
+}
+
+/// Fetch the bytes of `range` of `url`, checking that the server sent
+/// exactly those
+fn fetch_range(client: &ClientConfig, url: &str, headers: &Headers, range: ops::Range<u64>)
+               -> Result<Vec<u8>, DownloadError>
+{
+    let mut headers = headers.clone();
+    headers.set(Range::Bytes(vec![ByteRangeSpec::FromTo(range.start, range.end - 1)]));
+    let (response, _) = try!(get(client, url, headers));
+    // A server ignoring the range would send the whole file
+    if response.status != StatusCode::PartialContent {
+        return Err(DownloadError::unsupported_range(response.status).with_url(url))
+    }
+    let sent = match response.headers.get::<ContentRange>() {
+        Some(&ContentRange(ContentRangeSpec::Bytes { range: Some((first, last)), .. })) => {
+            Some((first, last + 1))
+        },
+        _ => None,
+    };
+    if sent != Some((range.start, range.end)) {
+        return Err(DownloadError::other(format!(
+            "asked for bytes {}-{} but the server sent {}", range.start, range.end - 1,
+            sent.map_or("no Content-Range".to_owned(), |(start, end)| {
+                format!("bytes {}-{}", start, end - 1)
+            }))).with_url(url))
+    }
+
+    let len = range.end - range.start;
+    let mut buf = Vec::with_capacity(len as usize);
+    try!(response.take(len).read_to_end(&mut buf));
+    if (buf.len() as u64) < len {
+        return Err(DownloadError::incomplete(buf.len() as u64, len).with_url(url))
+    }
+    Ok(buf)
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize>
+    {
+        if self.pos >= self.len || out.is_empty() {
+            return Ok(0)
+        }
+
+        let buf_end = self.buf_start + self.buf.len() as u64;
+        if self.pos < self.buf_start || self.pos >= buf_end {
+            let (pos, len) = (self.pos, out.len() as u64 + self.readahead);
+            try!(self.fill(pos, len).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())));
+        }
+
+        let offset = (self.pos - self.buf_start) as usize;
+        let n = cmp::min(out.len(), self.buf.len() - offset);
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      format!("{} ended early", self.url)))
+        }
+        out[..n].copy_from_slice(&self.buf[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for HttpReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64>
+    {
+        let pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.len as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "seek to a negative position"))
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        coalesce,
+        slice,
+        COALESCE_GAP,
+        MAX_COALESCED,
+    };
+    use std::ops;
+
+    /// Slice `ranges` from a file whose every byte is its offset mod 256
+    fn fetch(ranges: &[ops::Range<u64>]) -> Vec<Vec<u8>>
+    {
+        let spans = coalesce(ranges);
+        let bodies: Vec<Vec<u8>> = spans.iter()
+            .map(|span| (span.start..span.end).map(|i| i as u8).collect())
+            .collect();
+        slice(ranges, &spans, &bodies)
+    }
+
+    fn expected(range: &ops::Range<u64>) -> Vec<u8>
+    {
+        (range.start..range.end).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_share_a_request()
+    {
+        assert_eq!(coalesce(&[10..20, 0..15]), vec![0..20]);
+        assert_eq!(coalesce(&[0..10, 10..20, 20..30]), vec![0..30]);
+        // A range inside another
+        assert_eq!(coalesce(&[0..100, 40..50]), vec![0..100]);
+        assert_eq!(coalesce(&[0..10, 10 + COALESCE_GAP..20 + COALESCE_GAP]),
+                   vec![0..20 + COALESCE_GAP]);
+    }
+
+    #[test]
+    fn far_apart_ranges_are_separate_requests()
+    {
+        let far = 11 + COALESCE_GAP;
+        assert_eq!(coalesce(&[far..far + 10, 0..10]), vec![0..10, far..far + 10]);
+    }
+
+    #[test]
+    fn requests_stop_growing_at_the_cap()
+    {
+        let half = MAX_COALESCED / 2;
+        assert_eq!(coalesce(&[0..half, half + 1..MAX_COALESCED + 1]),
+                   vec![0..half, half + 1..MAX_COALESCED + 1]);
+        assert_eq!(coalesce(&[0..half, half..MAX_COALESCED]), vec![0..MAX_COALESCED]);
+        // Overlapping ranges are merged whatever the size
+        assert_eq!(coalesce(&[0..MAX_COALESCED, 10..MAX_COALESCED + 10]),
+                   vec![0..MAX_COALESCED + 10]);
+    }
+
+    #[test]
+    fn empty_ranges_are_not_requested()
+    {
+        assert!(coalesce(&[]).is_empty());
+        assert!(coalesce(&[5..5, 7..7]).is_empty());
+        assert_eq!(coalesce(&[5..5, 0..3]), vec![0..3]);
+        assert_eq!(fetch(&[5..5]), vec![Vec::<u8>::new()]);
+        assert!(fetch(&[]).is_empty());
+    }
+
+    #[test]
+    fn ranges_are_sliced_in_the_order_asked()
+    {
+        let far = 100 + COALESCE_GAP;
+        let ranges = vec![far..far + 300, 50..60, 0..100, 60..60, 90..110, far + 1..far + 2];
+        let fetched = fetch(&ranges);
+        assert_eq!(fetched.len(), ranges.len());
+        for (range, bytes) in ranges.iter().zip(fetched) {
+            assert_eq!(bytes, expected(range));
+        }
+    }
+}
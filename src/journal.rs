@@ -0,0 +1,473 @@
+//! Journal of the byte ranges durably written to a target
+//!
+//! After each chunk is flushed to disk an entry with its offset, length
+//! and md5 is appended to a sidecar file (`<target>.sledge`). After a
+//! crash or power loss the journal says exactly which ranges of the target
+//! can be trusted, rather than assuming the file length equals progress.
+
+use md5;
+use paths::os_path;
+use std::fs::{
+    self,
+    File,
+    OpenOptions,
+};
+use std::cmp;
+use std::io::{
+    self,
+    BufRead,
+    BufReader,
+    Read,
+    Seek,
+    SeekFrom,
+    Write,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::str::FromStr;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+
+
+/// Number of bytes written between journal entries
+pub const JOURNAL_CHUNK_SIZE: u64 = 4 * 1024 * 1024;  // 4 MB
+
+/// Permissions of a journal, which is only for the owner like the part
+/// file it describes
+#[cfg(unix)]
+const JOURNAL_FILE_MODE: u32 = 0o600;
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct JournalEntry {
+    /// Offset of the chunk in the target
+    pub offset: u64,
+    /// Length of the chunk
+    pub len: u64,
+    /// Hex md5 of the chunk's contents
+    pub md5: String,
+}
+
+/// Which journaled chunks are checked against their md5 before resuming
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum VerifyChunks {
+    /// Check every chunk
+    All,
+    /// Check this many chunks spread across the file, and every chunk if
+    /// any of them is corrupt
+    Sample(usize),
+    /// Trust the journal
+    Skip,
+}
+
+impl Default for VerifyChunks {
+    fn default() -> VerifyChunks
+    {
+        VerifyChunks::All
+    }
+}
+
+impl FromStr for VerifyChunks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<VerifyChunks, String>
+    {
+        match s {
+            "all" => Ok(VerifyChunks::All),
+            "none" => Ok(VerifyChunks::Skip),
+            _ => s.parse().map(VerifyChunks::Sample).map_err(|_| {
+                format!("expected 'all', 'none' or a number of chunks, not '{}'", s)
+            }),
+        }
+    }
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Journal {
+
+    /// Location of the journal for a target path
+    pub fn path_for(target: &str) -> PathBuf
+    {
+        os_path(&*format!("{}.sledge", target))
+    }
+
+    /// Start a new, empty journal for a target path
+    pub fn create(target: &str) -> io::Result<Journal>
+    {
+        let path = Journal::path_for(target);
+        debug!("journaling written ranges to {}", path.display());
+        let mut open_options = OpenOptions::new();
+        open_options.write(true).create(true).truncate(true);
+        let file = try!(open_restricted(&path, &mut open_options));
+        Ok(Journal { path: path, file: Mutex::new(file) })
+    }
+
+    /// Continue the journal of a target path, keeping its entries
+    pub fn open(target: &str) -> io::Result<Journal>
+    {
+        let path = Journal::path_for(target);
+        debug!("continuing the journal {}", path.display());
+        let file = try!(open_restricted(&path, OpenOptions::new().append(true).create(true)));
+        Ok(Journal { path: path, file: Mutex::new(file) })
+    }
+
+    /// Read the entries of an existing journal for a target path
+    ///
+    /// A partially written trailing entry (from a crash mid-write) is ignored.
+    pub fn read(target: &str) -> io::Result<Vec<JournalEntry>>
+    {
+        let file = try!(File::open(Journal::path_for(target)));
+        let mut entries = vec![];
+        for line in BufReader::new(file).lines() {
+            match parse_entry(&*try!(line)) {
+                Some(entry) => entries.push(entry),
+                None => break,
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Durably append an entry to the journal
+    pub fn record(&self, entry: &JournalEntry) -> io::Result<()>
+    {
+        let mut file = self.file.lock().unwrap();
+        try!(writeln!(file, "{} {} {}", entry.offset, entry.len, entry.md5));
+        file.sync_data()
+    }
+
+    /// Delete the journal once the target is complete
+    pub fn remove(&self) -> io::Result<()>
+    {
+        fs::remove_file(&self.path)
+    }
+}
+
+fn parse_entry(line: &str) -> Option<JournalEntry>
+{
+    let fields: Vec<&str> = line.split(' ').collect();
+    if fields.len() != 3 || fields[2].len() != 32 {
+        return None
+    }
+    match (fields[0].parse(), fields[1].parse()) {
+        (Ok(offset), Ok(len)) => Some(JournalEntry {
+            offset: offset,
+            len: len,
+            md5: fields[2].to_owned(),
+        }),
+        _ => None,
+    }
+}
+
+/// The entries whose range of the target still has the journaled md5
+pub fn verify(target: &str, entries: Vec<JournalEntry>) -> io::Result<Vec<JournalEntry>>
+{
+    let mut file = try!(File::open(os_path(target)));
+    let mut verified = vec![];
+    for entry in entries {
+        if try!(matches_entry(&mut file, &entry)) {
+            verified.push(entry);
+        } else {
+            warn!("Journaled bytes {} - {} of {} are corrupt, fetching them again",
+                  entry.offset, entry.offset + entry.len, target);
+        }
+    }
+    Ok(verified)
+}
+
+/// The entries that can be trusted after checking those `check` selects
+pub fn verify_chunks(target: &str, entries: Vec<JournalEntry>, check: VerifyChunks)
+                     -> io::Result<Vec<JournalEntry>>
+{
+    let n = match check {
+        VerifyChunks::All => return verify(target, entries),
+        VerifyChunks::Skip => return Ok(entries),
+        VerifyChunks::Sample(n) if n >= entries.len() => return verify(target, entries),
+        VerifyChunks::Sample(n) => n,
+    };
+    let mut file = try!(File::open(os_path(target)));
+    for i in 0..n {
+        let entry = &entries[i * entries.len() / n];
+        if !try!(matches_entry(&mut file, entry)) {
+            warn!("Journaled bytes {} - {} of {} are corrupt, checking every chunk",
+                  entry.offset, entry.offset + entry.len, target);
+            return verify(target, entries)
+        }
+    }
+    debug!("{} sampled chunks of {} match the journal", n, target);
+    Ok(entries)
+}
+
+/// Whether the entry's range of the file has its md5
+fn matches_entry(file: &mut File, entry: &JournalEntry) -> io::Result<bool>
+{
+    let mut buf = vec![0; 64 * 1024];
+    try!(file.seek(SeekFrom::Start(entry.offset)));
+    let mut hasher = md5::Context::new();
+    let mut left = entry.len;
+    while left > 0 {
+        let want = cmp::min(left, buf.len() as u64) as usize;
+        let n = try!(file.read(&mut buf[..want]));
+        if n == 0 {
+            break;
+        }
+        hasher.consume(&buf[..n]);
+        left -= n as u64;
+    }
+    Ok(left == 0 && format!("{:x}", hasher.compute()) == entry.md5)
+}
+
+/// Merge the entries of a journal into sorted, non-overlapping
+/// `(start, end)` ranges (`end` exclusive)
+pub fn valid_ranges(entries: &[JournalEntry]) -> Vec<(u64, u64)>
+{
+    let mut ranges: Vec<(u64, u64)> = entries.iter()
+        .map(|e| (e.offset, e.offset + e.len))
+        .collect();
+    ranges.sort();
+
+    let mut merged: Vec<(u64, u64)> = vec![];
+    for (start, end) in ranges {
+        if let Some(last) = merged.last_mut() {
+            if start <= last.1 {
+                if end > last.1 {
+                    last.1 = end;
+                }
+                continue
+            }
+        }
+        merged.push((start, end));
+    }
+    merged
+}
+
+/// Writes to a file starting at `offset`, syncing and journaling every
+/// `JOURNAL_CHUNK_SIZE` bytes by default (and on flush)
+pub struct JournaledWriter {
+    file: File,
+    journal: Arc<Journal>,
+    /// Offset of the first byte not yet journaled
+    offset: u64,
+    /// Number of bytes written but not yet journaled
+    pending: u64,
+    /// Number of bytes written between syncs
+    sync_interval: u64,
+    hasher: md5::Context,
+}
+
+impl JournaledWriter {
+    pub fn new(file: File, journal: Arc<Journal>, offset: u64) -> JournaledWriter
+    {
+        JournaledWriter {
+            file: file,
+            journal: journal,
+            offset: offset,
+            pending: 0,
+            sync_interval: JOURNAL_CHUNK_SIZE,
+            hasher: md5::Context::new(),
+        }
+    }
+
+    /// Sync and journal every `bytes` bytes instead
+    pub fn sync_every(mut self, bytes: u64) -> JournaledWriter
+    {
+        self.sync_interval = bytes;
+        self
+    }
+
+    /// Sync pending bytes to disk, then journal them
+    fn commit(&mut self) -> io::Result<()>
+    {
+        if self.pending == 0 {
+            return Ok(())
+        }
+        try!(self.file.sync_data());
+
+        let hasher = ::std::mem::replace(&mut self.hasher, md5::Context::new());
+        try!(self.journal.record(&JournalEntry {
+            offset: self.offset,
+            len: self.pending,
+            md5: format!("{:x}", hasher.compute()),
+        }));
+
+        self.offset += self.pending;
+        self.pending = 0;
+        Ok(())
+    }
+}
+
+impl Write for JournaledWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        try!(self.file.write_all(buf));
+        self.hasher.consume(buf);
+        self.pending += buf.len() as u64;
+        if self.pending >= self.sync_interval {
+            try!(self.commit());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        try!(self.file.flush());
+        self.commit()
+    }
+}
+
+/// Open a journal with `open_options`, readable only by the owner whether
+/// it is created or already there
+#[cfg(unix)]
+fn open_restricted(path: &Path, open_options: &mut OpenOptions) -> io::Result<File>
+{
+    use std::os::unix::fs::{
+        OpenOptionsExt,
+        PermissionsExt,
+    };
+    let file = try!(open_options.mode(JOURNAL_FILE_MODE).open(path));
+    try!(file.set_permissions(fs::Permissions::from_mode(JOURNAL_FILE_MODE)));
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn open_restricted(path: &Path, open_options: &mut OpenOptions) -> io::Result<File>
+{
+    open_options.open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        valid_ranges,
+        verify_chunks,
+        Journal,
+        JournalEntry,
+        VerifyChunks,
+    };
+    use md5;
+    use std::env;
+    use std::fs::{
+        self,
+        OpenOptions,
+    };
+    use std::io::Write;
+    use std::process;
+
+    /// A target path in the temp directory, with its journal removed
+    fn target(name: &str) -> String
+    {
+        let path = env::temp_dir().join(format!("sledge-journal-{}-{}", name, process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let _ = fs::remove_file(Journal::path_for(&*path));
+        path
+    }
+
+    fn entry(data: &[u8], offset: u64, len: u64) -> JournalEntry
+    {
+        let bytes = &data[offset as usize..(offset + len) as usize];
+        JournalEntry { offset: offset, len: len, md5: format!("{:x}", md5::compute(bytes)) }
+    }
+
+    fn clean_up(target: &str)
+    {
+        let _ = fs::remove_file(target);
+        let _ = fs::remove_file(Journal::path_for(target));
+    }
+
+    #[test]
+    fn records_are_read_back()
+    {
+        let target = target("record");
+        let data = b"0123456789";
+        let entries = vec![entry(data, 0, 4), entry(data, 4, 6)];
+        {
+            let journal = Journal::create(&*target).unwrap();
+            journal.record(&entries[0]).unwrap();
+        }
+        // Continuing keeps the entries, creating starts over
+        Journal::open(&*target).unwrap().record(&entries[1]).unwrap();
+        assert_eq!(Journal::read(&*target).unwrap(), entries);
+        Journal::create(&*target).unwrap();
+        assert_eq!(Journal::read(&*target).unwrap(), vec![]);
+        clean_up(&*target);
+    }
+
+    #[test]
+    fn truncated_trailing_entry_is_ignored()
+    {
+        let target = target("truncated");
+        let data = b"0123456789";
+        let first = entry(data, 0, 5);
+        let second = entry(data, 5, 5);
+        Journal::create(&*target).unwrap().record(&first).unwrap();
+        {
+            let mut file = OpenOptions::new().append(true).open(Journal::path_for(&*target)).unwrap();
+            write!(file, "{} {} {}", second.offset, second.len, &second.md5[..20]).unwrap();
+        }
+        assert_eq!(Journal::read(&*target).unwrap(), vec![first.clone()]);
+
+        // Nor is anything after a line that isn't an entry
+        Journal::create(&*target).unwrap();
+        {
+            let mut file = OpenOptions::new().append(true).open(Journal::path_for(&*target)).unwrap();
+            write!(file, "{} {}\n{} {} {}\n", first.offset, first.len,
+                   second.offset, second.len, second.md5).unwrap();
+        }
+        assert_eq!(Journal::read(&*target).unwrap(), vec![]);
+        clean_up(&*target);
+    }
+
+    #[test]
+    fn missing_journal_is_an_error()
+    {
+        assert!(Journal::read(&*target("missing")).is_err());
+    }
+
+    #[test]
+    fn ranges_are_merged()
+    {
+        let data = [0; 32];
+        assert_eq!(valid_ranges(&[]), vec![]);
+        assert_eq!(valid_ranges(&[entry(&data, 8, 4), entry(&data, 0, 4), entry(&data, 4, 4)]),
+                   vec![(0, 12)]);
+        assert_eq!(valid_ranges(&[entry(&data, 20, 4), entry(&data, 0, 8), entry(&data, 4, 2)]),
+                   vec![(0, 8), (20, 24)]);
+        assert_eq!(valid_ranges(&[entry(&data, 0, 10), entry(&data, 6, 10)]), vec![(0, 16)]);
+    }
+
+    #[test]
+    fn corrupt_entries_and_entries_beyond_the_end_are_dropped()
+    {
+        let target = target("verify");
+        let data = b"abcdefghijklmnop";
+        let entries = vec![entry(data, 0, 4), entry(data, 4, 4), entry(data, 8, 4), entry(data, 12, 4)];
+        // Shorter than the journal says, and with a corrupt chunk
+        fs::write(&*target, b"abcdXfghijkl").unwrap();
+
+        let verified = verify_chunks(&*target, entries.clone(), VerifyChunks::All).unwrap();
+        assert_eq!(verified, vec![entries[0].clone(), entries[2].clone()]);
+        // Sampling a corrupt chunk checks them all
+        let verified = verify_chunks(&*target, entries.clone(), VerifyChunks::Sample(3)).unwrap();
+        assert_eq!(verified, vec![entries[0].clone(), entries[2].clone()]);
+        // Sampling only intact chunks trusts the rest
+        let verified = verify_chunks(&*target, entries.clone(), VerifyChunks::Sample(2)).unwrap();
+        assert_eq!(verified, entries);
+        assert_eq!(verify_chunks(&*target, entries.clone(), VerifyChunks::Skip).unwrap(), entries);
+        clean_up(&*target);
+    }
+
+    #[test]
+    fn parses_verify_chunks()
+    {
+        assert_eq!("all".parse::<VerifyChunks>(), Ok(VerifyChunks::All));
+        assert_eq!("none".parse::<VerifyChunks>(), Ok(VerifyChunks::Skip));
+        assert_eq!("3".parse::<VerifyChunks>(), Ok(VerifyChunks::Sample(3)));
+        assert!("some".parse::<VerifyChunks>().is_err());
+    }
+}
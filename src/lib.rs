@@ -4,12 +4,78 @@
 extern crate hyper;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "pbr")]
 extern crate pbr;
+#[cfg(feature = "indicatif")]
+extern crate indicatif;
 
+extern crate chrono;
 extern crate env_logger;
+extern crate flate2;
+extern crate idna;
+#[cfg(unix)]
+extern crate libc;
+extern crate md5;
+extern crate rsa;
+extern crate sha1;
+extern crate sha2;
+extern crate xz2;
+extern crate zstd;
 
 pub const DEFAULT_BUFF_SIZE: usize = 1 * 1024 * 1024;  // 1 MB
 
+pub mod azure;
+pub mod blocks;
+pub mod cassette;
+pub mod checksum;
+pub mod cleanup;
+pub mod client;
+pub mod cloud;
+pub mod config;
+pub mod cookies;
+pub mod decompress;
+pub mod dns;
 pub mod download;
 pub mod errors;
+#[cfg(feature = "testing")]
+pub mod fault;
+pub mod filter;
+pub mod frontend;
+pub mod ftp;
+pub mod gcs;
+pub mod hls;
+pub mod http_reader;
+pub mod journal;
+pub mod listing;
+pub mod manifest;
+pub mod metadata;
+pub mod metalink;
+pub mod names;
+pub mod netfs;
+pub mod netrc;
+pub mod ordered;
+pub mod owner;
+pub mod outcome;
+pub mod paths;
+pub mod plan;
+pub mod progress;
+pub mod proxy;
+pub mod ratelimit;
 pub mod reporter;
+pub mod retry;
+pub mod s3;
+pub mod schedule;
+pub mod schemes;
+pub mod selftest;
+pub mod shaping;
+pub mod state;
+pub mod stats;
+pub mod sync;
+pub mod template;
+pub mod throttle;
+pub mod token;
+pub mod torrent;
+pub mod units;
+pub mod urls;
+pub mod usage;
+pub mod zip;
@@ -0,0 +1,391 @@
+//! Listing the files under a remote directory
+//!
+//! Two kinds of listing are understood: the HTML index pages generated by
+//! web servers (e.g. Apache's mod_autoindex or nginx's autoindex), which
+//! are followed recursively, and `ListObjectsV2` results for buckets
+//! addressed as `s3://<bucket>/<prefix>/`, `gs://<bucket>/<prefix>/` or
+//! `https://<bucket>.s3.amazonaws.com/<prefix>/`. Google Cloud Storage is
+//! listed through its S3 compatible XML API. `s3://` and `gs://` buckets are
+//! listed with signed or authenticated requests, and their objects are
+//! listed as `s3://` or `gs://` urls so that they are downloaded with such
+//! requests too.
+
+use chrono::DateTime;
+use client::ClientConfig;
+use download::{
+    get,
+    head,
+};
+use errors::DownloadError;
+use gcs::{
+    self,
+    GsUrl,
+};
+use hyper::Url;
+use hyper::header::{
+    ContentLength,
+    Headers,
+};
+use s3::{
+    self,
+    S3Url,
+};
+use std::io::Read;
+use std::str;
+
+
+/// Maximum depth of subdirectories followed in an index
+pub const MAX_DEPTH: usize = 16;
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct RemoteEntry {
+    /// Path relative to the listed directory, `/` separated
+    pub path: String,
+    pub url: String,
+    pub size: Option<u64>,
+    /// Last modification as seconds since the epoch
+    pub modified: Option<i64>,
+    /// The entity tag, without quotes
+    pub etag: Option<String>,
+}
+
+impl RemoteEntry {
+    /// The md5 of the contents if the ETag is one (as S3 uses for objects
+    /// that weren't uploaded in parts)
+    pub fn etag_md5(&self) -> Option<String>
+    {
+        match self.etag {
+            Some(ref etag) if etag.len() == 32 && etag.chars().all(|c| c.is_digit(16)) => {
+                Some(etag.to_lowercase())
+            },
+            _ => None,
+        }
+    }
+}
+
+/// List the files under the remote directory `url`
+pub fn list(client: &ClientConfig, url: &str) -> Result<Vec<RemoteEntry>, DownloadError>
+{
+    if url.starts_with("s3://") {
+        return list_s3_url(client, url)
+    }
+    if url.starts_with("gs://") {
+        return list_gs_url(client, url)
+    }
+    let base = try!(directory_url(url));
+    match bucket(&base) {
+        Some((bucket, prefix)) => list_s3(client, &bucket, &prefix),
+        None => list_index(client, &base, "", 0),
+    }
+}
+
+/// The url with a trailing slash, so relative links resolve beneath it
+fn directory_url(url: &str) -> Result<Url, DownloadError>
+{
+    let mut url = try!(Url::parse(url).map_err(|e| {
+        DownloadError::invalid_url(url, e.to_string())
+    }));
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&*path);
+    }
+    Ok(url)
+}
+
+/// Follow an HTML index, HEADing each file for its metadata
+fn list_index(client: &ClientConfig, dir: &Url, prefix: &str, depth: usize)
+              -> Result<Vec<RemoteEntry>, DownloadError>
+{
+    debug!("Listing {}", dir);
+    let html = try!(get_text(client, dir.as_str()));
+
+    let mut entries = vec![];
+    for link in index_links(&*html) {
+        let url = match dir.join(&*link) {
+            Ok(url) => url,
+            Err(_) => continue,
+        };
+        let name = percent_decode(link.trim_end_matches('/'));
+        let path = format!("{}{}", prefix, name);
+
+        if link.ends_with('/') {
+            if depth < MAX_DEPTH {
+                let children = try!(list_index(client, &url, &*format!("{}/", path), depth + 1));
+                entries.extend(children);
+            } else {
+                warn!("Not following {}, the index is nested too deeply", url);
+            }
+            continue;
+        }
+
+        let (response, _) = try!(head(client, url.as_str(), Headers::new()));
+        entries.push(RemoteEntry {
+            path: path,
+            url: url.to_string(),
+            size: response.headers.get::<ContentLength>().map(|l| l.0),
+            modified: raw_header(&response.headers, "Last-Modified")
+                .and_then(|date| DateTime::parse_from_rfc2822(&*date).ok())
+                .map(|date| date.timestamp()),
+            etag: raw_header(&response.headers, "ETag").map(|tag| unquote(&*tag)),
+        });
+    }
+    Ok(entries)
+}
+
+/// The links of an index page that lead to its files and subdirectories
+pub fn index_links(html: &str) -> Vec<String>
+{
+    let mut links = vec![];
+    let mut rest = html;
+    while let Some(start) = rest.find("href=") {
+        rest = &rest[start + 5..];
+        let quote = match rest.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => quote,
+            _ => continue,
+        };
+        let end = match rest[1..].find(quote) {
+            Some(end) => end + 1,
+            None => break,
+        };
+        let link = unescape(&rest[1..end]);
+        rest = &rest[end..];
+
+        // Skip sorting links, parents, absolute paths and other sites
+        let relative = !link.is_empty()
+            && !link.starts_with('?')
+            && !link.starts_with('#')
+            && !link.starts_with('/')
+            && !link.starts_with('.')
+            && !link.contains(':');
+        let name = link.trim_end_matches('/');
+        if relative && !name.contains('/') && !links.contains(&link) {
+            links.push(link);
+        }
+    }
+    links
+}
+
+/// Whether `url` names a bucket prefix (`s3://` or `gs://`) rather than
+/// something that can be downloaded directly. Bucket urls name an object
+/// unless they end with a slash or are of the bucket itself.
+pub fn is_bucket_url(url: &str) -> bool
+{
+    let key = S3Url::parse(url).map(|s3_url| s3_url.key)
+        .or_else(|_| GsUrl::parse(url).map(|gs_url| gs_url.object));
+    match key {
+        Ok(key) => key.is_empty() || key.ends_with('/'),
+        Err(_) => false,
+    }
+}
+
+/// List the objects under an `s3://` prefix with signed requests
+fn list_s3_url(client: &ClientConfig, url: &str) -> Result<Vec<RemoteEntry>, DownloadError>
+{
+    let s3_url = try!(S3Url::parse(url).map_err(|e| DownloadError::invalid_url(url, e)));
+    let bucket = S3Url { bucket: s3_url.bucket.clone(), key: String::new() };
+    let (base, client) = try!(s3::resolve(client, &bucket).map_err(|e| {
+        DownloadError::invalid_url(url, e)
+    }));
+    let mut entries = try!(list_s3(&client, &base, &*s3_url.key));
+    for entry in &mut entries {
+        entry.url = format!("s3://{}/{}{}", s3_url.bucket, s3_url.key, entry.path);
+    }
+    Ok(entries)
+}
+
+/// List the objects under a `gs://` prefix with authenticated requests
+fn list_gs_url(client: &ClientConfig, url: &str) -> Result<Vec<RemoteEntry>, DownloadError>
+{
+    let gs_url = try!(GsUrl::parse(url).map_err(|e| DownloadError::invalid_url(url, e)));
+    let bucket = GsUrl { bucket: gs_url.bucket.clone(), object: String::new() };
+    let (base, client) = try!(gcs::resolve(client, &bucket).map_err(|e| {
+        DownloadError::invalid_url(url, e)
+    }));
+    let mut entries = try!(list_s3(&client, &base, &*gs_url.object));
+    for entry in &mut entries {
+        entry.url = format!("gs://{}/{}{}", gs_url.bucket, gs_url.object, entry.path);
+    }
+    Ok(entries)
+}
+
+/// The url objects in a bucket are found under and the key prefix, if
+/// `url` addresses a bucket
+fn bucket(url: &Url) -> Option<(Url, String)>
+{
+    let host = match url.host_str() {
+        Some(host) => host.to_owned(),
+        None => return None,
+    };
+    let prefix = percent_decode(url.path().trim_start_matches('/'));
+    if !(host.ends_with(".amazonaws.com") && host.contains(".s3")) {
+        return None
+    }
+    let base = format!("{}://{}/", url.scheme(), host);
+    Url::parse(&*base).ok().map(|base| (base, prefix))
+}
+
+/// List the objects under `prefix`, following continuation tokens
+fn list_s3(client: &ClientConfig, bucket: &Url, prefix: &str)
+           -> Result<Vec<RemoteEntry>, DownloadError>
+{
+    let mut entries = vec![];
+    let mut token: Option<String> = None;
+    loop {
+        // Buckets addressed by path are listed without the trailing slash
+        let mut url = bucket.clone();
+        if url.path() != "/" {
+            let path = url.path().trim_end_matches('/').to_owned();
+            url.set_path(&*path);
+        }
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("list-type", "2").append_pair("prefix", prefix);
+            if let Some(ref token) = token {
+                query.append_pair("continuation-token", token);
+            }
+        }
+        debug!("Listing {}", url);
+        let xml = try!(get_text(client, url.as_str()));
+        let (page, next) = try!(parse_s3_listing(bucket, prefix, &*xml));
+        entries.extend(page);
+        match next {
+            Some(next) => token = Some(next),
+            None => return Ok(entries),
+        }
+    }
+}
+
+/// Parse a page of `ListObjectsV2` results, returning its objects and the
+/// token for the next page if the results were truncated
+pub fn parse_s3_listing(bucket: &Url, prefix: &str, xml: &str)
+                        -> Result<(Vec<RemoteEntry>, Option<String>), DownloadError>
+{
+    if !xml.contains("<ListBucketResult") {
+        return Err(DownloadError::other("response is not an S3 bucket listing"))
+    }
+
+    let mut entries = vec![];
+    for object in elements(xml, "Contents") {
+        let key = match elements(object, "Key").first() {
+            Some(key) => unescape(key),
+            None => continue,
+        };
+        // Directory placeholders have keys ending in a slash
+        if key.ends_with('/') {
+            continue;
+        }
+        let url = try!(bucket.join(&*percent_encode_path(&*key)).map_err(|e| {
+            DownloadError::other(format!("invalid key {}: {}", key, e))
+        }));
+        let path = if key.starts_with(prefix) { &key[prefix.len()..] } else { &*key };
+        entries.push(RemoteEntry {
+            path: path.trim_start_matches('/').to_owned(),
+            url: url.to_string(),
+            size: elements(object, "Size").first().and_then(|s| s.parse().ok()),
+            modified: elements(object, "LastModified").first()
+                .and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+                .map(|date| date.timestamp()),
+            etag: elements(object, "ETag").first().map(|tag| unquote(&*unescape(tag))),
+        });
+    }
+
+    let truncated = elements(xml, "IsTruncated").first() == Some(&"true");
+    let next = match elements(xml, "NextContinuationToken").first() {
+        Some(token) if truncated => Some(unescape(token)),
+        _ => None,
+    };
+    Ok((entries, next))
+}
+
+fn get_text(client: &ClientConfig, url: &str) -> Result<String, DownloadError>
+{
+    let (mut response, _) = try!(get(client, url, Headers::new()));
+    let mut body = String::new();
+    try!(response.read_to_string(&mut body));
+    Ok(body)
+}
+
+fn raw_header(headers: &Headers, name: &str) -> Option<String>
+{
+    headers.get_raw(name)
+        .and_then(|lines| lines.first())
+        .map(|line| String::from_utf8_lossy(line).trim().to_owned())
+}
+
+/// The contents of each `<tag>...</tag>` element
+fn elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str>
+{
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut found = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find(&*open) {
+        rest = &rest[start + open.len()..];
+        match rest.find(&*close) {
+            Some(end) => {
+                found.push(&rest[..end]);
+                rest = &rest[end + close.len()..];
+            },
+            None => break,
+        }
+    }
+    found
+}
+
+/// Strip the quotes (and weak validator prefix) from an ETag
+fn unquote(etag: &str) -> String
+{
+    etag.trim_start_matches("W/").trim_matches('"').to_owned()
+}
+
+/// Replace the XML/HTML entities that appear in names
+fn unescape(text: &str) -> String
+{
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn percent_decode(text: &str) -> String
+{
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = if bytes[i] == b'%' && i + 2 < bytes.len() {
+            str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Percent encode a key for use as a url path, keeping its slashes
+fn percent_encode_path(key: &str) -> String
+{
+    let mut encoded = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            },
+            _ => encoded.push_str(&*format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
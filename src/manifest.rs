@@ -0,0 +1,128 @@
+//! Manifests of files to keep in sync
+//!
+//! Each line of a manifest holds the hex md5 of a file, the url it is
+//! downloaded from and optionally the path to save it to (the last segment
+//! of the url by default), separated by whitespace. Lines starting with `#` are comments.
+//! Syncing only downloads files that are missing or whose md5 differs, so
+//! it can safely be re-run.
+
+use md5;
+use std::fs::File;
+use std::io::{
+    self,
+    Read,
+};
+use std::path::Path;
+
+use outcome::{
+    BatchOutcome,
+    Status,
+};
+
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct ManifestEntry {
+    /// Expected hex md5 of the file
+    pub md5: String,
+    pub url: String,
+    /// Where the file is saved
+    pub path: String,
+}
+
+/// Read the manifest at `path`
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<ManifestEntry>, String>
+{
+    let path = path.as_ref();
+    let mut contents = String::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_string(&mut contents)).map_err(|e| {
+        format!("unable to read manifest {}: {}", path.display(), e)
+    }));
+    parse(&*contents)
+}
+
+/// Parse the contents of a manifest
+pub fn parse(contents: &str) -> Result<Vec<ManifestEntry>, String>
+{
+    let mut entries = vec![];
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 2 || fields.len() > 3 {
+            return Err(format!("line {}: expected '<md5> <url> [path]'", n + 1))
+        }
+        let md5 = fields[0].to_lowercase();
+        if md5.len() != 32 || !md5.chars().all(|c| c.is_digit(16)) {
+            return Err(format!("line {}: '{}' is not an md5", n + 1, fields[0]))
+        }
+        let path = match fields.get(2) {
+            Some(path) => path.to_string(),
+            None => try!(default_path(fields[1]).ok_or_else(|| {
+                format!("line {}: no file name in '{}', give a path", n + 1, fields[1])
+            })),
+        };
+
+        entries.push(ManifestEntry { md5: md5, url: fields[1].to_owned(), path: path });
+    }
+    Ok(entries)
+}
+
+/// The last segment of the url's path, ignoring any query or fragment
+fn default_path(url: &str) -> Option<String>
+{
+    let url = url.split(|c| c == '?' || c == '#').next().unwrap_or("");
+    let rest = url.splitn(2, "://").last().unwrap_or("");
+    let path = match rest.find('/') {
+        Some(i) => &rest[i..],
+        None => return None,
+    };
+    match path.rsplit('/').next() {
+        Some(name) if !name.is_empty() => Some(name.to_owned()),
+        _ => None,
+    }
+}
+
+/// Hex md5 of the file at `path`, or None if it doesn't exist
+pub fn file_md5<P: AsRef<Path>>(path: P) -> io::Result<Option<String>>
+{
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut hasher = md5::Context::new();
+    let mut buf = vec![0; ::DEFAULT_BUFF_SIZE];
+    loop {
+        let n = try!(file.read(&mut buf));
+        if n == 0 {
+            break;
+        }
+        hasher.consume(&buf[..n]);
+    }
+    Ok(Some(format!("{:x}", hasher.compute())))
+}
+
+/// Whether the file for `entry` is missing or differs from the manifest
+pub fn needs_sync(entry: &ManifestEntry) -> io::Result<bool>
+{
+    Ok(try!(file_md5(&*entry.path)).as_ref() != Some(&entry.md5))
+}
+
+/// One `<changed|unchanged|failed>\t<url>` line per item of a sync
+pub fn sync_summary(outcome: &BatchOutcome) -> String
+{
+    let lines: Vec<String> = outcome.outcomes.iter().map(|o| {
+        let label = match o.status {
+            Status::Succeeded(_) | Status::Duplicate(_) => "changed",
+            Status::Unchanged => "unchanged",
+            Status::Failed(_) => "failed",
+            Status::Skipped => "skipped",
+        };
+        format!("{}\t{}", label, o.url)
+    }).collect();
+    lines.join("\n")
+}
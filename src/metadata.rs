@@ -0,0 +1,94 @@
+//! Metadata of probed urls, cached for the rest of a run
+//!
+//! A download probes its source (see `Probe`) for the size, range support
+//! and validators like the ETag, and may do so again when it is replanned,
+//! e.g. to continue a partial file and then download in parallel. Retried
+//! items of a batch would probe again too. The cache keeps the headers of
+//! the first probe of each url so later ones are answered without a
+//! request.
+
+use download::Redirect;
+use hyper::client::response::Response;
+use hyper::header::Headers;
+use hyper::status::StatusCode;
+use hyper::Url;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+
+/// The parts of a response describing the file, without its body
+#[derive(Clone,Debug)]
+pub struct Metadata {
+    /// Url that responded, after any redirects
+    pub url: Url,
+    pub status: StatusCode,
+    pub headers: Headers,
+}
+
+impl Metadata {
+
+    /// The metadata of `response`, which is left unread
+    pub fn of(response: &Response) -> Metadata
+    {
+        Metadata {
+            url: response.url.clone(),
+            status: response.status,
+            headers: response.headers.clone(),
+        }
+    }
+}
+
+#[derive(Clone,Debug)]
+struct Entry {
+    metadata: Metadata,
+    redirects: Vec<Redirect>,
+    /// Whether a range request was honored, once one has been tried
+    ranges: Option<bool>,
+}
+
+#[derive(Debug,Default)]
+pub struct MetadataCache {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl MetadataCache {
+
+    pub fn new() -> MetadataCache
+    {
+        MetadataCache::default()
+    }
+
+    /// The metadata and redirects of an earlier probe of `url`
+    pub fn get(&self, url: &str) -> Option<(Metadata, Vec<Redirect>)>
+    {
+        self.entries.lock().unwrap().get(url)
+            .map(|entry| (entry.metadata.clone(), entry.redirects.clone()))
+    }
+
+    /// Record the result of probing `url`
+    pub fn insert(&self, url: &str, metadata: Metadata, redirects: Vec<Redirect>)
+    {
+        let entry = Entry { metadata: metadata, redirects: redirects, ranges: None };
+        self.entries.lock().unwrap().insert(url.to_owned(), entry);
+    }
+
+    /// Whether `url` honored a range request, if one has been tried
+    pub fn ranges(&self, url: &str) -> Option<bool>
+    {
+        self.entries.lock().unwrap().get(url).and_then(|entry| entry.ranges)
+    }
+
+    /// Record whether `url` honored a range request
+    pub fn set_ranges(&self, url: &str, ranges: bool)
+    {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(url) {
+            entry.ranges = Some(ranges);
+        }
+    }
+
+    /// Forget `url`, e.g. because it changed, so it is probed again
+    pub fn forget(&self, url: &str)
+    {
+        self.entries.lock().unwrap().remove(url);
+    }
+}
@@ -0,0 +1,105 @@
+//! Mirror and hash discovery from Metalink/HTTP headers (RFC 6249)
+//!
+//! Well configured mirror servers advertise other copies of a file with
+//! `Link: <url>; rel=duplicate` headers and its hashes with RFC 3230
+//! `Digest: SHA-256=...` headers.
+
+use hyper::header::Headers;
+
+
+/// Another location the same file can be downloaded from
+#[derive(Clone,Debug,PartialEq)]
+pub struct Mirror {
+    pub url: String,
+    /// Priority advertised by the server (lower is preferred)
+    pub pri: Option<u32>,
+}
+
+/// A hash of the file advertised by the server
+#[derive(Clone,Debug,PartialEq)]
+pub struct Digest {
+    /// Lowercase algorithm name, e.g. `sha-256` or `md5`
+    pub algorithm: String,
+    /// Base64 encoded digest, as sent by the server
+    pub value: String,
+}
+
+/// The mirrors advertised in the `Link` headers, most preferred first
+pub fn mirrors(headers: &Headers) -> Vec<Mirror>
+{
+    let mut mirrors: Vec<Mirror> = raw_values(headers, "Link").iter()
+        .flat_map(|value| parse_links(value))
+        .collect();
+    mirrors.sort_by_key(|m| m.pri.unwrap_or(u32::max_value()));
+    mirrors
+}
+
+/// The digests advertised in the `Digest` headers
+pub fn digests(headers: &Headers) -> Vec<Digest>
+{
+    raw_values(headers, "Digest").iter()
+        .flat_map(|value| parse_digests(value))
+        .collect()
+}
+
+fn raw_values(headers: &Headers, name: &str) -> Vec<String>
+{
+    match headers.get_raw(name) {
+        Some(lines) => lines.iter()
+            .map(|line| String::from_utf8_lossy(line).into_owned())
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// Parse the `rel=duplicate` links of a `Link` header value
+pub fn parse_links(value: &str) -> Vec<Mirror>
+{
+    let mut mirrors = vec![];
+    let mut rest = value;
+
+    while let Some(open) = rest.find('<') {
+        let close = match rest[open..].find('>') {
+            Some(close) => open + close,
+            None => break,
+        };
+        let url = rest[open + 1..close].trim().to_owned();
+
+        // Parameters run until the next link
+        rest = &rest[close + 1..];
+        let end = rest.find('<').unwrap_or(rest.len());
+        let params = &rest[..end];
+        rest = &rest[end..];
+
+        let mut duplicate = false;
+        let mut pri = None;
+        for param in params.split(|c| c == ';' || c == ',') {
+            let mut kv = param.splitn(2, '=');
+            let key = kv.next().unwrap_or("").trim().to_lowercase();
+            let value = kv.next().unwrap_or("").trim().trim_matches('"');
+            match &*key {
+                "rel" => duplicate = value.split_whitespace().any(|r| r == "duplicate"),
+                "pri" => pri = value.parse().ok(),
+                _ => (),
+            }
+        }
+
+        if duplicate {
+            mirrors.push(Mirror { url: url, pri: pri });
+        }
+    }
+
+    mirrors
+}
+
+/// Parse a `Digest` header value, e.g. `SHA-256=X48E9q...=, MD5=HUXZ...==`
+pub fn parse_digests(value: &str) -> Vec<Digest>
+{
+    value.split(',').filter_map(|part| {
+        let part = part.trim();
+        part.find('=').map(|eq| Digest {
+            algorithm: part[..eq].trim().to_lowercase(),
+            value: part[eq + 1..].trim().to_owned(),
+        })
+    }).collect()
+}
@@ -0,0 +1,103 @@
+//! Disambiguation of default file names within a batch
+//!
+//! When several URLs in a batch resolve to the same default file name,
+//! later downloads would overwrite earlier ones. A `FileNames` registry
+//! shared by the batch hands out unique names instead.
+
+use std::str::FromStr;
+use std::sync::Mutex;
+
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Disambiguate {
+    /// Append a number to the name, e.g. `file-1.iso`
+    Numbered,
+    /// Prefix the name with the host it came from, e.g. `example.com_file.iso`
+    Host,
+}
+
+impl FromStr for Disambiguate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Disambiguate, String>
+    {
+        match s {
+            "numbered" => Ok(Disambiguate::Numbered),
+            "host" => Ok(Disambiguate::Host),
+            _ => Err(format!("unknown strategy '{}', expected 'numbered' or 'host'", s)),
+        }
+    }
+}
+
+#[derive(Clone,Debug)]
+pub struct NameMapping {
+    /// The url that was downloaded
+    pub url: String,
+    /// The default file name the url resolved to
+    pub requested: String,
+    /// The file name that was actually used
+    pub name: String,
+}
+
+pub struct FileNames {
+    strategy: Disambiguate,
+    claimed: Mutex<Vec<NameMapping>>,
+}
+
+impl FileNames {
+
+    /// Create an empty registry using the given strategy
+    pub fn new(strategy: Disambiguate) -> FileNames
+    {
+        FileNames {
+            strategy: strategy,
+            claimed: Mutex::new(vec![]),
+        }
+    }
+
+    /// Claim a unique file name for `url`, which defaults to `name`
+    pub fn claim(&self, url: &str, host: Option<&str>, name: &str) -> String
+    {
+        let mut claimed = self.claimed.lock().unwrap();
+        let taken = |candidate: &str| claimed.iter().any(|m| m.name == candidate);
+
+        let mut unique = name.to_owned();
+        if taken(&unique) && self.strategy == Disambiguate::Host {
+            if let Some(host) = host {
+                unique = format!("{}_{}", host, name);
+            }
+        }
+
+        let base = unique.clone();
+        let mut n = 1;
+        while taken(&unique) {
+            unique = numbered(&base, n);
+            n += 1;
+        }
+
+        claimed.push(NameMapping {
+            url: url.to_owned(),
+            requested: name.to_owned(),
+            name: unique.clone(),
+        });
+        unique
+    }
+
+    /// The names claimed that differ from their default
+    pub fn renamed(&self) -> Vec<NameMapping>
+    {
+        self.claimed.lock().unwrap().iter()
+            .filter(|m| m.name != m.requested)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Insert a number before the extension(s), e.g. `file.tar.gz` -> `file-1.tar.gz`
+pub fn numbered(name: &str, n: usize) -> String
+{
+    match name.char_indices().skip(1).find(|&(_, c)| c == '.') {
+        Some((i, _)) => format!("{}-{}{}", &name[..i], n, &name[i..]),
+        None => format!("{}-{}", name, n),
+    }
+}
@@ -0,0 +1,94 @@
+//! Writing to network filesystems
+//!
+//! On NFS or SMB every small positioned write, and every fsync after a
+//! journaled chunk, costs a round trip to the file server. Targets on a
+//! network filesystem are written in larger sequential batches and synced
+//! less often.
+
+use std::path::Path;
+
+#[cfg(target_os = "linux")]
+use libc;
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+#[cfg(target_os = "linux")]
+use std::mem;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
+
+
+/// Default bytes buffered before writing to a network filesystem
+pub const DEFAULT_WRITE_BATCH: usize = 8 * 1024 * 1024;  // 8 MB
+
+/// Default bytes written between syncs on a network filesystem
+pub const DEFAULT_SYNC_INTERVAL: u64 = 64 * 1024 * 1024;  // 64 MB
+
+// Filesystem magic numbers reported by statfs(2)
+#[cfg(target_os = "linux")]
+const NETWORK_MAGIC: &'static [u32] = &[
+    0x6969,      // NFS
+    0x517b,      // SMB
+    0xff534d42,  // CIFS
+    0xfe534d42,  // SMB2
+    0x564c,      // NCP
+    0x73757245,  // Coda
+    0x5346414f,  // AFS
+    0x47504653,  // GPFS
+    0x0bd00bd0,  // Lustre
+];
+
+/// How targets on a network filesystem are written
+#[derive(Clone,Copy,Debug)]
+pub struct NetworkWrites {
+    /// Bytes buffered before writing
+    pub batch: usize,
+    /// Bytes written between syncs of the journaled target
+    pub sync_interval: u64,
+}
+
+impl Default for NetworkWrites {
+    fn default() -> NetworkWrites
+    {
+        NetworkWrites {
+            batch: DEFAULT_WRITE_BATCH,
+            sync_interval: DEFAULT_SYNC_INTERVAL,
+        }
+    }
+}
+
+/// Whether a file (which need not exist yet) is on a network filesystem
+pub fn is_network_fs(path: &Path) -> bool
+{
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs_magic(dir).map_or(false, |magic| NETWORK_MAGIC.contains(&magic))
+}
+
+#[cfg(target_os = "linux")]
+fn fs_magic(dir: &Path) -> Option<u32>
+{
+    let path = match CString::new(dir.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return None,
+    };
+    unsafe {
+        let mut stat: libc::statfs = mem::zeroed();
+        if libc::statfs(path.as_ptr(), &mut stat) != 0 {
+            return None
+        }
+        // f_type is signed on some architectures
+        Some(stat.f_type as u32)
+    }
+}
+
+/// Other platforms are assumed to write locally
+#[cfg(not(target_os = "linux"))]
+fn fs_magic(_dir: &Path) -> Option<u32>
+{
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+const NETWORK_MAGIC: &'static [u32] = &[];
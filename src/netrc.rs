@@ -0,0 +1,211 @@
+//! Credentials from a `.netrc` file
+//!
+//! As with curl and wget, a download given no credentials of its own uses
+//! the `login` and `password` of the `machine` entry for the host of its url,
+//! or of the `default` entry. The file is `~/.netrc` unless `--netrc-file`
+//! names another:
+//!
+//! ```text
+//! machine example.com login alice password s3cret
+//! default login anonymous password guest
+//! ```
+//!
+//! `#` starts a comment and `macdef` definitions are skipped.
+
+use std::env;
+use std::fs::File;
+use std::io::{
+    self,
+    Read,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+
+#[derive(Clone,Debug,Default,PartialEq)]
+struct Entry {
+    login: Option<String>,
+    password: Option<String>,
+}
+
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Netrc {
+    /// Entries for each machine, in the order of the file
+    machines: Vec<(String, Entry)>,
+    default: Option<Entry>,
+}
+
+impl Netrc {
+
+    /// Location of the user's netrc file
+    pub fn default_path() -> Option<PathBuf>
+    {
+        env::var("HOME").ok().map(|home| PathBuf::from(home).join(".netrc"))
+    }
+
+    /// Load the netrc file at `path`
+    pub fn load(path: &Path) -> io::Result<Netrc>
+    {
+        let mut contents = String::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut contents)));
+        Netrc::parse(&*contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Parse the contents of a netrc file
+    pub fn parse(contents: &str) -> Result<Netrc, String>
+    {
+        let mut netrc = Netrc::default();
+        let mut tokens = tokenize(contents).into_iter();
+        // The entry being read: the machine's name, or None for the default
+        let mut current: Option<(Option<String>, Entry)> = None;
+        while let Some(token) = tokens.next() {
+            match token {
+                "machine" | "default" => {
+                    netrc.finish(current.take());
+                    let name = if token == "machine" {
+                        match tokens.next() {
+                            Some(name) => Some(name.to_lowercase()),
+                            None => return Err("'machine' is missing a name".to_owned()),
+                        }
+                    } else {
+                        None
+                    };
+                    current = Some((name, Entry::default()));
+                },
+                "login" | "password" | "account" => {
+                    let value = match tokens.next() {
+                        Some(value) => value.to_owned(),
+                        None => return Err(format!("'{}' is missing a value", token)),
+                    };
+                    let entry = match current {
+                        Some((_, ref mut entry)) => entry,
+                        None => return Err(format!("'{}' outside of a machine entry", token)),
+                    };
+                    match token {
+                        "login" => entry.login = Some(value),
+                        "password" => entry.password = Some(value),
+                        _ => (),
+                    }
+                },
+                _ => return Err(format!("unexpected '{}'", token)),
+            }
+        }
+        netrc.finish(current);
+        Ok(netrc)
+    }
+
+    fn finish(&mut self, entry: Option<(Option<String>, Entry)>)
+    {
+        match entry {
+            Some((Some(name), entry)) => self.machines.push((name, entry)),
+            // Only the first default counts
+            Some((None, entry)) => if self.default.is_none() { self.default = Some(entry) },
+            None => (),
+        }
+    }
+
+    /// The login and password for `host`, from its first entry or the
+    /// default. An entry without a login gives none.
+    pub fn credentials(&self, host: &str) -> Option<(String, Option<String>)>
+    {
+        let host = host.to_lowercase();
+        let entry = self.machines.iter()
+            .find(|&&(ref name, _)| *name == host)
+            .map(|&(_, ref entry)| entry)
+            .or(self.default.as_ref());
+        entry.and_then(|entry| {
+            entry.login.as_ref().map(|login| (login.clone(), entry.password.clone()))
+        })
+    }
+}
+
+/// Split the contents into whitespace separated tokens, leaving out
+/// comments and macro definitions
+fn tokenize(contents: &str) -> Vec<&str>
+{
+    let mut tokens = vec![];
+    // A macro definition runs to the next blank line
+    let mut in_macdef = false;
+    for line in contents.lines() {
+        if in_macdef {
+            in_macdef = !line.trim().is_empty();
+            continue
+        }
+        let mut words = line.split_whitespace();
+        while let Some(word) = words.next() {
+            if word.starts_with('#') {
+                break
+            }
+            if word == "macdef" {
+                in_macdef = true;
+                break
+            }
+            tokens.push(word);
+        }
+    }
+    tokens
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Netrc;
+
+    fn credentials(login: &str, password: Option<&str>) -> Option<(String, Option<String>)>
+    {
+        Some((login.to_owned(), password.map(|p| p.to_owned())))
+    }
+
+    #[test]
+    fn machines_and_default()
+    {
+        let netrc = Netrc::parse("machine example.com login alice password s3cret\n\
+                                  machine Other.org\n  login bob\n\
+                                  default login anonymous password guest\n").unwrap();
+        assert_eq!(netrc.credentials("example.com"), credentials("alice", Some("s3cret")));
+        assert_eq!(netrc.credentials("EXAMPLE.COM"), credentials("alice", Some("s3cret")));
+        assert_eq!(netrc.credentials("other.org"), credentials("bob", None));
+        assert_eq!(netrc.credentials("elsewhere.net"), credentials("anonymous", Some("guest")));
+    }
+
+    #[test]
+    fn first_entry_wins()
+    {
+        let netrc = Netrc::parse("machine h login first\nmachine h login second\n\
+                                  default login d1\ndefault login d2").unwrap();
+        assert_eq!(netrc.credentials("h"), credentials("first", None));
+        assert_eq!(netrc.credentials("x"), credentials("d1", None));
+    }
+
+    #[test]
+    fn entry_without_login_gives_none()
+    {
+        let netrc = Netrc::parse("machine h password p account a").unwrap();
+        assert_eq!(netrc.credentials("h"), None);
+        assert_eq!(Netrc::parse("").unwrap().credentials("h"), None);
+    }
+
+    #[test]
+    fn skips_comments_and_macros()
+    {
+        let netrc = Netrc::parse("# machine evil login mallory\n\
+                                  machine h login alice # password not-this\n\
+                                  macdef init\ncd /pub\nmachine m login macro\n\n\
+                                  machine g login gail").unwrap();
+        assert_eq!(netrc.credentials("evil"), None);
+        assert_eq!(netrc.credentials("h"), credentials("alice", None));
+        assert_eq!(netrc.credentials("m"), None);
+        assert_eq!(netrc.credentials("g"), credentials("gail", None));
+    }
+
+    #[test]
+    fn refuses_malformed()
+    {
+        assert!(Netrc::parse("machine").is_err());
+        assert!(Netrc::parse("machine h login").is_err());
+        assert!(Netrc::parse("login alice").is_err());
+        assert!(Netrc::parse("machine h user alice").is_err());
+    }
+}
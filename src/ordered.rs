@@ -0,0 +1,162 @@
+//! Fetching pieces in parallel and writing them in order
+//!
+//! Workers fetch pieces (e.g. HLS segments or byte ranges) ahead of the
+//! one being written, each at most a few pieces ahead, and the caller's
+//! thread writes them to the output in order. Memory use is bounded by the
+//! lookahead rather than the size of the output.
+//!
+//! When the output is slow (e.g. a pipe to a slow consumer) pieces read
+//! ahead pile up. Workers reading through `Backpressure` pause once the
+//! bytes held exceed a budget, leaving their connections unread so the
+//! server stops sending too, until the output catches up. The piece to be
+//! written next is never paused, so the output always makes progress.
+
+use errors::DownloadError;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::{
+    Arc,
+    Condvar,
+    Mutex,
+};
+use std::sync::mpsc::{
+    Receiver,
+    channel,
+};
+use std::thread;
+
+
+/// Pieces each worker may fetch ahead of the one being written
+pub const LOOKAHEAD_PER_WORKER: usize = 2;
+
+/// Bytes of pieces not yet written each worker may hold in memory
+pub const BUFFER_PER_WORKER: u64 = 2 * 1024 * 1024;  // 2 MB
+
+struct Progress {
+    /// Index of the next piece to hand to a worker
+    next: usize,
+    /// Number of pieces written to the output
+    written: usize,
+    /// Set when writing fails, so workers stop
+    aborted: bool,
+    /// Bytes reserved by workers and not yet written
+    buffered: u64,
+}
+
+/// Lets workers pause reading a piece while too much is held unwritten
+#[derive(Clone)]
+pub struct Backpressure {
+    progress: Arc<(Mutex<Progress>, Condvar)>,
+    limit: u64,
+}
+
+impl Backpressure {
+
+    /// Account for `len` more bytes of piece `index` held in memory,
+    /// waiting first while that would exceed the budget (unless the piece
+    /// is the next to be written). Returns false once writing has failed,
+    /// so the worker can stop.
+    pub fn reserve(&self, index: usize, len: usize) -> bool
+    {
+        let &(ref lock, ref cvar) = &*self.progress;
+        let mut state = lock.lock().unwrap();
+        while !state.aborted && index != state.written
+            && state.buffered + len as u64 > self.limit {
+            state = cvar.wait(state).unwrap();
+        }
+        state.buffered += len as u64;
+        !state.aborted
+    }
+}
+
+/// Fetch `pieces` with `workers` threads, writing them to `out` in order,
+/// and return the number of bytes written. `fetch` may read through the
+/// `Backpressure` it is given to pause while the output is behind.
+/// `written` is called with each piece once it has been written.
+pub fn fetch_in_order<T, F, W, P>(pieces: Vec<T>, workers: usize, fetch: F, out: &mut W,
+                                  mut written: P) -> Result<u64, DownloadError>
+    where T: Send + Sync + 'static,
+          F: Fn(usize, &T, &Backpressure) -> Result<Vec<u8>, DownloadError> + Clone + Send + 'static,
+          W: Write,
+          P: FnMut(&[u8])
+{
+    let pieces = Arc::new(pieces);
+    let lookahead = workers * LOOKAHEAD_PER_WORKER;
+    let progress = Arc::new((Mutex::new(Progress { next: 0, written: 0, aborted: false, buffered: 0 }),
+                             Condvar::new()));
+    let backpressure = Backpressure {
+        progress: progress.clone(),
+        limit: workers as u64 * BUFFER_PER_WORKER,
+    };
+    let (tx, rx) = channel();
+
+    let mut children = vec![];
+    for _ in 0..workers {
+        let (pieces, fetch, progress, backpressure, tx) =
+            (pieces.clone(), fetch.clone(), progress.clone(), backpressure.clone(), tx.clone());
+        children.push(thread::spawn(move || loop {
+            let index = {
+                let &(ref lock, ref cvar) = &*progress;
+                let mut state = lock.lock().unwrap();
+                while !state.aborted && state.next < pieces.len()
+                    && state.next >= state.written + lookahead {
+                    state = cvar.wait(state).unwrap();
+                }
+                if state.aborted || state.next >= pieces.len() {
+                    return;
+                }
+                state.next += 1;
+                state.next - 1
+            };
+            let _ = tx.send((index, fetch(index, &pieces[index], &backpressure)));
+        }));
+    }
+    drop(tx);
+
+    let result = write_in_order(&rx, pieces.len(), &progress, out, &mut written);
+    if result.is_err() {
+        let &(ref lock, ref cvar) = &*progress;
+        lock.lock().unwrap().aborted = true;
+        cvar.notify_all();
+    }
+    // Drain so workers blocked on sending can finish
+    for _ in rx.iter() {}
+    for child in children {
+        let _ = child.join();
+    }
+    result
+}
+
+/// Write pieces as they arrive, buffering those received out of order
+fn write_in_order<W, P>(rx: &Receiver<(usize, Result<Vec<u8>, DownloadError>)>, count: usize,
+                        progress: &Arc<(Mutex<Progress>, Condvar)>, out: &mut W,
+                        on_written: &mut P) -> Result<u64, DownloadError>
+    where W: Write, P: FnMut(&[u8])
+{
+    let mut pending = BTreeMap::new();
+    let mut written = 0;
+    let mut bytes = 0;
+    while written < count {
+        let (index, piece) = match rx.recv() {
+            Ok(received) => received,
+            Err(_) => return Err(DownloadError::other("workers stopped early")),
+        };
+        pending.insert(index, try!(piece));
+
+        while let Some(piece) = pending.remove(&written) {
+            try!(out.write_all(&piece));
+            bytes += piece.len() as u64;
+            written += 1;
+            on_written(&piece);
+            let &(ref lock, ref cvar) = &**progress;
+            let mut state = lock.lock().unwrap();
+            state.written = written;
+            // Pieces fetched without reserving weren't counted
+            state.buffered = state.buffered.saturating_sub(piece.len() as u64);
+            cvar.notify_all();
+        }
+        debug!("Wrote {} of {} pieces", written, count);
+    }
+    try!(out.flush());
+    Ok(bytes)
+}
@@ -0,0 +1,187 @@
+//! Per-item outcomes of a batch of downloads
+//!
+//! A batch may partly succeed. Each url's outcome is recorded so the
+//! batch can be summarized, and callers can tell exactly which items
+//! need attention. `BatchOutcome::to_json` renders a canonical summary
+//! that is the same byte for byte whenever the outcomes are.
+
+use std::fmt;
+
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum OnError {
+    /// Keep downloading the remaining items after a failure
+    Continue,
+    /// Stop at the first failure, skipping the remaining items
+    FailFast,
+    /// Stop once more than this percentage of the batch has failed
+    Budget(u8),
+}
+
+impl OnError {
+
+    /// Whether to skip the remaining items of a batch of `total` items
+    pub fn should_stop(&self, outcome: &BatchOutcome, total: usize) -> bool
+    {
+        match *self {
+            OnError::Continue => false,
+            OnError::FailFast => outcome.failed() > 0,
+            OnError::Budget(percent) => outcome.failed() * 100 > percent as usize * total,
+        }
+    }
+}
+
+impl Default for OnError {
+    fn default() -> OnError
+    {
+        OnError::Continue
+    }
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum Status {
+    /// The item was downloaded, writing this many bytes
+    Succeeded(u64),
+    /// The item was already up to date, so wasn't downloaded
+    Unchanged,
+    /// The item's url was already downloaded in the batch, to this path,
+    /// so its target was linked or copied from there
+    Duplicate(String),
+    /// The item failed with this error
+    Failed(String),
+    /// The item wasn't attempted because an earlier one failed
+    Skipped,
+}
+
+#[derive(Clone,Debug)]
+pub struct Outcome {
+    pub url: String,
+    pub status: Status,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.status {
+            Status::Succeeded(bytes) => write!(f, "ok      {} ({} bytes)", self.url, bytes),
+            Status::Failed(ref err) => write!(f, "failed  {}: {}", self.url, err),
+            Status::Unchanged => write!(f, "ok      {} (unchanged)", self.url),
+            Status::Duplicate(ref path) => write!(f, "ok      {} (duplicate of {})", self.url, path),
+            Status::Skipped => write!(f, "skipped {}", self.url),
+        }
+    }
+}
+
+#[derive(Clone,Debug,Default)]
+pub struct BatchOutcome {
+    pub outcomes: Vec<Outcome>,
+}
+
+impl BatchOutcome {
+
+    pub fn new() -> BatchOutcome
+    {
+        BatchOutcome::default()
+    }
+
+    pub fn record(&mut self, url: &str, status: Status)
+    {
+        self.outcomes.push(Outcome { url: url.to_owned(), status: status });
+    }
+
+    /// Number of items that were downloaded
+    pub fn succeeded(&self) -> usize
+    {
+        self.count(|s| match *s { Status::Succeeded(_) => true, _ => false })
+    }
+
+    /// Number of items that failed
+    pub fn failed(&self) -> usize
+    {
+        self.count(|s| match *s { Status::Failed(_) => true, _ => false })
+    }
+
+    /// Number of items that weren't attempted
+    pub fn skipped(&self) -> usize
+    {
+        self.count(|s| *s == Status::Skipped)
+    }
+
+    /// Number of items that were already up to date
+    pub fn unchanged(&self) -> usize
+    {
+        self.count(|s| *s == Status::Unchanged)
+    }
+
+    /// Number of items whose url was already downloaded in the batch
+    pub fn duplicates(&self) -> usize
+    {
+        self.count(|s| match *s { Status::Duplicate(_) => true, _ => false })
+    }
+
+    /// Whether every item was downloaded or already up to date
+    pub fn is_success(&self) -> bool
+    {
+        self.succeeded() + self.unchanged() + self.duplicates() == self.outcomes.len()
+    }
+
+    /// The outcomes as canonical JSON: no whitespace, object keys sorted,
+    /// items in batch order, and nothing that varies between identical
+    /// runs such as timings
+    pub fn to_json(&self) -> String
+    {
+        let items: Vec<String> = self.outcomes.iter().map(|outcome| {
+            let url = json_string(&*outcome.url);
+            match outcome.status {
+                Status::Succeeded(bytes) => format!(
+                    "{{\"bytes\":{},\"status\":\"succeeded\",\"url\":{}}}", bytes, url),
+                Status::Unchanged => format!("{{\"status\":\"unchanged\",\"url\":{}}}", url),
+                Status::Duplicate(ref path) => format!(
+                    "{{\"path\":{},\"status\":\"duplicate\",\"url\":{}}}", json_string(&**path), url),
+                Status::Failed(ref err) => format!(
+                    "{{\"error\":{},\"status\":\"failed\",\"url\":{}}}", json_string(&**err), url),
+                Status::Skipped => format!("{{\"status\":\"skipped\",\"url\":{}}}", url),
+            }
+        }).collect();
+        format!("{{\"counts\":{{\"duplicate\":{},\"failed\":{},\"skipped\":{},\"succeeded\":{},\
+                 \"unchanged\":{}}},\"items\":[{}],\"success\":{}}}",
+                self.duplicates(), self.failed(), self.skipped(), self.succeeded(), self.unchanged(),
+                items.join(","), self.is_success())
+    }
+
+    fn count<F: Fn(&Status) -> bool>(&self, matches: F) -> usize
+    {
+        self.outcomes.iter().filter(|o| matches(&o.status)).count()
+    }
+}
+
+impl fmt::Display for BatchOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{} of {} downloads succeeded ({} unchanged, {} duplicate, {} failed, \
+                        {} skipped)",
+                    self.succeeded() + self.unchanged() + self.duplicates(), self.outcomes.len(),
+                    self.unchanged(), self.duplicates(), self.failed(), self.skipped()));
+        for outcome in &self.outcomes {
+            try!(write!(f, "\n  {}", outcome));
+        }
+        Ok(())
+    }
+}
+
+/// `s` as a JSON string literal
+fn json_string(s: &str) -> String
+{
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&*format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,85 @@
+//! Giving downloaded files to the user who ran sledge through sudo
+//!
+//! Run as root by `sudo`, e.g. from a provisioning script, sledge would
+//! leave root owned files (and directories) in the user's tree. With
+//! `--chown-caller` they are given to the invoking user, named by the
+//! `$SUDO_UID` and `$SUDO_GID` sudo sets, once they are complete.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+
+/// The user and group files are given to
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Owner {
+
+    /// The user who ran sledge through sudo, if it is running as root
+    /// because of it
+    #[cfg(unix)]
+    pub fn sudo_caller() -> Option<Owner>
+    {
+        use libc;
+        use std::env;
+        if unsafe { libc::geteuid() } != 0 {
+            return None
+        }
+        let id = |name: &str| env::var(name).ok().and_then(|id| id.trim().parse().ok());
+        match (id("SUDO_UID"), id("SUDO_GID")) {
+            // sudo from root to root changes nothing
+            (Some(0), _) => None,
+            (Some(uid), Some(gid)) => Some(Owner { uid: uid, gid: gid }),
+            _ => None,
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn sudo_caller() -> Option<Owner>
+    {
+        None
+    }
+
+    /// Give the file or directory at `path` to the owner, not following
+    /// symlinks
+    #[cfg(unix)]
+    pub fn chown(&self, path: &Path) -> io::Result<()>
+    {
+        debug!("giving {} to {}:{}", path.display(), self.uid, self.gid);
+        ::std::os::unix::fs::lchown(path, Some(self.uid), Some(self.gid))
+    }
+
+    #[cfg(not(unix))]
+    pub fn chown(&self, _: &Path) -> io::Result<()>
+    {
+        Ok(())
+    }
+
+    /// Create `dir` and its missing parents, giving those created to the
+    /// owner
+    pub fn create_dir_all(&self, dir: &Path) -> io::Result<()>
+    {
+        let missing: Vec<&Path> = dir.ancestors()
+            .take_while(|d| !d.as_os_str().is_empty() && !d.exists())
+            .collect();
+        try!(fs::create_dir_all(dir));
+        for created in missing.into_iter().rev() {
+            try!(self.chown(created));
+        }
+        Ok(())
+    }
+}
+
+/// Create `dir` and its missing parents, giving those created to `owner`
+/// if there is one
+pub fn create_dir_all(dir: &Path, owner: Option<&Owner>) -> io::Result<()>
+{
+    match owner {
+        Some(owner) => owner.create_dir_all(dir),
+        None => fs::create_dir_all(dir),
+    }
+}
@@ -0,0 +1,125 @@
+//! Paths handed to the operating system
+//!
+//! Windows refuses paths longer than MAX_PATH (260 characters) unless they
+//! are given in the extended-length `\\?\C:\...` or `\\?\UNC\server\share`
+//! form, which deep download directories easily exceed. Targets and their
+//! control files are opened through `os_path`, which converts them to that
+//! form on Windows and leaves them alone elsewhere.
+//!
+//! Files sledge writes beside its targets are opened with `no_follow`, so
+//! a symlink planted at their path is refused rather than written through.
+//! Files replacing a target are written to a new file from
+//! `create_staging` and renamed into place.
+
+use std::fs::{
+    File,
+    OpenOptions,
+};
+use std::io;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::process;
+
+#[cfg(windows)]
+use std::env;
+#[cfg(windows)]
+use std::path::Component;
+
+
+/// The path to open for `path`
+#[cfg(not(windows))]
+pub fn os_path(path: &str) -> PathBuf
+{
+    PathBuf::from(path)
+}
+
+/// The path to open for `path`: its extended-length form, which must be
+/// absolute with `.` and `..` resolved as Windows won't do either
+#[cfg(windows)]
+pub fn os_path(path: &str) -> PathBuf
+{
+    // Already extended, or a device path
+    if path.starts_with(r"\\?\") || path.starts_with(r"\\.\") {
+        return PathBuf::from(path)
+    }
+    let path = path.replace('/', "\\");
+    let absolute = if Path::new(&*path).is_absolute() {
+        PathBuf::from(&*path)
+    } else {
+        match env::current_dir() {
+            Ok(dir) => dir.join(&*path),
+            Err(_) => return PathBuf::from(path),
+        }
+    };
+
+    let mut prefix = String::new();
+    let mut parts: Vec<String> = vec![];
+    for component in absolute.components() {
+        match component {
+            Component::Prefix(p) => prefix = p.as_os_str().to_string_lossy().into_owned(),
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir => { parts.pop(); },
+            Component::Normal(part) => parts.push(part.to_string_lossy().into_owned()),
+        }
+    }
+    let rest = parts.join("\\");
+    if prefix.starts_with(r"\\?\") {
+        // The working directory may itself be extended
+        PathBuf::from(format!("{}\\{}", prefix, rest))
+    } else if prefix.starts_with(r"\\") {
+        // A UNC share, \\server\share
+        PathBuf::from(format!(r"\\?\UNC\{}\{}", &prefix[2..], rest))
+    } else {
+        PathBuf::from(format!(r"\\?\{}\{}", prefix, rest))
+    }
+}
+
+/// Make `open_options` fail on a symlink at the path instead of opening
+/// where it leads
+#[cfg(unix)]
+pub fn no_follow(open_options: &mut OpenOptions)
+{
+    use libc;
+    use std::os::unix::fs::OpenOptionsExt;
+    open_options.custom_flags(libc::O_NOFOLLOW);
+}
+
+#[cfg(not(unix))]
+pub fn no_follow(_: &mut OpenOptions)
+{
+}
+
+/// Create a new file, named uniquely for `purpose`, beside `to`, to write
+/// and then rename over it. It is readable only by the owner, and a file
+/// (or symlink) already there is never opened.
+pub fn create_staging(to: &Path, purpose: &str) -> io::Result<(PathBuf, File)>
+{
+    let name = to.file_name().map_or("download".into(), |name| name.to_string_lossy());
+    for attempt in 0.. {
+        let staging = to.with_file_name(format!(".{}.sledge-{}-{}-{}", name, purpose, process::id(),
+                                                attempt));
+        let mut open_options = OpenOptions::new();
+        open_options.write(true).create_new(true);
+        owner_only(&mut open_options);
+        no_follow(&mut open_options);
+        match open_options.open(&staging) {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            result => return result.map(|file| (staging, file)),
+        }
+    }
+    unreachable!()
+}
+
+#[cfg(unix)]
+fn owner_only(open_options: &mut OpenOptions)
+{
+    use std::os::unix::fs::OpenOptionsExt;
+    open_options.mode(0o600);
+}
+
+#[cfg(not(unix))]
+fn owner_only(_: &mut OpenOptions)
+{
+}
@@ -0,0 +1,163 @@
+//! Planning which byte ranges of a file are downloaded first
+//!
+//! A `Planner` divides a file into regions, in the order they should be
+//! fetched. The regions are handed to a `Schedule` (see `schedule`), which
+//! splits them into the chunks workers request, finishing earlier regions
+//! before later ones. The default `WholeFile` plan is a single region;
+//! custom planners can e.g. fetch the parts of a file needed to inspect it
+//! before the bulk of it.
+
+use schedule::Chunk;
+use std::cmp;
+
+
+/// Decides the order in which the regions of a file are fetched
+pub trait Planner: Send + Sync {
+    /// The regions of a file of `size` bytes, in the order to fetch them.
+    /// Together they must cover the file without overlapping.
+    fn plan(&self, size: u64) -> Vec<Chunk>;
+}
+
+/// Fetch the file from start to end
+#[derive(Clone,Copy,Debug,Default)]
+pub struct WholeFile;
+
+impl Planner for WholeFile {
+    fn plan(&self, size: u64) -> Vec<Chunk>
+    {
+        if size == 0 {
+            vec![]
+        } else {
+            vec![Chunk { start: 0, end: size }]
+        }
+    }
+}
+
+/// Fetch the first and last `len` bytes before the middle, so formats
+/// with a header or trailing index (e.g. MP4, zip, parquet) can be
+/// inspected while the rest downloads
+#[derive(Clone,Copy,Debug)]
+pub struct HeaderFooter {
+    pub len: u64,
+}
+
+impl Planner for HeaderFooter {
+    fn plan(&self, size: u64) -> Vec<Chunk>
+    {
+        if size <= self.len.saturating_mul(2) {
+            return WholeFile.plan(size)
+        }
+        vec![
+            Chunk { start: 0, end: self.len },
+            Chunk { start: size - self.len, end: size },
+            Chunk { start: self.len, end: size - self.len },
+        ]
+    }
+}
+
+/// Check that `regions` cover a file of `size` bytes exactly once
+pub fn validate(regions: &[Chunk], size: u64) -> Result<(), String>
+{
+    let mut sorted = regions.to_vec();
+    sorted.sort_by_key(|region| region.start);
+    let mut covered = 0;
+    for region in sorted {
+        if region.start != covered || region.end < region.start {
+            return Err(format!("planned regions leave a gap or overlap at byte {}", covered))
+        }
+        covered = region.end;
+    }
+    if covered != size {
+        return Err(format!("planned regions end at byte {} of {}", covered, size))
+    }
+    Ok(())
+}
+
+/// The parts of `regions` outside the sorted, non-overlapping `done`
+/// ranges (`(start, end)`, `end` exclusive), keeping the regions' order
+pub fn subtract(regions: Vec<Chunk>, done: &[(u64, u64)]) -> Vec<Chunk>
+{
+    let mut remaining = vec![];
+    for region in regions {
+        let mut start = region.start;
+        for &(done_start, done_end) in done {
+            if done_end <= start || done_start >= region.end {
+                continue;
+            }
+            if done_start > start {
+                remaining.push(Chunk { start: start, end: done_start });
+            }
+            start = cmp::max(start, done_end);
+        }
+        if start < region.end {
+            remaining.push(Chunk { start: start, end: region.end });
+        }
+    }
+    remaining
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        subtract,
+        validate,
+        HeaderFooter,
+        Planner,
+        WholeFile,
+    };
+    use schedule::Chunk;
+
+    fn chunk(start: u64, end: u64) -> Chunk
+    {
+        Chunk { start: start, end: end }
+    }
+
+    #[test]
+    fn planners_cover_the_file()
+    {
+        assert_eq!(WholeFile.plan(0), vec![]);
+        assert_eq!(WholeFile.plan(10), vec![chunk(0, 10)]);
+        let header_footer = HeaderFooter { len: 4 };
+        assert_eq!(header_footer.plan(20), vec![chunk(0, 4), chunk(16, 20), chunk(4, 16)]);
+        // Too small to split
+        assert_eq!(header_footer.plan(8), vec![chunk(0, 8)]);
+        for size in 0..20 {
+            assert_eq!(validate(&header_footer.plan(size), size), Ok(()));
+        }
+    }
+
+    #[test]
+    fn validate_accepts_regions_in_any_order()
+    {
+        assert_eq!(validate(&[], 0), Ok(()));
+        assert_eq!(validate(&[chunk(5, 10), chunk(0, 5)], 10), Ok(()));
+        assert_eq!(validate(&[chunk(0, 0), chunk(0, 3)], 3), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_gaps_overlaps_and_wrong_sizes()
+    {
+        assert!(validate(&[chunk(0, 4), chunk(5, 10)], 10).is_err());
+        assert!(validate(&[chunk(0, 6), chunk(5, 10)], 10).is_err());
+        assert!(validate(&[chunk(1, 10)], 10).is_err());
+        assert!(validate(&[chunk(0, 9)], 10).is_err());
+        assert!(validate(&[chunk(0, 11)], 10).is_err());
+        assert!(validate(&[], 10).is_err());
+        assert!(validate(&[chunk(0, 10), chunk(10, 5)], 10).is_err());
+    }
+
+    #[test]
+    fn subtract_removes_journaled_ranges()
+    {
+        let regions = vec![chunk(0, 4), chunk(16, 20), chunk(4, 16)];
+        // Nothing journaled
+        assert_eq!(subtract(regions.clone(), &[]), regions);
+        // Everything journaled
+        assert_eq!(subtract(regions.clone(), &[(0, 20)]), vec![]);
+        // Ranges spanning, splitting and ending regions, in the regions' order
+        assert_eq!(subtract(regions, &[(2, 6), (8, 10), (14, 18)]),
+                   vec![chunk(0, 2), chunk(18, 20), chunk(6, 8), chunk(10, 14)]);
+        assert_eq!(subtract(vec![chunk(0, 10)], &[(0, 3), (3, 5), (9, 12)]), vec![chunk(5, 9)]);
+        assert_eq!(subtract(vec![], &[(0, 10)]), vec![]);
+    }
+}
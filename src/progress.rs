@@ -0,0 +1,168 @@
+//! Machine readable progress for wrapping processes
+//!
+//! With `--progress-fd` or `--progress-socket`, progress is written as
+//! messages that GUI wrappers can read without scraping the terminal. Each
+//! message is a 4 byte big-endian length followed by that many bytes of
+//! JSON, one of:
+//!
+//! - `{"event":"start","file":1,"files":1,"total":1048576}`
+//! - `{"event":"progress","file":1,"files":1,"done":524288,"total":1048576,
+//!   "rate":262144.0,"eta":2}`
+//! - `{"event":"finish","file":1,"files":1,"done":1048576,"total":1048576,
+//!   "elapsed":4}`
+//!
+//! `file` and `files` are the position in a batch of downloads, and `eta`
+//! is null while unknown. Times are in seconds. Messages about a file in a
+//! batch also carry the progress of the whole batch, e.g.
+//! `"batch":{"done":3145728,"total":8388608,"rate":262144.0,"eta":20}`,
+//! whose `total` and `eta` are null until the size of every file is known.
+
+use stats::{
+    BatchStats,
+    Stats,
+};
+use std::io::{
+    self,
+    Write,
+};
+use std::sync::Mutex;
+use std::time::{
+    Duration,
+    Instant,
+};
+#[cfg(unix)]
+use std::fs::File;
+#[cfg(unix)]
+use std::os::unix::io::FromRawFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+
+/// Minimum time between progress messages
+const MESSAGE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where progress messages are written
+pub struct ProgressSink {
+    /// None once writing failed (e.g. the reader went away)
+    out: Mutex<Option<Box<dyn Write + Send>>>,
+}
+
+impl ProgressSink {
+
+    pub fn new<W: Write + Send + 'static>(out: W) -> ProgressSink
+    {
+        ProgressSink { out: Mutex::new(Some(Box::new(out))) }
+    }
+
+    /// Write to a file descriptor inherited from the parent process
+    #[cfg(unix)]
+    pub fn from_fd(fd: i32) -> ProgressSink
+    {
+        ProgressSink::new(unsafe { File::from_raw_fd(fd) })
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_fd(_: i32) -> ProgressSink
+    {
+        warn!("--progress-fd is only supported on unix");
+        ProgressSink { out: Mutex::new(None) }
+    }
+
+    /// Write to a unix socket the parent process listens on
+    #[cfg(unix)]
+    pub fn connect(path: &str) -> io::Result<ProgressSink>
+    {
+        Ok(ProgressSink::new(try!(UnixStream::connect(path))))
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect(_: &str) -> io::Result<ProgressSink>
+    {
+        Err(io::Error::new(io::ErrorKind::Other, "unix sockets are not supported"))
+    }
+
+    fn send(&self, message: &str)
+    {
+        let mut out = self.out.lock().unwrap();
+        let result = match *out {
+            Some(ref mut out) => write_message(out, message),
+            None => return,
+        };
+        if let Err(e) = result {
+            warn!("Unable to write progress messages, no longer sending them: {}", e);
+            *out = None;
+        }
+    }
+
+    pub fn start(&self, stats: &Stats, batch: Option<&BatchStats>)
+    {
+        self.send(&format!("{{\"event\":\"start\",{},\"total\":{}}}",
+                           position(batch), stats.total));
+    }
+
+    pub fn finish(&self, stats: &Stats, batch: Option<&BatchStats>)
+    {
+        self.send(&format!("{{\"event\":\"finish\",{},\"done\":{},\"total\":{},\"elapsed\":{}}}",
+                           position(batch), stats.done, stats.total,
+                           stats.elapsed().as_secs()));
+    }
+}
+
+/// Sends the progress of one download, at most every `MESSAGE_INTERVAL`
+pub struct ProgressMessages<'a> {
+    sink: &'a ProgressSink,
+    last: Option<Instant>,
+}
+
+impl<'a> ProgressMessages<'a> {
+
+    pub fn new(sink: &'a ProgressSink) -> ProgressMessages<'a>
+    {
+        ProgressMessages { sink: sink, last: None }
+    }
+
+    pub fn update(&mut self, stats: &Stats, batch: Option<&BatchStats>)
+    {
+        if self.last.map_or(false, |last| last.elapsed() < MESSAGE_INTERVAL) {
+            return
+        }
+        self.last = Some(Instant::now());
+        self.sink.send(&format!(
+            "{{\"event\":\"progress\",{},\"done\":{},\"total\":{},\"rate\":{:.1},\"eta\":{}}}",
+            position(batch), stats.done, stats.total, stats.rate(), format_eta(stats)));
+    }
+}
+
+/// The `file` and `files` fields of a message, and the `batch` field if
+/// the download is part of one
+fn position(batch: Option<&BatchStats>) -> String
+{
+    let batch = match batch {
+        Some(batch) => batch,
+        None => return "\"file\":1,\"files\":1".to_owned(),
+    };
+    let (total, eta) = match batch.total() {
+        Some(total) => (total.to_string(), format_eta(&batch.stats)),
+        None => ("null".to_owned(), "null".to_owned()),
+    };
+    format!("\"file\":{},\"files\":{},\"batch\":{{\"done\":{},\"total\":{},\"rate\":{:.1},\
+             \"eta\":{}}}",
+            batch.file, batch.files, batch.stats.done, total, batch.stats.rate(), eta)
+}
+
+/// The `eta` field of a message
+fn format_eta(stats: &Stats) -> String
+{
+    match stats.eta() {
+        Some(eta) => eta.as_secs().to_string(),
+        None => "null".to_owned(),
+    }
+}
+
+fn write_message(out: &mut Box<dyn Write + Send>, message: &str) -> io::Result<()>
+{
+    let len = message.len() as u32;
+    try!(out.write_all(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]));
+    try!(out.write_all(message.as_bytes()));
+    out.flush()
+}
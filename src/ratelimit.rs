@@ -0,0 +1,148 @@
+//! Detection of rate limiting
+//!
+//! Servers announce their limits with `X-RateLimit-*` or `RateLimit-*`
+//! headers and ask clients to slow down with `Retry-After`. CDNs and object
+//! stores also throttle with recognizable responses (e.g. S3's `SlowDown`).
+//! What is detected is logged and used to delay retries.
+
+use chrono::{
+    DateTime,
+    Utc,
+};
+use errors::DownloadError;
+use hyper::header::Headers;
+use hyper::status::StatusCode;
+use std::fmt;
+use std::time::Duration;
+
+
+/// Values above this are taken to be a time since the epoch rather than a
+/// number of seconds to wait
+const EPOCH_THRESHOLD: u64 = 1000000000;
+
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct RateLimit {
+    /// Requests allowed in the current window
+    pub limit: Option<u64>,
+    /// Requests remaining in the current window
+    pub remaining: Option<u64>,
+    /// Time until the window resets
+    pub reset: Option<Duration>,
+    /// Time the server asked us to wait before retrying
+    pub retry_after: Option<Duration>,
+    /// Description of a recognized throttling response
+    pub throttled: Option<&'static str>,
+}
+
+impl RateLimit {
+    /// Whether requests are being refused until the limit resets
+    pub fn is_exhausted(&self) -> bool
+    {
+        self.throttled.is_some() || self.remaining == Some(0)
+    }
+
+    /// How long to wait before making another request, if the server said
+    pub fn wait(&self) -> Option<Duration>
+    {
+        match (self.retry_after, self.is_exhausted()) {
+            (Some(wait), _) => Some(wait),
+            (None, true) => self.reset,
+            (None, false) => None,
+        }
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(throttled) = self.throttled {
+            parts.push(throttled.to_owned());
+        }
+        match (self.remaining, self.limit) {
+            (Some(remaining), Some(limit)) => {
+                parts.push(format!("{} of {} requests left", remaining, limit))
+            },
+            (Some(remaining), None) => parts.push(format!("{} requests left", remaining)),
+            _ => (),
+        }
+        if let Some(reset) = self.reset {
+            parts.push(format!("resets in {}s", reset.as_secs()));
+        }
+        if let Some(wait) = self.retry_after {
+            parts.push(format!("retry after {}s", wait.as_secs()));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// The rate limiting described by a response, if any
+pub fn detect(status: StatusCode, headers: &Headers, body: &str) -> Option<RateLimit>
+{
+    let limit = RateLimit {
+        limit: first_int(headers, &["X-RateLimit-Limit", "RateLimit-Limit"]),
+        remaining: first_int(headers, &["X-RateLimit-Remaining", "RateLimit-Remaining"]),
+        reset: first_int(headers, &["X-RateLimit-Reset", "RateLimit-Reset"]).map(until),
+        retry_after: raw(headers, "Retry-After").and_then(|value| parse_retry_after(&*value)),
+        throttled: throttle_signature(status, headers, body),
+    };
+    if limit == RateLimit::default() {
+        None
+    } else {
+        Some(limit)
+    }
+}
+
+/// The rate limiting described by a failed request, if any
+pub fn from_error(err: &DownloadError) -> Option<RateLimit>
+{
+    match *err {
+        DownloadError::Http { status, ref headers, ref body, .. } => detect(status, headers, body),
+        _ => None,
+    }
+}
+
+/// Recognize responses that mean the client is being throttled
+fn throttle_signature(status: StatusCode, headers: &Headers, body: &str) -> Option<&'static str>
+{
+    match status.to_u16() {
+        429 => Some("too many requests"),
+        503 if body.contains("<Code>SlowDown</Code>") => Some("S3 SlowDown"),
+        403 if raw(headers, "cf-mitigated").is_some() => Some("Cloudflare challenge"),
+        503 if raw(headers, "Retry-After").is_some() => Some("service unavailable, retry later"),
+        _ => None,
+    }
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration>
+{
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => DateTime::parse_from_rfc2822(value).ok().map(|date| {
+            let seconds = date.timestamp() - Utc::now().timestamp();
+            Duration::from_secs(if seconds > 0 { seconds as u64 } else { 0 })
+        }),
+    }
+}
+
+/// Reset headers hold either seconds to wait or a time since the epoch
+fn until(reset: u64) -> Duration
+{
+    if reset < EPOCH_THRESHOLD {
+        return Duration::from_secs(reset)
+    }
+    let now = Utc::now().timestamp();
+    Duration::from_secs(if reset as i64 > now { reset - now as u64 } else { 0 })
+}
+
+fn first_int(headers: &Headers, names: &[&str]) -> Option<u64>
+{
+    names.iter().filter_map(|name| raw(headers, name)).filter_map(|v| v.parse().ok()).next()
+}
+
+fn raw(headers: &Headers, name: &str) -> Option<String>
+{
+    headers.get_raw(name)
+        .and_then(|lines| lines.first())
+        .map(|line| String::from_utf8_lossy(line).trim().to_owned())
+}
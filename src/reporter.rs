@@ -1,34 +1,206 @@
+use std::sync::{
+    Arc,
+    Mutex,
+};
 use std::sync::mpsc::Receiver;
-use pbr::{ProgressBar, Units};
+use frontend::{
+    Frontend,
+    FrontendKind,
+    SpinnerFrontend,
+};
+use progress::{
+    ProgressMessages,
+    ProgressSink,
+};
+use stats::{
+    BatchStats,
+    Stats,
+};
+use template::ProgressTemplate;
+use units::Units;
 
 
 #[derive(Debug)]
 pub struct CompletedSegment {
     pub start: u64,
     pub len: u64,
+    /// Hex md5 of the segment's bytes, empty if they were not hashed
     pub md5: String,
 }
 
 pub trait Reporter {
     fn new() -> Self;
     fn listen(&self, size: u64, receiver: Receiver<CompletedSegment>);
+
+    /// Listen to a transfer whose size isn't known until it completes
+    fn listen_unsized(&self, receiver: Receiver<CompletedSegment>)
+    {
+        self.listen(0, receiver)
+    }
+}
+
+/// Aggregate progress shared by the reporters of a batch of downloads
+///
+/// The total grows as the size of each file becomes known, and is only
+/// reported once all of them are.
+pub struct Batch {
+    state: Mutex<BatchStats>,
 }
 
-pub struct ProgressBarReporter;
+impl Batch {
+
+    /// Start tracking a batch of `files` downloads
+    pub fn new(files: usize) -> Batch
+    {
+        Batch {
+            state: Mutex::new(BatchStats {
+                file: 0,
+                files: files,
+                stats: Stats::new(0),
+                sized: 0,
+            }),
+        }
+    }
+
+    /// Record the start of the next file in the batch, of `size` bytes if
+    /// known
+    fn start_file(&self, size: Option<u64>) -> BatchStats
+    {
+        let mut state = self.state.lock().unwrap();
+        state.file += 1;
+        if let Some(size) = size {
+            state.stats.grow(size);
+            state.sized += 1;
+        }
+        state.clone()
+    }
+
+    /// Record that `len` more bytes of the current file were transferred
+    fn add(&self, len: u64) -> BatchStats
+    {
+        let mut state = self.state.lock().unwrap();
+        state.stats.add(len);
+        state.clone()
+    }
+}
+
+#[derive(Clone)]
+pub struct ProgressBarReporter {
+    /// Units used to display sizes and rates
+    units: Units,
+    /// The frontend used to draw the progress bar
+    frontend: FrontendKind,
+    /// Aggregate progress if this download is part of a batch
+    batch: Option<Arc<Batch>>,
+    /// Where machine readable progress messages are sent
+    progress: Option<Arc<ProgressSink>>,
+}
+
+impl ProgressBarReporter {
+
+    /// Set the units used to display sizes and rates
+    pub fn units(mut self, units: Units) -> ProgressBarReporter
+    {
+        self.units = units;
+        self
+    }
+
+    /// Set the frontend used to draw the progress bar
+    pub fn frontend(mut self, frontend: FrontendKind) -> ProgressBarReporter
+    {
+        self.frontend = frontend;
+        self
+    }
+
+    /// Report aggregate progress of a batch alongside this download
+    pub fn batch(mut self, batch: Arc<Batch>) -> ProgressBarReporter
+    {
+        self.batch = Some(batch);
+        self
+    }
+
+    /// Also send machine readable progress messages to `sink`
+    pub fn progress_sink(mut self, sink: Arc<ProgressSink>) -> ProgressBarReporter
+    {
+        self.progress = Some(sink);
+        self
+    }
+
+    /// Draw the progress bar with a custom template
+    pub fn template(self, template: ProgressTemplate) -> ProgressBarReporter
+    {
+        self.frontend(FrontendKind::Template(template))
+    }
+}
 
 impl Reporter for ProgressBarReporter {
 
     fn new() -> ProgressBarReporter
     {
-        ProgressBarReporter
+        ProgressBarReporter {
+            units: Units::default(),
+            frontend: FrontendKind::default(),
+            batch: None,
+            progress: None,
+        }
     }
 
     fn listen(&self, size: u64, receiver: Receiver<CompletedSegment>)
     {
-        let mut pb = ProgressBar::new(size);
-        pb.set_units(Units::Bytes);
+        self.report(Some(size), self.frontend.create(size, self.units), receiver)
+    }
+
+    fn listen_unsized(&self, receiver: Receiver<CompletedSegment>)
+    {
+        self.report(None, Box::new(SpinnerFrontend::new(self.units)), receiver)
+    }
+}
+
+impl ProgressBarReporter {
+
+    /// Draw progress of a transfer of `size` bytes (if known) until the
+    /// senders of `receiver` are dropped
+    fn report(&self, size: Option<u64>, mut frontend: Box<dyn Frontend>,
+              receiver: Receiver<CompletedSegment>)
+    {
+        let mut stats = Stats::new(size.unwrap_or(0));
+        let mut batch = self.batch.as_ref().map(|b| b.start_file(size));
+        let mut messages = self.progress.as_ref().map(|sink| {
+            sink.start(&stats, batch.as_ref());
+            ProgressMessages::new(sink)
+        });
+
         for segment in receiver {
-            pb.add(segment.len);
+            stats.add(segment.len);
+            if let Some(ref shared) = self.batch {
+                batch = Some(shared.add(segment.len));
+            }
+            frontend.update(&stats, batch.as_ref());
+            if let Some(ref mut messages) = messages {
+                messages.update(&stats, batch.as_ref());
+            }
         }
+
+        frontend.finish(&stats, batch.as_ref());
+        if let Some(ref sink) = self.progress {
+            sink.finish(&stats, batch.as_ref());
+        }
+    }
+}
+
+/// Consumes progress without displaying it
+#[derive(Clone)]
+pub struct SilentReporter;
+
+impl Reporter for SilentReporter {
+
+    fn new() -> SilentReporter
+    {
+        SilentReporter
+    }
+
+    fn listen(&self, _: u64, receiver: Receiver<CompletedSegment>)
+    {
+        for _ in receiver {}
     }
 }
@@ -0,0 +1,470 @@
+//! Deciding which failures are worth retrying, and when
+//!
+//! Failed requests and interrupted transfers are retried after an
+//! exponentially growing delay with jitter, so parallel workers that
+//! failed together don't retry in lockstep. A rate limiting server's
+//! requested wait takes precedence. A batch can share a budget of
+//! retries, so a broken mirror can't keep a long unattended run retrying
+//! for hours.
+//!
+//! A policy can also wait out a network outage (e.g. wifi roaming or a VPN
+//! reconnecting): while connections are refused, reset or unreachable,
+//! they are retried without using up attempts, until the network has been
+//! down for longer than the time allowed. Every request sharing the policy
+//! waits on the same outage.
+
+use errors::{
+    DownloadError,
+    TimeoutKind,
+};
+use hyper;
+use hyper::status::StatusCode;
+use ratelimit;
+use std::io;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::time::{
+    Duration,
+    Instant,
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+
+/// Default number of attempts made for a request before giving up
+pub const DEFAULT_ATTEMPTS: u32 = 3;
+
+/// Default delay before the first retry, doubled for each one after
+pub const DEFAULT_BASE_DELAY_MS: u64 = 1000;
+
+/// Upper bound on the backoff between attempts
+pub const MAX_BACKOFF: u64 = 60;  // 1 minute
+
+/// How long connection failures are retried by default while waiting for
+/// the network
+pub const DEFAULT_MAX_OUTAGE: u64 = 10 * 60;  // 10 minutes
+
+/// Longest a server can make us wait before a retry
+pub const MAX_RATE_LIMIT_WAIT: u64 = 5 * 60;  // 5 minutes
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum StatusClass {
+    /// The request may succeed if made again (e.g. 429, 503)
+    Retryable,
+    /// Making the request again won't help (e.g. 401, 404)
+    Fatal,
+}
+
+/// Classify a status by the default table: request timeouts, rate limiting
+/// and server errors are retryable, everything else is fatal
+pub fn classify(status: StatusCode) -> StatusClass
+{
+    match status.to_u16() {
+        408 | 429 => StatusClass::Retryable,
+        500..=599 => StatusClass::Retryable,
+        _ => StatusClass::Fatal,
+    }
+}
+
+/// Connection failures since the network went down
+#[derive(Clone,Copy,Debug)]
+struct Outage {
+    started: Instant,
+    /// When the last connection failed
+    last: Instant,
+    /// Connection failures retried so far
+    retries: u32,
+}
+
+#[derive(Clone,Debug)]
+pub struct RetryPolicy {
+    /// Total attempts made for a request, including the first
+    pub attempts: u32,
+    /// Statuses retried in addition to the default table
+    retry_on: Vec<u16>,
+    /// Delay before the first retry
+    base_delay: Duration,
+    /// Retries left for every request sharing the policy, if limited
+    budget: Option<Arc<Mutex<u32>>>,
+    /// Whether delays are randomized
+    jitter: bool,
+    /// How long connection failures are retried without using up
+    /// attempts, if at all
+    max_outage: Option<Duration>,
+    /// The current outage, shared by every request using the policy
+    outage: Arc<Mutex<Option<Outage>>>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy
+    {
+        RetryPolicy {
+            attempts: DEFAULT_ATTEMPTS,
+            retry_on: vec![],
+            base_delay: Duration::from_millis(DEFAULT_BASE_DELAY_MS),
+            budget: None,
+            jitter: true,
+            max_outage: None,
+            outage: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl RetryPolicy {
+
+    /// Retry a failed request up to `retries` times
+    pub fn retries(mut self, retries: u32) -> RetryPolicy
+    {
+        self.attempts = retries + 1;
+        self
+    }
+
+    /// Set the delay before the first retry, which doubles for each one
+    /// after
+    pub fn base_delay(mut self, delay: Duration) -> RetryPolicy
+    {
+        self.base_delay = delay;
+        self
+    }
+
+    /// Share `retries` between every request made with this policy (and
+    /// its clones), after which failures aren't retried
+    pub fn budget(mut self, retries: u32) -> RetryPolicy
+    {
+        self.budget = Some(Arc::new(Mutex::new(retries)));
+        self
+    }
+
+    /// Randomize delays (the default), or always wait the full delay
+    pub fn jitter(mut self, jitter: bool) -> RetryPolicy
+    {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Retry connections that are refused, reset or unreachable for up to
+    /// `max_time` without using up attempts, to wait for the network to
+    /// come back
+    pub fn wait_for_network(mut self, max_time: Duration) -> RetryPolicy
+    {
+        self.max_outage = Some(max_time);
+        self
+    }
+
+    /// Also retry requests that fail with `status` (e.g. 403 from a
+    /// server that intermittently refuses requests)
+    pub fn retry_on_status(mut self, status: u16) -> RetryPolicy
+    {
+        self.retry_on.push(status);
+        self
+    }
+
+    /// Classify a status, taking overrides into account
+    pub fn classify(&self, status: StatusCode) -> StatusClass
+    {
+        if self.retry_on.contains(&status.to_u16()) {
+            StatusClass::Retryable
+        } else {
+            classify(status)
+        }
+    }
+
+    /// How long to wait before retrying a failed attempt (numbered from 1):
+    /// as long as a rate limiting server asked, or else the backoff
+    pub fn delay(&self, err: &DownloadError, attempt: u32) -> Duration
+    {
+        if let Some(outage) = self.waiting_out(err) {
+            return self.backoff(outage.retries)
+        }
+        let requested = ratelimit::from_error(err)
+            .and_then(|limit| limit.wait())
+            .map(|wait| if wait.as_secs() > MAX_RATE_LIMIT_WAIT {
+                Duration::from_secs(MAX_RATE_LIMIT_WAIT)
+            } else {
+                wait
+            });
+        requested.unwrap_or_else(|| self.backoff(attempt))
+    }
+
+    /// The base delay doubled for each earlier retry, capped, then reduced
+    /// by up to half at random
+    pub fn backoff(&self, attempt: u32) -> Duration
+    {
+        let exponent = if attempt > 1 { attempt - 1 } else { 0 };
+        let delay = as_millis(self.base_delay).saturating_mul(1 << exponent.min(20));
+        let capped = delay.min(MAX_BACKOFF * 1000);
+        if !self.jitter {
+            return Duration::from_millis(capped)
+        }
+        Duration::from_millis(capped / 2 + (capped as f64 / 2.0 * jitter()) as u64)
+    }
+
+    /// Whether a failed attempt (numbered from 1) should be retried:
+    /// responses with a retryable status and failures to connect or
+    /// transfer are, other statuses and errors aren't. A retry is taken
+    /// from the shared budget, if there is one.
+    pub fn should_retry(&self, err: &DownloadError, attempt: u32) -> bool
+    {
+        if self.wait_out(err) {
+            return true
+        }
+        if attempt >= self.attempts {
+            return false
+        }
+        let retryable = match *err {
+            DownloadError::Http { status, .. } => self.classify(status) == StatusClass::Retryable,
+            // Only reads from the network; local file errors (disk full,
+            // permissions, preallocation) won't go away by retrying
+            DownloadError::Network { .. }
+            | DownloadError::Io { path: None, .. }
+            | DownloadError::Incomplete { .. } => true,
+            // Running out of time for the whole download is final
+            DownloadError::Timeout { elapsed, .. } => elapsed.kind != TimeoutKind::Total,
+            _ => false,
+        };
+        retryable && self.take_retry()
+    }
+
+    /// The number of the attempt after `attempt` failed with `err`, which
+    /// stays the same while waiting out a network outage
+    pub fn next_attempt(&self, err: &DownloadError, attempt: u32) -> u32
+    {
+        if self.waiting_out(err).is_some() { attempt } else { attempt + 1 }
+    }
+
+    /// Record a failure, returning whether it is a connection failure to be
+    /// retried while waiting for the network. Any other failure means the
+    /// network is up.
+    fn wait_out(&self, err: &DownloadError) -> bool
+    {
+        let max_outage = match self.max_outage {
+            Some(max_outage) => max_outage,
+            None => return false,
+        };
+        let mut outage = self.outage.lock().unwrap();
+        if !connection_failed(err) {
+            *outage = None;
+            return false
+        }
+        let now = Instant::now();
+        // Connection failures are retried at least every `MAX_BACKOFF`, so
+        // a longer gap means the network came back in between
+        let gap = Duration::from_secs(2 * MAX_BACKOFF);
+        let current = match *outage {
+            Some(current) if now.duration_since(current.last) <= gap => current,
+            _ => {
+                warn!("Unable to connect ({}), waiting up to {}s for the network",
+                      err, max_outage.as_secs());
+                Outage { started: now, last: now, retries: 0 }
+            },
+        };
+        if now.duration_since(current.started) >= max_outage {
+            warn!("The network has been down for longer than {}s", max_outage.as_secs());
+            *outage = Some(Outage { last: now, ..current });
+            return false
+        }
+        *outage = Some(Outage { last: now, retries: current.retries + 1, ..current });
+        true
+    }
+
+    /// The outage `err` is being retried in, if any
+    fn waiting_out(&self, err: &DownloadError) -> Option<Outage>
+    {
+        let max_outage = match self.max_outage {
+            Some(max_outage) if connection_failed(err) => max_outage,
+            _ => return None,
+        };
+        let outage = *self.outage.lock().unwrap();
+        outage.filter(|outage| outage.started.elapsed() < max_outage)
+    }
+
+    fn take_retry(&self) -> bool
+    {
+        let mut left = match self.budget {
+            Some(ref budget) => budget.lock().unwrap(),
+            None => return true,
+        };
+        if *left == 0 {
+            debug!("The retry budget is used up");
+            return false
+        }
+        *left -= 1;
+        if *left == 0 {
+            warn!("The retry budget is used up, failures will no longer be retried");
+        }
+        true
+    }
+}
+
+/// Whether `err` is a failure to reach the server, rather than an error
+/// from it: a connection refused, reset or unreachable, or timing out
+/// while connecting
+fn connection_failed(err: &DownloadError) -> bool
+{
+    match *err {
+        DownloadError::Network { source: hyper::Error::Io(ref e), .. } => connection_lost(e),
+        // Interrupted transfers
+        DownloadError::Io { path: None, ref source, .. } => connection_lost(source),
+        DownloadError::Timeout { elapsed, .. } => elapsed.kind == TimeoutKind::Connect,
+        _ => false,
+    }
+}
+
+fn connection_lost(err: &io::Error) -> bool
+{
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::AddrNotAvailable
+        | io::ErrorKind::HostUnreachable
+        | io::ErrorKind::NetworkUnreachable
+        | io::ErrorKind::NetworkDown => true,
+        _ => false,
+    }
+}
+
+fn as_millis(duration: Duration) -> u64
+{
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1000000
+}
+
+/// A number in [0, 1) that differs between calls and threads
+fn jitter() -> f64
+{
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    // Spread nearby clock readings across the range
+    (nanos.wrapping_mul(2654435761) % 1000) as f64 / 1000.0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use errors::{
+        DownloadError,
+        ErrorContext,
+        TimeoutKind,
+    };
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+    use std::io;
+    use std::time::Duration;
+    use super::{
+        classify,
+        RetryPolicy,
+        StatusClass,
+        MAX_BACKOFF,
+    };
+
+    fn refused() -> DownloadError
+    {
+        io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused").into()
+    }
+
+    fn http(status: u16) -> DownloadError
+    {
+        DownloadError::Http {
+            status: StatusCode::from_u16(status),
+            headers: Headers::new(),
+            body: String::new(),
+            truncated: false,
+            context: ErrorContext::default(),
+        }
+    }
+
+    #[test]
+    fn statuses_are_classified_by_the_default_table()
+    {
+        for &status in &[408, 429, 500, 502, 503, 599] {
+            let class = classify(StatusCode::from_u16(status));
+            assert_eq!(class, StatusClass::Retryable, "{}", status);
+        }
+        for &status in &[200, 301, 400, 401, 403, 404, 416] {
+            let class = classify(StatusCode::from_u16(status));
+            assert_eq!(class, StatusClass::Fatal, "{}", status);
+        }
+    }
+
+    #[test]
+    fn overrides_make_statuses_retryable()
+    {
+        let retry = RetryPolicy::default().retry_on_status(403);
+        assert_eq!(retry.classify(StatusCode::Forbidden), StatusClass::Retryable);
+        assert_eq!(retry.classify(StatusCode::ServiceUnavailable), StatusClass::Retryable);
+        assert_eq!(retry.classify(StatusCode::NotFound), StatusClass::Fatal);
+        assert!(retry.should_retry(&http(403), 1));
+        assert!(!retry.should_retry(&http(404), 1));
+        assert!(!RetryPolicy::default().should_retry(&http(403), 1));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap()
+    {
+        let retry = RetryPolicy::default().base_delay(Duration::from_secs(1)).jitter(false);
+        let secs = |attempt| retry.backoff(attempt).as_secs();
+        assert_eq!((secs(0), secs(1), secs(2), secs(3), secs(6)), (1, 1, 2, 4, 32));
+        assert_eq!(secs(7), MAX_BACKOFF);
+        assert_eq!(secs(1000), MAX_BACKOFF);
+
+        // Jitter takes off up to half
+        let retry = retry.jitter(true);
+        for attempt in 1..20 {
+            let full = RetryPolicy::default().jitter(false).backoff(attempt);
+            let delay = retry.backoff(attempt);
+            assert!(full / 2 <= delay && delay <= full, "{:?} of {:?}", delay, full);
+        }
+    }
+
+    #[test]
+    fn the_budget_is_shared_between_clones()
+    {
+        let retry = RetryPolicy::default().retries(10).budget(2);
+        let other = retry.clone();
+        let incomplete = DownloadError::incomplete(1, 2);
+        // Failures that aren't retried don't use it up
+        assert!(!retry.should_retry(&http(404), 1));
+        assert!(retry.should_retry(&incomplete, 1));
+        assert!(other.should_retry(&incomplete, 1));
+        assert!(!retry.should_retry(&incomplete, 2));
+        assert!(!other.should_retry(&incomplete, 1));
+        // A new budget isn't shared with the policy it was built from
+        assert!(retry.budget(1).should_retry(&incomplete, 1));
+    }
+
+    #[test]
+    fn connection_failures_wait_for_the_network_without_using_up_attempts()
+    {
+        let retry = RetryPolicy::default().retries(1).jitter(false)
+            .wait_for_network(Duration::from_secs(60));
+        for _ in 0..5 {
+            assert!(retry.should_retry(&refused(), 2));
+            assert_eq!(retry.next_attempt(&refused(), 2), 2);
+        }
+        let timeout = DownloadError::timeout(TimeoutKind::Connect, Duration::from_secs(1));
+        assert!(retry.should_retry(&timeout, 2));
+
+        // Reaching the server ends the outage, and other errors use up attempts
+        let incomplete = DownloadError::incomplete(1, 2);
+        assert!(!retry.should_retry(&incomplete, 2));
+        assert_eq!(retry.next_attempt(&incomplete, 1), 2);
+        assert_eq!(retry.next_attempt(&refused(), 1), 2);
+    }
+
+    #[test]
+    fn waiting_for_the_network_is_limited()
+    {
+        let retry = RetryPolicy::default().retries(0).wait_for_network(Duration::from_secs(0));
+        assert!(!retry.should_retry(&refused(), 1));
+
+        // Without waiting, connection failures use up attempts like others
+        let retry = RetryPolicy::default().retries(1);
+        assert!(retry.should_retry(&refused(), 1));
+        assert!(!retry.should_retry(&refused(), 2));
+        assert_eq!(retry.next_attempt(&refused(), 1), 2);
+    }
+}
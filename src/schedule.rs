@@ -0,0 +1,272 @@
+//! Scheduling of byte ranges across parallel workers
+//!
+//! Workers repeatedly take the next chunk of the file from a shared
+//! `Scheduler`. Chunks start small so the first bytes arrive quickly, then
+//! grow as measured throughput stabilizes so that per-request overhead
+//! (a round trip per chunk) stays small relative to transfer time. Near the
+//! end of the file chunks shrink again so idle workers can pick up the
+//! remaining work instead of waiting on one large chunk.
+//!
+//! The regions planned for a file (see `plan`) are scheduled in order. The
+//! `Schedule` trait lets a download use a different strategy than the
+//! adaptive `Scheduler`, such as a `FixedScheduler` whose chunks don't
+//! depend on timing.
+
+use stats::as_secs_f64;
+use std::cmp::{
+    max,
+    min,
+};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+
+/// Size of the first chunks handed out
+pub const MIN_CHUNK_SIZE: u64 = 256 * 1024;  // 256 KB
+
+/// Upper bound on the size of a chunk
+pub const MAX_CHUNK_SIZE: u64 = 64 * 1024 * 1024;  // 64 MB
+
+/// Size of the chunks a `FixedScheduler` hands out by default
+pub const FIXED_CHUNK_SIZE: u64 = 4 * 1024 * 1024;  // 4 MB
+
+/// How long a chunk should take to transfer once throughput is known
+const TARGET_CHUNK_SECS: f64 = 2.0;
+
+/// Chunks should take at least this many round trips to transfer
+const MIN_RTTS_PER_CHUNK: f64 = 10.0;
+
+/// A range of bytes `[start, end)` of the file
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Chunk {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Chunk {
+    pub fn len(&self) -> u64
+    {
+        self.end - self.start
+    }
+}
+
+/// Hands out the chunks of a file to workers
+pub trait Schedule: Send {
+    /// The next chunk to fetch, or None once every chunk was handed out
+    fn next_chunk(&mut self) -> Option<Chunk>;
+
+    /// Called when a chunk has been fetched, with its round trip time (time
+    /// to first byte) and total transfer time
+    fn report(&mut self, _chunk: &Chunk, _rtt: Duration, _elapsed: Duration)
+    {
+    }
+}
+
+/// Creates the schedule of a download from its planned regions and its
+/// number of workers
+pub type ScheduleFactory = Arc<dyn Fn(Vec<Chunk>, u64) -> Box<dyn Schedule> + Send + Sync>;
+
+/// The default schedule, adapting chunk sizes to measured throughput
+pub struct Scheduler {
+    /// The regions with bytes not yet handed out, in order
+    regions: VecDeque<Chunk>,
+    /// Number of bytes not yet handed out
+    remaining: u64,
+    /// Number of workers taking chunks
+    workers: u64,
+    /// Size of the next chunk to hand out
+    chunk_size: u64,
+}
+
+impl Scheduler {
+
+    /// Schedule a file of `size` bytes across `workers` workers
+    pub fn new(size: u64, workers: u64) -> Scheduler
+    {
+        Scheduler::with_plan(vec![Chunk { start: 0, end: size }], workers)
+    }
+
+    /// Schedule the planned `regions` of a file, in order, across
+    /// `workers` workers
+    pub fn with_plan(regions: Vec<Chunk>, workers: u64) -> Scheduler
+    {
+        let regions: VecDeque<Chunk> = regions.into_iter().filter(|r| r.len() > 0).collect();
+        Scheduler {
+            remaining: regions.iter().map(|r| r.len()).sum(),
+            regions: regions,
+            workers: max(workers, 1),
+            chunk_size: MIN_CHUNK_SIZE,
+        }
+    }
+}
+
+impl Schedule for Scheduler {
+
+    /// Hand out the next chunk, if any remain
+    fn next_chunk(&mut self) -> Option<Chunk>
+    {
+        // Split the tail evenly so no worker is left holding a large chunk
+        let fair_share = max(self.remaining / self.workers, MIN_CHUNK_SIZE);
+        let region = match self.regions.front_mut() {
+            Some(region) => region,
+            None => return None,
+        };
+        let len = min(min(self.chunk_size, fair_share), region.len());
+
+        let chunk = Chunk { start: region.start, end: region.start + len };
+        region.start = chunk.end;
+        self.remaining -= len;
+        if region.len() == 0 {
+            self.regions.pop_front();
+        }
+        Some(chunk)
+    }
+
+    /// Adapt the chunk size to a completed chunk's round trip time (time to
+    /// first byte) and total transfer time
+    fn report(&mut self, chunk: &Chunk, rtt: Duration, elapsed: Duration)
+    {
+        let secs = as_secs_f64(elapsed);
+        if secs <= 0.0 {
+            return
+        }
+
+        let throughput = chunk.len() as f64 / secs;
+        let target_secs = TARGET_CHUNK_SECS.max(MIN_RTTS_PER_CHUNK * as_secs_f64(rtt));
+        let ideal = (throughput * target_secs) as u64;
+
+        // Grow gradually so one fast chunk doesn't cause a huge jump
+        let grown = min(ideal, self.chunk_size.saturating_mul(2));
+        self.chunk_size = min(max(grown, MIN_CHUNK_SIZE), MAX_CHUNK_SIZE);
+        trace!("{:.0} B/s over {:?} (rtt {:?}), chunk size now {}",
+               throughput, elapsed, rtt, self.chunk_size);
+    }
+}
+
+/// Hands out chunks of a fixed size, so the requests made for a file are
+/// the same on every run
+pub struct FixedScheduler {
+    regions: VecDeque<Chunk>,
+    chunk_size: u64,
+}
+
+impl FixedScheduler {
+
+    /// Schedule the planned `regions` of a file, in order, in chunks of
+    /// `chunk_size` bytes
+    pub fn with_plan(regions: Vec<Chunk>, chunk_size: u64) -> FixedScheduler
+    {
+        FixedScheduler {
+            regions: regions.into_iter().filter(|r| r.len() > 0).collect(),
+            chunk_size: max(chunk_size, 1),
+        }
+    }
+}
+
+impl Schedule for FixedScheduler {
+
+    fn next_chunk(&mut self) -> Option<Chunk>
+    {
+        let region = match self.regions.front_mut() {
+            Some(region) => region,
+            None => return None,
+        };
+        let chunk = Chunk { start: region.start, end: region.start + min(self.chunk_size, region.len()) };
+        region.start = chunk.end;
+        if region.len() == 0 {
+            self.regions.pop_front();
+        }
+        Some(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Chunk,
+        FixedScheduler,
+        Schedule,
+        Scheduler,
+        MAX_CHUNK_SIZE,
+        MIN_CHUNK_SIZE,
+    };
+    use std::time::Duration;
+
+    fn chunk(start: u64, end: u64) -> Chunk
+    {
+        Chunk { start: start, end: end }
+    }
+
+    fn all_chunks(schedule: &mut dyn Schedule) -> Vec<Chunk>
+    {
+        let mut chunks = vec![];
+        while let Some(chunk) = schedule.next_chunk() {
+            chunks.push(chunk);
+        }
+        chunks
+    }
+
+    /// Whether `chunks` are contiguous from the start of `region`, in order,
+    /// and end with it
+    fn covers(chunks: &[Chunk], region: Chunk) -> bool
+    {
+        let mut pos = region.start;
+        for chunk in chunks {
+            if chunk.start != pos || chunk.len() == 0 {
+                return false
+            }
+            pos = chunk.end;
+        }
+        pos == region.end
+    }
+
+    #[test]
+    fn fixed_scheduler_follows_the_plan()
+    {
+        let mut schedule = FixedScheduler::with_plan(vec![chunk(0, 4), chunk(10, 12), chunk(4, 10)], 3);
+        assert_eq!(all_chunks(&mut schedule),
+                   vec![chunk(0, 3), chunk(3, 4), chunk(10, 12), chunk(4, 7), chunk(7, 10)]);
+    }
+
+    #[test]
+    fn empty_plans_have_no_chunks()
+    {
+        assert_eq!(all_chunks(&mut Scheduler::new(0, 4)), vec![]);
+        assert_eq!(all_chunks(&mut Scheduler::with_plan(vec![chunk(5, 5)], 4)), vec![]);
+        assert_eq!(all_chunks(&mut FixedScheduler::with_plan(vec![], 4)), vec![]);
+        assert_eq!(all_chunks(&mut FixedScheduler::with_plan(vec![chunk(3, 3)], 0)), vec![]);
+    }
+
+    #[test]
+    fn scheduler_covers_regions_in_order()
+    {
+        let size = 10 * MIN_CHUNK_SIZE;
+        let (head, tail) = (chunk(0, 3 * MIN_CHUNK_SIZE), chunk(3 * MIN_CHUNK_SIZE, size));
+        let chunks = all_chunks(&mut Scheduler::with_plan(vec![tail, head], 4));
+        let split = chunks.iter().position(|c| c.start == 0).unwrap();
+        assert!(covers(&chunks[..split], tail));
+        assert!(covers(&chunks[split..], head));
+        assert!(chunks.iter().all(|c| c.len() <= MIN_CHUNK_SIZE));
+    }
+
+    #[test]
+    fn scheduler_grows_chunks_with_throughput()
+    {
+        let size = 4 * MAX_CHUNK_SIZE;
+        let mut schedule = Scheduler::new(size, 2);
+        let first = schedule.next_chunk().unwrap();
+        assert_eq!(first, chunk(0, MIN_CHUNK_SIZE));
+        // Fast enough that the ideal chunk is far larger, so it only doubles
+        schedule.report(&first, Duration::from_millis(1), Duration::from_millis(10));
+        assert_eq!(schedule.next_chunk().unwrap().len(), 2 * MIN_CHUNK_SIZE);
+        for _ in 0..20 {
+            schedule.report(&first, Duration::from_millis(1), Duration::from_millis(1));
+        }
+        let mut rest = vec![chunk(0, 3 * MIN_CHUNK_SIZE)];
+        rest.extend(all_chunks(&mut schedule));
+        assert!(covers(&rest, chunk(0, size)));
+        assert!(rest.iter().all(|c| c.len() <= MAX_CHUNK_SIZE));
+        assert!(rest.iter().any(|c| c.len() == MAX_CHUNK_SIZE));
+    }
+}
@@ -0,0 +1,166 @@
+//! Downloading from custom url schemes
+//!
+//! Organizations with internal artifact protocols can teach sledge to
+//! download `myproto://...` urls by registering a `SchemeHandler` for the
+//! scheme, either from a program using the library or from a plugin loaded
+//! with `--plugin`. Urls with a registered scheme are downloaded through
+//! their handler, with the usual targets, checksums and retries: serially,
+//! or in parallel segments and resuming partial files if the handler can
+//! tell the size of a file before opening it.
+//!
+//! A plugin is a shared library exporting
+//!
+//! ```text
+//! #[no_mangle]
+//! pub fn sledge_plugin_register(registrar: &mut sledge::schemes::Registrar)
+//! ```
+//!
+//! which must be built with the same compiler and version of sledge.
+
+use hyper::Url;
+use std::io::{
+    self,
+    Read,
+};
+use std::path::Path;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+
+
+/// Symbol plugins export to register their handlers
+pub const PLUGIN_REGISTER: &'static str = "sledge_plugin_register";
+
+/// Downloads urls of a custom scheme
+pub trait SchemeHandler: Send + Sync {
+    /// Open `url` for reading from byte `offset`, returning the body and
+    /// the size of the whole file if known. An interrupted body is opened
+    /// again at the offset reached, if the retry policy allows.
+    fn open(&self, url: &Url, offset: u64) -> io::Result<(Box<dyn Read + Send>, Option<u64>)>;
+
+    /// The size of the file at `url`, if it can be told without opening
+    /// it. Files of a known size are fetched in segments opened at their
+    /// offsets.
+    fn size(&self, _: &Url) -> io::Result<Option<u64>>
+    {
+        Ok(None)
+    }
+
+    /// A connection to open `url` over again and again, e.g. at the start
+    /// of each chunk a worker takes, without connecting or logging in each
+    /// time. Handlers without one are opened with `open` every time.
+    fn connect(&self, _: &Url) -> Option<Box<dyn Connection>>
+    {
+        None
+    }
+}
+
+/// Opens files of a custom scheme one after another, keeping whatever
+/// they are fetched over between them
+pub trait Connection: Send {
+    /// Like `SchemeHandler::open`
+    fn open(&mut self, url: &Url, offset: u64) -> io::Result<(Box<dyn Read + Send>, Option<u64>)>;
+}
+
+static HANDLERS: Mutex<Vec<(String, Arc<dyn SchemeHandler>)>> = Mutex::new(Vec::new());
+
+/// Download urls of `scheme` with `handler`, replacing any handler
+/// registered for it before. The http schemes can't be replaced.
+pub fn register_scheme<H: SchemeHandler + 'static>(scheme: &str, handler: H) -> Result<(), String>
+{
+    register(scheme, Arc::new(handler))
+}
+
+fn register(scheme: &str, handler: Arc<dyn SchemeHandler>) -> Result<(), String>
+{
+    let scheme = scheme.to_lowercase();
+    if scheme == "http" || scheme == "https" {
+        return Err(format!("the {} scheme can't be replaced", scheme))
+    }
+    let mut handlers = HANDLERS.lock().unwrap();
+    handlers.retain(|&(ref registered, _)| *registered != scheme);
+    debug!("registered a handler for {}:// urls", scheme);
+    handlers.push((scheme, handler));
+    Ok(())
+}
+
+/// The handler registered for `scheme`, if any
+pub fn handler(scheme: &str) -> Option<Arc<dyn SchemeHandler>>
+{
+    HANDLERS.lock().unwrap().iter()
+        .find(|&&(ref registered, _)| registered == scheme)
+        .map(|&(_, ref handler)| handler.clone())
+}
+
+/// Schemes with a registered handler
+pub fn registered_schemes() -> Vec<String>
+{
+    HANDLERS.lock().unwrap().iter().map(|&(ref scheme, _)| scheme.clone()).collect()
+}
+
+/// Collects the handlers a plugin registers
+pub struct Registrar {
+    handlers: Vec<(String, Arc<dyn SchemeHandler>)>,
+}
+
+impl Registrar {
+    pub fn register<H: SchemeHandler + 'static>(&mut self, scheme: &str, handler: H)
+    {
+        self.handlers.push((scheme.to_owned(), Arc::new(handler)));
+    }
+}
+
+/// Load the plugin at `path` and register its handlers, returning their
+/// schemes. The library stays loaded for the rest of the process.
+#[cfg(unix)]
+pub fn load_plugin(path: &Path) -> Result<Vec<String>, String>
+{
+    use libc;
+    use std::ffi::{
+        CStr,
+        CString,
+    };
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dl_error = || unsafe {
+        let message = libc::dlerror();
+        if message.is_null() {
+            "unknown error".to_owned()
+        } else {
+            CStr::from_ptr(message).to_string_lossy().into_owned()
+        }
+    };
+
+    let c_path = try!(CString::new(path.as_os_str().as_bytes())
+                      .map_err(|_| "the path contains a NUL byte".to_owned()));
+    let symbol = CString::new(PLUGIN_REGISTER).unwrap();
+    let register_fn: fn(&mut Registrar) = unsafe {
+        let library = libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+        if library.is_null() {
+            return Err(dl_error())
+        }
+        let address = libc::dlsym(library, symbol.as_ptr());
+        if address.is_null() {
+            return Err(format!("{} does not export {}", path.display(), PLUGIN_REGISTER))
+        }
+        mem::transmute(address)
+    };
+
+    let mut registrar = Registrar { handlers: vec![] };
+    register_fn(&mut registrar);
+    let mut schemes = vec![];
+    for (scheme, handler) in registrar.handlers {
+        try!(register(&*scheme, handler));
+        schemes.push(scheme.to_lowercase());
+    }
+    Ok(schemes)
+}
+
+/// Plugins are only supported on unix
+#[cfg(not(unix))]
+pub fn load_plugin(path: &Path) -> Result<Vec<String>, String>
+{
+    Err(format!("unable to load {}: plugins are not supported on this platform", path.display()))
+}
@@ -0,0 +1,477 @@
+//! Self test against an embedded HTTP server
+//!
+//! A small local server serves a generated file, optionally with added
+//! latency, intermittent failures or without range support. Downloading it
+//! in different modes and comparing the result validates the build and the
+//! local network stack without depending on any remote server.
+
+use download::{
+    Download,
+    DownloadMode,
+    DownloadTarget,
+    OverwritePolicy,
+};
+use manifest::file_md5;
+use md5;
+use proxy::ProxySettings;
+use reporter::SilentReporter;
+use std::env;
+use std::fs;
+use std::io::{
+    self,
+    BufRead,
+    BufReader,
+    Write,
+};
+use std::net::{
+    SocketAddr,
+    TcpListener,
+    TcpStream,
+};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{
+    AtomicBool,
+    AtomicUsize,
+    Ordering,
+};
+use std::thread;
+use std::time::Duration;
+
+
+/// Size of the file served by default
+pub const DEFAULT_SIZE: u64 = 8 * 1024 * 1024;  // 8 MB
+
+#[derive(Clone,Debug)]
+pub struct ServerOptions {
+    /// Size of the served file
+    pub size: u64,
+    /// Delay before each response
+    pub latency: Duration,
+    /// Answer every nth request with a 503
+    pub fail_every: Option<usize>,
+    /// Whether Range requests are honored
+    pub ranges: bool,
+    /// Whether HEAD requests are answered, rather than rejected with a 405
+    pub head: bool,
+    /// Whether responses have a Content-Length (otherwise the body ends
+    /// when the connection closes)
+    pub content_length: bool,
+    /// Pause once, for the given time, before sending the byte at the given
+    /// offset
+    pub stall: Option<(u64, Duration)>,
+    /// XORed into every byte served, so that another server serves other
+    /// content
+    pub salt: u8,
+}
+
+impl Default for ServerOptions {
+    fn default() -> ServerOptions
+    {
+        ServerOptions {
+            size: DEFAULT_SIZE,
+            latency: Duration::from_millis(0),
+            fail_every: None,
+            ranges: true,
+            head: true,
+            content_length: true,
+            stall: None,
+            salt: 0,
+        }
+    }
+}
+
+/// A server on a local port, running until the process exits
+pub struct TestServer {
+    addr: SocketAddr,
+}
+
+impl TestServer {
+
+    pub fn start(options: ServerOptions) -> io::Result<TestServer>
+    {
+        let listener = try!(TcpListener::bind("127.0.0.1:0"));
+        let addr = try!(listener.local_addr());
+        let options = Arc::new(options);
+        let requests = Arc::new(AtomicUsize::new(0));
+        let stalled = Arc::new(AtomicBool::new(false));
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (options, requests, stalled) = (options.clone(), requests.clone(), stalled.clone());
+                match stream {
+                    Ok(stream) => {
+                        thread::spawn(move || {
+                            if let Err(e) = serve(stream, &options, &requests, &stalled) {
+                                debug!("selftest server: {}", e);
+                            }
+                        });
+                    },
+                    Err(e) => debug!("selftest server: {}", e),
+                }
+            }
+        });
+        Ok(TestServer { addr: addr })
+    }
+
+    /// Url of the served file
+    pub fn url(&self) -> String
+    {
+        format!("http://{}/selftest.bin", self.addr)
+    }
+}
+
+/// The byte at `offset` of the served file
+fn payload(offset: u64) -> u8
+{
+    (offset.wrapping_mul(31) ^ (offset >> 11)) as u8
+}
+
+/// The md5 of the served file
+fn payload_md5(size: u64) -> String
+{
+    let mut hasher = md5::Context::new();
+    let mut buf = Vec::with_capacity(64 * 1024);
+    let mut offset = 0;
+    while offset < size {
+        buf.clear();
+        let end = ::std::cmp::min(size, offset + 64 * 1024);
+        buf.extend((offset..end).map(payload));
+        hasher.consume(&buf);
+        offset = end;
+    }
+    format!("{:x}", hasher.compute())
+}
+
+/// Answer a single request, then close the connection
+fn serve(stream: TcpStream, options: &ServerOptions, requests: &AtomicUsize,
+         stalled: &AtomicBool) -> io::Result<()>
+{
+    let mut reader = BufReader::new(try!(stream.try_clone()));
+    let mut request_line = String::new();
+    try!(reader.read_line(&mut request_line));
+    let mut range = None;
+    loop {
+        let mut line = String::new();
+        if try!(reader.read_line(&mut line)) == 0 || line.trim().is_empty() {
+            break;
+        }
+        let lower = line.to_lowercase();
+        if lower.starts_with("range:") {
+            range = parse_range(line[6..].trim(), options.size);
+        }
+    }
+
+    thread::sleep(options.latency);
+    let mut stream = stream;
+    let n = requests.fetch_add(1, Ordering::SeqCst) + 1;
+    if options.fail_every.map_or(false, |every| n % every == 0) {
+        return write!(stream, "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\
+                               Connection: close\r\n\r\n")
+    }
+
+    if !options.head && request_line.starts_with("HEAD") {
+        return write!(stream, "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\
+                               Connection: close\r\n\r\n")
+    }
+
+    let (start, end) = match range {
+        Some(range) if options.ranges => range,
+        _ => (0, options.size),
+    };
+    let partial = options.ranges && range.is_some();
+    try!(write!(stream, "HTTP/1.1 {}\r\nConnection: close\r\n",
+                if partial { "206 Partial Content" } else { "200 OK" }));
+    if options.content_length {
+        try!(write!(stream, "Content-Length: {}\r\n", end - start));
+    }
+    if options.ranges {
+        try!(write!(stream, "Accept-Ranges: bytes\r\n"));
+    }
+    if partial {
+        try!(write!(stream, "Content-Range: bytes {}-{}/{}\r\n", start, end - 1, options.size));
+    }
+    try!(write!(stream, "\r\n"));
+
+    if request_line.starts_with("HEAD") {
+        return Ok(())
+    }
+    let mut buf = Vec::with_capacity(64 * 1024);
+    let mut offset = start;
+    while offset < end {
+        buf.clear();
+        let mut chunk_end = ::std::cmp::min(end, offset + 64 * 1024);
+        if let Some((at, pause)) = options.stall {
+            if at == offset && !stalled.swap(true, Ordering::SeqCst) {
+                try!(stream.flush());
+                thread::sleep(pause);
+            } else if at > offset && at < chunk_end && !stalled.load(Ordering::SeqCst) {
+                // Send up to the byte so the stall starts there
+                chunk_end = at;
+            }
+        }
+        buf.extend((offset..chunk_end).map(|offset| payload(offset) ^ options.salt));
+        try!(stream.write_all(&buf));
+        offset = chunk_end;
+    }
+    Ok(())
+}
+
+/// Parse `bytes=a-b` (inclusive) into an exclusive range within the file
+pub fn parse_range(value: &str, size: u64) -> Option<(u64, u64)>
+{
+    if !value.starts_with("bytes=") {
+        return None
+    }
+    let mut bounds = value[6..].splitn(2, '-');
+    let start = match bounds.next().and_then(|s| s.trim().parse::<u64>().ok()) {
+        Some(start) if start < size => start,
+        _ => return None,
+    };
+    let end = match bounds.next().map(|e| e.trim()) {
+        Some("") | None => size,
+        Some(end) => match end.parse::<u64>() {
+            Ok(end) => ::std::cmp::min(end + 1, size),
+            Err(_) => return None,
+        },
+    };
+    if end > start { Some((start, end)) } else { None }
+}
+
+#[derive(Clone,Debug)]
+pub struct Scenario {
+    pub name: String,
+    pub server: ServerOptions,
+    pub mode: DownloadMode,
+}
+
+/// The scenarios run when no server behavior is specified
+pub fn default_scenarios(threads: u8) -> Vec<Scenario>
+{
+    let scenario = |name: &str, server: ServerOptions, mode: DownloadMode| Scenario {
+        name: name.to_owned(),
+        server: server,
+        mode: mode,
+    };
+    vec![
+        scenario("serial", ServerOptions::default(), DownloadMode::Serial),
+        scenario("parallel", ServerOptions::default(), DownloadMode::Parallel(threads)),
+        scenario("streaming", ServerOptions::default(), DownloadMode::Streaming(threads)),
+        scenario("parallel with latency", ServerOptions {
+            latency: Duration::from_millis(200), ..Default::default()
+        }, DownloadMode::Parallel(threads)),
+        scenario("parallel with a flaky server", ServerOptions {
+            fail_every: Some(3), ..Default::default()
+        }, DownloadMode::Parallel(threads)),
+        scenario("serial without ranges", ServerOptions {
+            ranges: false, ..Default::default()
+        }, DownloadMode::Serial),
+        scenario("serial without a content length", ServerOptions {
+            content_length: false, ..Default::default()
+        }, DownloadMode::Serial),
+        scenario("parallel without HEAD", ServerOptions {
+            head: false, ..Default::default()
+        }, DownloadMode::Parallel(threads)),
+        scenario("parallel falling back without ranges", ServerOptions {
+            ranges: false, ..Default::default()
+        }, DownloadMode::Parallel(threads)),
+    ]
+}
+
+/// Download from a server set up for the scenario and check the result
+pub fn run(scenario: &Scenario) -> Result<(), String>
+{
+    let server = try!(TestServer::start(scenario.server.clone()).map_err(|e| {
+        format!("unable to start the test server: {}", e)
+    }));
+    let path = env::temp_dir().join(format!("sledge-selftest-{}.bin", process::id()));
+    let target = path.to_string_lossy().into_owned();
+
+    let result = Download::<SilentReporter>::new(server.url())
+        .target(DownloadTarget::File(target.clone()))
+        // A file left by an interrupted run is replaced
+        .overwrite(OverwritePolicy::Overwrite)
+        // The server is local, so never behind a proxy
+        .proxies(ProxySettings::default())
+        .mode(scenario.mode.clone())
+        .download();
+
+    let checked = match result {
+        Err(e) => Err(format!("download failed: {}", e)),
+        Ok(result) if result.bytes != scenario.server.size => Err(format!(
+            "wrote {} bytes, expected {}", result.bytes, scenario.server.size)),
+        Ok(_) => match file_md5(&*target) {
+            Ok(Some(ref md5)) if *md5 == payload_md5(scenario.server.size) => Ok(()),
+            Ok(_) => Err("the downloaded file is corrupt".to_owned()),
+            Err(e) => Err(format!("unable to read the downloaded file: {}", e)),
+        },
+    };
+    let _ = fs::remove_file(&path);
+    checked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        payload,
+        payload_md5,
+        ServerOptions,
+        TestServer,
+    };
+    use download::{
+        Download,
+        DownloadMode,
+        DownloadTarget,
+        OverwritePolicy,
+    };
+    use journal::{
+        Journal,
+        JournalEntry,
+    };
+    use manifest::file_md5;
+    use md5;
+    use proxy::ProxySettings;
+    use reporter::SilentReporter;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::time::{
+        Duration,
+        Instant,
+    };
+
+    /// Download the served file in parallel with a stall timeout, checking
+    /// its content, and return how long it took
+    fn parallel_with_stall_timeout(name: &str, server: ServerOptions) -> Duration
+    {
+        let size = server.size;
+        let server = TestServer::start(server).unwrap();
+        let path = env::temp_dir().join(format!("sledge-test-{}-{}.bin", name, process::id()));
+        let target = path.to_string_lossy().into_owned();
+
+        let started = Instant::now();
+        let result = Download::<SilentReporter>::new(server.url())
+            .target(DownloadTarget::File(target.clone()))
+            .overwrite(OverwritePolicy::Overwrite)
+            .proxies(ProxySettings::default())
+            .mode(DownloadMode::Parallel(4))
+            .stall_timeout(Some(Duration::from_millis(500)))
+            .download()
+            .unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!(result.bytes, size);
+        assert_eq!(file_md5(&*target).unwrap(), Some(payload_md5(size)));
+        let _ = fs::remove_file(&path);
+        elapsed
+    }
+
+    /// Resume a serial download of the served file from `partial`, checking
+    /// its content, and return how many bytes were downloaded
+    fn resume_from(name: &str, partial: &[u8]) -> u64
+    {
+        let size = 256 * 1024;
+        let server = TestServer::start(ServerOptions { size: size, ..Default::default() }).unwrap();
+        let path = env::temp_dir().join(format!("sledge-test-{}-{}.bin", name, process::id()));
+        let target = path.to_string_lossy().into_owned();
+        fs::write(&path, partial).unwrap();
+
+        let result = Download::<SilentReporter>::new(server.url())
+            .target(DownloadTarget::File(target.clone()))
+            .part_file(false)
+            .resume(true)
+            .proxies(ProxySettings::default())
+            .mode(DownloadMode::Serial)
+            .download()
+            .unwrap();
+
+        assert_eq!(file_md5(&*target).unwrap(), Some(payload_md5(size)));
+        let _ = fs::remove_file(&path);
+        result.bytes
+    }
+
+    #[test]
+    fn resumes_a_partial_file_of_the_same_content()
+    {
+        let partial: Vec<u8> = (0..100 * 1000).map(payload).collect();
+        assert_eq!(resume_from("resume", &*partial), 256 * 1024 - 100 * 1000);
+    }
+
+    #[test]
+    fn starts_over_when_the_source_serves_other_bytes()
+    {
+        let mut partial: Vec<u8> = (0..100 * 1000).map(payload).collect();
+        partial[99 * 1000] ^= 0xff;
+        assert_eq!(resume_from("resume-other", &*partial), 256 * 1024);
+    }
+
+    /// Resume a parallel download from the first half of the served file,
+    /// journaled as a run against another url would have left it, from a
+    /// server XORing `salt` into what it serves. Checks that the result is
+    /// what that server serves.
+    fn resume_journaled_from(name: &str, salt: u8)
+    {
+        let (size, kept) = (256 * 1024, 128 * 1024);
+        let path = env::temp_dir().join(format!("sledge-test-{}-{}.bin", name, process::id()));
+        let target = path.to_string_lossy().into_owned();
+        let mut partial: Vec<u8> = (0..kept).map(payload).collect();
+        partial.resize(size as usize, 0);
+        fs::write(&path, &*partial).unwrap();
+        let entry = JournalEntry {
+            offset: 0,
+            len: kept,
+            md5: format!("{:x}", md5::compute(&partial[..kept as usize])),
+        };
+        Journal::create(&*target).unwrap().record(&entry).unwrap();
+
+        let server = TestServer::start(ServerOptions {
+            size: size, salt: salt, ..Default::default()
+        }).unwrap();
+        Download::<SilentReporter>::new(server.url())
+            .target(DownloadTarget::File(target.clone()))
+            .part_file(false)
+            .journal(true)
+            .resume(true)
+            .proxies(ProxySettings::default())
+            .mode(DownloadMode::Parallel(4))
+            .download()
+            .unwrap();
+
+        let served: Vec<u8> = (0..size).map(|offset| payload(offset) ^ salt).collect();
+        assert_eq!(file_md5(&*target).unwrap(), Some(format!("{:x}", md5::compute(&served))));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(Journal::path_for(&*target));
+    }
+
+    #[test]
+    fn resumes_journaled_ranges_from_another_url()
+    {
+        resume_journaled_from("resume-journal", 0);
+    }
+
+    #[test]
+    fn starts_over_when_another_url_serves_other_bytes_than_journaled()
+    {
+        resume_journaled_from("resume-journal-other", 0x5a);
+    }
+
+    #[test]
+    fn parallel_without_stall_completes()
+    {
+        parallel_with_stall_timeout("no-stall", ServerOptions {
+            size: 1024 * 1024, ..Default::default()
+        });
+    }
+
+    #[test]
+    fn stalled_segment_is_redispatched()
+    {
+        // Waiting out the stall would take far longer than re-dispatching
+        let elapsed = parallel_with_stall_timeout("stall", ServerOptions {
+            size: 1024 * 1024,
+            stall: Some((300 * 1000, Duration::from_secs(10))),
+            ..Default::default()
+        });
+        assert!(elapsed < Duration::from_secs(8), "took {:?}", elapsed);
+    }
+}
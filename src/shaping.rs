@@ -0,0 +1,86 @@
+//! Simulated link bandwidth and latency
+//!
+//! Development aid for reproducing slow links locally: response bodies are
+//! read through a `ShapedReader`, which delays the first byte of each
+//! response by the latency and holds the bytes read by all responses
+//! sharing a `Link` to its bandwidth.
+
+use std::io::{
+    self,
+    Read,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
+use stats::as_secs_f64;
+
+
+/// A simulated link shared by every connection of a download
+pub struct Link {
+    /// Bytes per second, or None for unlimited
+    bandwidth: Option<u64>,
+    /// Delay before the first byte of each response
+    latency: Duration,
+    /// When the link was first used and the bytes carried since
+    usage: Mutex<Option<(Instant, u64)>>,
+}
+
+impl Link {
+    pub fn new(bandwidth: Option<u64>, latency: Duration) -> Link
+    {
+        Link { bandwidth: bandwidth, latency: latency, usage: Mutex::new(None) }
+    }
+
+    /// Account for `len` bytes, sleeping until the link could carry them
+    fn carry(&self, len: usize)
+    {
+        let bandwidth = match self.bandwidth {
+            Some(bandwidth) if bandwidth > 0 => bandwidth,
+            _ => return,
+        };
+        let wait = {
+            let mut usage = self.usage.lock().unwrap();
+            let (start, bytes) = usage.unwrap_or((Instant::now(), 0));
+            let bytes = bytes + len as u64;
+            *usage = Some((start, bytes));
+            let due = bytes as f64 / bandwidth as f64;
+            due - as_secs_f64(start.elapsed())
+        };
+        if wait > 0.0 {
+            thread::sleep(Duration::from_millis((wait * 1000.0) as u64));
+        }
+    }
+}
+
+/// Reads a response body as if over a `Link`
+pub struct ShapedReader<R> {
+    inner: R,
+    link: Arc<Link>,
+    started: bool,
+}
+
+impl<R: Read> ShapedReader<R> {
+    pub fn new(inner: R, link: Arc<Link>) -> ShapedReader<R>
+    {
+        ShapedReader { inner: inner, link: link, started: false }
+    }
+}
+
+impl<R: Read> Read for ShapedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        if !self.started {
+            self.started = true;
+            thread::sleep(self.link.latency);
+        }
+        let n = try!(self.inner.read(buf));
+        self.link.carry(n);
+        Ok(n)
+    }
+}
@@ -0,0 +1,171 @@
+//! Moving an interrupted download between machines
+//!
+//! A state bundle holds what `--continue` needs to finish a transfer: the
+//! url, the name of the partial file, its journal (if one was kept) and the
+//! partial file itself. Importing it on another machine with access to the
+//! same url recreates the partial file and journal there:
+//!
+//! ```text
+//! sledge-state 1
+//! url https://example.com/big.iso
+//! name big.iso
+//! length 8388608
+//! journal 0 4194304 <md5>
+//! journal 4194304 4194304 <md5>
+//! data
+//! <length bytes of the partial file>
+//! ```
+
+use journal::{
+    Journal,
+    JournalEntry,
+};
+use std::fs::{
+    self,
+    File,
+    OpenOptions,
+};
+use std::io::{
+    self,
+    BufRead,
+    BufReader,
+    Read,
+    Write,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+
+const HEADER: &'static str = "sledge-state 1";
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct TransferState {
+    /// Url being downloaded
+    pub url: String,
+    /// File name of the partial download
+    pub name: String,
+    /// Bytes of the partial file in the bundle
+    pub length: u64,
+    /// Journal of the partial file, empty if none was kept
+    pub journal: Vec<JournalEntry>,
+}
+
+/// Bundle the partial download at `path` of `url`, with its journal if it
+/// has one, into `bundle`
+pub fn export(url: &str, path: &Path, bundle: &Path) -> io::Result<TransferState>
+{
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Err(invalid("the partial download is not a file")),
+    };
+    let target = path.to_string_lossy().into_owned();
+    let journal = match Journal::read(&*target) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e),
+    };
+    let partial = try!(File::open(path));
+    let length = try!(partial.metadata()).len();
+    let state = TransferState { url: url.to_owned(), name: name, length: length, journal: journal };
+
+    let mut out = try!(File::create(bundle));
+    try!(writeln!(out, "{}", HEADER));
+    try!(writeln!(out, "url {}", state.url));
+    try!(writeln!(out, "name {}", state.name));
+    try!(writeln!(out, "length {}", state.length));
+    for entry in &state.journal {
+        try!(writeln!(out, "journal {} {} {}", entry.offset, entry.len, entry.md5));
+    }
+    try!(writeln!(out, "data"));
+    let copied = try!(io::copy(&mut partial.take(length), &mut out));
+    if copied != length {
+        return Err(invalid("the partial download changed while exporting"))
+    }
+    try!(out.sync_all());
+    Ok(state)
+}
+
+/// Recreate the partial download (and its journal) of `bundle` in `dir`,
+/// returning its state and path. An existing file is not overwritten.
+pub fn import(bundle: &Path, dir: &Path) -> io::Result<(TransferState, PathBuf)>
+{
+    let mut reader = BufReader::new(try!(File::open(bundle)));
+    if try!(read_line(&mut reader)) != HEADER {
+        return Err(invalid("not a sledge state bundle"))
+    }
+    let url = try!(field(&*try!(read_line(&mut reader)), "url"));
+    let name = try!(field(&*try!(read_line(&mut reader)), "name"));
+    let length = try!(try!(field(&*try!(read_line(&mut reader)), "length")).parse()
+                      .map_err(|_| invalid("invalid length")));
+    let mut journal = vec![];
+    loop {
+        let line = try!(read_line(&mut reader));
+        if line == "data" {
+            break
+        }
+        journal.push(try!(parse_entry(&*try!(field(&*line, "journal")))));
+    }
+    // The name comes from another machine, so it may not leave `dir`
+    if name.is_empty() || name.contains('/') || name.contains('\\') || name == "." || name == ".." {
+        return Err(invalid("invalid file name"))
+    }
+    let state = TransferState { url: url, name: name, length: length, journal: journal };
+
+    let path = dir.join(&state.name);
+    let mut partial = try!(OpenOptions::new().write(true).create_new(true).open(&path));
+    let copied = try!(io::copy(&mut reader.by_ref().take(length), &mut partial));
+    if copied != length {
+        let _ = fs::remove_file(&path);
+        return Err(invalid("truncated bundle"))
+    }
+    try!(partial.sync_all());
+
+    if !state.journal.is_empty() {
+        let journal = try!(Journal::create(&*path.to_string_lossy()));
+        for entry in &state.journal {
+            try!(journal.record(entry));
+        }
+    }
+    Ok((state, path))
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String>
+{
+    let mut line = String::new();
+    if try!(reader.read_line(&mut line)) == 0 {
+        return Err(invalid("truncated bundle"))
+    }
+    Ok(line.trim_end_matches('\n').to_owned())
+}
+
+fn field(line: &str, name: &str) -> io::Result<String>
+{
+    let mut parts = line.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if key == name => Ok(value.to_owned()),
+        _ => Err(invalid(&*format!("invalid {}", name))),
+    }
+}
+
+fn parse_entry(value: &str) -> io::Result<JournalEntry>
+{
+    let fields: Vec<&str> = value.split(' ').collect();
+    if fields.len() != 3 {
+        return Err(invalid("invalid journal entry"))
+    }
+    match (fields[0].parse(), fields[1].parse()) {
+        (Ok(offset), Ok(len)) => Ok(JournalEntry {
+            offset: offset,
+            len: len,
+            md5: fields[2].to_owned(),
+        }),
+        _ => Err(invalid("invalid journal entry")),
+    }
+}
+
+fn invalid(message: &str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,137 @@
+//! Transfer statistics used to report progress and estimate completion
+
+use chrono::{
+    DateTime,
+    Local,
+};
+use chrono::Duration as ChronoDuration;
+use std::time::{
+    Duration,
+    Instant,
+};
+
+
+#[derive(Clone,Debug)]
+pub struct Stats {
+    /// Number of bytes transferred so far
+    pub done: u64,
+    /// Number of bytes expected in total
+    pub total: u64,
+    /// When the transfer started
+    start: Instant,
+}
+
+impl Stats {
+
+    /// Start tracking a transfer of `total` bytes
+    pub fn new(total: u64) -> Stats
+    {
+        Stats {
+            done: 0,
+            total: total,
+            start: Instant::now(),
+        }
+    }
+
+    /// Record that `len` more bytes were transferred
+    pub fn add(&mut self, len: u64)
+    {
+        self.done += len;
+    }
+
+    /// Record that `len` more bytes are expected
+    pub fn grow(&mut self, len: u64)
+    {
+        self.total += len;
+    }
+
+    /// Time since the transfer started
+    pub fn elapsed(&self) -> Duration
+    {
+        self.start.elapsed()
+    }
+
+    /// Percentage of the transfer that is complete
+    pub fn percent(&self) -> f64
+    {
+        if self.total == 0 {
+            100.0
+        } else {
+            100.0 * self.done as f64 / self.total as f64
+        }
+    }
+
+    /// Average transfer rate in bytes per second
+    pub fn rate(&self) -> f64
+    {
+        let secs = as_secs_f64(self.elapsed());
+        if secs > 0.0 { self.done as f64 / secs } else { 0.0 }
+    }
+
+    /// Estimated time remaining, if the rate is known
+    pub fn eta(&self) -> Option<Duration>
+    {
+        let rate = self.rate();
+        if rate <= 0.0 {
+            return None
+        }
+        let remaining = self.total.saturating_sub(self.done) as f64 / rate;
+        Some(Duration::from_secs(remaining.ceil() as u64))
+    }
+
+    /// Estimated wall clock time at which the transfer will finish
+    pub fn finishes_at(&self) -> Option<DateTime<Local>>
+    {
+        self.eta()
+            .and_then(|eta| ChronoDuration::from_std(eta).ok())
+            .map(|eta| Local::now() + eta)
+    }
+}
+
+#[derive(Clone,Debug)]
+pub struct BatchStats {
+    /// Index (starting at 1) of the file currently downloading
+    pub file: usize,
+    /// Number of files in the batch
+    pub files: usize,
+    /// Bytes transferred across all files in the batch
+    pub stats: Stats,
+    /// Number of files whose size is known so far
+    pub sized: usize,
+}
+
+impl BatchStats {
+
+    /// Bytes expected across all files in the batch, once the size of
+    /// every one of them is known
+    pub fn total(&self) -> Option<u64>
+    {
+        if self.sized == self.files { Some(self.stats.total) } else { None }
+    }
+}
+
+/// Format a wall clock time as `14:32`
+pub fn format_clock(time: &DateTime<Local>) -> String
+{
+    time.format("%H:%M").to_string()
+}
+
+/// Convert a duration to fractional seconds
+pub fn as_secs_f64(duration: Duration) -> f64
+{
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+/// Format a duration as `1h02m03s`, `2m03s` or `3s`
+pub fn format_duration(duration: Duration) -> String
+{
+    let secs = duration.as_secs();
+    let (h, m, s) = (secs / 3600, (secs / 60) % 60, secs % 60);
+    if h > 0 {
+        format!("{}h{:02}m{:02}s", h, m, s)
+    } else if m > 0 {
+        format!("{}m{:02}s", m, s)
+    } else {
+        format!("{}s", s)
+    }
+}
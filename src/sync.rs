@@ -0,0 +1,137 @@
+//! Syncing a local directory with a remote one
+//!
+//! The remote directory is listed (see `listing`) and each file compared
+//! with its local copy by size, modification time and, where the ETag is
+//! an md5, contents. Only new or changed files need downloading.
+
+use client::ClientConfig;
+use errors::DownloadError;
+use filter::Filter;
+use listing::{
+    self,
+    RemoteEntry,
+};
+use manifest::file_md5;
+use std::fs;
+use std::io;
+use std::path::{
+    Component,
+    Path,
+    PathBuf,
+};
+use std::time::UNIX_EPOCH;
+
+
+#[derive(Clone,Debug,Default)]
+pub struct SyncPlan {
+    /// Remote files that are new or changed, and where to save them
+    pub fetch: Vec<(RemoteEntry, PathBuf)>,
+    /// Remote files whose local copy is up to date
+    pub unchanged: Vec<RemoteEntry>,
+    /// Local files with no remote counterpart
+    pub orphans: Vec<PathBuf>,
+}
+
+/// Compare the files `filter` allows in the remote directory `url` with
+/// those in the local directory `local`
+pub fn plan(client: &ClientConfig, url: &str, local: &Path, filter: &Filter)
+            -> Result<SyncPlan, DownloadError>
+{
+    let entries = try!(listing::list(client, url));
+    let mut plan = SyncPlan::default();
+    let mut expected = vec![];
+
+    for entry in entries.into_iter().filter(|e| filter.allows(&*e.path)) {
+        let path = match local_path(local, &*entry.path) {
+            Some(path) => path,
+            None => {
+                warn!("Skipping {}, its path leaves {}", entry.url, local.display());
+                continue;
+            },
+        };
+        expected.push(path.clone());
+        if try!(is_changed(&entry, &path)) {
+            plan.fetch.push((entry, path));
+        } else {
+            plan.unchanged.push(entry);
+        }
+    }
+
+    let mut local_files = vec![];
+    try!(walk(local, &mut local_files));
+    // Files the filter excludes are left alone rather than deleted
+    plan.orphans = local_files.into_iter()
+        .filter(|p| !expected.contains(p))
+        .filter(|p| match p.strip_prefix(local) {
+            Ok(relative) => filter.allows(&*relative.to_string_lossy().replace('\\', "/")),
+            Err(_) => false,
+        })
+        .collect();
+    Ok(plan)
+}
+
+/// Where a remote path is saved under `local`, or None if it would end up
+/// outside of it
+pub fn local_path(local: &Path, remote: &str) -> Option<PathBuf>
+{
+    let relative = Path::new(remote);
+    let normal = relative.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    });
+    if normal && !remote.is_empty() {
+        Some(local.join(relative))
+    } else {
+        None
+    }
+}
+
+/// Whether the local copy of `entry` is missing or differs from it
+fn is_changed(entry: &RemoteEntry, path: &Path) -> io::Result<bool>
+{
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e),
+    };
+
+    if let Some(size) = entry.size {
+        if size != metadata.len() {
+            return Ok(true)
+        }
+    }
+
+    if let Some(modified) = entry.modified {
+        let local = try!(metadata.modified());
+        let local = local.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+        if modified > local {
+            return Ok(true)
+        }
+    }
+
+    match entry.etag_md5() {
+        Some(md5) => Ok(try!(file_md5(path)) != Some(md5)),
+        None => Ok(false),
+    }
+}
+
+/// Collect the files under `dir`, ignoring journals of partial downloads
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()>
+{
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = try!(entry);
+        let path = entry.path();
+        let file_type = try!(entry.file_type());
+        if file_type.is_dir() {
+            try!(walk(&path, files));
+        } else if path.extension().map_or(true, |ext| ext != "sledge") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,144 @@
+//! Customizable progress line templates
+//!
+//! A template is a string with placeholders, e.g. `"{bar} {percent} {rate}
+//! {eta}"`. The bar expands to fill whatever width the other fields leave.
+
+use std::cmp::min;
+use stats::{
+    Stats,
+    format_clock,
+    format_duration,
+};
+use std::str::FromStr;
+use units::{
+    Units,
+    format_bytes,
+    format_rate,
+};
+
+
+pub const DEFAULT_TEMPLATE: &'static str = "{bytes} / {total} {bar} {percent} {rate} {eta} (~ finishes {finish})";
+
+#[derive(Clone,Debug,PartialEq)]
+enum Token {
+    Literal(String),
+    Bar,
+    Percent,
+    Rate,
+    Eta,
+    Finish,
+    Elapsed,
+    Bytes,
+    Total,
+}
+
+#[derive(Clone,Debug)]
+pub struct ProgressTemplate {
+    tokens: Vec<Token>,
+}
+
+impl Default for ProgressTemplate {
+    fn default() -> ProgressTemplate
+    {
+        DEFAULT_TEMPLATE.parse().unwrap()
+    }
+}
+
+impl FromStr for ProgressTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ProgressTemplate, String>
+    {
+        let mut tokens = vec![];
+        let mut rest = s;
+
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                tokens.push(Token::Literal(rest[..open].to_owned()));
+            }
+            let close = match rest[open..].find('}') {
+                Some(close) => open + close,
+                None => return Err(format!("unterminated placeholder in template '{}'", s)),
+            };
+            tokens.push(match &rest[open + 1..close] {
+                "bar" => Token::Bar,
+                "percent" => Token::Percent,
+                "rate" => Token::Rate,
+                "eta" => Token::Eta,
+                "finish" => Token::Finish,
+                "elapsed" => Token::Elapsed,
+                "bytes" => Token::Bytes,
+                "total" => Token::Total,
+                other => return Err(format!(
+                    "unknown placeholder '{{{}}}' in template, expected one of {{bar}}, \
+                     {{percent}}, {{rate}}, {{eta}}, {{finish}}, {{elapsed}}, {{bytes}}, {{total}}", other)),
+            });
+            rest = &rest[close + 1..];
+        }
+
+        if !rest.is_empty() {
+            tokens.push(Token::Literal(rest.to_owned()));
+        }
+
+        Ok(ProgressTemplate { tokens: tokens })
+    }
+}
+
+impl ProgressTemplate {
+
+    /// Render the template to a line at most `width` characters wide
+    pub fn render(&self, stats: &Stats, units: Units, width: usize) -> String
+    {
+        let fields: Vec<Option<String>> = self.tokens.iter().map(|token| match *token {
+            Token::Literal(ref s) => Some(s.clone()),
+            Token::Bar => None,
+            Token::Percent => Some(format!("{:5.1}%", stats.percent())),
+            Token::Rate => Some(format_rate(stats.rate(), units)),
+            Token::Eta => Some(match stats.eta() {
+                Some(eta) => format_duration(eta),
+                None => "--".to_owned(),
+            }),
+            Token::Finish => Some(match stats.finishes_at() {
+                Some(time) => format_clock(&time),
+                None => "--:--".to_owned(),
+            }),
+            Token::Elapsed => Some(format_duration(stats.elapsed())),
+            Token::Bytes => Some(format_bytes(stats.done, units)),
+            Token::Total => Some(format_bytes(stats.total, units)),
+        }).collect();
+
+        let used: usize = fields.iter()
+            .map(|f| f.as_ref().map_or(0, |s| s.chars().count()))
+            .sum();
+        let bars = self.tokens.iter().filter(|t| **t == Token::Bar).count();
+        let bar_width = if bars > 0 { width.saturating_sub(used) / bars } else { 0 };
+
+        fields.into_iter()
+            .map(|f| f.unwrap_or_else(|| render_bar(stats, bar_width)))
+            .collect()
+    }
+}
+
+/// Render a bar like `[=====>    ]` of the given width
+fn render_bar(stats: &Stats, width: usize) -> String
+{
+    if width < 3 {
+        return String::new()
+    }
+    let inner = width - 2;
+    let filled = min((stats.percent() / 100.0 * inner as f64) as usize, inner);
+
+    let mut bar = String::with_capacity(width);
+    bar.push('[');
+    for i in 0..inner {
+        bar.push(if i + 1 < filled || filled == inner {
+            '='
+        } else if i + 1 == filled {
+            '>'
+        } else {
+            ' '
+        });
+    }
+    bar.push(']');
+    bar
+}
@@ -0,0 +1,64 @@
+//! Limiting the rate of a download
+//!
+//! A `RateLimiter` is a token bucket shared by every connection of a
+//! download, so `--limit-rate` caps their combined throughput. Each read
+//! takes tokens for its bytes; a reader taking more than the bucket holds
+//! goes into debt and sleeps until the debt is repaid, and so do readers
+//! after it, in turn.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
+use stats::as_secs_f64;
+
+
+/// Seconds of transfer the bucket holds, allowing short bursts
+const BURST_SECS: f64 = 0.25;
+
+pub struct RateLimiter {
+    /// Bytes per second
+    rate: u64,
+    /// When tokens were last added, and the tokens (bytes) available,
+    /// negative while in debt
+    bucket: Mutex<(Instant, f64)>,
+}
+
+impl RateLimiter {
+
+    /// Limit transfers to `rate` bytes per second
+    pub fn new(rate: u64) -> RateLimiter
+    {
+        let rate = if rate > 0 { rate } else { 1 };
+        RateLimiter {
+            rate: rate,
+            bucket: Mutex::new((Instant::now(), rate as f64 * BURST_SECS)),
+        }
+    }
+
+    /// Bytes per second allowed
+    pub fn rate(&self) -> u64
+    {
+        self.rate
+    }
+
+    /// Take tokens for `len` bytes, sleeping until they are available
+    pub fn take(&self, len: usize)
+    {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (filled, tokens) = *bucket;
+            let now = Instant::now();
+            let capacity = self.rate as f64 * BURST_SECS;
+            let tokens = capacity.min(tokens + as_secs_f64(now - filled) * self.rate as f64)
+                - len as f64;
+            *bucket = (now, tokens);
+            -tokens / self.rate as f64
+        };
+        if wait > 0.0 {
+            thread::sleep(Duration::from_millis((wait * 1000.0) as u64));
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Bearer tokens sent with every request
+//!
+//! A token is given directly or as a file, which is read again for each
+//! request so that a long queue of downloads picks up a token refreshed on
+//! disk (e.g. by a credential helper) without restarting. A `TokenSource`
+//! obtains tokens some other way, e.g. from a cloud provider's token
+//! service, refreshing them as they expire.
+
+use std::fs;
+use std::io;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::Arc;
+
+
+/// Obtains the current token for each request
+pub trait TokenSource: Send + Sync {
+    fn token(&self) -> io::Result<String>;
+}
+
+#[derive(Clone)]
+pub enum BearerToken {
+    /// The token itself
+    Value(String),
+    /// A file holding the token, surrounding whitespace ignored
+    File(PathBuf),
+    /// Tokens obtained from a source
+    Source(Arc<dyn TokenSource>),
+}
+
+impl BearerToken {
+
+    /// The file the token is read from, if any
+    pub fn path(&self) -> Option<&Path>
+    {
+        match *self {
+            BearerToken::File(ref path) => Some(path),
+            BearerToken::Value(_) | BearerToken::Source(_) => None,
+        }
+    }
+
+    /// The current token
+    pub fn read(&self) -> io::Result<String>
+    {
+        let token = match *self {
+            BearerToken::Value(ref token) => token.trim().to_owned(),
+            BearerToken::File(ref path) => try!(fs::read_to_string(path)).trim().to_owned(),
+            BearerToken::Source(ref source) => try!(source.token()),
+        };
+        if token.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the bearer token is empty"))
+        }
+        Ok(token)
+    }
+}
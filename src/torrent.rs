@@ -0,0 +1,300 @@
+//! Piece hashes from `.torrent` files
+//!
+//! A torrent's info dictionary holds the SHA-1 of every fixed size piece
+//! of its content. For a single file torrent these are authoritative
+//! block-level hashes for the same file served over HTTP(S), so a download
+//! can be verified piece by piece and only corrupt pieces fetched again.
+//! Nothing is ever fetched over BitTorrent.
+
+use sha1;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{
+    self,
+    Read,
+    Seek,
+    SeekFrom,
+};
+use std::path::Path;
+
+
+#[derive(Clone,Debug,PartialEq)]
+enum Bencode {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Bencode>),
+    Dict(BTreeMap<Vec<u8>, Bencode>),
+}
+
+impl Bencode {
+    fn get(&self, key: &str) -> Option<&Bencode>
+    {
+        match *self {
+            Bencode::Dict(ref dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    fn int(&self) -> Option<i64>
+    {
+        match *self {
+            Bencode::Int(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn bytes(&self) -> Option<&[u8]>
+    {
+        match *self {
+            Bencode::Bytes(ref bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+}
+
+/// Decode the bencoded value at the start of `input`, returning it and the
+/// rest of the input
+fn decode(input: &[u8]) -> Result<(Bencode, &[u8]), String>
+{
+    match input.first() {
+        Some(&b'i') => {
+            let end = try!(input.iter().position(|&b| b == b'e')
+                           .ok_or("unterminated integer".to_owned()));
+            let n = try!(parse_int(&input[1..end]));
+            Ok((Bencode::Int(n), &input[end + 1..]))
+        },
+        Some(&b'l') => {
+            let mut list = vec![];
+            let mut rest = &input[1..];
+            while rest.first() != Some(&b'e') {
+                let (value, next) = try!(decode(rest));
+                list.push(value);
+                rest = next;
+            }
+            Ok((Bencode::List(list), &rest[1..]))
+        },
+        Some(&b'd') => {
+            let mut dict = BTreeMap::new();
+            let mut rest = &input[1..];
+            while rest.first() != Some(&b'e') {
+                let (key, next) = try!(decode(rest));
+                let key = match key {
+                    Bencode::Bytes(key) => key,
+                    _ => return Err("dictionary key is not a string".to_owned()),
+                };
+                let (value, next) = try!(decode(next));
+                dict.insert(key, value);
+                rest = next;
+            }
+            Ok((Bencode::Dict(dict), &rest[1..]))
+        },
+        Some(&c) if (c as char).is_digit(10) => {
+            let colon = try!(input.iter().position(|&b| b == b':')
+                             .ok_or("unterminated string length".to_owned()));
+            let len = try!(parse_int(&input[..colon])) as usize;
+            let start = colon + 1;
+            if len > input.len() - start {
+                return Err("truncated string".to_owned())
+            }
+            Ok((Bencode::Bytes(input[start..start + len].to_vec()), &input[start + len..]))
+        },
+        Some(_) => Err("invalid bencoding".to_owned()),
+        None => Err("unexpected end of input".to_owned()),
+    }
+}
+
+fn parse_int(digits: &[u8]) -> Result<i64, String>
+{
+    String::from_utf8_lossy(digits).parse().map_err(|e| format!("invalid integer: {}", e))
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct PieceHashes {
+    /// The file name suggested by the torrent
+    pub name: String,
+    /// Length of the file
+    pub length: u64,
+    /// Length of every piece but the last
+    pub piece_length: u64,
+    /// SHA-1 of each piece
+    pub hashes: Vec<[u8; 20]>,
+}
+
+impl PieceHashes {
+
+    /// Read the piece hashes of a single file torrent
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<PieceHashes, String>
+    {
+        let path = path.as_ref();
+        let mut contents = vec![];
+        try!(File::open(path).and_then(|mut f| f.read_to_end(&mut contents)).map_err(|e| {
+            format!("unable to read {}: {}", path.display(), e)
+        }));
+        PieceHashes::parse(&contents)
+    }
+
+    /// Parse the contents of a single file torrent
+    pub fn parse(contents: &[u8]) -> Result<PieceHashes, String>
+    {
+        let (torrent, _) = try!(decode(contents));
+        let info = try!(torrent.get("info").ok_or("torrent has no info dictionary".to_owned()));
+        if info.get("files").is_some() {
+            return Err("multi-file torrents are not supported".to_owned())
+        }
+
+        let name = info.get("name").and_then(|n| n.bytes())
+            .map(|n| String::from_utf8_lossy(n).into_owned())
+            .unwrap_or_default();
+        let length = try!(info.get("length").and_then(|l| l.int())
+                          .ok_or("torrent has no length".to_owned()));
+        let piece_length = try!(info.get("piece length").and_then(|l| l.int())
+                                .ok_or("torrent has no piece length".to_owned()));
+        let pieces = try!(info.get("pieces").and_then(|p| p.bytes())
+                          .ok_or("torrent has no pieces".to_owned()));
+        if length < 0 || piece_length <= 0 || pieces.len() % 20 != 0 {
+            return Err("torrent has invalid lengths".to_owned())
+        }
+
+        let hashes: Vec<[u8; 20]> = pieces.chunks(20).map(|chunk| {
+            let mut hash = [0; 20];
+            hash.copy_from_slice(chunk);
+            hash
+        }).collect();
+        let expected = (length as u64 + piece_length as u64 - 1) / piece_length as u64;
+        if hashes.len() as u64 != expected {
+            return Err(format!("torrent has {} piece hashes, expected {}", hashes.len(), expected))
+        }
+
+        Ok(PieceHashes {
+            name: name,
+            length: length as u64,
+            piece_length: piece_length as u64,
+            hashes: hashes,
+        })
+    }
+
+    /// The offset and length of piece `index`
+    pub fn piece(&self, index: usize) -> (u64, u64)
+    {
+        let start = index as u64 * self.piece_length;
+        let len = if start + self.piece_length > self.length {
+            self.length - start
+        } else {
+            self.piece_length
+        };
+        (start, len)
+    }
+
+    /// The indices of the pieces of the file at `path` whose hash differs
+    pub fn corrupt_pieces<P: AsRef<Path>>(&self, path: P) -> io::Result<Vec<usize>>
+    {
+        let mut file = try!(File::open(path));
+        let mut buf = vec![0; self.piece_length as usize];
+        let mut corrupt = vec![];
+        for (index, expected) in self.hashes.iter().enumerate() {
+            let (start, len) = self.piece(index);
+            try!(file.seek(SeekFrom::Start(start)));
+            let piece = &mut buf[..len as usize];
+            let matches = match file.read_exact(piece) {
+                Ok(_) => {
+                    let mut hasher = sha1::Sha1::new();
+                    hasher.update(piece);
+                    hasher.digest().bytes() == *expected
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+                Err(e) => return Err(e),
+            };
+            if !matches {
+                corrupt.push(index);
+            }
+        }
+        Ok(corrupt)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, Bencode, PieceHashes};
+    use sha1;
+    use std::collections::BTreeMap;
+
+    fn torrent(name: &str, length: i64, piece_length: i64, pieces: &[u8]) -> Vec<u8>
+    {
+        let mut torrent = format!("d8:announce17:http://tracker/an4:infod6:lengthi{}e4:name{}:{}\
+                                   12:piece lengthi{}e6:pieces{}:",
+                                  length, name.len(), name, piece_length, pieces.len()).into_bytes();
+        torrent.extend_from_slice(pieces);
+        torrent.extend_from_slice(b"ee");
+        torrent
+    }
+
+    fn sha1(data: &[u8]) -> [u8; 20]
+    {
+        let mut hasher = sha1::Sha1::new();
+        hasher.update(data);
+        hasher.digest().bytes()
+    }
+
+    #[test]
+    fn decodes_values()
+    {
+        assert_eq!(decode(b"i42e"), Ok((Bencode::Int(42), &b""[..])));
+        assert_eq!(decode(b"i-3eextra"), Ok((Bencode::Int(-3), &b"extra"[..])));
+        assert_eq!(decode(b"4:spam"), Ok((Bencode::Bytes(b"spam".to_vec()), &b""[..])));
+        assert_eq!(decode(b"0:"), Ok((Bencode::Bytes(vec![]), &b""[..])));
+        assert_eq!(decode(b"l4:spami7ee"), Ok((Bencode::List(vec![
+            Bencode::Bytes(b"spam".to_vec()),
+            Bencode::Int(7),
+        ]), &b""[..])));
+
+        let mut dict = BTreeMap::new();
+        dict.insert(b"cow".to_vec(), Bencode::Bytes(b"moo".to_vec()));
+        dict.insert(b"list".to_vec(), Bencode::List(vec![]));
+        assert_eq!(decode(b"d3:cow3:moo4:listlee"), Ok((Bencode::Dict(dict), &b""[..])));
+    }
+
+    #[test]
+    fn refuses_malformed()
+    {
+        for input in &[&b""[..], b"x", b"i12", b"iabce", b"ie", b"l", b"li1e", b"d3:cow",
+                       b"d3:cow3:moo", b"di1e3:mooe", b"5:spam", b"4spam", b"-1:x",
+                       b"99999999999999999999:x", b"9223372036854775807:x"] {
+            assert!(decode(input).is_err(), "{:?}", String::from_utf8_lossy(input));
+        }
+    }
+
+    #[test]
+    fn parses_single_file()
+    {
+        let pieces = [sha1(b"abcd"), sha1(b"ef")];
+        let mut bytes = vec![];
+        for piece in &pieces {
+            bytes.extend_from_slice(piece);
+        }
+        let hashes = PieceHashes::parse(&torrent("a.iso", 6, 4, &bytes)).unwrap();
+        assert_eq!(hashes, PieceHashes {
+            name: "a.iso".to_owned(),
+            length: 6,
+            piece_length: 4,
+            hashes: pieces.to_vec(),
+        });
+        assert_eq!(hashes.piece(0), (0, 4));
+        assert_eq!(hashes.piece(1), (4, 2));
+    }
+
+    #[test]
+    fn refuses_invalid_torrents()
+    {
+        let hash = [0; 20];
+        assert!(PieceHashes::parse(b"de").is_err());
+        assert!(PieceHashes::parse(b"d4:infod5:filesleee").is_err());
+        assert!(PieceHashes::parse(&torrent("a", 6, 4, &hash)).is_err());
+        assert!(PieceHashes::parse(&torrent("a", 6, 4, &[0; 45])).is_err());
+        assert!(PieceHashes::parse(&torrent("a", 6, 0, &hash)).is_err());
+        assert!(PieceHashes::parse(&torrent("a", -1, 4, &hash)).is_err());
+
+        let truncated = torrent("a", 4, 4, &hash);
+        assert!(PieceHashes::parse(&truncated[..truncated.len() - 10]).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+//! Human readable formatting of byte counts and transfer rates
+
+use std::fmt;
+use std::str::FromStr;
+
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Units {
+    /// Powers of 1000 (kB, MB, GB, ...)
+    Si,
+    /// Powers of 1024 (KiB, MiB, GiB, ...)
+    Binary,
+}
+
+impl Default for Units {
+    fn default() -> Units
+    {
+        Units::Binary
+    }
+}
+
+impl FromStr for Units {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Units, String>
+    {
+        match s {
+            "si" => Ok(Units::Si),
+            "binary" => Ok(Units::Binary),
+            _ => Err(format!("unknown units '{}', expected 'si' or 'binary'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Units {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Units::Si => write!(f, "si"),
+            Units::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+impl Units {
+    /// The multiplier between successive prefixes
+    fn base(&self) -> f64
+    {
+        match *self {
+            Units::Si => 1000.0,
+            Units::Binary => 1024.0,
+        }
+    }
+
+    /// The unit suffixes in increasing order of magnitude
+    fn suffixes(&self) -> &'static [&'static str]
+    {
+        match *self {
+            Units::Si => &["B", "kB", "MB", "GB", "TB", "PB", "EB"],
+            Units::Binary => &["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"],
+        }
+    }
+}
+
+/// Format a byte count, e.g. `1.50 MiB` or `1.57 MB`
+pub fn format_bytes(bytes: u64, units: Units) -> String
+{
+    format_scaled(bytes as f64, units)
+}
+
+/// Format a transfer rate in bytes per second, e.g. `1.50 MiB/s`
+pub fn format_rate(bytes_per_sec: f64, units: Units) -> String
+{
+    format!("{}/s", format_scaled(bytes_per_sec, units))
+}
+
+/// Parse a byte count with an optional binary suffix, e.g. `512K` or `2M`
+pub fn parse_bytes(s: &str) -> Result<u64, String>
+{
+    let s = s.trim();
+    let (digits, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    digits.parse::<u64>()
+        .map(|n| n * multiplier)
+        .map_err(|e| format!("invalid byte count '{}': {}", s, e))
+}
+
+/// Format an integer with thousands separators, e.g. `1,234,567`
+pub fn group_thousands(n: u64) -> String
+{
+    let digits = n.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    grouped
+}
+
+fn format_scaled(value: f64, units: Units) -> String
+{
+    let suffixes = units.suffixes();
+    let mut value = value;
+    let mut i = 0;
+    while value >= units.base() && i < suffixes.len() - 1 {
+        value /= units.base();
+        i += 1;
+    }
+
+    if i == 0 {
+        format!("{} {}", group_thousands(value as u64), suffixes[i])
+    } else {
+        format!("{:.2} {}", value, suffixes[i])
+    }
+}
@@ -0,0 +1,100 @@
+//! Validation and normalization of user supplied urls
+
+use hyper::Url;
+use schemes;
+
+
+/// Schemes that can be downloaded from
+pub const SUPPORTED_SCHEMES: &'static [&'static str] = &["http", "https", "ftp", "ftps", "ftpes"];
+
+/// A url ready to be requested
+#[derive(Clone,Debug)]
+pub struct NormalizedUrl {
+    pub url: Url,
+    /// Whether the scheme was missing and https was assumed
+    pub inferred_scheme: bool,
+    /// The user name and password given in the url (decoded), which are
+    /// removed from `url` so they aren't logged or recorded
+    pub credentials: Option<(String, Option<String>)>,
+}
+
+/// Parse a url, assuming https if no scheme was given (e.g. `example.com/file.iso`)
+pub fn normalize_url(input: &str) -> Result<NormalizedUrl, String>
+{
+    let trimmed = input.trim();
+    if trimmed.is_empty() {
+        return Err("url is empty".to_owned())
+    }
+
+    let (candidate, inferred) = if trimmed.contains("://") {
+        (trimmed.to_owned(), false)
+    } else if trimmed.starts_with("//") {
+        (format!("https:{}", trimmed), true)
+    } else {
+        (format!("https://{}", trimmed), true)
+    };
+
+    let url = match Url::parse(&*candidate) {
+        Ok(url) => url,
+        Err(e) if inferred => return Err(format!(
+            "'{}' is not a valid url ({}); expected something like https://example.com/file",
+            input, e)),
+        Err(e) => return Err(format!("'{}' is not a valid url: {}", input, e)),
+    };
+
+    // Urls of a registered scheme are the handler's to interpret
+    if schemes::handler(url.scheme()).is_some() {
+        if url.cannot_be_a_base() {
+            return Err(format!("'{}' has no path to download", input))
+        }
+        return Ok(NormalizedUrl { url: url, inferred_scheme: false, credentials: None })
+    }
+
+    if !SUPPORTED_SCHEMES.contains(&url.scheme()) {
+        let mut supported: Vec<String> = SUPPORTED_SCHEMES.iter().map(|s| (*s).to_owned()).collect();
+        supported.extend(schemes::registered_schemes());
+        return Err(format!("unsupported scheme '{}' in {}, expected one of: {}",
+                           url.scheme(), input, supported.join(", ")))
+    }
+
+    match url.host_str() {
+        Some(host) if !host.is_empty() => (),
+        _ => return Err(format!("'{}' has no host to download from", input)),
+    }
+
+    let mut url = url;
+    let credentials = if url.username().is_empty() && url.password().is_none() {
+        None
+    } else {
+        let credentials = (percent_decode(url.username()), url.password().map(percent_decode));
+        let _ = url.set_username("");
+        let _ = url.set_password(None);
+        Some(credentials)
+    };
+
+    if inferred {
+        debug!("no scheme given, assuming {}", url);
+    }
+
+    Ok(NormalizedUrl { url: url, inferred_scheme: inferred, credentials: credentials })
+}
+
+/// Decode the `%XX` escapes of a url component
+pub fn percent_decode(s: &str) -> String
+{
+    let bytes = s.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(byte) = s.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                out.push(byte);
+                i += 3;
+                continue
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,127 @@
+//! Bandwidth used per host and per day
+//!
+//! Bytes downloaded are added up per host for each local day in a small
+//! database, so users on metered connections or egress budgets can see
+//! their usage and cap it. It is read from `$SLEDGE_USAGE` if set,
+//! otherwise from `$XDG_DATA_HOME/sledge/usage` or
+//! `~/.local/share/sledge/usage`, and holds a `<day> <host> <bytes>` line
+//! per host and day.
+
+use chrono::Local;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{
+    self,
+    File,
+};
+use std::io::{
+    self,
+    BufRead,
+    BufReader,
+    Write,
+};
+use std::path::PathBuf;
+use units::parse_bytes;
+
+
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Usage {
+    /// Bytes downloaded, by day (`YYYY-MM-DD`) and host
+    pub totals: BTreeMap<(String, String), u64>,
+}
+
+impl Usage {
+
+    /// Location of the usage database
+    pub fn path() -> Option<PathBuf>
+    {
+        if let Ok(path) = env::var("SLEDGE_USAGE") {
+            return Some(PathBuf::from(path))
+        }
+        let dir = match env::var("XDG_DATA_HOME") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => match env::var("HOME") {
+                Ok(home) => PathBuf::from(home).join(".local").join("share"),
+                Err(_) => return None,
+            },
+        };
+        Some(dir.join("sledge").join("usage"))
+    }
+
+    /// Load the usage database, or no usage if there isn't one
+    pub fn load() -> io::Result<Usage>
+    {
+        let path = match Usage::path() {
+            Some(path) => path,
+            None => return Ok(Usage::default()),
+        };
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Usage::default()),
+            Err(e) => return Err(e),
+        };
+        let mut usage = Usage::default();
+        for line in BufReader::new(file).lines() {
+            let line = try!(line);
+            let fields: Vec<&str> = line.split(' ').collect();
+            match (fields.len(), fields.get(2).map(|b| b.parse::<u64>())) {
+                (3, Some(Ok(bytes))) => {
+                    usage.totals.insert((fields[0].to_owned(), fields[1].to_owned()), bytes);
+                },
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "{}: invalid line '{}'", path.display(), line))),
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Add `bytes` downloaded from `host` today to the database. The
+    /// database is reloaded first, so concurrent runs don't lose usage.
+    pub fn record(host: &str, bytes: u64) -> io::Result<()>
+    {
+        let path = match Usage::path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut usage = try!(Usage::load());
+        *usage.totals.entry((today(), host.to_owned())).or_insert(0) += bytes;
+
+        if let Some(dir) = path.parent() {
+            try!(fs::create_dir_all(dir));
+        }
+        // Replace the database whole, so a crash can't leave it half written
+        let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+        {
+            let mut file = try!(File::create(&tmp));
+            for (&(ref day, ref host), bytes) in &usage.totals {
+                try!(writeln!(file, "{} {} {}", day, host, bytes));
+            }
+            try!(file.sync_all());
+        }
+        fs::rename(&tmp, &path)
+    }
+
+    /// Bytes downloaded from `host` today
+    pub fn today(&self, host: &str) -> u64
+    {
+        self.totals.get(&(today(), host.to_owned())).cloned().unwrap_or(0)
+    }
+}
+
+/// The local day, as `YYYY-MM-DD`
+fn today() -> String
+{
+    Local::now().format("%Y-%m-%d").to_string()
+}
+
+/// Parse a `host=BYTES` daily quota
+pub fn parse_quota(quota: &str) -> Result<(String, u64), String>
+{
+    match quota.find('=') {
+        Some(eq) if eq > 0 => {
+            let bytes = try!(parse_bytes(&quota[eq + 1..]));
+            Ok((quota[..eq].to_lowercase(), bytes))
+        },
+        _ => Err(format!("expected host=BYTES, not '{}'", quota)),
+    }
+}
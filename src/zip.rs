@@ -0,0 +1,458 @@
+//! Reading members of a zip archive without reading all of it
+//!
+//! The central directory at the end of a zip lists every member with its
+//! offset, so given a seekable reader (e.g. an `HttpReader`) only the
+//! directory and the requested members need to be read. Stored and
+//! deflated members are supported, including zip64 archives.
+
+use flate2::CrcReader;
+use flate2::read::DeflateDecoder;
+use std::cmp;
+use std::io::{
+    self,
+    Read,
+    Seek,
+    SeekFrom,
+    Write,
+};
+use std::path::{
+    Component,
+    Path,
+    PathBuf,
+};
+
+
+const END_OF_DIRECTORY: u32 = 0x06054b50;
+const ZIP64_END_OF_DIRECTORY: u32 = 0x06064b50;
+const ZIP64_LOCATOR: u32 = 0x07064b50;
+const DIRECTORY_ENTRY: u32 = 0x02014b50;
+const LOCAL_HEADER: u32 = 0x04034b50;
+
+/// The end of directory record is 22 bytes plus a comment of up to 64 KB
+const MAX_END_SEARCH: u64 = 22 + 0xffff;
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct ZipMember {
+    /// Path of the member within the archive, `/` separated
+    pub name: String,
+    /// Compression method (0 is stored, 8 is deflated)
+    pub method: u16,
+    pub crc32: u32,
+    pub compressed_size: u64,
+    pub size: u64,
+    /// Offset of the member's local header in the archive
+    pub header_offset: u64,
+}
+
+impl ZipMember {
+    pub fn is_dir(&self) -> bool
+    {
+        self.name.ends_with('/')
+    }
+
+    /// Where the member is extracted to under `dir`, or None if its name
+    /// would lead outside of it
+    pub fn local_path(&self, dir: &Path) -> Option<PathBuf>
+    {
+        let relative = Path::new(&*self.name);
+        let normal = relative.components().all(|c| match c {
+            Component::Normal(_) => true,
+            _ => false,
+        });
+        if normal && !self.name.is_empty() {
+            Some(dir.join(relative))
+        } else {
+            None
+        }
+    }
+}
+
+/// List the members of the archive from its central directory
+pub fn members<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<ZipMember>>
+{
+    let (count, size, offset) = try!(find_directory(reader));
+    try!(reader.seek(SeekFrom::Start(offset)));
+    let mut directory = vec![];
+    try!(reader.by_ref().take(size).read_to_end(&mut directory));
+
+    let mut members = vec![];
+    let mut pos = 0;
+    for _ in 0..count {
+        let entry = try!(slice(&directory, pos, 46));
+        if le32(entry, 0) != DIRECTORY_ENTRY {
+            return Err(invalid("corrupt central directory"))
+        }
+        let name_len = le16(entry, 28) as usize;
+        let extra_len = le16(entry, 30) as usize;
+        let comment_len = le16(entry, 32) as usize;
+        let name = try!(slice(&directory, pos + 46, name_len));
+        let extra = try!(slice(&directory, pos + 46 + name_len, extra_len));
+
+        let mut member = ZipMember {
+            name: String::from_utf8_lossy(name).into_owned(),
+            method: le16(entry, 10),
+            crc32: le32(entry, 16),
+            compressed_size: le32(entry, 20) as u64,
+            size: le32(entry, 24) as u64,
+            header_offset: le32(entry, 42) as u64,
+        };
+        try!(apply_zip64_extra(&mut member, extra));
+        members.push(member);
+        pos += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(members)
+}
+
+/// Write the uncompressed contents of `member` to `out`, checking its CRC
+pub fn extract<R: Read + Seek, W: Write>(reader: &mut R, member: &ZipMember, out: &mut W)
+                                          -> io::Result<u64>
+{
+    try!(reader.seek(SeekFrom::Start(member.header_offset)));
+    let mut header = [0; 30];
+    try!(reader.read_exact(&mut header));
+    if le32(&header, 0) != LOCAL_HEADER {
+        return Err(invalid(&*format!("corrupt local header for {}", member.name)))
+    }
+    // The local name and extra field may differ from the directory's
+    let skip = le16(&header, 26) as i64 + le16(&header, 28) as i64;
+    try!(reader.seek(SeekFrom::Current(skip)));
+
+    let data = reader.by_ref().take(member.compressed_size);
+    let (written, crc) = match member.method {
+        0 => {
+            let mut data = CrcReader::new(data);
+            let written = try!(io::copy(&mut data, out));
+            (written, data.crc().sum())
+        },
+        8 => {
+            let mut data = CrcReader::new(DeflateDecoder::new(data));
+            let written = try!(io::copy(&mut data, out));
+            (written, data.crc().sum())
+        },
+        method => return Err(io::Error::new(io::ErrorKind::Other, format!(
+            "{} uses unsupported compression method {}", member.name, method))),
+    };
+
+    if written != member.size || crc != member.crc32 {
+        return Err(invalid(&*format!("{} is corrupt (crc or size mismatch)", member.name)))
+    }
+    Ok(written)
+}
+
+/// The entry count, size and offset of the central directory
+fn find_directory<R: Read + Seek>(reader: &mut R) -> io::Result<(u64, u64, u64)>
+{
+    let len = try!(reader.seek(SeekFrom::End(0)));
+    let search = cmp::min(len, MAX_END_SEARCH);
+    try!(reader.seek(SeekFrom::Start(len - search)));
+    let mut tail = vec![];
+    try!(reader.by_ref().take(search).read_to_end(&mut tail));
+
+    let end = match (0..tail.len().saturating_sub(21)).rev()
+        .find(|&i| le32(&tail, i) == END_OF_DIRECTORY) {
+            Some(end) => end,
+            None => return Err(invalid("not a zip archive (no end of central directory)")),
+        };
+    let record = &tail[end..];
+    let count = le16(record, 10) as u64;
+    let size = le32(record, 12) as u64;
+    let offset = le32(record, 16) as u64;
+
+    if count != 0xffff && size != 0xffffffff && offset != 0xffffffff {
+        return Ok((count, size, offset))
+    }
+
+    // A zip64 locator precedes the record, pointing to the zip64 record
+    if end < 20 || le32(&tail, end - 20) != ZIP64_LOCATOR {
+        return Err(invalid("zip64 archive is missing its locator"))
+    }
+    let zip64_offset = le64(&tail, end - 20 + 8);
+    try!(reader.seek(SeekFrom::Start(zip64_offset)));
+    let mut record = [0; 56];
+    try!(reader.read_exact(&mut record));
+    if le32(&record, 0) != ZIP64_END_OF_DIRECTORY {
+        return Err(invalid("corrupt zip64 end of central directory"))
+    }
+    Ok((le64(&record, 32), le64(&record, 40), le64(&record, 48)))
+}
+
+/// Replace saturated sizes and offsets with those in the zip64 extra field
+fn apply_zip64_extra(member: &mut ZipMember, extra: &[u8]) -> io::Result<()>
+{
+    let mut pos = 0;
+    while pos + 4 <= extra.len() {
+        let id = le16(extra, pos);
+        let len = le16(extra, pos + 2) as usize;
+        let field = try!(slice(extra, pos + 4, len));
+        pos += 4 + len;
+        if id != 0x0001 {
+            continue;
+        }
+
+        // Only the saturated values are present, in this order
+        let mut values = field.chunks(8).filter(|c| c.len() == 8).map(|c| le64(c, 0));
+        let truncated = || invalid("truncated zip64 field");
+        if member.size == 0xffffffff {
+            member.size = try!(values.next().ok_or_else(&truncated));
+        }
+        if member.compressed_size == 0xffffffff {
+            member.compressed_size = try!(values.next().ok_or_else(&truncated));
+        }
+        if member.header_offset == 0xffffffff {
+            member.header_offset = try!(values.next().ok_or_else(&truncated));
+        }
+    }
+    Ok(())
+}
+
+fn slice(buf: &[u8], start: usize, len: usize) -> io::Result<&[u8]>
+{
+    if start + len > buf.len() {
+        return Err(invalid("truncated central directory"))
+    }
+    Ok(&buf[start..start + len])
+}
+
+fn invalid(message: &str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn le16(buf: &[u8], at: usize) -> u16
+{
+    buf[at] as u16 | (buf[at + 1] as u16) << 8
+}
+
+fn le32(buf: &[u8], at: usize) -> u32
+{
+    le16(buf, at) as u32 | (le16(buf, at + 2) as u32) << 16
+}
+
+fn le64(buf: &[u8], at: usize) -> u64
+{
+    le32(buf, at) as u64 | (le32(buf, at + 4) as u64) << 32
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{extract, members, ZipMember};
+    use std::io::Cursor;
+    use std::path::Path;
+
+    // "hello hello hello hello\n" deflated, and the CRC-32 of each member
+    const DEFLATED: &'static [u8] = &[203, 72, 205, 201, 201, 87, 200, 64, 39, 185, 0];
+    const HELLO_CRC: u32 = 0x0b598800;
+    const STORED_CRC: u32 = 0xa5539ce2;
+
+    struct Entry<'a> {
+        name: &'a str,
+        method: u16,
+        crc32: u32,
+        data: &'a [u8],
+        size: u64,
+    }
+
+    fn put16(buf: &mut Vec<u8>, n: u16)
+    {
+        buf.extend_from_slice(&[n as u8, (n >> 8) as u8]);
+    }
+
+    fn put32(buf: &mut Vec<u8>, n: u32)
+    {
+        put16(buf, n as u16);
+        put16(buf, (n >> 16) as u16);
+    }
+
+    fn put64(buf: &mut Vec<u8>, n: u64)
+    {
+        put32(buf, n as u32);
+        put32(buf, (n >> 32) as u32);
+    }
+
+    /// Build an archive of `entries`, with zip64 records and extra fields
+    /// if `zip64` is set
+    fn archive(entries: &[Entry], zip64: bool) -> Vec<u8>
+    {
+        let mut zip = vec![];
+        let mut offsets = vec![];
+        for entry in entries {
+            offsets.push(zip.len() as u64);
+            put32(&mut zip, 0x04034b50);
+            zip.extend_from_slice(&[20, 0, 0, 0]);
+            put16(&mut zip, entry.method);
+            put32(&mut zip, 0);
+            put32(&mut zip, entry.crc32);
+            put32(&mut zip, entry.data.len() as u32);
+            put32(&mut zip, entry.size as u32);
+            put16(&mut zip, entry.name.len() as u16);
+            put16(&mut zip, 0);
+            zip.extend_from_slice(entry.name.as_bytes());
+            zip.extend_from_slice(entry.data);
+        }
+
+        let directory = zip.len() as u64;
+        for (entry, &offset) in entries.iter().zip(&offsets) {
+            let saturated = if zip64 { 0xffffffff } else { 0 };
+            put32(&mut zip, 0x02014b50);
+            zip.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            put16(&mut zip, entry.method);
+            put32(&mut zip, 0);
+            put32(&mut zip, entry.crc32);
+            put32(&mut zip, saturated | entry.data.len() as u32);
+            put32(&mut zip, saturated | entry.size as u32);
+            put16(&mut zip, entry.name.len() as u16);
+            put16(&mut zip, if zip64 { 28 } else { 0 });
+            zip.extend_from_slice(&[0; 10]);
+            put32(&mut zip, saturated | offset as u32);
+            zip.extend_from_slice(entry.name.as_bytes());
+            if zip64 {
+                put16(&mut zip, 0x0001);
+                put16(&mut zip, 24);
+                put64(&mut zip, entry.size);
+                put64(&mut zip, entry.data.len() as u64);
+                put64(&mut zip, offset);
+            }
+        }
+        let size = zip.len() as u64 - directory;
+
+        if zip64 {
+            let record = zip.len() as u64;
+            put32(&mut zip, 0x06064b50);
+            put64(&mut zip, 44);
+            zip.extend_from_slice(&[45, 0, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            put64(&mut zip, entries.len() as u64);
+            put64(&mut zip, entries.len() as u64);
+            put64(&mut zip, size);
+            put64(&mut zip, directory);
+            put32(&mut zip, 0x07064b50);
+            put32(&mut zip, 0);
+            put64(&mut zip, record);
+            put32(&mut zip, 1);
+        }
+
+        put32(&mut zip, 0x06054b50);
+        put32(&mut zip, 0);
+        if zip64 {
+            zip.extend_from_slice(&[0xff; 12]);
+        } else {
+            put16(&mut zip, entries.len() as u16);
+            put16(&mut zip, entries.len() as u16);
+            put32(&mut zip, size as u32);
+            put32(&mut zip, directory as u32);
+        }
+        let comment = b"an archive comment";
+        put16(&mut zip, comment.len() as u16);
+        zip.extend_from_slice(comment);
+        zip
+    }
+
+    fn entries<'a>() -> Vec<Entry<'a>>
+    {
+        vec![
+            Entry { name: "docs/", method: 0, crc32: 0, data: b"", size: 0 },
+            Entry { name: "docs/stored.txt", method: 0, crc32: STORED_CRC, data: b"stored\n", size: 7 },
+            Entry { name: "hello.txt", method: 8, crc32: HELLO_CRC, data: DEFLATED, size: 24 },
+        ]
+    }
+
+    fn extracted(zip: &[u8], member: &ZipMember) -> Vec<u8>
+    {
+        let mut out = vec![];
+        let written = extract(&mut Cursor::new(zip), member, &mut out).unwrap();
+        assert_eq!(written, out.len() as u64);
+        out
+    }
+
+    #[test]
+    fn lists_and_extracts_members()
+    {
+        for &zip64 in &[false, true] {
+            let zip = archive(&entries(), zip64);
+            let members = members(&mut Cursor::new(&zip)).unwrap();
+            assert_eq!(members.iter().map(|m| &*m.name).collect::<Vec<_>>(),
+                       vec!["docs/", "docs/stored.txt", "hello.txt"]);
+            assert!(members[0].is_dir() && !members[1].is_dir());
+            assert_eq!(members[1], ZipMember {
+                name: "docs/stored.txt".to_owned(),
+                method: 0,
+                crc32: STORED_CRC,
+                compressed_size: 7,
+                size: 7,
+                header_offset: 35,
+            });
+            assert_eq!(members[2].compressed_size, DEFLATED.len() as u64);
+            assert_eq!(members[2].size, 24);
+
+            assert_eq!(extracted(&zip, &members[0]), b"");
+            assert_eq!(extracted(&zip, &members[1]), b"stored\n");
+            assert_eq!(extracted(&zip, &members[2]), &b"hello hello hello hello\n"[..]);
+        }
+    }
+
+    #[test]
+    fn checks_crc_and_method()
+    {
+        let mut entries = entries();
+        entries[1].crc32 ^= 1;
+        entries[2].method = 12;
+        let zip = archive(&entries, false);
+        let members = members(&mut Cursor::new(&zip)).unwrap();
+        assert!(extract(&mut Cursor::new(&zip), &members[1], &mut vec![]).is_err());
+        assert!(extract(&mut Cursor::new(&zip), &members[2], &mut vec![]).is_err());
+
+        let mut moved = members[1].clone();
+        moved.header_offset += 1;
+        assert!(extract(&mut Cursor::new(&zip), &moved, &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn refuses_malformed_archives()
+    {
+        let zip = archive(&entries(), false);
+        let zip64 = archive(&entries(), true);
+        let directory = zip.len() - 22 - 18 - (46 * 3 + 5 + 15 + 9);
+        let locator = zip64.len() - 22 - 18 - 20;
+
+        assert!(members(&mut Cursor::new(&b""[..])).is_err());
+        assert!(members(&mut Cursor::new(&b"PK\x05\x06"[..])).is_err());
+        assert!(members(&mut Cursor::new(&zip[..zip.len() - 30])).is_err());
+        assert!(members(&mut Cursor::new(&zip[directory..])).is_err());
+
+        // A corrupt entry signature, and a count beyond the directory
+        let mut corrupt = zip.clone();
+        corrupt[directory] ^= 0xff;
+        assert!(members(&mut Cursor::new(&corrupt)).is_err());
+        let mut overcounted = zip.clone();
+        let count = zip.len() - 18 - 12;
+        overcounted[count] = 4;
+        assert!(members(&mut Cursor::new(&overcounted)).is_err());
+
+        // Saturated zip64 values without a locator or record
+        let mut missing = zip64.clone();
+        missing[locator] ^= 0xff;
+        assert!(members(&mut Cursor::new(&missing)).is_err());
+        let mut misplaced = zip64.clone();
+        misplaced[locator + 8] ^= 1;
+        assert!(members(&mut Cursor::new(&misplaced)).is_err());
+    }
+
+    #[test]
+    fn local_paths_stay_inside()
+    {
+        let member = |name: &str| ZipMember {
+            name: name.to_owned(),
+            method: 0,
+            crc32: 0,
+            compressed_size: 0,
+            size: 0,
+            header_offset: 0,
+        };
+        let dir = Path::new("/out");
+        assert_eq!(member("a/b.txt").local_path(dir), Some(dir.join("a/b.txt")));
+        assert_eq!(member("../b.txt").local_path(dir), None);
+        assert_eq!(member("a/../../b.txt").local_path(dir), None);
+        assert_eq!(member("/etc/passwd").local_path(dir), None);
+        assert_eq!(member("").local_path(dir), None);
+    }
+}